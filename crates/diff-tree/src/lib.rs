@@ -21,177 +21,4771 @@
 
 use pyo3::prelude::*;
 
-use pyo3::exceptions::PyTypeError;
-use pyo3::types::{PyBytes, PyList, PyTuple};
+use pyo3::buffer::PyBuffer;
+use pyo3::exceptions::{PyKeyError, PyTypeError, PyValueError};
+use pyo3::types::{PyBytes, PyDict, PyList, PyTuple};
 use pyo3::Python;
 
+use rayon::prelude::*;
+use xxhash_rust::xxh3::xxh3_64;
+
+use std::borrow::Cow;
 use std::cmp::Ordering;
+use std::collections::{hash_map, BTreeMap, HashMap, VecDeque};
+use std::convert::Infallible;
+use std::iter::Peekable;
+use std::ops::Range;
+use std::rc::Rc;
+use std::sync::Arc;
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+const S_IFGITLINK: u32 = 0o160000;
+
+const MAX_SCORE: usize = 100;
+
+/// The number of trees whose entries _tree_changes caches by default.
+const DEFAULT_TREE_CACHE_SIZE: usize = 256;
+
+/// The SHA-1 and SHA-256 ids of the empty tree.
+const EMPTY_TREE_IDS: [&[u8]; 2] = [
+    b"4b825dc642cb6eb9a060e54bf8d69288fbee4904",
+    b"6ef19b41225c5369f1c104d45d8d85efa9b057b53b14b4b9b939dd74decc5321",
+];
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// The hash function used to key block counts.
+#[derive(Clone, Copy, Default)]
+enum BlockHash {
+    /// Python's hash of the block, which is randomized per process.
+    #[default]
+    Python,
+    /// 64-bit FNV-1a, which is the same across processes.
+    Fnv1a,
+    /// 64-bit XXH3, which is the same across processes and has fewer
+    /// collisions than FNV-1a.
+    Xxh3,
+}
+
+impl BlockHash {
+    fn new(stable_hash: bool) -> Self {
+        if stable_hash {
+            BlockHash::Fnv1a
+        } else {
+            BlockHash::Python
+        }
+    }
+
+    /// The block hash for a hash_algo of _count_blocks, which stable_hash
+    /// can only be combined with for FNV-1a.
+    fn parse(hash_algo: &str, stable_hash: bool) -> PyResult<Self> {
+        match hash_algo {
+            "py" => Ok(BlockHash::new(stable_hash)),
+            "fnv" => Ok(BlockHash::Fnv1a),
+            "xxh3" if stable_hash => Err(PyValueError::new_err(
+                "stable_hash cannot be combined with hash_algo 'xxh3'",
+            )),
+            "xxh3" => Ok(BlockHash::Xxh3),
+            _ => Err(PyValueError::new_err(format!(
+                "invalid hash_algo: {hash_algo:?}"
+            ))),
+        }
+    }
+}
+
+fn fnv1a(data: &[u8]) -> u64 {
+    fnv1a_update(FNV_OFFSET_BASIS, data)
+}
+
+/// Continue an FNV-1a hash with more data.
+fn fnv1a_update(hash: u64, data: &[u8]) -> u64 {
+    data.iter().fold(hash, |hash, c| {
+        (hash ^ u64::from(*c)).wrapping_mul(FNV_PRIME)
+    })
+}
+
+/// Hash a block.
+///
+/// Stable hashes are unsigned, but are returned as i64 bits so that both
+/// kinds of hash fit in the same map; block_counts_to_dict converts them
+/// back.
+fn hash_block(string: &[u8], block_hash: BlockHash, py: Python) -> PyResult<i64> {
+    Ok(match block_hash {
+        BlockHash::Python => PyBytes::new(py, string).hash()? as i64,
+        BlockHash::Fnv1a => fnv1a(string) as i64,
+        BlockHash::Xxh3 => xxh3_64(string) as i64,
+    })
+}
+
+/// Add a block to the block counts.
+fn add_hash(
+    counts: &mut HashMap<i64, usize>,
+    string: &[u8],
+    block_hash: BlockHash,
+    py: Python,
+) -> PyResult<()> {
+    let hash = hash_block(string, block_hash, py)?;
+    *counts.entry(hash).or_insert(0) += string.len();
+    Ok(())
+}
+
+fn block_counts_to_dict<'py>(
+    py: Python<'py>,
+    counts: &HashMap<i64, usize>,
+    block_hash: BlockHash,
+) -> PyResult<Bound<'py, PyDict>> {
+    let dict = PyDict::new(py);
+    for (hash, count) in counts {
+        match block_hash {
+            BlockHash::Python => dict.set_item(hash, count)?,
+            BlockHash::Fnv1a | BlockHash::Xxh3 => dict.set_item(*hash as u64, count)?,
+        }
+    }
+    Ok(dict)
+}
+
+fn resolve_block_size(py: Python, block_size: Option<usize>) -> PyResult<usize> {
+    match block_size {
+        Some(block_size) => Ok(block_size),
+        None => py
+            .import("dulwich.diff_tree")?
+            .getattr("_BLOCK_SIZE")?
+            .extract::<usize>(),
+    }
+}
+
+fn resolve_rolling_window(py: Python) -> PyResult<usize> {
+    py.import("dulwich.diff_tree")?
+        .getattr("_ROLLING_WINDOW")?
+        .extract::<usize>()
+}
+
+const ROLLING_BASE: u64 = 0x9E3779B97F4A7C15;
+
+/// A polynomial rolling hash over the last window bytes.
+struct RollingHash {
+    window: usize,
+    out_factor: u64,
+    history: VecDeque<u8>,
+    hash: u64,
+}
+
+impl RollingHash {
+    fn new(window: usize) -> Self {
+        let exp = u32::try_from(window).unwrap_or(u32::MAX);
+        RollingHash {
+            window,
+            out_factor: ROLLING_BASE.wrapping_pow(exp),
+            history: VecDeque::with_capacity(window + 1),
+            hash: 0,
+        }
+    }
+
+    /// Add a byte, returning the new hash or None if fewer than window bytes
+    /// have been seen.
+    fn roll(&mut self, c: u8) -> Option<u64> {
+        self.hash = self
+            .hash
+            .wrapping_mul(ROLLING_BASE)
+            .wrapping_add(u64::from(c));
+        self.history.push_back(c);
+        if self.history.len() > self.window {
+            let old = self.history.pop_front().unwrap_or(0);
+            self.hash = self
+                .hash
+                .wrapping_sub(u64::from(old).wrapping_mul(self.out_factor));
+        }
+        (self.history.len() == self.window).then_some(self.hash)
+    }
+}
+
+/// What ends a line when splitting blocks: see _count_blocks in
+/// dulwich.diff_tree.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+enum LineEndings {
+    #[default]
+    Lf,
+    Crlf,
+    Any,
+}
+
+impl LineEndings {
+    fn parse(line_endings: &str) -> PyResult<Self> {
+        match line_endings {
+            "lf" => Ok(LineEndings::Lf),
+            "crlf" => Ok(LineEndings::Crlf),
+            "any" => Ok(LineEndings::Any),
+            _ => Err(PyValueError::new_err(format!(
+                "invalid line_endings: {line_endings:?}"
+            ))),
+        }
+    }
+}
+
+/// Splits data into blocks, either on lines or after block_size bytes.
+///
+/// Data can be fed in several pieces; a block that spans two pieces is
+/// carried over until it is complete.
+///
+/// With a rolling hash, blocks are content-defined instead: see
+/// _rolling_blocks in dulwich.diff_tree.
+struct BlockSplitter {
+    block_size: usize,
+    delimiter: u8,
+    line_endings: LineEndings,
+    /// Whether block ends with a CR that may yet be followed by a newline.
+    pending_cr: bool,
+    block: Vec<u8>,
+    rolling: Option<RollingHash>,
+}
+
+impl BlockSplitter {
+    fn new(block_size: usize) -> Self {
+        Self::with_delimiter(block_size, b'\n')
+    }
+
+    /// Split on delimiter rather than on lines.
+    fn with_delimiter(block_size: usize, delimiter: u8) -> Self {
+        BlockSplitter {
+            block_size,
+            delimiter,
+            line_endings: LineEndings::Lf,
+            pending_cr: false,
+            block: Vec::with_capacity(block_size),
+            rolling: None,
+        }
+    }
+
+    /// Split on lines ended as described by line_endings.
+    fn with_line_endings(block_size: usize, line_endings: LineEndings) -> Self {
+        BlockSplitter {
+            line_endings,
+            ..Self::new(block_size)
+        }
+    }
+
+    fn new_rolling(block_size: usize, window: usize) -> Self {
+        BlockSplitter {
+            block_size,
+            delimiter: b'\n',
+            line_endings: LineEndings::Lf,
+            pending_cr: false,
+            block: Vec::with_capacity(4 * block_size),
+            rolling: Some(RollingHash::new(window)),
+        }
+    }
+
+    fn is_boundary(&mut self, c: u8) -> bool {
+        match &mut self.rolling {
+            Some(rolling) => {
+                let hash = rolling.roll(c);
+                hash.is_some_and(|hash| (hash >> 32) % self.block_size as u64 == 0)
+                    || self.block.len() == 4 * self.block_size
+            }
+            None if self.block.len() == self.block_size => true,
+            None => match self.line_endings {
+                LineEndings::Lf => c == self.delimiter,
+                LineEndings::Crlf => c == b'\n' && self.block.ends_with(b"\r\n"),
+                LineEndings::Any => {
+                    // A CR ends a line unless a newline follows it, which
+                    // is only known once the next byte arrives.
+                    self.pending_cr = c == b'\r';
+                    c == b'\n'
+                }
+            },
+        }
+    }
+
+    fn feed<E, F>(&mut self, data: &[u8], mut emit: F) -> Result<(), E>
+    where
+        F: FnMut(&[u8]) -> Result<(), E>,
+    {
+        for c in data {
+            if self.pending_cr {
+                self.pending_cr = false;
+                if *c != b'\n' {
+                    emit(&self.block)?;
+                    self.block.clear();
+                }
+            }
+            self.block.push(*c);
+            if self.is_boundary(*c) {
+                emit(&self.block)?;
+                self.block.clear();
+            }
+        }
+        Ok(())
+    }
+
+    fn finish<E, F>(&mut self, mut emit: F) -> Result<(), E>
+    where
+        F: FnMut(&[u8]) -> Result<(), E>,
+    {
+        self.pending_cr = false;
+        if !self.block.is_empty() {
+            emit(&self.block)?;
+            self.block.clear();
+        }
+        Ok(())
+    }
+}
+
+/// Run f with the GIL released.
+///
+/// Python::allow_threads is deprecated from pyo3 0.26 in favour of
+/// Python::detach, which pyo3 0.25 doesn't have yet.
+#[allow(deprecated)]
+fn allow_threads<T, F>(py: Python, f: F) -> T
+where
+    F: pyo3::marker::Ungil + FnOnce() -> T,
+    T: pyo3::marker::Ungil,
+{
+    py.allow_threads(f)
+}
+
+/// Get the contents of a chunk, which can be any object supporting the
+/// buffer protocol.
+///
+/// The contents of bytes objects are borrowed; other buffers may be mutable,
+/// so they are copied.
+fn chunk_data<'a>(py: Python, chunk: &'a Bound<PyAny>) -> PyResult<Cow<'a, [u8]>> {
+    if let Ok(data) = chunk.extract::<&[u8]>() {
+        return Ok(Cow::Borrowed(data));
+    }
+    let buffer =
+        PyBuffer::<u8>::get(chunk).map_err(|_| PyTypeError::new_err("chunk is not a string"))?;
+    Ok(Cow::Owned(buffer.to_vec(py)?))
+}
+
+/// Options for how count_blocks splits and hashes blocks.
+#[derive(Clone, Copy, Default)]
+struct CountOptions {
+    block_hash: BlockHash,
+    rolling: bool,
+    normalize_whitespace: bool,
+    delimiter: Option<u8>,
+    skip_partial: bool,
+    size_hint: Option<usize>,
+    line_endings: LineEndings,
+    verify_collisions: bool,
+    max_blocks: Option<usize>,
+    dedup_runs: bool,
+    overlap: usize,
+}
+
+/// Strip trailing spaces, tabs and carriage returns from a block, keeping
+/// the newline that terminated it (if any).
+fn normalize_block(block: &[u8]) -> Cow<'_, [u8]> {
+    let (content, newline) = match block.strip_suffix(b"\n") {
+        Some(content) => (content, true),
+        None => (block, false),
+    };
+    let end = content
+        .iter()
+        .rposition(|c| !matches!(c, b' ' | b'\t' | b'\r'))
+        .map_or(0, |i| i + 1);
+    if end == content.len() {
+        return Cow::Borrowed(block);
+    }
+    let mut normalized = content[..end].to_vec();
+    if newline {
+        normalized.push(b'\n');
+    }
+    Cow::Owned(normalized)
+}
+
+/// The first block seen with each hash, for verify_collisions.
+type SeenBlocks = HashMap<i64, Vec<u8>>;
+
+/// Count a block, returning false (without counting it) if it would take
+/// counts beyond options.max_blocks distinct blocks.
+///
+/// previous is the last block counted, for options.dedup_runs.
+fn add_block(
+    counts: &mut HashMap<i64, usize>,
+    seen: Option<&mut SeenBlocks>,
+    previous: Option<&mut Vec<u8>>,
+    block: &[u8],
+    options: CountOptions,
+    py: Python,
+) -> PyResult<bool> {
+    let block = if options.normalize_whitespace {
+        let block = normalize_block(block);
+        if block.is_empty() {
+            return Ok(true);
+        }
+        block
+    } else {
+        Cow::Borrowed(block)
+    };
+    // Blocks are never empty, so an empty previous block means there was
+    // none yet.
+    if let Some(previous) = previous {
+        if previous.as_slice() == &*block {
+            return Ok(true);
+        }
+        previous.clear();
+        previous.extend_from_slice(&block);
+    }
+    let hash = hash_block(&block, options.block_hash, py)?;
+    if options
+        .max_blocks
+        .is_some_and(|max_blocks| counts.len() >= max_blocks && !counts.contains_key(&hash))
+    {
+        return Ok(false);
+    }
+    if let Some(seen) = seen {
+        match seen.entry(hash) {
+            hash_map::Entry::Occupied(first) if first.get().as_slice() != &*block => {
+                return Err(PyValueError::new_err(format!(
+                    "hash collision between blocks {:?} and {:?}",
+                    PyBytes::new(py, first.get()),
+                    PyBytes::new(py, &block),
+                )));
+            }
+            hash_map::Entry::Occupied(_) => {}
+            hash_map::Entry::Vacant(first) => {
+                first.insert(block.to_vec());
+            }
+        }
+    }
+    *counts.entry(hash).or_insert(0) += block.len();
+    Ok(true)
+}
+
+/// Split data into windows of block_size bytes starting every
+/// block_size - overlap bytes, up to the first window reaching the end of
+/// the data, which may be shorter.
+fn overlapping_blocks(
+    data: &[u8],
+    block_size: usize,
+    overlap: usize,
+) -> impl Iterator<Item = &[u8]> {
+    let step = block_size - overlap;
+    (0..data.len())
+        .step_by(step)
+        .take_while(move |&start| start == 0 || start - step + block_size < data.len())
+        .map(move |start| &data[start..std::cmp::min(start + block_size, data.len())])
+}
+
+/// The chunks of a ShaFile, from its as_raw_chunks().
+fn raw_chunks<'py>(obj: &Bound<'py, PyAny>) -> PyResult<Bound<'py, PyList>> {
+    obj.call_method0("as_raw_chunks")?
+        .extract::<Bound<PyList>>()
+        .map_err(|_| PyTypeError::new_err("as_raw_chunks() did not return a list"))
+}
+
+/// The most blocks to reserve space for based on the size_hint passed to
+/// _count_blocks.
+const MAX_HINTED_BLOCKS: usize = 1 << 16;
+
+/// Count the blocks of obj, along with whether counting stopped early
+/// because there were more than options.max_blocks distinct blocks.
+fn count_blocks(
+    py: Python,
+    obj: &Bound<PyAny>,
+    block_size: Option<usize>,
+    options: CountOptions,
+) -> PyResult<(HashMap<i64, usize>, bool)> {
+    let block_size = resolve_block_size(py, block_size)?;
+    if options.rolling && block_size == 0 {
+        return Err(PyValueError::new_err(format!(
+            "block_size must be positive with rolling: {}",
+            block_size
+        )));
+    }
+    if options.overlap >= block_size && options.overlap > 0 {
+        return Err(PyValueError::new_err(format!(
+            "overlap must be less than block_size ({}): {}",
+            block_size, options.overlap
+        )));
+    }
+    if options.overlap > 0
+        && (options.rolling
+            || options.delimiter.is_some()
+            || options.line_endings != LineEndings::Lf)
+    {
+        return Err(PyValueError::new_err(
+            "overlap cannot be combined with rolling, delimiter or line_endings",
+        ));
+    }
+    // A block_size of 0 doesn't cap lines, so there is nothing to go by.
+    let capacity = options.size_hint.map_or(0, |size| {
+        std::cmp::min(size.checked_div(block_size).unwrap_or(0), MAX_HINTED_BLOCKS)
+    });
+    let mut counts = HashMap::with_capacity(capacity);
+    let mut seen = options.verify_collisions.then(SeenBlocks::new);
+    let mut previous = options.dedup_runs.then(Vec::new);
+    let mut splitter = if options.rolling {
+        BlockSplitter::new_rolling(block_size, resolve_rolling_window(py)?)
+    } else if let Some(delimiter) = options.delimiter {
+        BlockSplitter::with_delimiter(block_size, delimiter)
+    } else {
+        BlockSplitter::with_line_endings(block_size, options.line_endings)
+    };
+    let mut truncated = false;
+    let mut add = |block: &[u8]| {
+        if !truncated
+            && !add_block(
+                &mut counts,
+                seen.as_mut(),
+                previous.as_mut(),
+                block,
+                options,
+                py,
+            )?
+        {
+            truncated = true;
+        }
+        Ok::<_, PyErr>(())
+    };
+    if options.overlap > 0 {
+        // Windows can span chunks, so the data is gathered up front.
+        let data = match chunk_data(py, obj) {
+            Ok(data) => data,
+            Err(_) => {
+                let mut data = Vec::new();
+                for chunk in raw_chunks(obj)?.iter() {
+                    data.extend_from_slice(&chunk_data(py, &chunk)?);
+                }
+                Cow::Owned(data)
+            }
+        };
+        for block in overlapping_blocks(&data, block_size, options.overlap) {
+            if !options.skip_partial || block.len() == block_size {
+                add(block)?;
+            }
+        }
+        return Ok((counts, truncated));
+    }
+    // Contiguous data (such as bytes) can be split as it is; otherwise obj
+    // is a ShaFile, whose data has to be gathered from its chunks.
+    match chunk_data(py, obj) {
+        Ok(data) => splitter.feed(&data, &mut add)?,
+        Err(_) => {
+            for chunk in raw_chunks(obj)?.iter() {
+                splitter.feed(&chunk_data(py, &chunk)?, &mut add)?;
+            }
+        }
+    }
+    // A block ending in a lone CR is a complete line, not a partial block.
+    if !options.skip_partial || splitter.pending_cr {
+        splitter.finish(&mut add)?;
+    }
+
+    Ok((counts, truncated))
+}
+
+#[pyfunction]
+#[pyo3(signature = (obj, block_size=None, stable_hash=false, rolling=false, normalize_whitespace=false, delimiter=None, skip_partial=false, size_hint=None, line_endings="lf", verify_collisions=false, hash_algo="py", dedup_runs=false, overlap=0))]
+#[allow(clippy::too_many_arguments)]
+fn _count_blocks<'py>(
+    py: Python<'py>,
+    obj: &Bound<'py, PyAny>,
+    block_size: Option<usize>,
+    stable_hash: bool,
+    rolling: bool,
+    normalize_whitespace: bool,
+    delimiter: Option<u8>,
+    skip_partial: bool,
+    size_hint: Option<usize>,
+    line_endings: &str,
+    verify_collisions: bool,
+    hash_algo: &str,
+    dedup_runs: bool,
+    overlap: usize,
+) -> PyResult<Bound<'py, PyDict>> {
+    let (blocks, _) = _count_blocks_bounded(
+        py,
+        obj,
+        None,
+        block_size,
+        stable_hash,
+        rolling,
+        normalize_whitespace,
+        delimiter,
+        skip_partial,
+        size_hint,
+        line_endings,
+        verify_collisions,
+        hash_algo,
+        dedup_runs,
+        overlap,
+    )?;
+    Ok(blocks)
+}
+
+/// Count the blocks in an object, stopping at the first block that would
+/// make for more than max_blocks distinct blocks. Returns the counts so far
+/// and whether counting stopped early.
+#[pyfunction]
+#[pyo3(signature = (obj, max_blocks, block_size=None, stable_hash=false, rolling=false, normalize_whitespace=false, delimiter=None, skip_partial=false, size_hint=None, line_endings="lf", verify_collisions=false, hash_algo="py", dedup_runs=false, overlap=0))]
+#[allow(clippy::too_many_arguments)]
+fn _count_blocks_bounded<'py>(
+    py: Python<'py>,
+    obj: &Bound<'py, PyAny>,
+    max_blocks: Option<usize>,
+    block_size: Option<usize>,
+    stable_hash: bool,
+    rolling: bool,
+    normalize_whitespace: bool,
+    delimiter: Option<u8>,
+    skip_partial: bool,
+    size_hint: Option<usize>,
+    line_endings: &str,
+    verify_collisions: bool,
+    hash_algo: &str,
+    dedup_runs: bool,
+    overlap: usize,
+) -> PyResult<(Bound<'py, PyDict>, bool)> {
+    let options = CountOptions {
+        block_hash: BlockHash::parse(hash_algo, stable_hash)?,
+        rolling,
+        normalize_whitespace,
+        delimiter,
+        skip_partial,
+        size_hint,
+        line_endings: LineEndings::parse(line_endings)?,
+        verify_collisions,
+        max_blocks,
+        dedup_runs,
+        overlap,
+    };
+    let (counts, truncated) = count_blocks(py, obj, block_size, options)?;
+    let blocks = block_counts_to_dict(py, &counts, options.block_hash)?;
+    Ok((blocks, truncated))
+}
+
+#[pyfunction]
+#[pyo3(signature = (data, block_size=None, stable_hash=false))]
+fn _count_blocks_bytes<'py>(
+    py: Python<'py>,
+    data: &[u8],
+    block_size: Option<usize>,
+    stable_hash: bool,
+) -> PyResult<Bound<'py, PyDict>> {
+    let block_hash = BlockHash::new(stable_hash);
+    let mut counts = HashMap::new();
+    let mut splitter = BlockSplitter::new(resolve_block_size(py, block_size)?);
+    splitter.feed(data, |block| add_hash(&mut counts, block, block_hash, py))?;
+    splitter.finish(|block| add_hash(&mut counts, block, block_hash, py))?;
+    block_counts_to_dict(py, &counts, block_hash)
+}
+
+/// Count the blocks of content that is already split into lines, without
+/// scanning it for newlines again.
+///
+/// Each line ends a block, and lines longer than block_size are split into
+/// blocks of block_size bytes, so for lines ending in newlines the result is
+/// the same as from _count_blocks for the joined lines. As for _count_blocks,
+/// a block_size of 0 doesn't cap the lines.
+#[pyfunction]
+#[pyo3(signature = (lines, block_size=None))]
+fn _count_blocks_lines<'py>(
+    py: Python<'py>,
+    lines: Vec<Bound<'py, PyAny>>,
+    block_size: Option<usize>,
+) -> PyResult<Bound<'py, PyDict>> {
+    let block_size = match resolve_block_size(py, block_size)? {
+        0 => usize::MAX,
+        block_size => block_size,
+    };
+    let mut counts = HashMap::new();
+    for line in &lines {
+        for block in chunk_data(py, line)?.chunks(block_size) {
+            add_hash(&mut counts, block, BlockHash::Python, py)?;
+        }
+    }
+    block_counts_to_dict(py, &counts, BlockHash::Python)
+}
+
+/// Count the blocks in data, as (FNV-1a hash, count) pairs sorted by hash.
+fn count_blocks_sorted(data: &[u8], block_size: usize) -> Vec<(u64, u32)> {
+    let mut counts: HashMap<u64, usize> = HashMap::new();
+    let mut splitter = BlockSplitter::new(block_size);
+    let mut add = |block: &[u8]| {
+        *counts.entry(fnv1a(block)).or_default() += block.len();
+        Ok::<_, Infallible>(())
+    };
+    let Ok(()) = splitter.feed(data, &mut add);
+    let Ok(()) = splitter.finish(&mut add);
+    let mut result = counts
+        .into_iter()
+        .map(|(hash, count)| (hash, u32::try_from(count).unwrap_or(u32::MAX)))
+        .collect::<Vec<_>>();
+    result.sort_unstable();
+    result
+}
+
+/// Count the blocks in data in a form that can be stored and reused, with
+/// the GIL released.
+///
+/// Returns a list of (hash, count) pairs sorted by hash, for comparing with
+/// _common_bytes_sorted. The hashes are 64-bit FNV-1a hashes, which are the
+/// same in every process; they are not interchangeable with the keys
+/// returned by _count_blocks, which uses hash() unless stable_hash is set.
+#[pyfunction]
+#[pyo3(signature = (data, block_size=None))]
+fn _count_blocks_cacheable(
+    py: Python,
+    data: &[u8],
+    block_size: Option<usize>,
+) -> PyResult<Vec<(u64, u32)>> {
+    let block_size = resolve_block_size(py, block_size)?;
+    Ok(allow_threads(py, || count_blocks_sorted(data, block_size)))
+}
+
+/// Count the bytes in common between two sorted lists of block counts.
+fn common_bytes_sorted(blocks1: &[(u64, u32)], blocks2: &[(u64, u32)]) -> u64 {
+    let mut iter1 = blocks1.iter().peekable();
+    let mut iter2 = blocks2.iter().peekable();
+    let mut common = 0;
+    while let (Some(&&(hash1, count1)), Some(&&(hash2, count2))) = (iter1.peek(), iter2.peek()) {
+        match hash1.cmp(&hash2) {
+            Ordering::Less => {
+                iter1.next();
+            }
+            Ordering::Greater => {
+                iter2.next();
+            }
+            Ordering::Equal => {
+                common += u64::from(std::cmp::min(count1, count2));
+                iter1.next();
+                iter2.next();
+            }
+        }
+    }
+    common
+}
+
+/// Like _common_bytes, but for block counts returned by
+/// _count_blocks_cacheable.
+#[pyfunction]
+fn _common_bytes_sorted(blocks1: Vec<(u64, u32)>, blocks2: Vec<(u64, u32)>) -> PyResult<u64> {
+    for blocks in [&blocks1, &blocks2] {
+        if !blocks.is_sorted_by(|(hash1, _), (hash2, _)| hash1 < hash2) {
+            return Err(PyValueError::new_err("block counts are not sorted by hash"));
+        }
+    }
+    Ok(common_bytes_sorted(&blocks1, &blocks2))
+}
+
+/// List the (hash, length) of the blocks of an object, in order and with
+/// duplicates.
+///
+/// The blocks are split in the same way as by _count_blocks.
+#[pyfunction]
+#[pyo3(signature = (obj, block_size=None))]
+fn _iter_blocks(
+    py: Python,
+    obj: &Bound<PyAny>,
+    block_size: Option<usize>,
+) -> PyResult<Vec<(isize, usize)>> {
+    let chunks = obj.call_method0("as_raw_chunks")?;
+    let chunks = chunks
+        .extract::<Bound<PyList>>()
+        .map_err(|_| PyTypeError::new_err("as_raw_chunks() did not return a list"))?;
+
+    let mut blocks = Vec::new();
+    let mut add_block = |block: &[u8]| -> PyResult<()> {
+        blocks.push((PyBytes::new(py, block).hash()?, block.len()));
+        Ok(())
+    };
+    let mut splitter = BlockSplitter::new(resolve_block_size(py, block_size)?);
+    for chunk in chunks.iter() {
+        let data = chunk_data(py, &chunk)?;
+        splitter.feed(&data, &mut add_block)?;
+    }
+    splitter.finish(&mut add_block)?;
+    Ok(blocks)
+}
+
+/// Count the blocks in several objects at once.
+///
+/// The chunks of each object are collected with the GIL held, but the
+/// splitting is done in parallel with the GIL released. Only hashing the
+/// distinct blocks needs the GIL again, since the keys have to match those
+/// computed by _count_blocks.
+#[pyfunction]
+#[pyo3(signature = (objs, block_size=None))]
+fn _count_blocks_many<'py>(
+    py: Python<'py>,
+    objs: Vec<Bound<'py, PyAny>>,
+    block_size: Option<usize>,
+) -> PyResult<Bound<'py, PyList>> {
+    let block_size = resolve_block_size(py, block_size)?;
+
+    let chunk_lists = objs
+        .iter()
+        .map(|obj| {
+            obj.call_method0("as_raw_chunks")?
+                .extract::<Bound<PyList>>()
+                .map_err(|_| PyTypeError::new_err("as_raw_chunks() did not return a list"))
+        })
+        .collect::<PyResult<Vec<_>>>()?;
+    let all_chunks = chunk_lists
+        .iter()
+        .map(|chunks| chunks.iter().collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    // Borrowed chunk contents come from bytes objects, which are immutable
+    // and kept alive by all_chunks, so they can be used without the GIL.
+    let data = all_chunks
+        .iter()
+        .map(|chunks| chunks.iter().map(|chunk| chunk_data(py, chunk)).collect())
+        .collect::<PyResult<Vec<Vec<Cow<[u8]>>>>>()?;
+
+    let block_contents = allow_threads(py, || {
+        data.par_iter()
+            .map(|chunks| {
+                let mut counts: HashMap<Vec<u8>, usize> = HashMap::new();
+                let mut add_block = |block: &[u8]| {
+                    *counts.entry(block.to_vec()).or_insert(0) += block.len();
+                    Ok::<_, Infallible>(())
+                };
+                let mut splitter = BlockSplitter::new(block_size);
+                for chunk in chunks {
+                    let Ok(()) = splitter.feed(chunk, &mut add_block);
+                }
+                let Ok(()) = splitter.finish(&mut add_block);
+                counts
+            })
+            .collect::<Vec<_>>()
+    });
+
+    let result = PyList::empty(py);
+    for contents in block_contents {
+        let mut counts = HashMap::new();
+        for (block, count) in contents {
+            let hash = PyBytes::new(py, &block).hash()? as i64;
+            *counts.entry(hash).or_insert(0) += count;
+        }
+        result.append(block_counts_to_dict(py, &counts, BlockHash::Python)?)?;
+    }
+    Ok(result)
+}
+
+/// The number of bytes requested from read() by _count_blocks_from_read.
+const READ_SIZE: usize = 64 * 1024;
+
+/// Count the blocks in the data read from a file-like object.
+///
+/// Blocks that span several reads are carried over, so the result is the
+/// same as for _count_blocks on an object with the same contents.
+#[pyfunction]
+#[pyo3(signature = (reader, block_size=None))]
+fn _count_blocks_from_read<'py>(
+    py: Python<'py>,
+    reader: &Bound<'py, PyAny>,
+    block_size: Option<usize>,
+) -> PyResult<Bound<'py, PyDict>> {
+    let mut counts = HashMap::new();
+    read_blocks(py, reader, block_size, |block| {
+        add_hash(&mut counts, block, BlockHash::Python, py)
+    })?;
+    block_counts_to_dict(py, &counts, BlockHash::Python)
+}
+
+/// Split the data read from a file-like object into blocks, carrying blocks
+/// that span several reads over.
+fn read_blocks(
+    py: Python,
+    reader: &Bound<PyAny>,
+    block_size: Option<usize>,
+    mut emit: impl FnMut(&[u8]) -> PyResult<()>,
+) -> PyResult<()> {
+    let mut splitter = BlockSplitter::new(resolve_block_size(py, block_size)?);
+    loop {
+        let data = reader.call_method1("read", (READ_SIZE,))?;
+        let data = data
+            .extract::<Bound<PyBytes>>()
+            .map_err(|_| PyTypeError::new_err("read() did not return bytes"))?;
+        if data.as_bytes().is_empty() {
+            break;
+        }
+        splitter.feed(data.as_bytes(), &mut emit)?;
+    }
+    splitter.finish(emit)
+}
+
+/// Compute the similarity score of the data read from two file-like
+/// objects, as _similarity_score does for objects.
+///
+/// Only the block counts of reader1 are kept: the blocks of reader2 are
+/// matched against them as they are read, using up the counts they match.
+#[pyfunction]
+#[pyo3(signature = (reader1, reader2, block_size=None))]
+fn _similarity_streaming(
+    py: Python,
+    reader1: &Bound<PyAny>,
+    reader2: &Bound<PyAny>,
+    block_size: Option<usize>,
+) -> PyResult<usize> {
+    let mut counts = HashMap::new();
+    let mut size1 = 0;
+    read_blocks(py, reader1, block_size, |block| {
+        size1 += block.len();
+        add_hash(&mut counts, block, BlockHash::Python, py)
+    })?;
+    let mut size2 = 0;
+    let mut common = 0;
+    read_blocks(py, reader2, block_size, |block| {
+        size2 += block.len();
+        let hash = hash_block(block, BlockHash::Python, py)?;
+        if let Some(remaining) = counts.get_mut(&hash) {
+            let matched = std::cmp::min(*remaining, block.len());
+            *remaining -= matched;
+            common += matched;
+        }
+        Ok(())
+    })?;
+    Ok(score(common, size1, size2))
+}
+
+/// The block counts of an object, read from a dict or kept by a
+/// BlockCountCache.
+///
+/// Block hashes may be signed (hash()) or unsigned (the stable hashes), so
+/// they are kept as i128.
+type BlockMap = HashMap<i128, usize>;
+
+/// Count the number of bytes in common between two block counts.
+///
+/// Only approximate, due to possible hash collisions.
+fn common_bytes(blocks1: &BlockMap, blocks2: &BlockMap) -> usize {
+    // Iterate over the smaller of the two maps, since this is symmetrical.
+    let (blocks1, blocks2) = if blocks1.len() > blocks2.len() {
+        (blocks2, blocks1)
+    } else {
+        (blocks1, blocks2)
+    };
+    blocks1
+        .iter()
+        .filter_map(|(block, count1)| blocks2.get(block).map(|count2| *count1.min(count2)))
+        .sum()
+}
+
+/// Like common_bytes, but give up as soon as fewer than needed bytes can be
+/// in common, returning None.
+///
+/// totals are the sums of the counts in blocks1 and blocks2, if known.
+fn common_bytes_at_least(
+    blocks1: &BlockMap,
+    blocks2: &BlockMap,
+    totals: Option<(usize, usize)>,
+    needed: usize,
+) -> Option<usize> {
+    let (blocks1, blocks2, total) = if blocks1.len() > blocks2.len() {
+        (blocks2, blocks1, totals.map(|(_, total2)| total2))
+    } else {
+        (blocks1, blocks2, totals.map(|(total1, _)| total1))
+    };
+    // The bytes in blocks1 that haven't been looked at yet are all that can
+    // still be in common.
+    let mut remaining = total.unwrap_or_else(|| blocks_size(blocks1));
+    let mut common = 0;
+    if remaining < needed {
+        return None;
+    }
+    for (block, &count1) in blocks1 {
+        remaining = remaining.saturating_sub(count1);
+        if let Some(&count2) = blocks2.get(block) {
+            common += std::cmp::min(count1, count2);
+        }
+        if common + remaining < needed {
+            return None;
+        }
+    }
+    Some(common)
+}
+
+#[pyfunction]
+fn _common_bytes(blocks1: BlockMap, blocks2: BlockMap) -> usize {
+    common_bytes(&blocks1, &blocks2)
+}
+
+/// Sum abs(count1 - count2) over the union of the blocks of two block count
+/// dicts.
+#[pyfunction]
+fn _block_count_distance(blocks1: &Bound<PyDict>, blocks2: &Bound<PyDict>) -> PyResult<usize> {
+    // Walk the smaller dict, then whatever is only in the larger one.
+    let (blocks1, blocks2) = if blocks1.len() > blocks2.len() {
+        (blocks2, blocks1)
+    } else {
+        (blocks1, blocks2)
+    };
+    let mut distance = 0;
+    for (block, count1) in blocks1.iter() {
+        let count1 = count1.extract::<usize>()?;
+        distance += match blocks2.get_item(block)? {
+            Some(count2) => count1.abs_diff(count2.extract::<usize>()?),
+            None => count1,
+        };
+    }
+    for (block, count2) in blocks2.iter() {
+        if !blocks1.contains(block)? {
+            distance += count2.extract::<usize>()?;
+        }
+    }
+    Ok(distance)
+}
+
+/// Find the lengths of the common prefix and of the common suffix that
+/// follows it.
+fn common_prefix_suffix(a: &[u8], b: &[u8]) -> (usize, usize) {
+    let prefix = a.iter().zip(b).take_while(|(c1, c2)| c1 == c2).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(c1, c2)| c1 == c2)
+        .count();
+    (prefix, suffix)
+}
+
+#[pyfunction]
+fn _common_prefix_suffix(a: &[u8], b: &[u8]) -> (usize, usize) {
+    common_prefix_suffix(a, b)
+}
+
+/// List the (start, end) offsets of each line of data, including its
+/// newline, so that lines can be sliced out of data without copying them
+/// all. A final line without a newline is included too.
+#[pyfunction]
+fn _line_offsets(data: &[u8]) -> Vec<(usize, usize)> {
+    let mut end = 0;
+    data.split_inclusive(|&c| c == b'\n')
+        .map(|line| {
+            end += line.len();
+            (end - line.len(), end)
+        })
+        .collect()
+}
+
+/// List the counts of every block in either of two block count dicts.
+///
+/// Block hashes may be signed (hash()) or unsigned (the stable hash), so
+/// they are read as i128.
+#[pyfunction]
+fn _block_count_diff(
+    blocks1: &Bound<PyDict>,
+    blocks2: &Bound<PyDict>,
+) -> PyResult<Vec<(i128, usize, usize)>> {
+    let mut counts = BTreeMap::<i128, (usize, usize)>::new();
+    for (block, count) in blocks1.iter() {
+        counts.entry(block.extract()?).or_default().0 = count.extract()?;
+    }
+    for (block, count) in blocks2.iter() {
+        counts.entry(block.extract()?).or_default().1 = count.extract()?;
+    }
+    Ok(counts
+        .into_iter()
+        .map(|(block, (count1, count2))| (block, count1, count2))
+        .collect())
+}
+
+/// A cache of the block counts of objects, keyed by SHA.
+///
+/// This can be used as the block_cache of _similarity_score and the
+/// candidate scorers in place of a dict. The counts are kept as native maps,
+/// which the scorers read directly; they are only converted to dicts when
+/// they are looked up from Python.
+#[pyclass(module = "dulwich._diff_tree")]
+#[derive(Default)]
+struct BlockCountCache {
+    counts: HashMap<Vec<u8>, Arc<BlockMap>>,
+}
+
+impl BlockCountCache {
+    /// The block counts of an object, counting them if they aren't cached
+    /// yet.
+    fn blocks(slf: &Bound<Self>, obj: &Bound<PyAny>) -> PyResult<Arc<BlockMap>> {
+        let sha = obj.getattr("id")?.extract::<Vec<u8>>()?;
+        if let Some(blocks) = slf.borrow().counts.get(&sha) {
+            return Ok(Arc::clone(blocks));
+        }
+        let (counts, _) = count_blocks(slf.py(), obj, None, CountOptions::default())?;
+        let blocks = Arc::new(block_map(counts));
+        slf.borrow_mut().counts.insert(sha, Arc::clone(&blocks));
+        Ok(blocks)
+    }
+}
+
+#[pymethods]
+impl BlockCountCache {
+    #[new]
+    fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the block counts of an object, counting them if they aren't
+    /// cached yet.
+    fn get<'py>(slf: &Bound<'py, Self>, obj: &Bound<'py, PyAny>) -> PyResult<Bound<'py, PyDict>> {
+        Self::blocks(slf, obj)?.as_ref().into_pyobject(slf.py())
+    }
+
+    fn clear(&mut self) {
+        self.counts.clear();
+    }
+
+    fn __len__(&self) -> usize {
+        self.counts.len()
+    }
+
+    fn __contains__(&self, sha: Vec<u8>) -> bool {
+        self.counts.contains_key(&sha)
+    }
+
+    fn __getitem__<'py>(&self, py: Python<'py>, sha: Vec<u8>) -> PyResult<Bound<'py, PyDict>> {
+        match self.counts.get(&sha) {
+            Some(blocks) => blocks.as_ref().into_pyobject(py),
+            None => Err(PyKeyError::new_err(PyBytes::new(py, &sha).unbind())),
+        }
+    }
+
+    fn __setitem__(&mut self, sha: Vec<u8>, blocks: BlockMap) {
+        self.counts.insert(sha, Arc::new(blocks));
+    }
+}
+
+/// Counts the blocks of several objects together, as if they were
+/// concatenated if carry is set.
+#[pyclass(module = "dulwich._diff_tree")]
+struct BlockAccumulator {
+    splitter: BlockSplitter,
+    carry: bool,
+    counts: HashMap<i64, usize>,
+}
+
+#[pymethods]
+impl BlockAccumulator {
+    #[new]
+    #[pyo3(signature = (block_size=None, carry=false))]
+    fn new(py: Python, block_size: Option<usize>, carry: bool) -> PyResult<Self> {
+        Ok(BlockAccumulator {
+            splitter: BlockSplitter::new(resolve_block_size(py, block_size)?),
+            carry,
+            counts: HashMap::new(),
+        })
+    }
+
+    /// Add the blocks of an object to the counts.
+    fn add(&mut self, py: Python, obj: &Bound<PyAny>) -> PyResult<()> {
+        let chunks = obj.call_method0("as_raw_chunks")?;
+        let chunks = chunks
+            .extract::<Bound<PyList>>()
+            .map_err(|_| PyTypeError::new_err("as_raw_chunks() did not return a list"))?;
+        let counts = &mut self.counts;
+        for chunk in chunks.iter() {
+            let data = chunk_data(py, &chunk)?;
+            self.splitter.feed(&data, |block| {
+                add_hash(counts, block, BlockHash::Python, py)
+            })?;
+        }
+        if !self.carry {
+            self.splitter
+                .finish(|block| add_hash(counts, block, BlockHash::Python, py))?;
+        }
+        Ok(())
+    }
+
+    /// Get the block counts of the objects added so far, including any
+    /// partial block that is still being carried over.
+    fn counts<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let mut counts = self.counts.clone();
+        if !self.splitter.block.is_empty() {
+            add_hash(&mut counts, &self.splitter.block, BlockHash::Python, py)?;
+        }
+        block_counts_to_dict(py, &counts, BlockHash::Python)
+    }
+
+    /// Forget all the objects added so far.
+    fn reset(&mut self) {
+        self.counts.clear();
+        self.splitter.block.clear();
+    }
+}
+
+/// Convert the block counts of count_blocks with BlockHash::Python to a
+/// BlockMap.
+fn block_map(counts: HashMap<i64, usize>) -> BlockMap {
+    counts
+        .into_iter()
+        .map(|(block, count)| (i128::from(block), count))
+        .collect()
+}
+
+/// Looks up the block counts of objects in an optional block_cache for the
+/// duration of one call.
+///
+/// The counts from a BlockCountCache are read as they are. Those from a
+/// dict, or some other mapping, have to be converted to native maps, so
+/// they are kept here to only be converted once per call; objects missing
+/// from it are counted and added to it.
+struct CachedBlockCounts<'a, 'py> {
+    block_cache: Option<&'a Bound<'py, PyAny>>,
+    looked_up: HashMap<Vec<u8>, Arc<BlockMap>>,
+}
+
+impl<'a, 'py> CachedBlockCounts<'a, 'py> {
+    fn new(block_cache: Option<&'a Bound<'py, PyAny>>) -> Self {
+        CachedBlockCounts {
+            block_cache,
+            looked_up: HashMap::new(),
+        }
+    }
+
+    /// The block counts of an object, counting them if they aren't cached.
+    fn get(&mut self, py: Python, obj: &Bound<PyAny>) -> PyResult<Arc<BlockMap>> {
+        if let Some(block_cache) = self.block_cache {
+            if let Ok(block_cache) = block_cache.extract::<Bound<BlockCountCache>>() {
+                return BlockCountCache::blocks(&block_cache, obj);
+            }
+        }
+        let id = obj.getattr("id")?;
+        let sha = id.extract::<Vec<u8>>()?;
+        if let Some(blocks) = self.looked_up.get(&sha) {
+            return Ok(Arc::clone(blocks));
+        }
+        let blocks = match self.block_cache {
+            Some(block_cache) if block_cache.contains(&id)? => {
+                Arc::new(block_cache.get_item(&id)?.extract()?)
+            }
+            block_cache => {
+                let (counts, _) = count_blocks(py, obj, None, CountOptions::default())?;
+                if let Some(block_cache) = block_cache {
+                    block_cache
+                        .set_item(&id, block_counts_to_dict(py, &counts, BlockHash::Python)?)?;
+                }
+                Arc::new(block_map(counts))
+            }
+        };
+        self.looked_up.insert(sha, Arc::clone(&blocks));
+        Ok(blocks)
+    }
+}
+
+/// The block counts of the object with sha in block_cache, which is a
+/// BlockCountCache or a mapping of SHAs to block count dicts.
+fn block_counts_for_sha(block_cache: &Bound<PyAny>, sha: &Bound<PyAny>) -> PyResult<Arc<BlockMap>> {
+    if let Ok(block_cache) = block_cache.extract::<PyRef<BlockCountCache>>() {
+        return match block_cache.counts.get(&sha.extract::<Vec<u8>>()?) {
+            Some(blocks) => Ok(Arc::clone(blocks)),
+            None => Err(PyKeyError::new_err(sha.clone().unbind())),
+        };
+    }
+    Ok(Arc::new(block_cache.get_item(sha)?.extract()?))
+}
+
+/// Compute a similarity score for two objects.
+///
+/// If min_score is given, stop counting common bytes as soon as the score
+/// can no longer reach it, and return some score below min_score.
+#[pyfunction]
+#[pyo3(signature = (obj1, obj2, block_cache=None, min_score=None))]
+fn _similarity_score(
+    py: Python,
+    obj1: &Bound<PyAny>,
+    obj2: &Bound<PyAny>,
+    block_cache: Option<&Bound<PyAny>>,
+    min_score: Option<u8>,
+) -> PyResult<usize> {
+    let mut block_counts = CachedBlockCounts::new(block_cache);
+    similarity_score(py, obj1, obj2, &mut block_counts, min_score)
+}
+
+/// Compute a similarity score for two objects, as _similarity_score does,
+/// looking up their block counts in block_counts.
+fn similarity_score(
+    py: Python,
+    obj1: &Bound<PyAny>,
+    obj2: &Bound<PyAny>,
+    block_counts: &mut CachedBlockCounts,
+    min_score: Option<u8>,
+) -> PyResult<usize> {
+    // Objects with the same SHA have the same contents.
+    let sha1 = obj1.getattr("id")?.extract::<Vec<u8>>()?;
+    let sha2 = obj2.getattr("id")?.extract::<Vec<u8>>()?;
+    if same_sha(&sha1, &sha2) {
+        return Ok(MAX_SCORE);
+    }
+
+    let blocks1 = block_counts.get(py, obj1)?;
+    let blocks2 = block_counts.get(py, obj2)?;
+    let size1 = obj1.call_method0("raw_length")?.extract::<usize>()?;
+    let size2 = obj2.call_method0("raw_length")?.extract::<usize>()?;
+
+    let Some(min_score) = min_score.map(usize::from) else {
+        return Ok(score(common_bytes(&blocks1, &blocks2), size1, size2));
+    };
+    let needed = needed_bytes(min_score, size1, size2);
+    match common_bytes_at_least(&blocks1, &blocks2, None, needed) {
+        Some(common) => Ok(score(common, size1, size2)),
+        None => Ok(min_score.saturating_sub(1)),
+    }
+}
+
+/// Split a path into its directory, empty at the top level, and name.
+fn split_path(path: &[u8]) -> (&[u8], &[u8]) {
+    match path.iter().rposition(|&c| c == b'/') {
+        Some(slash) => (&path[..slash], &path[slash + 1..]),
+        None => (b"", path),
+    }
+}
+
+/// The last component of a path.
+fn basename(path: &[u8]) -> &[u8] {
+    split_path(path).1
+}
+
+/// Find what a single deleted object was most likely renamed to, as the
+/// (add_path, score) of the best of adds, which are (path, object) pairs.
+///
+/// Ties are broken as described for _best_rename_for in dulwich.diff_tree.
+#[pyfunction]
+#[pyo3(signature = (deleted_obj, deleted_path, adds, block_cache=None, threshold=None))]
+fn _best_rename_for<'py>(
+    py: Python<'py>,
+    deleted_obj: &Bound<'py, PyAny>,
+    deleted_path: &[u8],
+    adds: Vec<(Vec<u8>, Bound<'py, PyAny>)>,
+    block_cache: Option<&Bound<'py, PyAny>>,
+    threshold: Option<usize>,
+) -> PyResult<Option<(Bound<'py, PyBytes>, usize)>> {
+    let threshold = match threshold {
+        Some(threshold) => threshold,
+        None => py
+            .import("dulwich.diff_tree")?
+            .getattr("RENAME_THRESHOLD")?
+            .extract()?,
+    };
+    // The deleted object is compared with every add, but its block counts
+    // are only looked up once.
+    let mut block_counts = CachedBlockCounts::new(block_cache);
+    let mut adds = adds;
+    adds.sort_by(|(path1, _), (path2, _)| path1.cmp(path2));
+
+    let deleted_basename = basename(deleted_path);
+    let mut best: Option<((usize, bool), &[u8])> = None;
+    for (add_path, add_obj) in &adds {
+        let min_score = best.map_or(threshold + 1, |((score, _), _)| score.max(threshold + 1));
+        let score = similarity_score(
+            py,
+            deleted_obj,
+            add_obj,
+            &mut block_counts,
+            Some(u8::try_from(min_score).unwrap_or(u8::MAX)),
+        )?;
+        if score < min_score {
+            continue;
+        }
+        let key = (score, basename(add_path) == deleted_basename);
+        if best.is_none_or(|(best_key, _)| key > best_key) {
+            best = Some((key, add_path));
+        }
+    }
+    Ok(best.map(|((score, _), path)| (PyBytes::new(py, path), score)))
+}
+
+/// Find the added objects that the content of a deleted object was split
+/// into, as the sorted paths of adds, which are (path, object) pairs.
+///
+/// Adds are picked greedily until they cover more than coverage_threshold
+/// percent of the deleted content, as described for _detect_split in
+/// dulwich.diff_tree; if all of them together don't, the result is empty.
+#[pyfunction]
+#[pyo3(signature = (deleted_obj, adds, block_cache=None, coverage_threshold=None))]
+fn _detect_split<'py>(
+    py: Python<'py>,
+    deleted_obj: &Bound<'py, PyAny>,
+    adds: Vec<(Vec<u8>, Bound<'py, PyAny>)>,
+    block_cache: Option<&Bound<'py, PyAny>>,
+    coverage_threshold: Option<usize>,
+) -> PyResult<Vec<Bound<'py, PyBytes>>> {
+    let coverage_threshold = match coverage_threshold {
+        Some(coverage_threshold) => coverage_threshold,
+        None => py
+            .import("dulwich.diff_tree")?
+            .getattr("RENAME_THRESHOLD")?
+            .extract()?,
+    };
+    let mut block_counts = CachedBlockCounts::new(block_cache);
+    let mut remaining = Arc::unwrap_or_clone(block_counts.get(py, deleted_obj)?);
+    let size = blocks_size(&remaining);
+    let mut candidates = adds
+        .into_iter()
+        .map(|(path, obj)| Ok((path, block_counts.get(py, &obj)?)))
+        .collect::<PyResult<Vec<_>>>()?;
+    candidates.sort_by(|(path1, _), (path2, _)| path1.cmp(path2));
+
+    let mut picked = Vec::new();
+    let mut covered = 0;
+    loop {
+        let coverage = |blocks: &BlockMap| {
+            blocks
+                .iter()
+                .map(|(block, count)| remaining.get(block).map_or(0, |left| *left.min(count)))
+                .sum::<usize>()
+        };
+        // Unlike max_by_key, this takes the first of equally good adds,
+        // which has the smallest path.
+        let best = candidates
+            .iter()
+            .enumerate()
+            .map(|(i, (_, blocks))| (coverage(blocks), i))
+            .filter(|(add_covered, _)| *add_covered > 0)
+            .min_by_key(|(add_covered, i)| (std::cmp::Reverse(*add_covered), *i));
+        let Some((add_covered, i)) = best else {
+            return Ok(Vec::new());
+        };
+        let (path, blocks) = candidates.remove(i);
+        for (block, &count) in blocks.iter() {
+            if let Some(left) = remaining.get_mut(block) {
+                *left -= (*left).min(count);
+            }
+        }
+        picked.push(path);
+        covered += add_covered;
+        if covered * MAX_SCORE > coverage_threshold * size {
+            picked.sort();
+            return Ok(picked.iter().map(|path| PyBytes::new(py, path)).collect());
+        }
+    }
+}
+
+/// Check whether two SHAs are the same, where either may be hex or binary.
+fn same_sha(sha1: &[u8], sha2: &[u8]) -> bool {
+    let hex_matches = |hex: &[u8], raw: &[u8]| {
+        hex.len() == 2 * raw.len()
+            && hex.chunks(2).zip(raw).all(|(pair, &byte)| {
+                std::str::from_utf8(pair)
+                    .ok()
+                    .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                    == Some(byte)
+            })
+    };
+    sha1 == sha2 || hex_matches(sha1, sha2) || hex_matches(sha2, sha1)
+}
+
+/// The number of bytes two objects need to have in common to score at least
+/// min_score.
+fn needed_bytes(min_score: usize, size1: usize, size2: usize) -> usize {
+    (min_score * std::cmp::max(size1, size2)).div_ceil(MAX_SCORE)
+}
+
+/// Scale the number of bytes two objects have in common by the size of the
+/// larger object.
+fn score(common: usize, size1: usize, size2: usize) -> usize {
+    let max_size = std::cmp::max(size1, size2);
+    if max_size == 0 {
+        return MAX_SCORE;
+    }
+    common * MAX_SCORE / max_size
+}
+
+/// The size of an object, given its block counts.
+fn blocks_size(blocks: &BlockMap) -> usize {
+    // The block counts of an object add up to its size.
+    blocks.values().sum()
+}
+
+/// A deleted or added entry along with its block counts.
+struct Candidate<'py> {
+    entry: Bound<'py, PyAny>,
+    path: Py<PyAny>,
+    sha: Vec<u8>,
+    fmt: u32,
+    extension: Vec<u8>,
+    blocks: Arc<BlockMap>,
+    size: usize,
+}
+
+/// What follows the last dot of the name of a path, or nothing if the name
+/// has no dot.
+fn path_extension(path: &[u8]) -> &[u8] {
+    let name = path.rsplit(|&c| c == b'/').next().unwrap_or(path);
+    match name.iter().rposition(|&c| c == b'.') {
+        Some(dot) => &name[dot + 1..],
+        None => b"",
+    }
+}
+
+/// The candidates for entries, with their block counts from block_cache, or
+/// no blocks at all without a block_cache. Entries whose paths start with
+/// any of exclude_prefixes are left out.
+fn candidates<'py>(
+    block_cache: Option<&Bound<'py, PyAny>>,
+    entries: &Bound<'py, PyAny>,
+    exclude_prefixes: &[Vec<u8>],
+) -> PyResult<Vec<Candidate<'py>>> {
+    let py = entries.py();
+    let mut result = Vec::new();
+    for entry in entries.try_iter()? {
+        let entry = entry?;
+        let (path, mode, sha) = entry.extract::<(Py<PyAny>, u32, Bound<PyAny>)>()?;
+        let path_bytes = path.extract::<Vec<u8>>(py)?;
+        if exclude_prefixes
+            .iter()
+            .any(|prefix| path_bytes.starts_with(prefix))
+        {
+            continue;
+        }
+        let blocks = match block_cache {
+            Some(block_cache) => block_counts_for_sha(block_cache, &sha)?,
+            None => Arc::default(),
+        };
+        let size = blocks_size(&blocks);
+        let extension = path_extension(&path_bytes).to_vec();
+        result.push(Candidate {
+            entry,
+            path,
+            sha: sha.extract()?,
+            fmt: mode & S_IFMT,
+            extension,
+            blocks,
+            size,
+        });
+    }
+    Ok(result)
+}
+
+/// The (path, index) of entries, keyed by their (SHA, file type).
+type ContentGroups = HashMap<(Vec<u8>, u32), Vec<(Vec<u8>, usize)>>;
+
+/// Group entries by SHA and file type, each group sorted by path.
+fn entries_by_content(entries: &[Bound<PyAny>]) -> PyResult<ContentGroups> {
+    let mut result: HashMap<_, Vec<_>> = HashMap::new();
+    for (i, entry) in entries.iter().enumerate() {
+        let (path, mode, sha) = entry.extract::<(Vec<u8>, u32, Vec<u8>)>()?;
+        result
+            .entry((sha, mode & S_IFMT))
+            .or_default()
+            .push((path, i));
+    }
+    for group in result.values_mut() {
+        group.sort();
+    }
+    Ok(result)
+}
+
+/// Pair up deleted and added entries with the same SHA and file type.
+///
+/// Returns the (delete_path, add_path) pairs sorted by delete path, and the
+/// deletes and adds that weren't paired. If several deletes share a SHA,
+/// they are paired with the adds of that SHA in order of path.
+#[pyfunction]
+#[allow(clippy::type_complexity)]
+fn _find_exact_renames<'py>(
+    py: Python<'py>,
+    deletes: Vec<Bound<'py, PyAny>>,
+    adds: Vec<Bound<'py, PyAny>>,
+) -> PyResult<(
+    Vec<(Bound<'py, PyBytes>, Bound<'py, PyBytes>)>,
+    Vec<Bound<'py, PyAny>>,
+    Vec<Bound<'py, PyAny>>,
+)> {
+    let add_map = entries_by_content(&adds)?;
+    let mut pairs = Vec::new();
+    let mut delete_matched = vec![false; deletes.len()];
+    let mut add_matched = vec![false; adds.len()];
+    for (key, group) in entries_by_content(&deletes)? {
+        let Some(add_group) = add_map.get(&key) else {
+            continue;
+        };
+        for ((delete_path, i), (add_path, j)) in group.into_iter().zip(add_group) {
+            delete_matched[i] = true;
+            add_matched[*j] = true;
+            pairs.push((delete_path, add_path.clone()));
+        }
+    }
+    pairs.sort();
+
+    let unmatched = |entries: Vec<Bound<'py, PyAny>>, matched: &[bool]| {
+        entries
+            .into_iter()
+            .zip(matched)
+            .filter(|(_, &matched)| !matched)
+            .map(|(entry, _)| entry)
+            .collect::<Vec<_>>()
+    };
+    let pairs = pairs
+        .iter()
+        .map(|(delete_path, add_path)| (PyBytes::new(py, delete_path), PyBytes::new(py, add_path)))
+        .collect();
+    Ok((
+        pairs,
+        unmatched(deletes, &delete_matched),
+        unmatched(adds, &add_matched),
+    ))
+}
+
+/// Compute similarity scores for all pairs of deleted and added entries,
+/// using the block counts in block_cache.
+///
+/// Returns (delete_path, add_path, score) tuples for the pairs scoring
+/// higher than rename_threshold. If best_only is set, only the best pair
+/// for each add is returned, in the order of adds; of several equally good
+/// deletes, the one with the smallest path wins.
+///
+/// If scorer is given, it is called with the delete and add entries of each
+/// pair with the same file type and different SHAs, and returns their
+/// score in place of the block count score; block_cache isn't used then.
+///
+/// If same_extension_only is set, pairs whose paths have different
+/// extensions are skipped: far fewer pairs need scoring with mixed file
+/// types, but renames that change the extension are missed.
+///
+/// Deletes and adds whose paths start with any of exclude_prefixes, such as
+/// vendored directories, are left out before pairing, and need no block
+/// counts in block_cache; they then stay plain deletes and adds.
+#[pyfunction]
+#[pyo3(signature = (deletes, adds, block_cache, rename_threshold, best_only=false, scorer=None, same_extension_only=false, exclude_prefixes=Vec::new()))]
+#[allow(clippy::too_many_arguments)]
+fn _score_candidates<'py>(
+    py: Python<'py>,
+    deletes: &Bound<PyAny>,
+    adds: &Bound<PyAny>,
+    block_cache: &Bound<PyAny>,
+    rename_threshold: usize,
+    best_only: bool,
+    scorer: Option<&Bound<PyAny>>,
+    same_extension_only: bool,
+    exclude_prefixes: Vec<Vec<u8>>,
+) -> PyResult<Bound<'py, PyList>> {
+    let (result, _) = _score_candidates_limited(
+        py,
+        deletes,
+        adds,
+        block_cache,
+        rename_threshold,
+        None,
+        best_only,
+        scorer,
+        same_extension_only,
+        exclude_prefixes,
+    )?;
+    Ok(result)
+}
+
+/// Compute similarity scores as _score_candidates does, but with more than
+/// rename_limit pairs to score, only score pairs with the same SHA.
+///
+/// Returns a (pairs, exhausted) tuple, where exhausted is set if there
+/// were more pairs than rename_limit. Entries left out by exclude_prefixes
+/// don't count towards rename_limit.
+#[pyfunction]
+#[pyo3(signature = (deletes, adds, block_cache, rename_threshold, rename_limit, best_only=false, scorer=None, same_extension_only=false, exclude_prefixes=Vec::new()))]
+#[allow(clippy::too_many_arguments)]
+fn _score_candidates_limited<'py>(
+    py: Python<'py>,
+    deletes: &Bound<PyAny>,
+    adds: &Bound<PyAny>,
+    block_cache: &Bound<PyAny>,
+    rename_threshold: usize,
+    rename_limit: Option<usize>,
+    best_only: bool,
+    scorer: Option<&Bound<PyAny>>,
+    same_extension_only: bool,
+    exclude_prefixes: Vec<Vec<u8>>,
+) -> PyResult<(Bound<'py, PyList>, bool)> {
+    let block_cache = Some(block_cache).filter(|_| scorer.is_none());
+    let deletes = candidates(block_cache, deletes, &exclude_prefixes)?;
+    let adds = candidates(block_cache, adds, &exclude_prefixes)?;
+    let exhausted = rename_limit.is_some_and(|limit| deletes.len() * adds.len() > limit);
+    let scoring = Scoring {
+        exact_only: exhausted,
+        scorer,
+        same_extension_only,
+    };
+    let result = if best_only {
+        best_pairs(py, &deletes, &adds, rename_threshold, scoring)?
+    } else {
+        score_pairs(py, &deletes, &adds, rename_threshold, scoring)?
+    };
+    Ok((PyList::new(py, result)?, exhausted))
+}
+
+/// Pair the deleted and added entries with the same name after the last
+/// "/", as likely renames to score before all the others.
+///
+/// Returns (delete_path, add_path) tuples in the order of deletes, then of
+/// adds. Entries with the same path aren't paired, as that isn't a move.
+#[pyfunction]
+fn _basename_rename_candidates<'py>(
+    deletes: &Bound<'py, PyAny>,
+    adds: &Bound<'py, PyAny>,
+) -> PyResult<Vec<(Bound<'py, PyAny>, Bound<'py, PyAny>)>> {
+    let mut add_paths = Vec::new();
+    let mut adds_by_name: HashMap<Vec<u8>, Vec<usize>> = HashMap::new();
+    for add in adds.try_iter()? {
+        let path = add?.getattr("path")?;
+        let path_bytes = path.extract::<Vec<u8>>()?;
+        adds_by_name
+            .entry(basename(&path_bytes).to_vec())
+            .or_default()
+            .push(add_paths.len());
+        add_paths.push((path_bytes, path));
+    }
+    let mut result = Vec::new();
+    for delete in deletes.try_iter()? {
+        let path = delete?.getattr("path")?;
+        let path_bytes = path.extract::<Vec<u8>>()?;
+        let Some(adds) = adds_by_name.get(basename(&path_bytes)) else {
+            continue;
+        };
+        for (add_bytes, add_path) in adds.iter().map(|&i| &add_paths[i]) {
+            if *add_bytes != path_bytes {
+                result.push((path.clone(), add_path.clone()));
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Infer renames of whole directories from the renames of their files, as
+/// (old_dir, new_dir, confidence) tuples sorted by old_dir and new_dir.
+///
+/// A directory counts as renamed to another when more than threshold
+/// percent of the renamed files directly in it moved directly into that
+/// other directory; the confidence is that percentage. Changes other than
+/// renames are ignored, and the top-level directory is never renamed, nor
+/// renamed to.
+#[pyfunction]
+#[pyo3(signature = (renames, threshold=50))]
+#[allow(clippy::type_complexity)]
+fn _detect_dir_renames<'py>(
+    py: Python<'py>,
+    renames: &Bound<'py, PyAny>,
+    threshold: usize,
+) -> PyResult<Vec<(Bound<'py, PyBytes>, Bound<'py, PyBytes>, usize)>> {
+    let change_rename = py.import("dulwich.diff_tree")?.getattr("CHANGE_RENAME")?;
+    let mut moves: BTreeMap<Vec<u8>, BTreeMap<Vec<u8>, usize>> = BTreeMap::new();
+    for change in renames.try_iter()? {
+        let change = change?;
+        if !change.getattr("type")?.eq(&change_rename)? {
+            continue;
+        }
+        let old_path = change
+            .getattr("old")?
+            .getattr("path")?
+            .extract::<Vec<u8>>()?;
+        let new_path = change
+            .getattr("new")?
+            .getattr("path")?
+            .extract::<Vec<u8>>()?;
+        let old_dir = split_path(&old_path).0;
+        if !old_dir.is_empty() {
+            *moves
+                .entry(old_dir.to_vec())
+                .or_default()
+                .entry(split_path(&new_path).0.to_vec())
+                .or_default() += 1;
+        }
+    }
+    let mut result = Vec::new();
+    for (old_dir, targets) in &moves {
+        let total = targets.values().sum::<usize>();
+        for (new_dir, count) in targets {
+            let confidence = count * 100 / total;
+            if !new_dir.is_empty() && new_dir != old_dir && confidence > threshold {
+                result.push((
+                    PyBytes::new(py, old_dir),
+                    PyBytes::new(py, new_dir),
+                    confidence,
+                ));
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Compute similarity scores for all pairs of copy sources and added
+/// entries, using the block counts in block_cache.
+///
+/// Unlike for _score_candidates, the sources include entries that are
+/// unchanged or modified, as for git diff -C. Returns (source_path,
+/// add_path, score) tuples for the pairs scoring higher than copy_threshold.
+#[pyfunction]
+fn _score_copy_candidates(
+    py: Python,
+    sources: &Bound<PyAny>,
+    adds: &Bound<PyAny>,
+    block_cache: &Bound<PyAny>,
+    copy_threshold: usize,
+) -> PyResult<Py<PyAny>> {
+    let sources = candidates(Some(block_cache), sources, &[])?;
+    let adds = candidates(Some(block_cache), adds, &[])?;
+    let result = score_pairs(py, &sources, &adds, copy_threshold, Scoring::default())?;
+    Ok(PyList::new(py, result)?.unbind().into())
+}
+
+/// Pair each of the renames or copies with its similarity score, computed
+/// from the block counts in block_cache the same way as by _score_candidates.
+///
+/// Returns a list of (change, score) tuples, in the order of renames.
+#[pyfunction]
+fn _annotate_rename_scores(
+    py: Python,
+    renames: &Bound<PyAny>,
+    block_cache: &Bound<PyAny>,
+) -> PyResult<Py<PyAny>> {
+    let result = PyList::empty(py);
+    for change in renames.try_iter()? {
+        let change = change?;
+        let old = change.getattr("old")?;
+        let new = change.getattr("new")?;
+        if old.is_none() || new.is_none() {
+            return Err(PyValueError::new_err(format!(
+                "not a rename or copy: {}",
+                change.repr()?
+            )));
+        }
+        let score = rename_score(block_cache, &old, &new)?;
+        result.append((change, score))?;
+    }
+    Ok(result.unbind().into())
+}
+
+/// The similarity score of the old and new entries of a rename or copy,
+/// from their block counts in block_cache.
+fn rename_score(
+    block_cache: &Bound<PyAny>,
+    old: &Bound<PyAny>,
+    new: &Bound<PyAny>,
+) -> PyResult<usize> {
+    let old_sha = old.getattr("sha")?;
+    let new_sha = new.getattr("sha")?;
+    if same_sha(
+        &old_sha.extract::<Vec<u8>>()?,
+        &new_sha.extract::<Vec<u8>>()?,
+    ) {
+        return Ok(MAX_SCORE);
+    }
+    let blocks1 = block_counts_for_sha(block_cache, &old_sha)?;
+    let blocks2 = block_counts_for_sha(block_cache, &new_sha)?;
+    Ok(score(
+        common_bytes(&blocks1, &blocks2),
+        blocks_size(&blocks1),
+        blocks_size(&blocks2),
+    ))
+}
+
+/// A (delete_path, add_path, score) tuple.
+type ScoredPair = (Py<PyAny>, Py<PyAny>, usize);
+
+/// How pairs of candidates with different SHAs are scored.
+#[derive(Clone, Copy, Default)]
+struct Scoring<'a, 'py> {
+    /// Don't score them at all.
+    exact_only: bool,
+    /// Score them with a Python callable taking the two entries, rather than
+    /// by their blocks.
+    scorer: Option<&'a Bound<'py, PyAny>>,
+    /// Skip the pairs whose paths have different extensions, even with the
+    /// same SHA.
+    same_extension_only: bool,
+}
+
+/// Score a pair of candidates, if their score is higher than threshold.
+fn pair_score(
+    delete: &Candidate,
+    add: &Candidate,
+    threshold: usize,
+    scoring: Scoring,
+) -> PyResult<Option<usize>> {
+    if delete.fmt != add.fmt {
+        return Ok(None);
+    }
+    if scoring.same_extension_only && delete.extension != add.extension {
+        return Ok(None);
+    }
+    if same_sha(&delete.sha, &add.sha) {
+        return Ok(Some(MAX_SCORE).filter(|score| *score > threshold));
+    }
+    if scoring.exact_only {
+        return Ok(None);
+    }
+    if let Some(scorer) = scoring.scorer {
+        let score = scorer
+            .call1((&delete.entry, &add.entry))?
+            .extract::<usize>()?;
+        return Ok(Some(score).filter(|score| *score > threshold));
+    }
+    let needed = needed_bytes(threshold + 1, delete.size, add.size);
+    let totals = Some((delete.size, add.size));
+    let Some(common) = common_bytes_at_least(&delete.blocks, &add.blocks, totals, needed) else {
+        return Ok(None);
+    };
+    Ok(Some(score(common, delete.size, add.size)).filter(|score| *score > threshold))
+}
+
+fn score_pairs(
+    py: Python,
+    deletes: &[Candidate],
+    adds: &[Candidate],
+    rename_threshold: usize,
+    scoring: Scoring,
+) -> PyResult<Vec<ScoredPair>> {
+    let mut result = Vec::new();
+    for delete in deletes {
+        for add in adds {
+            if let Some(score) = pair_score(delete, add, rename_threshold, scoring)? {
+                result.push((delete.path.clone_ref(py), add.path.clone_ref(py), score));
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Find the best scoring delete for each add, stopping at a perfect score.
+fn best_pairs(
+    py: Python,
+    deletes: &[Candidate],
+    adds: &[Candidate],
+    rename_threshold: usize,
+    scoring: Scoring,
+) -> PyResult<Vec<ScoredPair>> {
+    // With the deletes in order of path, the first best delete is also the
+    // one with the smallest path.
+    let mut deletes = deletes
+        .iter()
+        .map(|delete| Ok((delete.path.extract::<Vec<u8>>(py)?, delete)))
+        .collect::<PyResult<Vec<_>>>()?;
+    deletes.sort_by(|(path1, _), (path2, _)| path1.cmp(path2));
+
+    let mut result = Vec::new();
+    for add in adds {
+        let mut best: Option<(&Candidate, usize)> = None;
+        for &(_, delete) in &deletes {
+            // Only a higher score than the best so far can replace it, so
+            // scoring can give up as soon as that's out of reach.
+            let threshold = best.map_or(rename_threshold, |(_, score)| score);
+            if let Some(score) = pair_score(delete, add, threshold, scoring)? {
+                best = Some((delete, score));
+                if score == MAX_SCORE {
+                    break;
+                }
+            }
+        }
+        if let Some((delete, score)) = best {
+            result.push((delete.path.clone_ref(py), add.path.clone_ref(py), score));
+        }
+    }
+    Ok(result)
+}
+
+/// Count the lines added and deleted between two versions of a blob, as
+/// git diff --numstat does.
+///
+/// A None side counts as empty, so all lines of the other side are added or
+/// deleted. The diff runs with the GIL released.
+///
+/// If ignore_whitespace is set, lines are compared with runs of whitespace
+/// collapsed and leading and trailing whitespace stripped (see
+/// normalize_line_whitespace), so that reindenting doesn't count as a
+/// change. This is close to git diff -w, but whitespace between words still
+/// has to be present on both sides for lines to match.
+#[pyfunction]
+#[pyo3(signature = (old_data, new_data, ignore_whitespace=false))]
+fn _blob_line_stats(
+    py: Python,
+    old_data: Option<&[u8]>,
+    new_data: Option<&[u8]>,
+    ignore_whitespace: bool,
+) -> (usize, usize) {
+    let old_data = old_data.unwrap_or_default();
+    let new_data = new_data.unwrap_or_default();
+    if old_data == new_data {
+        return (0, 0);
+    }
+    allow_threads(py, || {
+        let old_lines = old_data
+            .split_inclusive(|&c| c == b'\n')
+            .collect::<Vec<_>>();
+        let new_lines = new_data
+            .split_inclusive(|&c| c == b'\n')
+            .collect::<Vec<_>>();
+        if ignore_whitespace {
+            let normalize = |lines: &[&[u8]]| {
+                lines
+                    .iter()
+                    .map(|line| normalize_line_whitespace(line))
+                    .collect::<Vec<_>>()
+            };
+            line_stats(&normalize(&old_lines), &normalize(&new_lines))
+        } else {
+            line_stats(&old_lines, &new_lines)
+        }
+    })
+}
+
+/// Count the (added, deleted) lines in a diff of two lists of lines.
+fn line_stats<T: Eq + std::hash::Hash + Ord>(old_lines: &[T], new_lines: &[T]) -> (usize, usize) {
+    let ops = similar::capture_diff_slices(similar::Algorithm::Myers, old_lines, new_lines);
+    let mut added = 0;
+    let mut deleted = 0;
+    for op in ops {
+        match op {
+            similar::DiffOp::Equal { .. } => {}
+            similar::DiffOp::Delete { old_len, .. } => deleted += old_len,
+            similar::DiffOp::Insert { new_len, .. } => added += new_len,
+            similar::DiffOp::Replace {
+                old_len, new_len, ..
+            } => {
+                deleted += old_len;
+                added += new_len;
+            }
+        }
+    }
+    (added, deleted)
+}
+
+/// A hunk of a line diff, as returned by _blob_hunks.
+#[derive(Debug, PartialEq)]
+struct Hunk {
+    old_start: usize,
+    old_len: usize,
+    new_start: usize,
+    new_len: usize,
+    lines: Vec<Vec<u8>>,
+}
+
+/// Diff two lists of lines into hunks with context lines of context around
+/// the changes, merging hunks whose changes are at most 2 * context lines
+/// apart.
+fn line_hunks(old_lines: &[&[u8]], new_lines: &[&[u8]], context: usize) -> Vec<Hunk> {
+    let ops = similar::capture_diff_slices(similar::Algorithm::Myers, old_lines, new_lines);
+    similar::group_diff_ops(ops, context)
+        .iter()
+        .filter(|group| !group.is_empty())
+        .map(|group| {
+            let group: Vec<_> = group.iter().map(|op| op.as_tag_tuple()).collect();
+            group_hunk(old_lines, new_lines, &group)
+        })
+        .collect()
+}
+
+/// Build the hunk for a non-empty group of diff ops.
+fn group_hunk(old_lines: &[&[u8]], new_lines: &[&[u8]], group: &[DiffOp]) -> Hunk {
+    let (first, last) = (&group[0], &group[group.len() - 1]);
+    let old_start = first.1.start;
+    let new_start = first.2.start;
+    let mut lines = Vec::new();
+    let mut push = |prefix: u8, line: &[u8]| {
+        let mut prefixed = Vec::with_capacity(line.len() + 1);
+        prefixed.push(prefix);
+        prefixed.extend_from_slice(line);
+        lines.push(prefixed);
+    };
+    for (tag, old_range, new_range) in group {
+        if *tag == similar::DiffTag::Equal {
+            for line in &old_lines[old_range.clone()] {
+                push(b' ', line);
+            }
+            continue;
+        }
+        // Deletes have no new lines and inserts no old lines, so this also
+        // covers replaces.
+        for line in &old_lines[old_range.clone()] {
+            push(b'-', line);
+        }
+        for line in &new_lines[new_range.clone()] {
+            push(b'+', line);
+        }
+    }
+    Hunk {
+        old_start,
+        old_len: last.1.end - old_start,
+        new_start,
+        new_len: last.2.end - new_start,
+        lines,
+    }
+}
+
+/// A diff op as a (tag, old range, new range) tuple.
+type DiffOp = (similar::DiffTag, Range<usize>, Range<usize>);
+
+/// Find the matching blocks between two lists of lines as difflib's
+/// SequenceMatcher does, as (old_start, new_start, len) tuples ending with
+/// an empty block at the ends of both.
+///
+/// Like SequenceMatcher without isjunk, a line that makes up more than 1%
+/// of new_lines of 200 or more lines is only matched by extending a match
+/// of other lines over it.
+fn difflib_matching_blocks(old_lines: &[&[u8]], new_lines: &[&[u8]]) -> Vec<(usize, usize, usize)> {
+    let mut b2j: HashMap<&[u8], Vec<usize>> = HashMap::new();
+    for (j, line) in new_lines.iter().enumerate() {
+        b2j.entry(line).or_default().push(j);
+    }
+    if new_lines.len() >= 200 {
+        let popular = new_lines.len() / 100 + 1;
+        b2j.retain(|_, js| js.len() <= popular);
+    }
+    let longest_match = |alo: usize, ahi: usize, blo: usize, bhi: usize| {
+        let (mut besti, mut bestj, mut bestsize) = (alo, blo, 0);
+        // The lengths of the matches ending at each new line against the
+        // previous old line.
+        let mut j2len: HashMap<usize, usize> = HashMap::new();
+        for (i, line) in old_lines.iter().enumerate().take(ahi).skip(alo) {
+            let mut new_j2len = HashMap::new();
+            for &j in b2j.get(line).into_iter().flatten() {
+                if j < blo {
+                    continue;
+                }
+                if j >= bhi {
+                    break;
+                }
+                let k = j
+                    .checked_sub(1)
+                    .and_then(|j| j2len.get(&j))
+                    .map_or(1, |k| k + 1);
+                new_j2len.insert(j, k);
+                if k > bestsize {
+                    (besti, bestj, bestsize) = (i + 1 - k, j + 1 - k, k);
+                }
+            }
+            j2len = new_j2len;
+        }
+        while besti > alo && bestj > blo && old_lines[besti - 1] == new_lines[bestj - 1] {
+            (besti, bestj, bestsize) = (besti - 1, bestj - 1, bestsize + 1);
+        }
+        while besti + bestsize < ahi
+            && bestj + bestsize < bhi
+            && old_lines[besti + bestsize] == new_lines[bestj + bestsize]
+        {
+            bestsize += 1;
+        }
+        (besti, bestj, bestsize)
+    };
+    let mut blocks = Vec::new();
+    let mut queue = vec![(0, old_lines.len(), 0, new_lines.len())];
+    while let Some((alo, ahi, blo, bhi)) = queue.pop() {
+        let (i, j, k) = longest_match(alo, ahi, blo, bhi);
+        if k == 0 {
+            continue;
+        }
+        blocks.push((i, j, k));
+        if alo < i && blo < j {
+            queue.push((alo, i, blo, j));
+        }
+        if i + k < ahi && j + k < bhi {
+            queue.push((i + k, ahi, j + k, bhi));
+        }
+    }
+    blocks.sort_unstable();
+    let mut merged: Vec<(usize, usize, usize)> = Vec::with_capacity(blocks.len() + 1);
+    for (i, j, k) in blocks {
+        match merged.last_mut() {
+            Some(last) if last.0 + last.2 == i && last.1 + last.2 == j => last.2 += k,
+            _ => merged.push((i, j, k)),
+        }
+    }
+    merged.push((old_lines.len(), new_lines.len(), 0));
+    merged
+}
+
+/// Diff two lists of lines into hunks as difflib's unified_diff does, with
+/// SequenceMatcher's alignment and get_grouped_opcodes' grouping.
+fn difflib_hunks(old_lines: &[&[u8]], new_lines: &[&[u8]], context: usize) -> Vec<Hunk> {
+    use similar::DiffTag;
+    let mut ops: Vec<DiffOp> = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    for (ai, bj, size) in difflib_matching_blocks(old_lines, new_lines) {
+        let tag = match (i < ai, j < bj) {
+            (true, true) => Some(DiffTag::Replace),
+            (true, false) => Some(DiffTag::Delete),
+            (false, true) => Some(DiffTag::Insert),
+            (false, false) => None,
+        };
+        if let Some(tag) = tag {
+            ops.push((tag, i..ai, j..bj));
+        }
+        (i, j) = (ai + size, bj + size);
+        if size > 0 {
+            ops.push((DiffTag::Equal, ai..i, bj..j));
+        }
+    }
+    if let Some((DiffTag::Equal, old, new)) = ops.first_mut() {
+        old.start = old.start.max(old.end.saturating_sub(context));
+        new.start = new.start.max(new.end.saturating_sub(context));
+    }
+    if let Some((DiffTag::Equal, old, new)) = ops.last_mut() {
+        old.end = old.end.min(old.start + context);
+        new.end = new.end.min(new.start + context);
+    }
+    let mut hunks = Vec::new();
+    let mut group = Vec::new();
+    for (tag, mut old, mut new) in ops {
+        if tag == DiffTag::Equal && old.len() > 2 * context {
+            group.push((
+                tag,
+                old.start..old.end.min(old.start + context),
+                new.start..new.end.min(new.start + context),
+            ));
+            hunks.push(group_hunk(old_lines, new_lines, &group));
+            group.clear();
+            old.start = old.start.max(old.end - context);
+            new.start = new.start.max(new.end - context);
+        }
+        group.push((tag, old, new));
+    }
+    if group.iter().any(|(tag, _, _)| *tag != DiffTag::Equal) {
+        hunks.push(group_hunk(old_lines, new_lines, &group));
+    }
+    hunks
+}
+
+/// Diff two versions of a blob into hunks, as for a unified diff.
+///
+/// Each hunk is an (old_start, old_len, new_start, new_len, lines) tuple,
+/// where the starts are 0-based line numbers and lines are the context,
+/// deleted and added lines prefixed with b" ", b"-" and b"+". Each hunk has
+/// up to context unchanged lines around its changes, and changes at most
+/// 2 * context lines apart share a hunk.
+///
+/// A None side counts as empty. The diff runs with the GIL released.
+#[pyfunction]
+#[pyo3(signature = (old_data, new_data, context=3))]
+#[allow(clippy::type_complexity)]
+fn _blob_hunks<'py>(
+    py: Python<'py>,
+    old_data: Option<&[u8]>,
+    new_data: Option<&[u8]>,
+    context: usize,
+) -> Vec<(usize, usize, usize, usize, Vec<Bound<'py, PyBytes>>)> {
+    let old_data = old_data.unwrap_or_default();
+    let new_data = new_data.unwrap_or_default();
+    if old_data == new_data {
+        return Vec::new();
+    }
+    let hunks = allow_threads(py, || {
+        let old_lines = old_data
+            .split_inclusive(|&c| c == b'\n')
+            .collect::<Vec<_>>();
+        let new_lines = new_data
+            .split_inclusive(|&c| c == b'\n')
+            .collect::<Vec<_>>();
+        line_hunks(&old_lines, &new_lines, context)
+    });
+    hunks
+        .into_iter()
+        .map(|hunk| {
+            let lines = hunk
+                .lines
+                .iter()
+                .map(|line| PyBytes::new(py, line))
+                .collect();
+            (
+                hunk.old_start,
+                hunk.old_len,
+                hunk.new_start,
+                hunk.new_len,
+                lines,
+            )
+        })
+        .collect()
+}
+
+/// Collapse each run of whitespace in a line to a single space, and strip
+/// leading and trailing whitespace (including the newline).
+fn normalize_line_whitespace(line: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(line.len());
+    for word in line
+        .split(u8::is_ascii_whitespace)
+        .filter(|word| !word.is_empty())
+    {
+        if !result.is_empty() {
+            result.push(b' ');
+        }
+        result.extend_from_slice(word);
+    }
+    result
+}
+
+/// Git looks for a NUL in this many bytes to decide whether data is binary.
+const FIRST_FEW_BYTES: usize = 8000;
+
+/// Check whether data is binary, i.e. has a NUL in its first 8000 bytes.
+///
+/// At most 8000 bytes are scanned, which is too quick to be worth releasing
+/// the GIL for.
+#[pyfunction]
+fn _is_binary(data: &[u8]) -> bool {
+    data[..data.len().min(FIRST_FEW_BYTES)].contains(&0)
+}
+
+/// Check whether the contents of an object are binary, only looking at the
+/// chunks making up the first 8000 bytes.
+///
+/// If force_binary is not None, it is returned without looking at the
+/// contents, e.g. as already decided by .gitattributes.
+#[pyfunction]
+#[pyo3(signature = (obj, force_binary=None))]
+fn _is_binary_obj(py: Python, obj: &Bound<PyAny>, force_binary: Option<bool>) -> PyResult<bool> {
+    if let Some(force_binary) = force_binary {
+        return Ok(force_binary);
+    }
+    let chunks = obj.call_method0("as_raw_chunks")?;
+    let mut remaining = FIRST_FEW_BYTES;
+    for chunk in chunks.try_iter()? {
+        let chunk = chunk?;
+        let data = chunk_data(py, &chunk)?;
+        if data[..data.len().min(remaining)].contains(&0) {
+            return Ok(true);
+        }
+        remaining = remaining.saturating_sub(data.len());
+        if remaining == 0 {
+            break;
+        }
+    }
+    Ok(false)
+}
+
+/// Return the mode of a tree entry, or None if there is no entry or it has
+/// no mode.
+fn entry_mode(entry: &Bound<PyAny>) -> PyResult<Option<u32>> {
+    if entry.is_none() {
+        return Ok(None);
+    }
+
+    let mode = entry.getattr("mode")?;
+
+    if mode.is_none() {
+        Ok(None)
+    } else {
+        Ok(Some(mode.extract::<u32>()?))
+    }
+}
+
+#[pyfunction]
+fn _is_tree(_py: Python, entry: &Bound<PyAny>) -> PyResult<bool> {
+    Ok(entry_mode(entry)?.is_some_and(|mode| (mode & S_IFMT) == S_IFDIR))
+}
+
+#[pyfunction]
+fn _are_trees(entries: Vec<Bound<PyAny>>) -> PyResult<Vec<bool>> {
+    entries
+        .iter()
+        .map(|entry| Ok(entry_mode(entry)?.is_some_and(|mode| (mode & S_IFMT) == S_IFDIR)))
+        .collect()
+}
+
+#[pyfunction]
+fn _is_blob(_py: Python, entry: &Bound<PyAny>) -> PyResult<bool> {
+    Ok(entry_mode(entry)?.is_some_and(|mode| {
+        let fmt = mode & S_IFMT;
+        fmt == S_IFREG || fmt == S_IFLNK
+    }))
+}
+
+#[pyfunction]
+fn _is_gitlink(_py: Python, entry: &Bound<PyAny>) -> PyResult<bool> {
+    Ok(entry_mode(entry)?.is_some_and(|mode| (mode & S_IFMT) == S_IFGITLINK))
+}
+
+// Kinds of change between two entries, as returned by _classify_change.
+const KIND_ADD: u8 = 0;
+const KIND_DELETE: u8 = 1;
+const KIND_MODIFY: u8 = 2;
+const KIND_UNCHANGED: u8 = 3;
+const KIND_TYPECHANGE: u8 = 4;
+const KIND_MODE_CHANGE: u8 = 5;
+
+/// Classify the change between two entries for the same path as one of the
+/// KIND_* constants.
+#[pyfunction]
+fn _classify_change(entry1: &Bound<PyAny>, entry2: &Bound<PyAny>) -> PyResult<u8> {
+    match (entry1.is_none(), entry2.is_none()) {
+        (true, true) => return Err(PyValueError::new_err("at least one entry must not be None")),
+        (true, false) => return Ok(KIND_ADD),
+        (false, true) => return Ok(KIND_DELETE),
+        (false, false) => {}
+    }
+    let mode1 = entry_mode(entry1)?;
+    let mode2 = entry_mode(entry2)?;
+    let same_sha = entry1.getattr("sha")?.eq(entry2.getattr("sha")?)?;
+    if mode1 == mode2 && same_sha {
+        return Ok(KIND_UNCHANGED);
+    }
+    match (mode1, mode2) {
+        (Some(mode1), Some(mode2)) if (mode1 & S_IFMT) != (mode2 & S_IFMT) => Ok(KIND_TYPECHANGE),
+        _ if same_sha => Ok(KIND_MODE_CHANGE),
+        _ => Ok(KIND_MODIFY),
+    }
+}
+
+/// The path, mode and sha of an entry, or Nones if there is no entry.
+fn entry_fields<'py>(entry: &Bound<'py, PyAny>) -> PyResult<[Bound<'py, PyAny>; 3]> {
+    if entry.is_none() {
+        return Ok([entry.clone(), entry.clone(), entry.clone()]);
+    }
+    Ok([
+        entry.getattr("path")?,
+        entry.getattr("mode")?,
+        entry.getattr("sha")?,
+    ])
+}
+
+/// Describe the change between two entries for the same path, as the kind
+/// returned by _classify_change followed by the path, mode and sha of each
+/// entry.
+#[pyfunction]
+fn _change_record<'py>(
+    entry1: &Bound<'py, PyAny>,
+    entry2: &Bound<'py, PyAny>,
+) -> PyResult<Bound<'py, PyTuple>> {
+    let py = entry1.py();
+    let kind = _classify_change(entry1, entry2)?
+        .into_pyobject(py)?
+        .into_any();
+    let [old_path, old_mode, old_sha] = entry_fields(entry1)?;
+    let [new_path, new_mode, new_sha] = entry_fields(entry2)?;
+    PyTuple::new(
+        py,
+        [
+            kind, old_path, old_mode, old_sha, new_path, new_mode, new_sha,
+        ],
+    )
+}
+
+// Outcomes of a three-way merge of one path, as returned by _merge3_entry.
+const MERGE3_UNCHANGED: u8 = 0;
+const MERGE3_OURS: u8 = 1;
+const MERGE3_THEIRS: u8 = 2;
+const MERGE3_SAME: u8 = 3;
+const MERGE3_CONFLICT: u8 = 4;
+
+/// Check whether two entries (or Nones) have the same mode and sha.
+fn same_entry_contents(entry1: &Bound<PyAny>, entry2: &Bound<PyAny>) -> PyResult<bool> {
+    if entry1.is_none() || entry2.is_none() {
+        return Ok(entry1.is_none() && entry2.is_none());
+    }
+    Ok(entry_mode(entry1)? == entry_mode(entry2)?
+        && entry1.getattr("sha")?.eq(entry2.getattr("sha")?)?)
+}
+
+/// Decide how a path merges in a three-way merge, as one of the MERGE3_*
+/// constants.
+#[pyfunction]
+fn _merge3_entry(base: &Bound<PyAny>, ours: &Bound<PyAny>, theirs: &Bound<PyAny>) -> PyResult<u8> {
+    let ours_changed = !same_entry_contents(base, ours)?;
+    let theirs_changed = !same_entry_contents(base, theirs)?;
+    Ok(match (ours_changed, theirs_changed) {
+        (false, false) => MERGE3_UNCHANGED,
+        (true, false) => MERGE3_OURS,
+        (false, true) => MERGE3_THEIRS,
+        (true, true) if same_entry_contents(ours, theirs)? => MERGE3_SAME,
+        (true, true) => MERGE3_CONFLICT,
+    })
+}
+
+/// A TreeEntry object along with its fields, so that they don't have to be
+/// looked up on the object for every comparison.
+struct Entry {
+    path: Vec<u8>,
+    mode: u32,
+    sha: Py<PyAny>,
+    obj: Py<PyAny>,
+}
+
+impl Entry {
+    fn is_tree(&self) -> bool {
+        (self.mode & S_IFMT) == S_IFDIR
+    }
+
+    /// Equivalent of comparing the two TreeEntry objects with ==.
+    fn eq(&self, other: &Entry, py: Python) -> PyResult<bool> {
+        Ok(self.path == other.path
+            && self.mode == other.mode
+            && self.sha.bind(py).eq(other.sha.bind(py))?)
+    }
+}
+
+fn tree_entries(path: &[u8], tree: &Bound<PyAny>, py: Python) -> PyResult<Vec<Entry>> {
+    tree_entries_with(path, tree, py, false)
+}
+
+/// Like tree_entries, but with plain (path, mode, sha) tuples rather than
+/// TreeEntry objects for the objects of the entries if raw is set.
+fn tree_entries_with(
+    path: &[u8],
+    tree: &Bound<PyAny>,
+    py: Python,
+    raw: bool,
+) -> PyResult<Vec<Entry>> {
+    if tree.is_none() {
+        return Ok(Vec::new());
+    }
+    items_to_entries(path, &name_order_items(tree)?, py, raw)
+}
+
+/// The (name, mode, sha) of the entries of a tree, in name order.
+type NameOrderItems = Vec<(Vec<u8>, u32, Py<PyAny>)>;
+
+fn name_order_items(tree: &Bound<PyAny>) -> PyResult<NameOrderItems> {
+    let items = tree
+        .call_method1("iteritems", (true,))?
+        .extract::<Vec<Bound<PyAny>>>()?;
+    items.iter().map(|item| item.extract()).collect()
+}
+
+fn items_to_entries(
+    path: &[u8],
+    items: &NameOrderItems,
+    py: Python,
+    raw: bool,
+) -> PyResult<Vec<Entry>> {
+    if raw {
+        return items
+            .iter()
+            .map(|(name, mode, sha)| new_raw_entry(path, name, *mode, sha.clone_ref(py), py))
+            .collect();
+    }
+
+    let dom = py.import("dulwich.objects")?;
+    let tree_entry_cls = dom.getattr("TreeEntry")?;
+
+    let mut result = Vec::new();
+    for (name, mode, sha) in items {
+        result.push(new_entry(
+            path,
+            name,
+            *mode,
+            sha.clone_ref(py),
+            &tree_entry_cls,
+        )?);
+    }
+
+    Ok(result)
+}
+
+/// A bounded LRU cache of the entries of trees, keyed by SHA, so that a
+/// diff looks up each tree it meets more than once in the store only once.
+///
+/// The entries are cached without their paths, since the same tree may
+/// occur at several paths.
+struct TreeCache {
+    capacity: usize,
+    trees: HashMap<Vec<u8>, (u64, Option<Rc<NameOrderItems>>)>,
+    clock: u64,
+}
+
+impl TreeCache {
+    fn new(capacity: usize) -> Self {
+        TreeCache {
+            capacity,
+            trees: HashMap::new(),
+            clock: 0,
+        }
+    }
+
+    /// The entries of the tree for an entry, or None if the entry is not a
+    /// tree or the object in the store is not a Tree.
+    fn get(
+        &mut self,
+        py: Python,
+        store: &Bound<PyAny>,
+        tree_cls: &Bound<PyAny>,
+        entry: Option<&Entry>,
+    ) -> PyResult<Option<Rc<NameOrderItems>>> {
+        let Some(entry) = entry.filter(|entry| entry.is_tree()) else {
+            return Ok(None);
+        };
+        let sha = entry.sha.bind(py);
+        self.clock += 1;
+        let key = sha.extract::<Vec<u8>>()?;
+        if let Some((last_used, items)) = self.trees.get_mut(&key) {
+            *last_used = self.clock;
+            return Ok(items.clone());
+        }
+        let tree = store.get_item(sha)?;
+        let items = if tree.is_instance(tree_cls)? {
+            Some(Rc::new(name_order_items(&tree)?))
+        } else {
+            None
+        };
+        if self.capacity > 0 {
+            if self.trees.len() >= self.capacity {
+                let oldest = self
+                    .trees
+                    .iter()
+                    .min_by_key(|(_, (last_used, _))| *last_used)
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    self.trees.remove(&oldest);
+                }
+            }
+            self.trees.insert(key, (self.clock, items.clone()));
+        }
+        Ok(items)
+    }
+}
+
+/// List the entries of a tree given by its SHA, looking it up in store.
+///
+/// None, the null SHA and the empty tree SHA all give no entries, without a
+/// lookup.
+#[pyfunction]
+#[pyo3(signature = (store, tree_id, path=b"".as_slice()))]
+fn _tree_entries_from_store<'py>(
+    py: Python<'py>,
+    store: &Bound<'py, PyAny>,
+    tree_id: Option<&Bound<'py, PyBytes>>,
+    path: &[u8],
+) -> PyResult<Vec<Py<PyAny>>> {
+    let Some(tree_id) = tree_id else {
+        return Ok(Vec::new());
+    };
+    let sha = tree_id.as_bytes();
+    if EMPTY_TREE_IDS.contains(&sha) || sha.iter().all(|&c| c == b'0') {
+        return Ok(Vec::new());
+    }
+    let tree = store.get_item(tree_id)?;
+    Ok(tree_entries(path, &tree, py)?
+        .into_iter()
+        .map(|entry| entry.obj)
+        .collect())
+}
+
+/// Like tree_entries, but with plain (path, mode, sha) tuples, for callers
+/// that don't need TreeEntry objects.
+#[pyfunction]
+fn _tree_entry_tuples(py: Python, path: &[u8], tree: &Bound<PyAny>) -> PyResult<Vec<Py<PyAny>>> {
+    Ok(tree_entries_with(path, tree, py, true)?
+        .into_iter()
+        .map(|entry| entry.obj)
+        .collect())
+}
+
+fn new_entry(
+    path: &[u8],
+    name: &[u8],
+    mode: u32,
+    sha: Py<PyAny>,
+    tree_entry_cls: &Bound<PyAny>,
+) -> PyResult<Entry> {
+    let py = tree_entry_cls.py();
+    let new_path = join_path(path, name);
+    let tree_entry = tree_entry_cls.call1((PyBytes::new(py, &new_path), mode, &sha))?;
+    Ok(Entry {
+        path: new_path,
+        mode,
+        sha,
+        obj: tree_entry.unbind(),
+    })
+}
+
+/// Like new_entry, but with a plain (path, mode, sha) tuple for the object,
+/// which is much cheaper to create than a TreeEntry.
+fn new_raw_entry(
+    path: &[u8],
+    name: &[u8],
+    mode: u32,
+    sha: Py<PyAny>,
+    py: Python,
+) -> PyResult<Entry> {
+    let new_path = join_path(path, name);
+    let obj = (PyBytes::new(py, &new_path), mode, sha.clone_ref(py)).into_pyobject(py)?;
+    Ok(Entry {
+        path: new_path,
+        mode,
+        sha,
+        obj: obj.into_any().unbind(),
+    })
+}
+
+fn join_path(path: &[u8], name: &[u8]) -> Vec<u8> {
+    let mut new_path = Vec::with_capacity(path.len() + name.len() + 1);
+    if !path.is_empty() {
+        new_path.extend_from_slice(path);
+        new_path.push(b'/');
+    }
+    new_path.extend_from_slice(name);
+    new_path
+}
+
+/// Collapse repeated slashes in path and strip trailing ones.
+fn normalize_tree_path(path: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(path.len());
+    for &c in path {
+        if c == b'/' && result.last() == Some(&b'/') {
+            continue;
+        }
+        result.push(c);
+    }
+    while result.last() == Some(&b'/') {
+        result.pop();
+    }
+    result
+}
+
+/// Canonicalize a path for comparison with tree entry paths, which are
+/// joined with single slashes.
+#[pyfunction]
+fn _normalize_tree_path<'py>(py: Python<'py>, path: &[u8]) -> Bound<'py, PyBytes> {
+    PyBytes::new(py, &normalize_tree_path(path))
+}
+
+/// Like tree_entries, but only for the entries whose path starts with
+/// prefix. Since the entries are sorted by path, these are found by binary
+/// search, and no TreeEntry objects are created for the others.
+fn tree_entries_with_prefix(
+    path: &[u8],
+    tree: &Bound<PyAny>,
+    prefix: &[u8],
+    py: Python,
+) -> PyResult<Vec<Entry>> {
+    if tree.is_none() {
+        return Ok(Vec::new());
+    }
+
+    let dom = py.import("dulwich.objects")?;
+    let tree_entry_cls = dom.getattr("TreeEntry")?;
+
+    let items = tree
+        .call_method1("iteritems", (true,))?
+        .extract::<Vec<(Vec<u8>, u32, Py<PyAny>)>>()?;
+    let start = items.partition_point(|(name, _, _)| join_path(path, name).as_slice() < prefix);
+    let len =
+        items[start..].partition_point(|(name, _, _)| join_path(path, name).starts_with(prefix));
+
+    items
+        .into_iter()
+        .skip(start)
+        .take(len)
+        .map(|(name, mode, sha)| new_entry(path, &name, mode, sha, &tree_entry_cls))
+        .collect()
+}
+
+/// Like tree_entries, but for a list of (name, mode, sha) tuples, which have
+/// to be sorted by name.
+fn list_entries(path: &[u8], items: &Bound<PyAny>) -> PyResult<Vec<Entry>> {
+    let py = items.py();
+    let dom = py.import("dulwich.objects")?;
+    let tree_entry_cls = dom.getattr("TreeEntry")?;
+
+    let mut result = Vec::new();
+    let mut prev: Option<Vec<u8>> = None;
+    for item in items.try_iter()? {
+        let (name, mode, sha) = item?.extract::<(Vec<u8>, u32, Py<PyAny>)>()?;
+        if prev.as_ref().is_some_and(|prev| name <= *prev) {
+            return Err(PyValueError::new_err(format!(
+                "tree entries are not sorted by name at {:?}",
+                PyBytes::new(py, &name)
+            )));
+        }
+        result.push(new_entry(path, &name, mode, sha, &tree_entry_cls)?);
+        prev = Some(name);
+    }
+
+    Ok(result)
+}
+
+/// Compare the paths of two tree entries.
+///
+/// This is a plain byte comparison rather than Git's tree order (which sorts
+/// directories as if they had a trailing slash). That is deliberate: entries
+/// come from iteritems(name_order=True), and the comparison has to agree with
+/// the order the entries are iterated in. It also means that a blob and a
+/// tree with the same name are paired up, so that tree_changes can report
+/// the change of type.
+///
+/// If case_insensitive is set, ASCII letters are folded to lower case before
+/// comparing. The entries then have to be sorted with sort_case_insensitive.
+fn entry_path_cmp(path1: &[u8], path2: &[u8], case_insensitive: bool) -> Ordering {
+    if case_insensitive {
+        path1
+            .iter()
+            .map(u8::to_ascii_lowercase)
+            .cmp(path2.iter().map(u8::to_ascii_lowercase))
+    } else {
+        path1.cmp(path2)
+    }
+}
+
+/// Compare two tree entries in Git's tree order, where the name of a
+/// directory sorts as if it had a trailing slash.
+fn tree_order_cmp(path1: &[u8], mode1: u32, path2: &[u8], mode2: u32) -> Ordering {
+    let len = std::cmp::min(path1.len(), path2.len());
+    path1[..len].cmp(&path2[..len]).then_with(|| {
+        let suffix = |path: &[u8], mode: u32| match path.get(len) {
+            Some(&c) => c,
+            None if (mode & S_IFMT) == S_IFDIR => b'/',
+            None => 0,
+        };
+        suffix(path1, mode1).cmp(&suffix(path2, mode2))
+    })
+}
+
+/// Compare two TreeEntry objects in Git's tree order, returning -1, 0 or 1.
+#[pyfunction]
+fn _cmp_tree_entries(entry1: &Bound<PyAny>, entry2: &Bound<PyAny>) -> PyResult<i32> {
+    let (path1, mode1, _) = entry1.extract::<(Vec<u8>, u32, Bound<PyAny>)>()?;
+    let (path2, mode2, _) = entry2.extract::<(Vec<u8>, u32, Bound<PyAny>)>()?;
+    Ok(tree_order_cmp(&path1, mode1, &path2, mode2) as i32)
+}
+
+/// Sort a list of TreeEntry objects in place, in Git's tree order.
+#[pyfunction]
+fn _sort_tree_entries(entries: &Bound<PyList>) -> PyResult<()> {
+    let mut keyed = entries
+        .iter()
+        .map(|entry| {
+            let (path, mode, _) = entry.extract::<(Vec<u8>, u32, Bound<PyAny>)>()?;
+            Ok((path, mode, entry))
+        })
+        .collect::<PyResult<Vec<_>>>()?;
+    keyed.sort_by(|(path1, mode1, _), (path2, mode2, _)| {
+        tree_order_cmp(path1, *mode1, path2, *mode2)
+    });
+    for (i, (_, _, entry)) in keyed.into_iter().enumerate() {
+        entries.set_item(i, entry)?;
+    }
+    Ok(())
+}
+
+/// Find the first pair of entries of a tree, as serialized, that are out of
+/// Git's tree order, which matters since Tree objects sort their entries
+/// when iterating over them.
+///
+/// Returns None if there is none, or else (index, name1, name2) for the
+/// pair, where index is that of the second entry. Entries with the same
+/// name count as out of order.
+#[pyfunction]
+#[allow(clippy::type_complexity)]
+fn _check_tree_sorted<'py>(
+    tree: &Bound<'py, PyAny>,
+) -> PyResult<Option<(usize, Bound<'py, PyAny>, Bound<'py, PyAny>)>> {
+    let py = tree.py();
+    let kwargs = PyDict::new(py);
+    kwargs.set_item(
+        "sha_len",
+        tree.getattr("object_format")?.getattr("oid_length")?,
+    )?;
+    let entries = py
+        .import("dulwich.objects")?
+        .getattr("parse_tree")?
+        .call((tree.call_method0("as_raw_string")?,), Some(&kwargs))?;
+    let mut last: Option<(Bound<PyAny>, Vec<u8>, u32)> = None;
+    for (i, entry) in entries.try_iter()?.enumerate() {
+        let (name, mode, _) = entry?.extract::<(Bound<PyAny>, u32, Bound<PyAny>)>()?;
+        let name_bytes = name.extract::<Vec<u8>>()?;
+        if let Some((last_name, last_bytes, last_mode)) = last {
+            if tree_order_cmp(&last_bytes, last_mode, &name_bytes, mode) != Ordering::Less
+                || last_bytes == name_bytes
+            {
+                return Ok(Some((i, last_name, name)));
+            }
+        }
+        last = Some((name, name_bytes, mode));
+    }
+    Ok(None)
+}
+
+/// Sort entries in case-insensitive order, using the original paths to
+/// break ties between entries that only differ in case.
+fn sort_case_insensitive(entries: &mut [Entry]) {
+    entries.sort_by(|e1, e2| {
+        entry_path_cmp(&e1.path, &e2.path, true).then_with(|| e1.path.cmp(&e2.path))
+    });
+}
+
+type EntryIter = Peekable<std::vec::IntoIter<Entry>>;
+
+/// A pair of items that compare equal, with None in place of a missing
+/// item.
+pub type SortedPair<T> = (Option<T>, Option<T>);
+
+/// Take the next pair of items that compare equal from two sorted
+/// iterators, or None if both are exhausted. An item without an equal in
+/// the other iterator is paired with None.
+pub fn next_sorted_pair<T, I1, I2, E, F>(
+    iter1: &mut Peekable<I1>,
+    iter2: &mut Peekable<I2>,
+    cmp: &mut F,
+) -> Result<Option<SortedPair<T>>, E>
+where
+    I1: Iterator<Item = T>,
+    I2: Iterator<Item = T>,
+    F: FnMut(&T, &T) -> Result<Ordering, E>,
+{
+    let ordering = match (iter1.peek(), iter2.peek()) {
+        (Some(item1), Some(item2)) => cmp(item1, item2)?,
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => return Ok(None),
+    };
+    Ok(Some(match ordering {
+        Ordering::Equal => (iter1.next(), iter2.next()),
+        Ordering::Less => (iter1.next(), None),
+        Ordering::Greater => (None, iter2.next()),
+    }))
+}
+
+/// Pair up the items of two sequences sorted by cmp that compare equal,
+/// filling in None for items without an equal in the other sequence.
+pub fn merge_sorted<T, E, F>(
+    a: impl IntoIterator<Item = T>,
+    b: impl IntoIterator<Item = T>,
+    mut cmp: F,
+) -> Result<Vec<SortedPair<T>>, E>
+where
+    F: FnMut(&T, &T) -> Result<Ordering, E>,
+{
+    let mut iter1 = a.into_iter().peekable();
+    let mut iter2 = b.into_iter().peekable();
+    let mut result = Vec::with_capacity(iter1.size_hint().0.max(iter2.size_hint().0));
+    while let Some(pair) = next_sorted_pair(&mut iter1, &mut iter2, &mut cmp)? {
+        result.push(pair);
+    }
+    Ok(result)
+}
+
+/// Take the next pair of entries with the same path from two sorted entry
+/// iterators, or None if both are exhausted.
+fn next_entry_pair(
+    iter1: &mut EntryIter,
+    iter2: &mut EntryIter,
+    case_insensitive: bool,
+) -> Option<(Option<Entry>, Option<Entry>)> {
+    let Ok(pair) = next_sorted_pair(iter1, iter2, &mut |e1: &Entry, e2: &Entry| {
+        Ok::<_, Infallible>(entry_path_cmp(&e1.path, &e2.path, case_insensitive))
+    });
+    pair
+}
+
+/// Pair up the entries of two trees that have the same path.
+fn merge_entry_vecs(
+    entries1: Vec<Entry>,
+    entries2: Vec<Entry>,
+    case_insensitive: bool,
+) -> Vec<(Option<Entry>, Option<Entry>)> {
+    let Ok(pairs) = merge_sorted(entries1, entries2, |e1, e2| {
+        Ok::<_, Infallible>(entry_path_cmp(&e1.path, &e2.path, case_insensitive))
+    });
+    pairs
+}
+
+/// Pair up the items of two lists sorted by their key_attr attribute that
+/// have equal keys, filling in None for items without a match.
+#[pyfunction]
+fn _merge_sorted_lists<'py>(
+    py: Python<'py>,
+    list1: &Bound<'py, PyAny>,
+    list2: &Bound<'py, PyAny>,
+    key_attr: &str,
+) -> PyResult<Bound<'py, PyList>> {
+    let keyed = |list: &Bound<'py, PyAny>| {
+        list.try_iter()?
+            .map(|item| {
+                let item = item?;
+                Ok((item.getattr(key_attr)?, item))
+            })
+            .collect::<PyResult<Vec<_>>>()
+    };
+    let pairs = merge_sorted(keyed(list1)?, keyed(list2)?, |(key1, _), (key2, _)| {
+        key1.compare(key2)
+    })?;
+    let none = py.None().into_bound(py);
+    let result = pairs
+        .into_iter()
+        .map(|(item1, item2)| {
+            let item1 = item1.map_or_else(|| none.clone(), |(_, item)| item);
+            let item2 = item2.map_or_else(|| none.clone(), |(_, item)| item);
+            PyTuple::new(py, [item1, item2])
+        })
+        .collect::<PyResult<Vec<_>>>()?;
+    PyList::new(py, result)
+}
+
+fn entry_pair_to_tuple<'py>(
+    py: Python<'py>,
+    (e1, e2): (Option<Entry>, Option<Entry>),
+) -> PyResult<Bound<'py, PyTuple>> {
+    let e1 = e1.map_or_else(|| py.None(), |entry| entry.obj);
+    let e2 = e2.map_or_else(|| py.None(), |entry| entry.obj);
+    PyTuple::new(py, [e1, e2])
+}
+
+fn too_many_entries(max_entries: usize) -> PyErr {
+    PyValueError::new_err(format!(
+        "merged tree entries exceed max_entries ({max_entries})"
+    ))
+}
+
+#[pyfunction]
+#[pyo3(signature = (path, tree1, tree2, case_insensitive=false, max_entries=None, skip_identical=false, ignore_modes=Vec::new(), normalize_path=false, only_changed=false, raw=false, reverse=false))]
+#[allow(clippy::too_many_arguments)]
+fn _merge_entries(
+    py: Python,
+    path: &[u8],
+    tree1: &Bound<PyAny>,
+    tree2: &Bound<PyAny>,
+    case_insensitive: bool,
+    max_entries: Option<usize>,
+    skip_identical: bool,
+    ignore_modes: Vec<u32>,
+    normalize_path: bool,
+    only_changed: bool,
+    raw: bool,
+    reverse: bool,
+) -> PyResult<Py<PyAny>> {
+    if skip_identical
+        && !tree1.is_none()
+        && !tree2.is_none()
+        && (tree1.is(tree2) || tree1.getattr("id")?.eq(tree2.getattr("id")?)?)
+    {
+        return Ok(PyList::empty(py).unbind().into());
+    }
+
+    if let Some(max_entries) = max_entries.filter(|_| !only_changed) {
+        // There are at least as many pairs as entries in either tree, so
+        // check before creating any entries.
+        for tree in [tree1, tree2] {
+            if !tree.is_none() && tree.len()? > max_entries {
+                return Err(too_many_entries(max_entries));
+            }
+        }
+    }
+
+    let path = if normalize_path {
+        Cow::Owned(normalize_tree_path(path))
+    } else {
+        Cow::Borrowed(path)
+    };
+    let mut entries1 = tree_entries_with(&path, tree1, py, raw)?;
+    let mut entries2 = tree_entries_with(&path, tree2, py, raw)?;
+    if !ignore_modes.is_empty() {
+        let ignored = |entry: &Entry| {
+            ignore_modes
+                .iter()
+                .any(|mode| (mode & S_IFMT) == (entry.mode & S_IFMT))
+        };
+        entries1.retain(|entry| !ignored(entry));
+        entries2.retain(|entry| !ignored(entry));
+    }
+    if case_insensitive {
+        sort_case_insensitive(&mut entries1);
+        sort_case_insensitive(&mut entries2);
+    }
+
+    let mut pairs = merge_entry_vecs(entries1, entries2, case_insensitive);
+    if only_changed {
+        let mut changed = Vec::with_capacity(pairs.len());
+        for pair in pairs {
+            // The same test for an unchanged entry as in _classify_change.
+            if let (Some(entry1), Some(entry2)) = &pair {
+                if entry1.mode == entry2.mode && entry1.sha.bind(py).eq(&entry2.sha)? {
+                    continue;
+                }
+            }
+            changed.push(pair);
+        }
+        pairs = changed;
+    }
+    if let Some(max_entries) = max_entries {
+        if pairs.len() > max_entries {
+            return Err(too_many_entries(max_entries));
+        }
+    }
+    let result = pairs
+        .into_iter()
+        .map(|(entry1, entry2)| {
+            if reverse {
+                entry_pair_to_tuple(py, (entry2, entry1))
+            } else {
+                entry_pair_to_tuple(py, (entry1, entry2))
+            }
+        })
+        .collect::<PyResult<Vec<_>>>()?;
+
+    Ok(PyList::new(py, &result)?.unbind().into())
+}
+
+/// Like _merge_entries, but only return the pairs of entries whose path
+/// starts with prefix.
+#[pyfunction]
+fn _merge_entries_prefixed(
+    py: Python,
+    path: &[u8],
+    tree1: &Bound<PyAny>,
+    tree2: &Bound<PyAny>,
+    prefix: &[u8],
+) -> PyResult<Py<PyAny>> {
+    let entries1 = tree_entries_with_prefix(path, tree1, prefix, py)?;
+    let entries2 = tree_entries_with_prefix(path, tree2, prefix, py)?;
+    let result = merge_entry_vecs(entries1, entries2, false)
+        .into_iter()
+        .map(|pair| entry_pair_to_tuple(py, pair))
+        .collect::<PyResult<Vec<_>>>()?;
+
+    Ok(PyList::new(py, &result)?.unbind().into())
+}
+
+/// Like _merge_entries, but for two lists of (name, mode, sha) tuples sorted
+/// by name, so that in-memory entries don't need to be wrapped in Trees.
+#[pyfunction]
+fn _merge_entry_lists(
+    py: Python,
+    path: &[u8],
+    list1: &Bound<PyAny>,
+    list2: &Bound<PyAny>,
+) -> PyResult<Py<PyAny>> {
+    let entries1 = list_entries(path, list1)?;
+    let entries2 = list_entries(path, list2)?;
+    let result = merge_entry_vecs(entries1, entries2, false)
+        .into_iter()
+        .map(|pair| entry_pair_to_tuple(py, pair))
+        .collect::<PyResult<Vec<_>>>()?;
+
+    Ok(PyList::new(py, &result)?.unbind().into())
+}
+
+/// The KIND_* constant for two merged entries, as _classify_change returns
+/// for the TreeEntries.
+fn classify_entries(py: Python, entry1: Option<&Entry>, entry2: Option<&Entry>) -> PyResult<u8> {
+    let (entry1, entry2) = match (entry1, entry2) {
+        (None, None) => return Err(PyValueError::new_err("at least one entry must not be None")),
+        (None, Some(_)) => return Ok(KIND_ADD),
+        (Some(_), None) => return Ok(KIND_DELETE),
+        (Some(entry1), Some(entry2)) => (entry1, entry2),
+    };
+    let same_sha = entry1.sha.bind(py).eq(&entry2.sha)?;
+    Ok(if entry1.mode == entry2.mode && same_sha {
+        KIND_UNCHANGED
+    } else if (entry1.mode & S_IFMT) != (entry2.mode & S_IFMT) {
+        KIND_TYPECHANGE
+    } else if same_sha {
+        KIND_MODE_CHANGE
+    } else {
+        KIND_MODIFY
+    })
+}
+
+/// Merge the entries of two trees and classify each pair, returning
+/// (kind, entry1, entry2) tuples with the kinds of _classify_change.
+///
+/// Unchanged pairs are left out unless want_unchanged is set.
+#[pyfunction]
+#[pyo3(signature = (path, tree1, tree2, want_unchanged=false))]
+fn _diff_entries(
+    py: Python,
+    path: &[u8],
+    tree1: &Bound<PyAny>,
+    tree2: &Bound<PyAny>,
+    want_unchanged: bool,
+) -> PyResult<Py<PyAny>> {
+    let entries1 = tree_entries(path, tree1, py)?;
+    let entries2 = tree_entries(path, tree2, py)?;
+    let mut result = Vec::new();
+    for (entry1, entry2) in merge_entry_vecs(entries1, entries2, false) {
+        let kind = classify_entries(py, entry1.as_ref(), entry2.as_ref())?;
+        if kind == KIND_UNCHANGED && !want_unchanged {
+            continue;
+        }
+        let obj = |entry: Option<Entry>| entry.map_or_else(|| py.None(), |entry| entry.obj);
+        result.push((kind, obj(entry1), obj(entry2)).into_pyobject(py)?);
+    }
+
+    Ok(PyList::new(py, &result)?.unbind().into())
+}
+
+/// Add the entries of a tree other than subtrees to result, along with
+/// those of its subtrees, looked up in store.
+fn add_blob_entries(
+    py: Python,
+    path: &[u8],
+    tree: &Bound<PyAny>,
+    store: Option<&Bound<PyAny>>,
+    result: &mut Vec<Entry>,
+) -> PyResult<()> {
+    for entry in tree_entries(path, tree, py)? {
+        if (entry.mode & S_IFMT) != S_IFDIR {
+            result.push(entry);
+            continue;
+        }
+        let Some(store) = store else {
+            return Err(PyValueError::new_err(
+                "a store is needed to look up subtrees",
+            ));
+        };
+        let subtree = store.get_item(entry.sha.bind(py))?;
+        add_blob_entries(py, &entry.path, &subtree, Some(store), result)?;
+    }
+    Ok(())
+}
+
+/// Compare the (path, mode, sha) entries of an index, sorted by path, with
+/// the entries of a tree and its subtrees, as git status does.
+///
+/// Returns (kind, tree_entry, index_entry) tuples for the changed paths,
+/// sorted by path, with the kinds of _classify_change.
+#[pyfunction]
+#[pyo3(signature = (index_entries, tree, path=b"".as_slice(), store=None))]
+fn _index_tree_changes(
+    py: Python,
+    index_entries: &Bound<PyAny>,
+    tree: &Bound<PyAny>,
+    path: &[u8],
+    store: Option<&Bound<PyAny>>,
+) -> PyResult<Py<PyAny>> {
+    let mut committed = Vec::new();
+    add_blob_entries(py, path, tree, store, &mut committed)?;
+    // The entries of a subtree come right after its name, but in path order
+    // b"a/b" sorts after b"a.c".
+    committed.sort_by(|entry1, entry2| entry1.path.cmp(&entry2.path));
+    let staged = list_entries(path, index_entries)?;
+    let mut result = Vec::new();
+    for (entry1, entry2) in merge_entry_vecs(committed, staged, false) {
+        let kind = classify_entries(py, entry1.as_ref(), entry2.as_ref())?;
+        if kind == KIND_UNCHANGED {
+            continue;
+        }
+        let obj = |entry: Option<Entry>| entry.map_or_else(|| py.None(), |entry| entry.obj);
+        result.push((kind, obj(entry1), obj(entry2)).into_pyobject(py)?);
+    }
+
+    Ok(PyList::new(py, &result)?.unbind().into())
+}
+
+/// Iterator over the entries of a tree in name order, with their paths
+/// joined onto a base path.
+///
+/// Unlike tree_entries, each TreeEntry (and its path) is only created once
+/// it is reached, so stopping early avoids creating the rest.
+#[pyclass(module = "dulwich._diff_tree")]
+struct TreeEntryIter {
+    path: Vec<u8>,
+    items: std::vec::IntoIter<Py<PyAny>>,
+    tree_entry_cls: Py<PyAny>,
+}
+
+#[pymethods]
+impl TreeEntryIter {
+    #[new]
+    #[pyo3(signature = (tree, path=b"".to_vec()))]
+    fn new(py: Python, tree: &Bound<PyAny>, path: Vec<u8>) -> PyResult<Self> {
+        let items = if tree.is_none() {
+            Vec::new()
+        } else {
+            tree.call_method1("iteritems", (true,))?
+                .extract::<Vec<Py<PyAny>>>()?
+        };
+        let tree_entry_cls = py.import("dulwich.objects")?.getattr("TreeEntry")?;
+        Ok(TreeEntryIter {
+            path,
+            items: items.into_iter(),
+            tree_entry_cls: tree_entry_cls.unbind(),
+        })
+    }
+
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&mut self, py: Python) -> PyResult<Option<Py<PyAny>>> {
+        let Some(item) = self.items.next() else {
+            return Ok(None);
+        };
+        let (name, mode, sha) = item.extract::<(Vec<u8>, u32, Py<PyAny>)>(py)?;
+        let entry = new_entry(&self.path, &name, mode, sha, self.tree_entry_cls.bind(py))?;
+        Ok(Some(entry.obj))
+    }
+
+    fn __length_hint__(&self) -> usize {
+        self.items.len()
+    }
+}
 
-const S_IFMT: u32 = 0o170000;
-const S_IFDIR: u32 = 0o040000;
+/// Iterator over the pairs of entries of two trees, as returned by
+/// _iter_merge_entries.
+#[pyclass(module = "dulwich._diff_tree")]
+struct MergeEntriesIterator {
+    entries1: EntryIter,
+    entries2: EntryIter,
+}
 
-fn add_hash(get: &Bound<PyAny>, set: &Bound<PyAny>, string: &[u8], py: Python) -> PyResult<()> {
-    let str_obj = PyBytes::new(py, string);
-    let hash_obj = str_obj.hash()?;
-    let value = get.call1((hash_obj,))?;
-    let n = string.len();
-    set.call1((hash_obj, value.extract::<usize>()? + n))?;
-    Ok(())
+#[pymethods]
+impl MergeEntriesIterator {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__<'py>(&mut self, py: Python<'py>) -> PyResult<Option<Bound<'py, PyTuple>>> {
+        next_entry_pair(&mut self.entries1, &mut self.entries2, false)
+            .map(|pair| entry_pair_to_tuple(py, pair))
+            .transpose()
+    }
 }
 
+/// Like _merge_entries, but yield the pairs of entries one at a time rather
+/// than building a list of all of them.
 #[pyfunction]
-fn _count_blocks(py: Python, obj: &Bound<PyAny>) -> PyResult<Py<PyAny>> {
-    let default_dict_cls = PyModule::import(py, "collections")?.getattr("defaultdict")?;
-    let int_cls = PyModule::import(py, "builtins")?.getattr("int")?;
+fn _iter_merge_entries(
+    py: Python,
+    path: &[u8],
+    tree1: &Bound<PyAny>,
+    tree2: &Bound<PyAny>,
+) -> PyResult<MergeEntriesIterator> {
+    Ok(MergeEntriesIterator {
+        entries1: tree_entries(path, tree1, py)?.into_iter().peekable(),
+        entries2: tree_entries(path, tree2, py)?.into_iter().peekable(),
+    })
+}
 
-    let counts = default_dict_cls.call1((int_cls,))?;
-    let get = counts.getattr("__getitem__")?;
-    let set = counts.getattr("__setitem__")?;
+#[pyfunction]
+fn _merge_entries_multi(py: Python, path: &[u8], trees: Vec<Bound<PyAny>>) -> PyResult<Py<PyAny>> {
+    let entries = trees
+        .iter()
+        .map(|tree| tree_entries(path, tree, py))
+        .collect::<PyResult<Vec<_>>>()?;
+    let mut positions = vec![0; entries.len()];
 
-    let chunks = obj.call_method0("as_raw_chunks")?;
-    if !chunks.is_instance_of::<PyList>() {
-        return Err(PyTypeError::new_err(
-            "as_raw_chunks() did not return a list",
-        ));
+    let mut result = Vec::new();
+    loop {
+        // Find the smallest path among the current entry of each tree.
+        let min = entries
+            .iter()
+            .zip(&positions)
+            .filter_map(|(tree_entries, &i)| tree_entries.get(i))
+            .map(|entry| entry.path.as_slice())
+            .min();
+        let Some(min) = min else {
+            break;
+        };
+
+        let mut row = Vec::with_capacity(entries.len());
+        for (tree_entries, i) in entries.iter().zip(positions.iter_mut()) {
+            match tree_entries.get(*i) {
+                Some(entry) if entry.path == min => {
+                    row.push(entry.obj.clone_ref(py));
+                    *i += 1;
+                }
+                _ => row.push(py.None()),
+            }
+        }
+        result.push(PyTuple::new(py, row)?);
     }
 
-    let num_chunks = chunks.extract::<Vec<Py<PyAny>>>()?.len();
-    let pym = py.import("dulwich.diff_tree")?;
-    let block_size = pym.getattr("_BLOCK_SIZE")?.extract::<usize>()?;
-    let mut block: Vec<u8> = Vec::with_capacity(block_size);
+    Ok(PyList::new(py, &result)?.unbind().into())
+}
+
+/// The path a TreeChange is about: the old path for deletes, otherwise the
+/// new path.
+fn change_path(change: &Bound<PyAny>, change_delete: &Bound<PyAny>) -> PyResult<Vec<u8>> {
+    let entry = if change.getattr("type")?.eq(change_delete)? {
+        change.getattr("old")?
+    } else {
+        change.getattr("new")?
+    };
+    entry.getattr("path")?.extract::<Vec<u8>>()
+}
 
-    for i in 0..num_chunks {
-        let chunk = chunks.get_item(i)?;
-        if !chunk.is_instance_of::<PyBytes>() {
-            return Err(PyTypeError::new_err("chunk is not a string"));
+/// Check whether key is the same for all changes.
+fn all_same<'py>(
+    changes: &[&Bound<'py, PyAny>],
+    key: impl Fn(&Bound<'py, PyAny>) -> PyResult<Bound<'py, PyAny>>,
+) -> PyResult<bool> {
+    let Some((first, rest)) = changes.split_first() else {
+        return Ok(true);
+    };
+    let value = key(first)?;
+    for change in rest {
+        if !key(change)?.eq(&value)? {
+            return Ok(false);
         }
-        let chunk_str = chunk.extract::<&[u8]>()?;
+    }
+    Ok(true)
+}
 
-        for c in chunk_str {
-            block.push(*c);
-            if *c == b'\n' || block.len() == block_size {
-                add_hash(&get, &set, &block, py)?;
-                block.clear();
-            }
+/// Get the tree changes for a merge tree relative to all its parents.
+///
+/// This is the equivalent of tree_changes_for_merge, returning a list of
+/// the lists of TreeChanges for conflicted paths, sorted by path.
+#[pyfunction]
+#[pyo3(signature = (store, parent_tree_ids, tree_id, rename_detector=None))]
+fn _tree_changes_for_merge<'py>(
+    py: Python<'py>,
+    store: &Bound<'py, PyAny>,
+    parent_tree_ids: Vec<Bound<'py, PyAny>>,
+    tree_id: &Bound<'py, PyAny>,
+    rename_detector: Option<&Bound<'py, PyAny>>,
+) -> PyResult<Bound<'py, PyList>> {
+    let change_delete = py.import("dulwich.diff_tree")?.getattr("CHANGE_DELETE")?;
+    let num_parents = parent_tree_ids.len();
+
+    // Organize by path.
+    let mut changes_by_path: BTreeMap<Vec<u8>, Vec<Option<Bound<PyAny>>>> = BTreeMap::new();
+    for (i, parent_tree_id) in parent_tree_ids.iter().enumerate() {
+        let parent_changes = _tree_changes(
+            py,
+            store,
+            parent_tree_id,
+            tree_id,
+            false,
+            false,
+            rename_detector,
+            false,
+            None,
+            DEFAULT_TREE_CACHE_SIZE,
+            false,
+            None,
+        )?;
+        for change in parent_changes.bind(py).try_iter()? {
+            let change = change?;
+            let path = change_path(&change, &change_delete)?;
+            changes_by_path
+                .entry(path)
+                .or_insert_with(|| vec![None; num_parents])[i] = Some(change);
         }
     }
-    if !block.is_empty() {
-        add_hash(&get, &set, &block, py)?;
+
+    let old_sha = |change: &Bound<'py, PyAny>| {
+        let old = change.getattr("old")?;
+        if old.is_none() {
+            Ok(old)
+        } else {
+            old.getattr("sha")
+        }
+    };
+    let change_type = |change: &Bound<'py, PyAny>| change.getattr("type");
+
+    // Only keep conflicting changes.
+    let result = PyList::empty(py);
+    for changes in changes_by_path.into_values() {
+        let have = changes.iter().flatten().collect::<Vec<_>>();
+        let mut all_deletes = true;
+        for change in &have {
+            all_deletes &= change.getattr("type")?.eq(&change_delete)?;
+        }
+        let conflict = if all_deletes {
+            !all_same(&have, old_sha)?
+        } else if !all_same(&have, change_type)? {
+            true
+        } else {
+            // If no change was found relative to one parent, that means the
+            // SHA must have matched the SHA in that parent, so it is not a
+            // conflict.
+            have.len() == num_parents
+        };
+        if conflict {
+            let row = changes
+                .iter()
+                .map(|change| {
+                    change
+                        .as_ref()
+                        .map_or_else(|| py.None(), |c| c.clone().unbind())
+                })
+                .collect::<Vec<_>>();
+            result.append(PyList::new(py, row)?)?;
+        }
     }
+    Ok(result)
+}
 
-    Ok(counts.into_pyobject(py).unwrap().into())
+fn change_entry_path<'py>(entry: Bound<'py, PyAny>) -> PyResult<Option<Bound<'py, PyAny>>> {
+    if entry.is_none() {
+        return Ok(None);
+    }
+    let path = entry.getattr("path")?;
+    Ok((!path.is_none()).then_some(path))
 }
 
+/// Sort key for TreeChanges: the old path, then the new path. If only one
+/// of them exists, it is used for both.
 #[pyfunction]
-fn _is_tree(_py: Python, entry: &Bound<PyAny>) -> PyResult<bool> {
-    if entry.is_none() {
-        return Ok(false);
+fn _tree_change_key<'py>(
+    change: &Bound<'py, PyAny>,
+) -> PyResult<(Bound<'py, PyAny>, Bound<'py, PyAny>)> {
+    let path1 = change_entry_path(change.getattr("old")?)?;
+    let path2 = change_entry_path(change.getattr("new")?)?;
+    match (path1, path2) {
+        (Some(path1), Some(path2)) => Ok((path1, path2)),
+        (Some(path), None) | (None, Some(path)) => Ok((path.clone(), path)),
+        (None, None) => Err(PyValueError::new_err("change has no paths")),
     }
+}
 
-    let mode = entry.getattr("mode")?;
+/// Split TreeChanges into lists of adds, deletes and the rest, in a single
+/// pass.
+#[pyfunction]
+#[allow(clippy::type_complexity)]
+fn _bucket_changes<'py>(
+    py: Python<'py>,
+    changes: &Bound<'py, PyAny>,
+) -> PyResult<(
+    Vec<Bound<'py, PyAny>>,
+    Vec<Bound<'py, PyAny>>,
+    Vec<Bound<'py, PyAny>>,
+)> {
+    let diff_tree = py.import("dulwich.diff_tree")?;
+    let change_add = diff_tree.getattr("CHANGE_ADD")?;
+    let change_delete = diff_tree.getattr("CHANGE_DELETE")?;
+    let mut adds = Vec::new();
+    let mut deletes = Vec::new();
+    let mut others = Vec::new();
+    for change in changes.try_iter()? {
+        let change = change?;
+        let change_type = change.getattr("type")?;
+        if change_type.eq(&change_add)? {
+            adds.push(change);
+        } else if change_type.eq(&change_delete)? {
+            deletes.push(change);
+        } else {
+            others.push(change);
+        }
+    }
+    Ok((adds, deletes, others))
+}
 
-    if mode.is_none() {
-        Ok(false)
-    } else {
-        let lmode = mode.extract::<u32>()?;
-        Ok((lmode & S_IFMT) == S_IFDIR)
+/// Count the changes under each directory, as a dict of directory path to
+/// (adds, deletes, modifies) totals.
+///
+/// Each change counts towards every directory containing it, up to the
+/// top-level directory, whose path is empty. Renames and copies count as
+/// modifies of their new path, and unchanged entries are not counted.
+#[pyfunction]
+fn _dir_change_summary<'py>(
+    py: Python<'py>,
+    changes: &Bound<'py, PyAny>,
+) -> PyResult<Bound<'py, PyDict>> {
+    let diff_tree = py.import("dulwich.diff_tree")?;
+    let change_add = diff_tree.getattr("CHANGE_ADD")?;
+    let change_delete = diff_tree.getattr("CHANGE_DELETE")?;
+    let change_unchanged = diff_tree.getattr("CHANGE_UNCHANGED")?;
+    let mut totals: HashMap<Vec<u8>, [usize; 3]> = HashMap::new();
+    for change in changes.try_iter()? {
+        let change = change?;
+        let change_type = change.getattr("type")?;
+        let (kind, entry) = if change_type.eq(&change_unchanged)? {
+            continue;
+        } else if change_type.eq(&change_add)? {
+            (0, change.getattr("new")?)
+        } else if change_type.eq(&change_delete)? {
+            (1, change.getattr("old")?)
+        } else {
+            (2, change.getattr("new")?)
+        };
+        let mut path = entry.getattr("path")?.extract::<Vec<u8>>()?;
+        loop {
+            path.truncate(split_path(&path).0.len());
+            totals.entry(path.clone()).or_default()[kind] += 1;
+            if path.is_empty() {
+                break;
+            }
+        }
+    }
+    let result = PyDict::new(py);
+    for (path, [adds, deletes, modifies]) in totals {
+        result.set_item(PyBytes::new(py, &path), (adds, deletes, modifies))?;
     }
+    Ok(result)
 }
 
-fn tree_entries(path: &[u8], tree: &Bound<PyAny>, py: Python) -> PyResult<Vec<Py<PyAny>>> {
-    if tree.is_none() {
-        return Ok(Vec::new());
+/// Check whether the entry at path is max_depth or more levels below the
+/// root, and so too deep to recurse into.
+fn beyond_depth(path: &[u8], max_depth: Option<usize>) -> bool {
+    max_depth.is_some_and(|max_depth| {
+        !path.is_empty() && path.iter().filter(|&&c| c == b'/').count() >= max_depth
+    })
+}
+
+/// Check whether a tree at path has to be walked to find entries matching
+/// one of the filter paths.
+fn should_recurse(path: &[u8], paths: &[Vec<u8>]) -> bool {
+    path.is_empty()
+        || paths.iter().any(|filter_path| {
+            filter_path.as_slice() == path
+                || is_under(filter_path, path)
+                || is_under(path, filter_path)
+        })
+}
+
+/// Check whether an entry at path matches one of the filter paths.
+fn matches_paths(path: &[u8], is_tree: bool, paths: &[Vec<u8>]) -> bool {
+    paths.iter().any(|filter_path| {
+        filter_path.as_slice() == path
+            || is_under(path, filter_path)
+            || (is_tree && is_under(filter_path, path))
+    })
+}
+
+/// Check whether path is below the directory dir.
+fn is_under(path: &[u8], dir: &[u8]) -> bool {
+    path.len() > dir.len() && path.starts_with(dir) && path[dir.len()] == b'/'
+}
+
+fn root_entry(py: Python, tree_id: &Bound<PyAny>, raw: bool) -> PyResult<Option<Entry>> {
+    if !tree_id.is_truthy()? {
+        return Ok(None);
+    }
+    if raw {
+        return new_raw_entry(b"", b"", S_IFDIR, tree_id.clone().unbind(), py).map(Some);
     }
+    let tree_entry_cls = py.import("dulwich.objects")?.getattr("TreeEntry")?;
+    let obj = tree_entry_cls.call1((PyBytes::new(py, b""), S_IFDIR, tree_id))?;
+    Ok(Some(Entry {
+        path: Vec::new(),
+        mode: S_IFDIR,
+        sha: tree_id.clone().unbind(),
+        obj: obj.unbind(),
+    }))
+}
 
-    let dom = py.import("dulwich.objects")?;
-    let tree_entry_cls = dom.getattr("TreeEntry")?;
+/// The name, mode and sha of a tree entry.
+type TreeItem<'py> = (Vec<u8>, u32, Bound<'py, PyAny>);
 
-    let items = tree
-        .call_method1("iteritems", (true,))?
-        .extract::<Vec<Py<PyAny>>>()?;
+/// The items of a tree, sorted by name, without creating TreeEntry objects.
+fn tree_items<'py>(tree: &Bound<'py, PyAny>) -> PyResult<Vec<TreeItem<'py>>> {
+    if tree.is_none() {
+        return Ok(Vec::new());
+    }
+    let mut items = Vec::new();
+    for name in tree.try_iter()? {
+        let name = name?;
+        let (mode, sha) = tree.get_item(&name)?.extract::<(u32, Bound<PyAny>)>()?;
+        items.push((name.extract::<Vec<u8>>()?, mode, sha));
+    }
+    items.sort_by(|(name1, _, _), (name2, _, _)| name1.cmp(name2));
+    Ok(items)
+}
 
+/// List the paths of the files that differ between two trees, as
+/// tree_changes would report them, recursing into subtrees.
+#[pyfunction]
+#[pyo3(signature = (store, tree1, tree2, path=&b""[..]))]
+fn _changed_paths<'py>(
+    py: Python<'py>,
+    store: &Bound<'py, PyAny>,
+    tree1: &Bound<'py, PyAny>,
+    tree2: &Bound<'py, PyAny>,
+    path: &[u8],
+) -> PyResult<Vec<Bound<'py, PyBytes>>> {
     let mut result = Vec::new();
-    for item in items {
-        let (name, mode, sha) = item.extract::<(Vec<u8>, u32, Py<PyAny>)>(py)?;
+    add_changed_paths(py, store, tree1, tree2, path, &mut result)?;
+    Ok(result.iter().map(|path| PyBytes::new(py, path)).collect())
+}
 
-        let mut new_path = Vec::with_capacity(path.len() + name.len() + 1);
-        if !path.is_empty() {
-            new_path.extend_from_slice(path);
-            new_path.push(b'/');
+fn add_changed_paths<'py>(
+    py: Python<'py>,
+    store: &Bound<'py, PyAny>,
+    tree1: &Bound<'py, PyAny>,
+    tree2: &Bound<'py, PyAny>,
+    path: &[u8],
+    result: &mut Vec<Vec<u8>>,
+) -> PyResult<()> {
+    let mut items1 = tree_items(tree1)?.into_iter().peekable();
+    let mut items2 = tree_items(tree2)?.into_iter().peekable();
+    loop {
+        let cmp = match (items1.peek(), items2.peek()) {
+            (Some(item1), Some(item2)) => item1.0.cmp(&item2.0),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => return Ok(()),
+        };
+        let (item1, item2) = match cmp {
+            Ordering::Equal => (items1.next(), items2.next()),
+            Ordering::Less => (items1.next(), None),
+            Ordering::Greater => (None, items2.next()),
+        };
+        if let (Some((_, mode1, sha1)), Some((_, mode2, sha2))) = (&item1, &item2) {
+            if mode1 == mode2 && sha1.eq(sha2)? {
+                continue;
+            }
         }
-        new_path.extend_from_slice(name.as_slice());
 
-        let tree_entry = tree_entry_cls.call1((PyBytes::new(py, &new_path), mode, sha))?;
-        result.push(tree_entry.into_pyobject(py).unwrap().into());
+        let name = match (&item1, &item2) {
+            (Some((name, _, _)), _) | (None, Some((name, _, _))) => name,
+            (None, None) => unreachable!(),
+        };
+        let item_path = join_path(path, name);
+        let subtree = |item: &Option<TreeItem<'py>>| match item {
+            Some((_, mode, sha)) if (mode & S_IFMT) == S_IFDIR => store.get_item(sha).map(Some),
+            _ => Ok(None),
+        };
+        let subtree1 = subtree(&item1)?;
+        let subtree2 = subtree(&item2)?;
+        // A file replaced by a tree (or the other way around) is a change of
+        // both the file and the contents of the tree.
+        if (item1.is_some() && subtree1.is_none()) || (item2.is_some() && subtree2.is_none()) {
+            result.push(item_path.clone());
+        }
+        if subtree1.is_some() || subtree2.is_some() {
+            let none = py.None().into_bound(py);
+            add_changed_paths(
+                py,
+                store,
+                subtree1.as_ref().unwrap_or(&none),
+                subtree2.as_ref().unwrap_or(&none),
+                &item_path,
+                result,
+            )?;
+        }
     }
-
-    Ok(result)
 }
 
-fn entry_path_cmp(entry1: &Bound<PyAny>, entry2: &Bound<PyAny>) -> PyResult<Ordering> {
-    let path1_o = entry1.getattr("path")?;
-    let path1 = path1_o.extract::<&[u8]>()?;
-    let path2_o = entry2.getattr("path")?;
-    let path2 = path2_o.extract::<&[u8]>()?;
-    Ok(path1.cmp(path2))
+/// Check whether two trees have the same entries, stopping at the first
+/// difference.
+///
+/// With ignore_mode, only the names and SHAs of the entries are compared.
+/// Subtrees whose SHAs differ (such as because of the modes of their
+/// entries) are then compared recursively if a store is given, and
+/// otherwise count as different.
+#[pyfunction]
+#[pyo3(signature = (tree1, tree2, ignore_mode=false, store=None))]
+fn _trees_content_equal(
+    tree1: &Bound<PyAny>,
+    tree2: &Bound<PyAny>,
+    ignore_mode: bool,
+    store: Option<&Bound<PyAny>>,
+) -> PyResult<bool> {
+    let items1 = tree_items(tree1)?;
+    let items2 = tree_items(tree2)?;
+    if items1.len() != items2.len() {
+        return Ok(false);
+    }
+    for ((name1, mode1, sha1), (name2, mode2, sha2)) in items1.iter().zip(&items2) {
+        if name1 != name2 {
+            return Ok(false);
+        }
+        if (ignore_mode || mode1 == mode2) && sha1.eq(sha2)? {
+            continue;
+        }
+        let both_trees = (mode1 & S_IFMT) == S_IFDIR && (mode2 & S_IFMT) == S_IFDIR;
+        match store {
+            Some(store) if ignore_mode && both_trees => {
+                let subtree1 = store.get_item(sha1)?;
+                let subtree2 = store.get_item(sha2)?;
+                if !_trees_content_equal(&subtree1, &subtree2, ignore_mode, Some(store))? {
+                    return Ok(false);
+                }
+            }
+            _ => return Ok(false),
+        }
+    }
+    Ok(true)
 }
 
+/// Walk two trees and collect the TreeChanges between them.
+///
+/// This is the equivalent of tree_changes (along with walk_trees) without
+/// going back and forth to Python for every entry.
+///
+/// The entries of up to tree_cache_size trees are cached by SHA (see
+/// TreeCache), so that trees that occur more than once are only looked up
+/// once; 0 disables the cache.
+///
+/// Subtrees that are max_depth or more levels below the root (so every
+/// subtree for 0) are not recursed into. Instead a pruned subtree that
+/// differs is reported as a single change of its tree entry, a modify if it
+/// is a tree on both sides, even if include_trees is false. max_depth can't
+/// be combined with a rename_detector.
 #[pyfunction]
-fn _merge_entries(
+#[pyo3(signature = (store, tree1_id, tree2_id, want_unchanged=false, include_trees=false, rename_detector=None, change_type_same=false, paths=None, tree_cache_size=DEFAULT_TREE_CACHE_SIZE, raw=false, max_depth=None))]
+#[allow(clippy::too_many_arguments)]
+fn _tree_changes(
     py: Python,
-    path: &[u8],
-    tree1: &Bound<PyAny>,
-    tree2: &Bound<PyAny>,
+    store: &Bound<PyAny>,
+    tree1_id: &Bound<PyAny>,
+    tree2_id: &Bound<PyAny>,
+    want_unchanged: bool,
+    include_trees: bool,
+    rename_detector: Option<&Bound<PyAny>>,
+    change_type_same: bool,
+    paths: Option<Vec<Vec<u8>>>,
+    tree_cache_size: usize,
+    raw: bool,
+    max_depth: Option<usize>,
 ) -> PyResult<Py<PyAny>> {
-    let entries1 = tree_entries(path, tree1, py)?;
-    let entries2 = tree_entries(path, tree2, py)?;
+    if rename_detector.is_some() && max_depth.is_some() {
+        return Err(PyValueError::new_err(
+            "max_depth cannot be combined with a rename_detector",
+        ));
+    }
+    if let Some(rename_detector) = rename_detector {
+        if !tree1_id.is_none() && !tree2_id.is_none() {
+            let kwargs = PyDict::new(py);
+            kwargs.set_item("want_unchanged", want_unchanged)?;
+            kwargs.set_item("include_trees", include_trees)?;
+            let changes = rename_detector.call_method(
+                "changes_with_renames",
+                (tree1_id, tree2_id),
+                Some(&kwargs),
+            )?;
+            if !raw {
+                return Ok(changes.unbind());
+            }
+            let changes = changes
+                .try_iter()?
+                .map(|change| raw_change(&change?))
+                .collect::<PyResult<Vec<_>>>()?;
+            return Ok(PyList::new(py, changes)?.unbind().into());
+        }
+    }
+
+    let diff_tree = py.import("dulwich.diff_tree")?;
+    let tree_change_cls = diff_tree.getattr("TreeChange")?;
+    let change_add = diff_tree.getattr("CHANGE_ADD")?;
+    let change_delete = diff_tree.getattr("CHANGE_DELETE")?;
+    let change_modify = diff_tree.getattr("CHANGE_MODIFY")?;
+    let change_unchanged = diff_tree.getattr("CHANGE_UNCHANGED")?;
+    let tree_cls = py.import("dulwich.objects")?.getattr("Tree")?;
+    let new_change = |change_type: &Bound<PyAny>, old: &Py<PyAny>, new: &Py<PyAny>| {
+        let change = if raw {
+            (change_type, old, new).into_pyobject(py)?.into_any()
+        } else {
+            tree_change_cls.call1((change_type, old, new))?
+        };
+        Ok::<_, PyErr>(change.unbind())
+    };
 
+    let none = py.None().into_bound(py);
+    let mut tree_cache = TreeCache::new(tree_cache_size);
     let mut result = Vec::new();
 
-    let mut i1 = 0;
-    let mut i2 = 0;
-    while i1 < entries1.len() && i2 < entries2.len() {
-        let cmp = entry_path_cmp(entries1[i1].bind(py), entries2[i2].bind(py))?;
-        let (e1, e2) = match cmp {
-            Ordering::Equal => (entries1[i1].clone_ref(py), entries2[i2].clone_ref(py)),
-            Ordering::Less => (entries1[i1].clone_ref(py), py.None()),
-            Ordering::Greater => (py.None(), entries2[i2].clone_ref(py)),
+    if paths.is_none() && !tree1_id.is_truthy()? {
+        // Against no tree, as for a root commit, every entry is an add, so
+        // there is nothing to merge. The empty tree is still merged, as its
+        // root entry is reported as modified with include_trees.
+        let mut todo = Vec::from_iter(root_entry(py, tree2_id, raw)?);
+        while let Some(entry) = todo.pop() {
+            let pruned = beyond_depth(&entry.path, max_depth);
+            let items = if pruned {
+                None
+            } else {
+                tree_cache.get(py, store, &tree_cls, Some(&entry))?
+            };
+            if let Some(items) = items {
+                let mut children = items_to_entries(&entry.path, &items, py, raw)?;
+                children.reverse();
+                todo.extend(children);
+            }
+            if include_trees || pruned || !entry.is_tree() {
+                result.push(new_change(&change_add, &none.clone().unbind(), &entry.obj)?);
+            }
+        }
+        return Ok(PyList::new(py, &result)?.unbind().into());
+    }
+
+    let mut todo = vec![(
+        root_entry(py, tree1_id, raw)?,
+        root_entry(py, tree2_id, raw)?,
+    )];
+    while let Some((entry1, entry2)) = todo.pop() {
+        let is_tree1 = entry1.as_ref().is_some_and(Entry::is_tree);
+        let is_tree2 = entry2.as_ref().is_some_and(Entry::is_tree);
+        let equal = match (&entry1, &entry2) {
+            (Some(e1), Some(e2)) => e1.eq(e2, py)?,
+            _ => false,
         };
-        let pair = PyTuple::new(py, &[e1, e2]).unwrap();
-        result.push(pair);
-        match cmp {
-            Ordering::Equal => {
-                i1 += 1;
-                i2 += 1;
+        if !want_unchanged && is_tree1 && is_tree2 && equal {
+            continue;
+        }
+
+        let path = entry1
+            .as_ref()
+            .or(entry2.as_ref())
+            .map_or(&[][..], |entry| entry.path.as_slice())
+            .to_vec();
+        if let Some(paths) = &paths {
+            if (is_tree1 || is_tree2) && !should_recurse(&path, paths) {
+                continue;
             }
-            Ordering::Less => {
-                i1 += 1;
+        }
+
+        let pruned = beyond_depth(&path, max_depth);
+        let (items1, items2) = if pruned {
+            (None, None)
+        } else {
+            (
+                tree_cache.get(py, store, &tree_cls, entry1.as_ref())?,
+                tree_cache.get(py, store, &tree_cls, entry2.as_ref())?,
+            )
+        };
+        if items1.is_some() || items2.is_some() {
+            let entries = |items: Option<Rc<NameOrderItems>>| match items {
+                Some(items) => items_to_entries(&path, &items, py, raw),
+                None => Ok(Vec::new()),
+            };
+            let entries1 = entries(items1)?;
+            let entries2 = entries(items2)?;
+            let mut merged = merge_entry_vecs(entries1, entries2, false);
+            merged.reverse();
+            todo.extend(merged);
+        }
+
+        if let Some(paths) = &paths {
+            if !matches_paths(&path, is_tree1 || is_tree2, paths) {
+                continue;
             }
-            Ordering::Greater => {
-                i2 += 1;
+        }
+
+        if equal && !want_unchanged {
+            continue;
+        }
+
+        // Treat entries for trees as missing, unless they weren't recursed
+        // into.
+        let entry1 = entry1.filter(|entry| include_trees || pruned || !entry.is_tree());
+        let entry2 = entry2.filter(|entry| include_trees || pruned || !entry.is_tree());
+
+        let (change_type, old, new) = match (entry1, entry2) {
+            (Some(e1), Some(e2)) => {
+                if (e1.mode & S_IFMT) != (e2.mode & S_IFMT) && !change_type_same {
+                    // File type changed: report as delete/add.
+                    result.push(new_change(&change_delete, &e1.obj, &none.clone().unbind())?);
+                    (&change_add, none.clone().unbind(), e2.obj)
+                } else if equal {
+                    (&change_unchanged, e1.obj, e2.obj)
+                } else {
+                    (&change_modify, e1.obj, e2.obj)
+                }
             }
+            (Some(e1), None) => (&change_delete, e1.obj, none.clone().unbind()),
+            (None, Some(e2)) => (&change_add, none.clone().unbind(), e2.obj),
+            // Both were None because at least one was a tree.
+            (None, None) => continue,
+        };
+        result.push(new_change(change_type, &old, &new)?);
+    }
+
+    Ok(PyList::new(py, &result)?.unbind().into())
+}
+
+/// Walk two trees and collect the TreeChanges under changed_prefix only,
+/// for callers that already know which path changed.
+///
+/// Only the trees on the way to changed_prefix and the subtrees under it
+/// are walked, so the caller's prefix is trusted: changes elsewhere are not
+/// found. An added or deleted directory at changed_prefix shows up as adds
+/// or deletes of its contents. An empty prefix diffs the whole trees.
+#[pyfunction]
+fn _tree_changes_incremental(
+    py: Python,
+    store: &Bound<PyAny>,
+    tree1_id: &Bound<PyAny>,
+    tree2_id: &Bound<PyAny>,
+    changed_prefix: &[u8],
+) -> PyResult<Py<PyAny>> {
+    let end = changed_prefix
+        .iter()
+        .rposition(|&c| c != b'/')
+        .map_or(0, |i| i + 1);
+    let changed_prefix = &changed_prefix[..end];
+    let paths = Some(vec![changed_prefix.to_vec()]).filter(|_| !changed_prefix.is_empty());
+    _tree_changes(
+        py,
+        store,
+        tree1_id,
+        tree2_id,
+        false,
+        false,
+        None,
+        false,
+        paths,
+        DEFAULT_TREE_CACHE_SIZE,
+        false,
+        None,
+    )
+}
+
+/// A TreeChange as a plain (type, old, new) tuple, with the entries as plain
+/// (path, mode, sha) tuples too.
+fn raw_change<'py>(change: &Bound<'py, PyAny>) -> PyResult<Bound<'py, PyTuple>> {
+    let py = change.py();
+    let raw_entry = |entry: Bound<'py, PyAny>| -> PyResult<Bound<'py, PyAny>> {
+        if entry.is_none() {
+            return Ok(entry);
+        }
+        Ok(PyTuple::new(py, entry.try_iter()?.collect::<PyResult<Vec<_>>>()?)?.into_any())
+    };
+    PyTuple::new(
+        py,
+        [
+            change.getattr("type")?,
+            raw_entry(change.getattr("old")?)?,
+            raw_entry(change.getattr("new")?)?,
+        ],
+    )
+}
+
+/// The contents of the blob of a change's entry, or None if there is no
+/// entry or it is a gitlink.
+fn change_blob_data(store: &Bound<PyAny>, entry: &Bound<PyAny>) -> PyResult<Option<Vec<u8>>> {
+    match entry_mode(entry)? {
+        Some(mode) if (mode & S_IFMT) != S_IFGITLINK => {
+            let obj = store.get_item(entry.getattr("sha")?)?;
+            Ok(Some(obj.call_method0("as_raw_string")?.extract()?))
         }
+        _ => Ok(None),
     }
+}
 
-    while i1 < entries1.len() {
-        let pair = PyTuple::new(py, &[entries1[i1].clone_ref(py), py.None()]).unwrap();
-        result.push(pair);
-        i1 += 1;
+/// Compute an id for the contents of a change, as for git cherry.
+///
+/// The id only depends on the lines removed from and added to the old
+/// contents, each sorted, so two changes making the same edit have the same
+/// id even if their paths, blobs and unchanged lines differ. It is the
+/// 64-bit FNV-1a hash of the removed lines prefixed with "-", followed by
+/// the added lines prefixed with "+".
+#[pyfunction]
+fn _change_content_id(change: &Bound<PyAny>, store: &Bound<PyAny>) -> PyResult<u64> {
+    let old_data = change_blob_data(store, &change.getattr("old")?)?.unwrap_or_default();
+    let new_data = change_blob_data(store, &change.getattr("new")?)?.unwrap_or_default();
+    let mut counts: HashMap<&[u8], isize> = HashMap::new();
+    for line in old_data.split_inclusive(|&c| c == b'\n') {
+        *counts.entry(line).or_default() -= 1;
     }
+    for line in new_data.split_inclusive(|&c| c == b'\n') {
+        *counts.entry(line).or_default() += 1;
+    }
+    let mut removed = Vec::new();
+    let mut added = Vec::new();
+    for (line, count) in counts {
+        let lines = if count < 0 { &mut removed } else { &mut added };
+        lines.extend(std::iter::repeat_n(line, count.unsigned_abs()));
+    }
+    removed.sort_unstable();
+    added.sort_unstable();
+    let mut hash = FNV_OFFSET_BASIS;
+    for (prefix, lines) in [(b"-", removed), (b"+", added)] {
+        for line in lines {
+            hash = fnv1a_update(fnv1a_update(hash, prefix), line);
+        }
+    }
+    Ok(hash)
+}
 
-    while i2 < entries2.len() {
-        let pair = PyTuple::new(py, &[py.None(), entries2[i2].clone_ref(py)]).unwrap();
-        result.push(pair);
-        i2 += 1;
+/// Summarise the changes between two trees as (files_changed, insertions,
+/// deletions), as git diff --shortstat does.
+///
+/// Lines are counted as by _blob_line_stats. Binary files and gitlinks
+/// count as changed files, but add no lines; a change of file type counts
+/// as a single changed file.
+#[pyfunction]
+fn _diff_shortstat(
+    py: Python,
+    store: &Bound<PyAny>,
+    tree1_id: &Bound<PyAny>,
+    tree2_id: &Bound<PyAny>,
+) -> PyResult<(usize, usize, usize)> {
+    let changes = _tree_changes(
+        py,
+        store,
+        tree1_id,
+        tree2_id,
+        false,
+        false,
+        None,
+        true,
+        None,
+        DEFAULT_TREE_CACHE_SIZE,
+        false,
+        None,
+    )?;
+    let mut files_changed = 0;
+    let mut insertions = 0;
+    let mut deletions = 0;
+    for change in changes.bind(py).try_iter()? {
+        let change = change?;
+        files_changed += 1;
+        let old_data = change_blob_data(store, &change.getattr("old")?)?;
+        let new_data = change_blob_data(store, &change.getattr("new")?)?;
+        if [&old_data, &new_data]
+            .iter()
+            .any(|data| data.as_deref().is_some_and(_is_binary))
+        {
+            continue;
+        }
+        let (added, deleted) =
+            _blob_line_stats(py, old_data.as_deref(), new_data.as_deref(), false);
+        insertions += added;
+        deletions += deleted;
+    }
+    Ok((files_changed, insertions, deletions))
+}
+
+/// A (status, old_path, new_path) tuple of _name_status.
+type NameStatus<'py> = (
+    Vec<u8>,
+    Option<Bound<'py, PyAny>>,
+    Option<Bound<'py, PyAny>>,
+);
+
+/// Summarise the changes between two trees as git diff --name-status does.
+///
+/// Returns a list of (status, old_path, new_path) tuples, where status is
+/// b"A", b"D", b"M" or b"T" for a change of file type, or b"R" or b"C"
+/// followed by the similarity score as three digits (e.g. b"R087") for a
+/// rename or copy. The path of a missing side is None.
+///
+/// Without a rename_detector a change of file type is a single change;
+/// with one, the delete and add it is split into are joined again. The
+/// scores of renames and copies are computed from the block counts the
+/// RenameDetector found them with, rather than counting blocks again.
+#[pyfunction]
+#[pyo3(signature = (store, tree1_id, tree2_id, rename_detector=None))]
+fn _name_status<'py>(
+    py: Python<'py>,
+    store: &Bound<'py, PyAny>,
+    tree1_id: &Bound<'py, PyAny>,
+    tree2_id: &Bound<'py, PyAny>,
+    rename_detector: Option<&Bound<'py, PyAny>>,
+) -> PyResult<Vec<NameStatus<'py>>> {
+    let changes = _tree_changes(
+        py,
+        store,
+        tree1_id,
+        tree2_id,
+        false,
+        false,
+        rename_detector,
+        true,
+        None,
+        DEFAULT_TREE_CACHE_SIZE,
+        false,
+        None,
+    )?;
+    let diff_tree = py.import("dulwich.diff_tree")?;
+    let change_add = diff_tree.getattr("CHANGE_ADD")?;
+    let change_delete = diff_tree.getattr("CHANGE_DELETE")?;
+    let change_rename = diff_tree.getattr("CHANGE_RENAME")?;
+    let change_copy = diff_tree.getattr("CHANGE_COPY")?;
+    let block_cache = match rename_detector {
+        Some(rename_detector) => rename_detector.getattr("_block_cache")?,
+        None => PyDict::new(py).into_any(),
+    };
+    let path_of = |entry: &Bound<'py, PyAny>| -> PyResult<Option<Bound<'py, PyAny>>> {
+        if entry.is_none() {
+            Ok(None)
+        } else {
+            Ok(Some(entry.getattr("path")?))
+        }
+    };
+
+    let mut result: Vec<Option<NameStatus>> = Vec::new();
+    let mut deletes = HashMap::new();
+    let mut adds = HashMap::new();
+    for change in changes.bind(py).try_iter()? {
+        let change = change?;
+        let change_type = change.getattr("type")?;
+        let old = change.getattr("old")?;
+        let new = change.getattr("new")?;
+        let status = if change_type.eq(&change_add)? {
+            adds.insert(new.getattr("path")?.extract::<Vec<u8>>()?, result.len());
+            b"A".to_vec()
+        } else if change_type.eq(&change_delete)? {
+            deletes.insert(old.getattr("path")?.extract::<Vec<u8>>()?, result.len());
+            b"D".to_vec()
+        } else if change_type.eq(&change_rename)? || change_type.eq(&change_copy)? {
+            let score = rename_score(&block_cache, &old, &new)?;
+            let letter = if change_type.eq(&change_rename)? {
+                'R'
+            } else {
+                'C'
+            };
+            format!("{}{:03}", letter, score).into_bytes()
+        } else if entry_mode(&old)?.map(|mode| mode & S_IFMT)
+            != entry_mode(&new)?.map(|mode| mode & S_IFMT)
+        {
+            b"T".to_vec()
+        } else {
+            b"M".to_vec()
+        };
+        result.push(Some((status, path_of(&old)?, path_of(&new)?)));
+    }
+
+    // Join the deletes and adds of the same path, split from changes of file
+    // type by the rename detector, at the place of the first of the two.
+    for (path, delete) in deletes {
+        let Some(&add) = adds.get(&path) else {
+            continue;
+        };
+        let (first, second) = (delete.min(add), delete.max(add));
+        let old_path = result[delete].as_ref().and_then(|(_, old, _)| old.clone());
+        let new_path = result[add].as_ref().and_then(|(_, _, new)| new.clone());
+        result[first] = Some((b"T".to_vec(), old_path, new_path));
+        result[second] = None;
+    }
+    Ok(result.into_iter().flatten().collect())
+}
+
+/// Summarise the changes a merge commit made against its first parent only,
+/// as name-status records, for the git show --first-parent shortcut of
+/// rendering merges without their combined diff.
+///
+/// This is _name_status from first_parent_tree_id to commit_tree_id, with
+/// renames detected by rename_detector, or a default RenameDetector for the
+/// store if it is None. The other parents are ignored, so changes brought
+/// in from them show up as changes of the merge.
+#[pyfunction]
+#[pyo3(signature = (store, commit_tree_id, first_parent_tree_id, rename_detector=None))]
+fn _first_parent_changes<'py>(
+    py: Python<'py>,
+    store: &Bound<'py, PyAny>,
+    commit_tree_id: &Bound<'py, PyAny>,
+    first_parent_tree_id: &Bound<'py, PyAny>,
+    rename_detector: Option<&Bound<'py, PyAny>>,
+) -> PyResult<Vec<NameStatus<'py>>> {
+    let rename_detector = match rename_detector {
+        Some(rename_detector) => rename_detector.clone(),
+        None => py
+            .import("dulwich.diff_tree")?
+            .getattr("RenameDetector")?
+            .call1((store,))?,
+    };
+    _name_status(
+        py,
+        store,
+        first_parent_tree_id,
+        commit_tree_id,
+        Some(&rename_detector),
+    )
+}
+
+/// Read the contents of one side of a change as write_object_diff in
+/// dulwich.patch does: a missing side is empty and a gitlink reads as the
+/// commit it points at.
+fn patch_side(store: &Bound<PyAny>, entry: &Bound<PyAny>) -> PyResult<Vec<u8>> {
+    if entry.is_none() {
+        return Ok(Vec::new());
+    }
+    let sha = entry.getattr("sha")?;
+    if sha.is_none() {
+        return Ok(Vec::new());
+    }
+    let data = match entry_mode(entry)? {
+        Some(mode) if (mode & S_IFMT) == S_IFGITLINK => {
+            let mut data = b"Subproject commit ".to_vec();
+            data.extend(sha.extract::<Vec<u8>>()?);
+            data.push(b'\n');
+            data
+        }
+        _ => store
+            .get_item(&sha)?
+            .call_method0("as_raw_string")?
+            .extract()?,
+    };
+    Ok(data)
+}
+
+/// Split data into lines keeping their endings, breaking on \n, \r\n and a
+/// lone \r as bytes.splitlines(True) does.
+fn split_lines_universal(data: &[u8]) -> Vec<&[u8]> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    let mut i = 0;
+    while i < data.len() {
+        match data[i] {
+            b'\n' => {}
+            b'\r' if data.get(i + 1) == Some(&b'\n') => i += 1,
+            b'\r' => {}
+            _ => {
+                i += 1;
+                continue;
+            }
+        }
+        i += 1;
+        lines.push(&data[start..i]);
+        start = i;
+    }
+    if start < data.len() {
+        lines.push(&data[start..]);
+    }
+    lines
+}
+
+/// Format a hunk range as difflib's unified_diff does.
+fn unified_range(start: usize, len: usize) -> String {
+    match len {
+        0 => format!("{},0", start),
+        1 => format!("{}", start + 1),
+        _ => format!("{},{}", start + 1, len),
     }
+}
+
+/// Write the part of the diff of one file that patch_id looks at: the hunks,
+/// unless either side is binary.
+fn write_patch_hunks(out: &mut Vec<u8>, old_data: &[u8], new_data: &[u8]) {
+    if _is_binary(old_data) || _is_binary(new_data) || old_data == new_data {
+        return;
+    }
+    let old_lines = split_lines_universal(old_data);
+    let new_lines = split_lines_universal(new_data);
+    for hunk in difflib_hunks(&old_lines, &new_lines, 3) {
+        out.extend_from_slice(
+            format!(
+                "@@ -{} +{} @@\n",
+                unified_range(hunk.old_start, hunk.old_len),
+                unified_range(hunk.new_start, hunk.new_len)
+            )
+            .as_bytes(),
+        );
+        for line in hunk.lines {
+            out.extend_from_slice(&line);
+            // Like unified_diff, context lines without a newline are left
+            // to run into the next line.
+            if line[0] != b' ' && !line.ends_with(b"\n") {
+                out.extend_from_slice(b"\n\\ No newline at end of file\n");
+            }
+        }
+    }
+}
+
+/// Normalize a diff as dulwich.patch.patch_id does before hashing it.
+///
+/// No file headers are written by write_patch_hunks, so deleted or added
+/// lines that look like --- or +++ headers are the only header lines to
+/// drop.
+fn normalize_patch(diff: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(diff.len());
+    let mut first = true;
+    for line in diff.split(|&c| c == b'\n') {
+        if line.starts_with(b"--- ") || line.starts_with(b"+++ ") {
+            continue;
+        }
+        let normalized = if line.starts_with(b"@@") {
+            b"@@".to_vec()
+        } else if line.starts_with(b"+") || line.starts_with(b"-") {
+            let mut normalized = vec![line[0]];
+            normalized.extend(line[1..].iter().filter(|&&c| c != b' ' && c != b'\t'));
+            normalized
+        } else if line.is_empty() || line.starts_with(b" ") {
+            line.to_vec()
+        } else {
+            continue;
+        };
+        if !first {
+            out.push(b'\n');
+        }
+        first = false;
+        out.extend(normalized);
+    }
+    out
+}
 
-    Ok(PyList::new(py, &result).unwrap().unbind().into())
+/// Compute the patch id of a list of TreeChanges, as a hex SHA-1.
+///
+/// This gives what dulwich.patch.patch_id gives for the diff
+/// write_tree_diff writes for the same changes, without writing it out:
+/// file headers, hunk line numbers and whitespace in changed lines are
+/// ignored, so binary files and mode changes do not count. The lines are
+/// aligned as difflib aligns them, not as _blob_hunks does, so that an
+/// ambiguous diff is split the same way. The diffs run with the GIL
+/// released.
+#[pyfunction]
+fn _patch_id<'py>(
+    py: Python<'py>,
+    changes: &Bound<'py, PyAny>,
+    store: &Bound<'py, PyAny>,
+) -> PyResult<Bound<'py, PyAny>> {
+    let mut sides = Vec::new();
+    for change in changes.try_iter()? {
+        let change = change?;
+        let old_data = patch_side(store, &change.getattr("old")?)?;
+        let new_data = patch_side(store, &change.getattr("new")?)?;
+        sides.push((old_data, new_data));
+    }
+    let normalized = allow_threads(py, || {
+        let mut diff = Vec::new();
+        for (old_data, new_data) in &sides {
+            write_patch_hunks(&mut diff, old_data, new_data);
+        }
+        normalize_patch(&diff)
+    });
+    let digest = py
+        .import("hashlib")?
+        .call_method1("sha1", (PyBytes::new(py, &normalized),))?
+        .call_method0("hexdigest")?;
+    digest.call_method1("encode", ("ascii",))
 }
 
 #[pymodule]
 fn _diff_tree(_py: Python, m: &Bound<PyModule>) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(_count_blocks, m)?)?;
+    m.add_function(wrap_pyfunction!(_count_blocks_bounded, m)?)?;
+    m.add_function(wrap_pyfunction!(_count_blocks_bytes, m)?)?;
+    m.add_function(wrap_pyfunction!(_count_blocks_lines, m)?)?;
+    m.add_function(wrap_pyfunction!(_count_blocks_cacheable, m)?)?;
+    m.add_function(wrap_pyfunction!(_common_bytes_sorted, m)?)?;
+    m.add_function(wrap_pyfunction!(_count_blocks_from_read, m)?)?;
+    m.add_function(wrap_pyfunction!(_count_blocks_many, m)?)?;
+    m.add_function(wrap_pyfunction!(_iter_blocks, m)?)?;
     m.add_function(wrap_pyfunction!(_is_tree, m)?)?;
+    m.add_function(wrap_pyfunction!(_are_trees, m)?)?;
+    m.add_function(wrap_pyfunction!(_is_blob, m)?)?;
+    m.add_function(wrap_pyfunction!(_is_gitlink, m)?)?;
+    m.add_function(wrap_pyfunction!(_is_binary, m)?)?;
+    m.add_function(wrap_pyfunction!(_blob_line_stats, m)?)?;
+    m.add_function(wrap_pyfunction!(_blob_hunks, m)?)?;
+    m.add_function(wrap_pyfunction!(_is_binary_obj, m)?)?;
+    m.add_function(wrap_pyfunction!(_changed_paths, m)?)?;
+    m.add_function(wrap_pyfunction!(_trees_content_equal, m)?)?;
+    m.add_function(wrap_pyfunction!(_classify_change, m)?)?;
+    m.add_function(wrap_pyfunction!(_change_record, m)?)?;
+    m.add_function(wrap_pyfunction!(_diff_entries, m)?)?;
+    m.add_function(wrap_pyfunction!(_index_tree_changes, m)?)?;
+    m.add_function(wrap_pyfunction!(_cmp_tree_entries, m)?)?;
+    m.add_function(wrap_pyfunction!(_sort_tree_entries, m)?)?;
+    m.add_function(wrap_pyfunction!(_check_tree_sorted, m)?)?;
+    m.add_function(wrap_pyfunction!(_merge3_entry, m)?)?;
     m.add_function(wrap_pyfunction!(_merge_entries, m)?)?;
+    m.add_function(wrap_pyfunction!(_tree_entries_from_store, m)?)?;
+    m.add_function(wrap_pyfunction!(_tree_entry_tuples, m)?)?;
+    m.add_function(wrap_pyfunction!(_normalize_tree_path, m)?)?;
+    m.add_function(wrap_pyfunction!(_merge_entries_multi, m)?)?;
+    m.add_function(wrap_pyfunction!(_merge_entries_prefixed, m)?)?;
+    m.add_function(wrap_pyfunction!(_merge_entry_lists, m)?)?;
+    m.add_function(wrap_pyfunction!(_merge_sorted_lists, m)?)?;
+    m.add_function(wrap_pyfunction!(_iter_merge_entries, m)?)?;
+    m.add_class::<BlockCountCache>()?;
+    m.add_class::<MergeEntriesIterator>()?;
+    m.add_class::<TreeEntryIter>()?;
+    m.add_class::<BlockAccumulator>()?;
+    m.add_function(wrap_pyfunction!(_tree_changes, m)?)?;
+    m.add_function(wrap_pyfunction!(_diff_shortstat, m)?)?;
+    m.add_function(wrap_pyfunction!(_name_status, m)?)?;
+    m.add_function(wrap_pyfunction!(_first_parent_changes, m)?)?;
+    m.add_function(wrap_pyfunction!(_change_content_id, m)?)?;
+    m.add_function(wrap_pyfunction!(_patch_id, m)?)?;
+    m.add_function(wrap_pyfunction!(_tree_change_key, m)?)?;
+    m.add_function(wrap_pyfunction!(_bucket_changes, m)?)?;
+    m.add_function(wrap_pyfunction!(_dir_change_summary, m)?)?;
+    m.add_function(wrap_pyfunction!(_basename_rename_candidates, m)?)?;
+    m.add_function(wrap_pyfunction!(_detect_dir_renames, m)?)?;
+    m.add_function(wrap_pyfunction!(_tree_changes_for_merge, m)?)?;
+    m.add_function(wrap_pyfunction!(_tree_changes_incremental, m)?)?;
+    m.add_function(wrap_pyfunction!(_common_bytes, m)?)?;
+    m.add_function(wrap_pyfunction!(_block_count_diff, m)?)?;
+    m.add_function(wrap_pyfunction!(_block_count_distance, m)?)?;
+    m.add_function(wrap_pyfunction!(_common_prefix_suffix, m)?)?;
+    m.add_function(wrap_pyfunction!(_line_offsets, m)?)?;
+    m.add_function(wrap_pyfunction!(_similarity_score, m)?)?;
+    m.add_function(wrap_pyfunction!(_similarity_streaming, m)?)?;
+    m.add_function(wrap_pyfunction!(_best_rename_for, m)?)?;
+    m.add_function(wrap_pyfunction!(_detect_split, m)?)?;
+    m.add_function(wrap_pyfunction!(_find_exact_renames, m)?)?;
+    m.add_function(wrap_pyfunction!(_score_candidates, m)?)?;
+    m.add_function(wrap_pyfunction!(_score_candidates_limited, m)?)?;
+    m.add_function(wrap_pyfunction!(_score_copy_candidates, m)?)?;
+    m.add_function(wrap_pyfunction!(_annotate_rename_scores, m)?)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(block_size: usize, pieces: &[&[u8]]) -> Vec<Vec<u8>> {
+        let mut blocks = Vec::new();
+        let mut splitter = BlockSplitter::new(block_size);
+        for piece in pieces {
+            splitter
+                .feed(piece, |block| {
+                    blocks.push(block.to_vec());
+                    Ok::<_, Infallible>(())
+                })
+                .unwrap();
+        }
+        splitter
+            .finish(|block| {
+                blocks.push(block.to_vec());
+                Ok::<_, Infallible>(())
+            })
+            .unwrap();
+        blocks
+    }
+
+    #[test]
+    fn test_tree_order_cmp() {
+        const F: u32 = 0o100644;
+        const D: u32 = 0o040000;
+        assert_eq!(tree_order_cmp(b"a", F, b"a", F), Ordering::Equal);
+        assert_eq!(tree_order_cmp(b"a", F, b"b", F), Ordering::Less);
+        assert_eq!(tree_order_cmp(b"a", F, b"a", D), Ordering::Less);
+        // "a/" sorts after "a.c" but before "a0".
+        assert_eq!(tree_order_cmp(b"a.c", F, b"a", D), Ordering::Less);
+        assert_eq!(tree_order_cmp(b"a", D, b"a0", F), Ordering::Less);
+        assert_eq!(tree_order_cmp(b"a", F, b"a.c", F), Ordering::Less);
+    }
+
+    #[test]
+    fn test_fnv1a() {
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(fnv1a(b"foobar"), 0x85944171f73967e8);
+    }
+
+    #[test]
+    fn test_normalize_block() {
+        assert_eq!(&*normalize_block(b"foo\n"), b"foo\n");
+        assert_eq!(&*normalize_block(b"foo \t\r\n"), b"foo\n");
+        assert_eq!(&*normalize_block(b"foo  "), b"foo");
+        assert_eq!(&*normalize_block(b" foo\r"), b" foo");
+        assert_eq!(&*normalize_block(b" \r\n"), b"\n");
+        assert_eq!(&*normalize_block(b"  "), b"");
+    }
+
+    #[test]
+    fn test_block_splitter_lines() {
+        assert_eq!(
+            split(64, &[b"a\nbb\nc"]),
+            vec![b"a\n".to_vec(), b"bb\n".to_vec(), b"c".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_block_splitter_block_size() {
+        assert_eq!(
+            split(2, &[b"aaaaa\n"]),
+            vec![b"aa".to_vec(), b"aa".to_vec(), b"a\n".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_block_splitter_across_pieces() {
+        assert_eq!(
+            split(64, &[b"a\nb", b"b\n", b"", b"c"]),
+            vec![b"a\n".to_vec(), b"bb\n".to_vec(), b"c".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_block_splitter_rolling_insertion() {
+        // A long line of pseudo-random bytes.
+        let mut state = 1u32;
+        let data = (0..4096)
+            .map(|_| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                b'a' + ((state >> 16) % 26) as u8
+            })
+            .collect::<Vec<_>>();
+        let mut shifted = b"x".to_vec();
+        shifted.extend_from_slice(&data);
+
+        let blocks = |data: &[u8]| {
+            let mut splitter = BlockSplitter::new_rolling(64, 16);
+            let mut blocks = Vec::new();
+            let mut emit = |block: &[u8]| {
+                blocks.push(block.to_vec());
+                Ok::<_, Infallible>(())
+            };
+            splitter.feed(data, &mut emit).unwrap();
+            splitter.finish(&mut emit).unwrap();
+            blocks
+        };
+        let blocks1 = blocks(&data);
+        let blocks2 = blocks(&shifted);
+        assert_eq!(blocks1.concat(), data);
+        assert!(blocks1.len() > 16);
+        // Only the first block is affected by the insertion.
+        assert_eq!(blocks1[1..], blocks2[1..]);
+    }
+
+    #[test]
+    fn test_merge_sorted() {
+        let Ok(pairs) = merge_sorted([1, 3, 4], [2, 3], |a, b| Ok::<_, Infallible>(a.cmp(b)));
+        assert_eq!(
+            pairs,
+            vec![
+                (Some(1), None),
+                (None, Some(2)),
+                (Some(3), Some(3)),
+                (Some(4), None)
+            ]
+        );
+        let result = merge_sorted([1], [2], |_, _| Err("incomparable"));
+        assert_eq!(result, Err("incomparable"));
+        let Ok(pairs) = merge_sorted(Vec::<u8>::new(), [], |a, b| Ok::<_, Infallible>(a.cmp(b)));
+        assert!(pairs.is_empty());
+    }
+
+    #[test]
+    fn test_common_prefix_suffix() {
+        assert_eq!(common_prefix_suffix(b"", b""), (0, 0));
+        assert_eq!(common_prefix_suffix(b"abc", b"abc"), (3, 0));
+        assert_eq!(common_prefix_suffix(b"abc", b"abcd"), (3, 0));
+        assert_eq!(common_prefix_suffix(b"bcd", b"abcd"), (0, 3));
+        assert_eq!(common_prefix_suffix(b"abxcd", b"abycd"), (2, 2));
+        assert_eq!(common_prefix_suffix(b"aa", b"aaa"), (2, 0));
+    }
+
+    #[test]
+    fn test_line_hunks() {
+        let lines = |data: &'static [u8]| data.split_inclusive(|&c| c == b'\n').collect::<Vec<_>>();
+        let old = lines(b"a\nb\nc\nd\ne\nf\ng\nh\n");
+        let new = lines(b"a\nB\nc\nd\ne\nf\ng\nh\ni\n");
+        assert_eq!(line_hunks(&old, &old, 3), vec![]);
+        assert_eq!(
+            line_hunks(&old, &new, 1),
+            vec![
+                Hunk {
+                    old_start: 0,
+                    old_len: 3,
+                    new_start: 0,
+                    new_len: 3,
+                    lines: vec![
+                        b" a\n".to_vec(),
+                        b"-b\n".to_vec(),
+                        b"+B\n".to_vec(),
+                        b" c\n".to_vec()
+                    ],
+                },
+                Hunk {
+                    old_start: 7,
+                    old_len: 1,
+                    new_start: 7,
+                    new_len: 2,
+                    lines: vec![b" h\n".to_vec(), b"+i\n".to_vec()],
+                },
+            ]
+        );
+        // With more context, the changes are close enough to share a hunk.
+        let hunks = line_hunks(&old, &new, 3);
+        assert_eq!(hunks.len(), 1);
+        assert_eq!((hunks[0].old_start, hunks[0].old_len), (0, 8));
+        assert_eq!((hunks[0].new_start, hunks[0].new_len), (0, 9));
+        assert_eq!(hunks[0].lines.len(), 10);
+    }
+
+    #[test]
+    fn test_normalize_tree_path() {
+        assert_eq!(normalize_tree_path(b""), b"");
+        assert_eq!(normalize_tree_path(b"a/b"), b"a/b");
+        assert_eq!(normalize_tree_path(b"a//b///c"), b"a/b/c");
+        assert_eq!(normalize_tree_path(b"a/b//"), b"a/b");
+        assert_eq!(normalize_tree_path(b"//a"), b"/a");
+        assert_eq!(normalize_tree_path(b"///"), b"");
+    }
+
+    #[test]
+    fn test_block_splitter_line_endings() {
+        let split = |line_endings, pieces: &[&[u8]]| {
+            let mut blocks = Vec::new();
+            let mut splitter = BlockSplitter::with_line_endings(4, line_endings);
+            let mut emit = |block: &[u8]| {
+                blocks.push(block.to_vec());
+                Ok::<_, Infallible>(())
+            };
+            for piece in pieces {
+                splitter.feed(piece, &mut emit).unwrap();
+            }
+            splitter.finish(&mut emit).unwrap();
+            blocks
+        };
+        assert_eq!(
+            split(LineEndings::Crlf, &[b"a\r", b"\nb\nc\rd"]),
+            vec![b"a\r\n".to_vec(), b"b\nc\r".to_vec(), b"d".to_vec()]
+        );
+        assert_eq!(
+            split(LineEndings::Any, &[b"a\r", b"\nb\rc\nd\r"]),
+            vec![
+                b"a\r\n".to_vec(),
+                b"b\r".to_vec(),
+                b"c\n".to_vec(),
+                b"d\r".to_vec()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_block_splitter_delimiter() {
+        let mut blocks = Vec::new();
+        let mut splitter = BlockSplitter::with_delimiter(4, b',');
+        let mut emit = |block: &[u8]| {
+            blocks.push(block.to_vec());
+            Ok::<_, Infallible>(())
+        };
+        splitter.feed(b"a,b\nc,defgh", &mut emit).unwrap();
+        splitter.finish(&mut emit).unwrap();
+        assert_eq!(
+            blocks,
+            vec![
+                b"a,".to_vec(),
+                b"b\nc,".to_vec(),
+                b"defg".to_vec(),
+                b"h".to_vec()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_count_blocks_sorted() {
+        let blocks = count_blocks_sorted(b"a\nb\na\nccc", 2);
+        let mut expected = vec![
+            (fnv1a(b"a\n"), 4),
+            (fnv1a(b"b\n"), 2),
+            (fnv1a(b"cc"), 2),
+            (fnv1a(b"c"), 1),
+        ];
+        expected.sort_unstable();
+        assert_eq!(blocks, expected);
+        assert!(count_blocks_sorted(b"", 2).is_empty());
+    }
+
+    #[test]
+    fn test_common_bytes_sorted() {
+        let blocks1 = count_blocks_sorted(b"a\nb\na\nc\n", 64);
+        let blocks2 = count_blocks_sorted(b"a\nc\nc\nd\n", 64);
+        assert_eq!(common_bytes_sorted(&blocks1, &blocks2), 4);
+        assert_eq!(common_bytes_sorted(&blocks1, &blocks1), 8);
+        assert_eq!(common_bytes_sorted(&blocks1, &[]), 0);
+    }
+
+    #[test]
+    fn test_normalize_line_whitespace() {
+        assert_eq!(normalize_line_whitespace(b"  a  b\tc \r\n"), b"a b c");
+        assert_eq!(normalize_line_whitespace(b"a b\n"), b"a b");
+        assert_eq!(normalize_line_whitespace(b" \t\n"), b"");
+        assert_eq!(normalize_line_whitespace(b""), b"");
+    }
+
+    #[test]
+    fn test_same_sha() {
+        let raw = [0xab, 0x01, 0xff];
+        assert!(same_sha(b"ab01ff", b"ab01ff"));
+        assert!(same_sha(b"ab01ff", &raw));
+        assert!(same_sha(&raw, b"AB01FF"));
+        assert!(same_sha(&raw, &raw));
+        assert!(!same_sha(b"ab01fe", &raw));
+        assert!(!same_sha(b"ab01ff", b"ab01fe"));
+        assert!(!same_sha(b"zz01ff", &[0x00, 0x01, 0xff]));
+        // SHA-256 shas, and SHA-1 shas that are only a prefix of them.
+        let hex256 = b"ab".repeat(32);
+        let raw256 = [0xab; 32];
+        assert!(same_sha(&hex256, &raw256));
+        assert!(same_sha(&raw256, &hex256));
+        assert!(!same_sha(&hex256[..40], &raw256));
+        assert!(!same_sha(&raw256[..20], &raw256));
+        assert!(!same_sha(&hex256[..40], &hex256));
+    }
+
+    #[test]
+    fn test_block_splitter_empty() {
+        assert!(split(64, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_difflib_hunks_repeated_line() {
+        let old: [&[u8]; 1] = [b"a\n"];
+        let new: [&[u8]; 3] = [b"b\n", b"a\n", b"a\n"];
+        assert_eq!(
+            difflib_matching_blocks(&old, &new),
+            vec![(0, 1, 1), (1, 3, 0)]
+        );
+        let lines: Vec<&[u8]> = vec![b"+b\n", b" a\n", b"+a\n"];
+        assert_eq!(
+            difflib_hunks(&old, &new, 3),
+            vec![Hunk {
+                old_start: 0,
+                old_len: 1,
+                new_start: 0,
+                new_len: 3,
+                lines: lines.into_iter().map(<[u8]>::to_vec).collect(),
+            }]
+        );
+    }
+}
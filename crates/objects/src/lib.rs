@@ -49,6 +49,8 @@ fn sha_to_pyhex(py: Python, sha: &[u8]) -> PyResult<Py<PyAny>> {
     Ok(PyBytes::new(py, hexsha.as_slice()).into())
 }
 
+type TreeEntryTuple = (Py<PyAny>, u32, Py<PyAny>);
+
 #[pyfunction]
 #[pyo3(signature = (text, sha_len, strict=None))]
 fn parse_tree(
@@ -56,7 +58,7 @@ fn parse_tree(
     mut text: &[u8],
     sha_len: usize,
     strict: Option<bool>,
-) -> PyResult<Vec<(Py<PyAny>, u32, Py<PyAny>)>> {
+) -> PyResult<Vec<TreeEntryTuple>> {
     let strict = strict.unwrap_or(false);
 
     let mut entries = Vec::new();
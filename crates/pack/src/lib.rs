@@ -604,7 +604,7 @@ mod tests {
         let delta = create_delta_internal(base, target);
 
         // Should have header plus insert operations with the new data
-        assert!(delta.len() > 0);
+        assert!(!delta.is_empty());
     }
 
     #[test]
@@ -625,7 +625,7 @@ mod tests {
         assert_eq!(dest_size, target.len());
 
         // The delta should be valid and smaller than sending the full target
-        assert!(delta.len() > 0);
+        assert!(!delta.is_empty());
     }
 
     #[test]
@@ -635,7 +635,7 @@ mod tests {
         let delta = create_delta_internal(base, target);
 
         // Should have a copy operation for "hello" and insert for " world"
-        assert!(delta.len() > 0);
+        assert!(!delta.is_empty());
     }
 
     #[test]
@@ -645,7 +645,7 @@ mod tests {
         let delta = create_delta_internal(base, target);
 
         // Should have a copy operation for "hello" only
-        assert!(delta.len() > 0);
+        assert!(!delta.is_empty());
     }
 }
 
@@ -39,16 +39,25 @@ __all__ = [
     "walk_trees",
 ]
 
+import binascii
 import stat
-from collections import defaultdict
-from collections.abc import Callable, Iterator, Mapping, Sequence
+from collections import Counter, defaultdict, deque
+from collections.abc import Callable, Iterable, Iterator, Mapping, Sequence
 from collections.abc import Set as AbstractSet
 from io import BytesIO
 from itertools import chain
-from typing import TYPE_CHECKING, Any, NamedTuple, TypeVar
+from typing import TYPE_CHECKING, Any, Literal, NamedTuple, TypeVar, overload
 
 from .object_store import BaseObjectStore
-from .objects import S_ISGITLINK, ObjectID, ShaFile, Tree, TreeEntry
+from .objects import (
+    S_ISGITLINK,
+    ObjectID,
+    RawObjectID,
+    ShaFile,
+    Tree,
+    TreeEntry,
+    parse_tree,
+)
 
 # TreeChange type constants.
 CHANGE_ADD = "add"
@@ -67,6 +76,19 @@ RENAME_THRESHOLD = 60
 MAX_FILES = 200
 REWRITE_THRESHOLD: int | None = None
 
+# The (path, mode, sha) of a tree entry, and the (type, old, new) of a
+# change, as plain tuples.
+_RawEntry = tuple[bytes, int, ObjectID]
+_RawChange = tuple[str, _RawEntry | None, _RawEntry | None]
+
+# The SHA-1 and SHA-256 ids of the empty tree.
+_EMPTY_TREE_IDS = frozenset(
+    [
+        b"4b825dc642cb6eb9a060e54bf8d69288fbee4904",
+        b"6ef19b41225c5369f1c104d45d8d85efa9b057b53b14b4b9b939dd74decc5321",
+    ]
+)
+
 
 class TreeChange(NamedTuple):
     """Named tuple a single change between two trees."""
@@ -100,7 +122,7 @@ class TreeChange(NamedTuple):
         return cls(CHANGE_DELETE, old, None)
 
 
-def _tree_entries(path: bytes, tree: Tree) -> list[TreeEntry]:
+def _tree_entries(path: bytes, tree: Tree | None) -> list[TreeEntry]:
     result: list[TreeEntry] = []
     if not tree:
         return result
@@ -109,45 +131,413 @@ def _tree_entries(path: bytes, tree: Tree) -> list[TreeEntry]:
     return result
 
 
+def _tree_entry_tuples(
+    path: bytes, tree: Tree | None
+) -> list[tuple[bytes, int, ObjectID]]:
+    """Like _tree_entries, but with plain (path, mode, sha) tuples.
+
+    This is for callers that don't need TreeEntry objects, which are then
+    not created.
+    """
+    if not tree:
+        return []
+    return [
+        (path + b"/" + name if path else name, mode, sha)
+        for name, mode, sha in tree.iteritems(name_order=True)
+    ]
+
+
+def _normalize_tree_path(path: bytes) -> bytes:
+    """Canonicalize a path for comparison with tree entry paths.
+
+    Tree entry paths are joined with single slashes, so this collapses
+    repeated slashes and strips trailing ones.
+    """
+    while b"//" in path:
+        path = path.replace(b"//", b"/")
+    return path.rstrip(b"/")
+
+
+def _tree_entries_from_store(
+    store: BaseObjectStore, tree_id: ObjectID | None, path: bytes = b""
+) -> list[TreeEntry]:
+    """List the entries of a tree given by its SHA.
+
+    Like _tree_entries, but the tree is looked up in store. None, the null
+    SHA and the empty tree SHA all give no entries, without a lookup.
+
+    Args:
+      store: An ObjectStore for looking up the tree.
+      tree_id: The SHA of the tree, or None.
+      path: A path to prepend to all tree entry names.
+
+    Returns:
+      A list of TreeEntry objects, in name order.
+    """
+    if tree_id is None or tree_id in _EMPTY_TREE_IDS or not tree_id.strip(b"0"):
+        return []
+    tree = store[tree_id]
+    assert isinstance(tree, Tree)
+    return _tree_entries(path, tree)
+
+
+def _tree_order_key(entry: TreeEntry) -> bytes:
+    assert entry.path is not None
+    if entry.mode is not None and stat.S_ISDIR(entry.mode):
+        return entry.path + b"/"
+    return entry.path
+
+
+def _cmp_tree_entries(entry1: TreeEntry, entry2: TreeEntry) -> int:
+    """Compare two tree entries in Git's tree order.
+
+    In tree order, the name of a directory sorts as if it had a trailing
+    slash.
+
+    Returns:
+      -1, 0 or 1 if entry1 sorts before, the same as or after entry2.
+    """
+    key1 = _tree_order_key(entry1)
+    key2 = _tree_order_key(entry2)
+    return (key1 > key2) - (key1 < key2)
+
+
+def _sort_tree_entries(entries: list[TreeEntry]) -> None:
+    """Sort a list of tree entries in place, in Git's tree order."""
+    entries.sort(key=_tree_order_key)
+
+
+def _check_tree_sorted(tree: Tree) -> tuple[int, bytes, bytes] | None:
+    """Find the first pair of entries of a tree that are out of tree order.
+
+    Tree objects sort their entries when iterating over them, so this looks
+    at the entries as serialized, to detect corrupt trees written by buggy
+    writers.
+
+    Args:
+      tree: The tree to check.
+
+    Returns:
+      None if the entries are in Git's tree order, or else (index, name1,
+        name2) for the first pair of consecutive entries that is not, where
+        index is that of the second entry. Entries with the same name count
+        as out of order.
+    """
+    last: tuple[bytes, bytes] | None = None
+    entries = parse_tree(tree.as_raw_string(), sha_len=tree.object_format.oid_length)
+    for i, (name, mode, sha) in enumerate(entries):
+        key = _tree_order_key(TreeEntry(name, mode, sha))
+        if last is not None and (key <= last[1] or name == last[0]):
+            return i, last[0], name
+        last = (name, key)
+    return None
+
+
+def _folded_path_key(entry: TreeEntry) -> tuple[bytes, bytes]:
+    return (entry.path.lower(), entry.path)
+
+
+@overload
 def _merge_entries(
-    path: bytes, tree1: Tree, tree2: Tree
-) -> list[tuple[TreeEntry | None, TreeEntry | None]]:
+    path: bytes,
+    tree1: Tree,
+    tree2: Tree,
+    case_insensitive: bool = False,
+    max_entries: int | None = None,
+    skip_identical: bool = False,
+    ignore_modes: Sequence[int] = (),
+    normalize_path: bool = False,
+    only_changed: bool = False,
+    raw: Literal[False] = False,
+    reverse: bool = False,
+) -> list[tuple[TreeEntry | None, TreeEntry | None]]: ...
+
+
+@overload
+def _merge_entries(
+    path: bytes,
+    tree1: Tree,
+    tree2: Tree,
+    case_insensitive: bool = False,
+    max_entries: int | None = None,
+    skip_identical: bool = False,
+    ignore_modes: Sequence[int] = (),
+    normalize_path: bool = False,
+    only_changed: bool = False,
+    *,
+    raw: Literal[True],
+    reverse: bool = False,
+) -> list[tuple[_RawEntry | None, _RawEntry | None]]: ...
+
+
+def _merge_entries(
+    path: bytes,
+    tree1: Tree,
+    tree2: Tree,
+    case_insensitive: bool = False,
+    max_entries: int | None = None,
+    skip_identical: bool = False,
+    ignore_modes: Sequence[int] = (),
+    normalize_path: bool = False,
+    only_changed: bool = False,
+    raw: bool = False,
+    reverse: bool = False,
+) -> (
+    list[tuple[TreeEntry | None, TreeEntry | None]]
+    | list[tuple[_RawEntry | None, _RawEntry | None]]
+):
     """Merge the entries of two trees.
 
     Args:
       path: A path to prepend to all tree entry names.
       tree1: The first Tree object to iterate, or None.
       tree2: The second Tree object to iterate, or None.
+      case_insensitive: If True, pair up entries whose paths only differ in
+        (ASCII) case.
+      max_entries: If given, the maximum number of pairs to return. This
+        bounds the memory used for untrusted trees.
+      skip_identical: If True, return no pairs at all when both trees have
+        the same SHA, without walking them. Only use this if unchanged
+        entries are not wanted.
+      ignore_modes: File types (compared by their S_IFMT bits) of entries to
+        leave out of the merge on both sides, e.g. [0o160000] to skip
+        submodules.
+      normalize_path: If True, canonicalize path with _normalize_tree_path
+        first, for paths that may have repeated or trailing slashes.
+      only_changed: If True, leave out the pairs of entries that are
+        unchanged (as classified by _classify_change), i.e. that have the
+        same mode and SHA.
+      raw: If True, return plain (path, mode, sha) tuples rather than
+        TreeEntry objects, which are cheaper to create.
+      reverse: If True, return each pair as (entry2, entry1), as for git
+        diff -R. The pairs are in the same order either way, so this gives
+        the same result as swapping tree1 and tree2.
 
     Returns:
       A list of pairs of TreeEntry objects for each pair of entries in
         the trees. If an entry exists in one tree but not the other, the other
         entry will be None. If both entries exist, they are guaranteed to match.
+
+    Raises:
+      ValueError: If there would be more than max_entries pairs.
     """
+    if (
+        skip_identical
+        and tree1 is not None
+        and tree2 is not None
+        and (tree1 is tree2 or tree1.id == tree2.id)
+    ):
+        return []
+    if max_entries is not None and not only_changed:
+        # There are at least as many pairs as entries in either tree.
+        for tree in (tree1, tree2):
+            if tree is not None and len(tree) > max_entries:
+                raise ValueError(
+                    f"merged tree entries exceed max_entries ({max_entries})"
+                )
+    if normalize_path:
+        path = _normalize_tree_path(path)
     entries1 = _tree_entries(path, tree1)
     entries2 = _tree_entries(path, tree2)
+    if ignore_modes:
+        ignored = {stat.S_IFMT(mode) for mode in ignore_modes}
+
+        def keep(entry: TreeEntry) -> bool:
+            assert entry.mode is not None
+            return stat.S_IFMT(entry.mode) not in ignored
+
+        entries1 = [entry for entry in entries1 if keep(entry)]
+        entries2 = [entry for entry in entries2 if keep(entry)]
+    if case_insensitive:
+        entries1.sort(key=_folded_path_key)
+        entries2.sort(key=_folded_path_key)
+    result = _merge_sorted_entries(entries1, entries2, case_insensitive)
+    if only_changed:
+        result = [
+            (entry1, entry2)
+            for entry1, entry2 in result
+            if _classify_change(entry1, entry2) != _KIND_UNCHANGED
+        ]
+    if max_entries is not None and len(result) > max_entries:
+        raise ValueError(f"merged tree entries exceed max_entries ({max_entries})")
+    if reverse:
+        result = [(entry2, entry1) for entry1, entry2 in result]
+    if raw:
+        return [(_raw_entry(entry1), _raw_entry(entry2)) for entry1, entry2 in result]
+    return result
+
+
+def _raw_entry(entry: TreeEntry | None) -> _RawEntry | None:
+    if entry is None:
+        return None
+    assert entry.path is not None and entry.mode is not None
+    assert entry.sha is not None
+    return (entry.path, entry.mode, entry.sha)
+
+
+def _raw_change(change: TreeChange) -> _RawChange:
+    return (change.type, _raw_entry(change.old), _raw_entry(change.new))
+
+
+def _merge_entries_prefixed(
+    path: bytes, tree1: Tree | None, tree2: Tree | None, prefix: bytes
+) -> list[tuple[TreeEntry | None, TreeEntry | None]]:
+    """Merge the entries of two trees whose paths start with a prefix.
+
+    Like _merge_entries, but only the pairs of entries whose path (including
+    path) starts with prefix are returned. Note that this is a plain string
+    prefix: to get the entries under a directory, include a trailing slash.
+
+    Args:
+      path: A path to prepend to all tree entry names.
+      tree1: The first Tree object to iterate, or None.
+      tree2: The second Tree object to iterate, or None.
+      prefix: The prefix of the paths to return.
+
+    Returns:
+      A list of pairs of TreeEntry objects, as returned by _merge_entries.
+    """
+    result = []
+    for entry1, entry2 in _merge_entries(path, tree1, tree2):
+        entry = entry1 if entry1 is not None else entry2
+        assert entry is not None and entry.path is not None
+        if entry.path.startswith(prefix):
+            result.append((entry1, entry2))
+    return result
+
+
+def _merge_sorted(
+    items1: Sequence[Any], items2: Sequence[Any], key: Callable[[Any], Any]
+) -> list[tuple[Any, Any]]:
     i1 = i2 = 0
-    len1 = len(entries1)
-    len2 = len(entries2)
+    len1 = len(items1)
+    len2 = len(items2)
 
-    result: list[tuple[TreeEntry | None, TreeEntry | None]] = []
+    result: list[tuple[Any, Any]] = []
     while i1 < len1 and i2 < len2:
-        entry1 = entries1[i1]
-        entry2 = entries2[i2]
-        if entry1.path < entry2.path:
-            result.append((entry1, None))
+        item1 = items1[i1]
+        item2 = items2[i2]
+        key1 = key(item1)
+        key2 = key(item2)
+        if key1 < key2:
+            result.append((item1, None))
             i1 += 1
-        elif entry1.path > entry2.path:
-            result.append((None, entry2))
+        elif key1 > key2:
+            result.append((None, item2))
             i2 += 1
         else:
-            result.append((entry1, entry2))
+            result.append((item1, item2))
             i1 += 1
             i2 += 1
     for i in range(i1, len1):
-        result.append((entries1[i], None))
+        result.append((items1[i], None))
     for i in range(i2, len2):
-        result.append((None, entries2[i]))
+        result.append((None, items2[i]))
+    return result
+
+
+def _merge_sorted_entries(
+    entries1: list[TreeEntry],
+    entries2: list[TreeEntry],
+    case_insensitive: bool = False,
+) -> list[tuple[TreeEntry | None, TreeEntry | None]]:
+    if case_insensitive:
+        return _merge_sorted(entries1, entries2, lambda entry: entry.path.lower())
+    return _merge_sorted(entries1, entries2, lambda entry: entry.path)
+
+
+def _merge_sorted_lists(
+    list1: Sequence[Any], list2: Sequence[Any], key_attr: str
+) -> list[tuple[Any, Any]]:
+    """Merge two lists sorted by an attribute of their items.
+
+    This is the same alignment as _merge_entries does for tree entries, so
+    that it can be reused for other sorted sequences, such as refs or index
+    entries.
+
+    Args:
+      list1: The first list, sorted by key_attr.
+      list2: The second list, sorted by key_attr.
+      key_attr: The name of the attribute to merge on.
+
+    Returns:
+      A list of (item1, item2) pairs of items with equal keys. If an item
+        has no match in the other list, the other item is None.
+    """
+    return _merge_sorted(list(list1), list(list2), lambda item: getattr(item, key_attr))
+
+
+def _list_entries(
+    path: bytes, items: Iterable[tuple[bytes, int, bytes]]
+) -> list[TreeEntry]:
+    result: list[TreeEntry] = []
+    prev = None
+    for name, mode, sha in items:
+        if prev is not None and name <= prev:
+            raise ValueError(f"tree entries are not sorted by name at {name!r}")
+        prev = name
+        result.append(TreeEntry(name, mode, sha).in_path(path))
+    return result
+
+
+def _merge_entry_lists(
+    path: bytes,
+    list1: Iterable[tuple[bytes, int, bytes]],
+    list2: Iterable[tuple[bytes, int, bytes]],
+) -> list[tuple[TreeEntry | None, TreeEntry | None]]:
+    """Merge two lists of tree entries, without building Tree objects.
+
+    Args:
+      path: A path to prepend to all entry names.
+      list1: The first list of (name, mode, sha) tuples.
+      list2: The second list of (name, mode, sha) tuples.
+
+    Returns:
+      A list of pairs of TreeEntry objects, as returned by _merge_entries.
+
+    Raises:
+      ValueError: If either list is not sorted by name, in the order of
+        Tree.iteritems(name_order=True), or has duplicate names.
+    """
+    return _merge_sorted_entries(_list_entries(path, list1), _list_entries(path, list2))
+
+
+def _merge_entries_multi(
+    path: bytes, trees: Sequence[Tree | None]
+) -> list[tuple[TreeEntry | None, ...]]:
+    """Merge the entries of any number of trees.
+
+    Args:
+      path: A path to prepend to all tree entry names.
+      trees: The Tree objects to iterate; each may be None.
+
+    Returns:
+      A list of tuples with one TreeEntry per tree, for each path that
+        occurs in any of the trees. Trees that don't have an entry for a
+        path have None in its place.
+    """
+    entries = [_tree_entries(path, tree) for tree in trees]
+    positions = [0] * len(entries)
+
+    result: list[tuple[TreeEntry | None, ...]] = []
+    while True:
+        heads = [
+            tree_entries[i]
+            for tree_entries, i in zip(entries, positions)
+            if i < len(tree_entries)
+        ]
+        if not heads:
+            break
+        min_path = min(entry.path for entry in heads)
+        row: list[TreeEntry | None] = []
+        for n, tree_entries in enumerate(entries):
+            i = positions[n]
+            if i < len(tree_entries) and tree_entries[i].path == min_path:
+                row.append(tree_entries[i])
+                positions[n] += 1
+            else:
+                row.append(None)
+        result.append(tuple(row))
     return result
 
 
@@ -157,12 +547,230 @@ def _is_tree(entry: TreeEntry | None) -> bool:
     return stat.S_ISDIR(entry.mode)
 
 
+def _are_trees(entries: Sequence[TreeEntry | None]) -> list[bool]:
+    return [_is_tree(entry) for entry in entries]
+
+
+def _is_blob(entry: TreeEntry | None) -> bool:
+    if entry is None or entry.mode is None:
+        return False
+    return stat.S_ISREG(entry.mode) or stat.S_ISLNK(entry.mode)
+
+
+def _is_gitlink(entry: TreeEntry | None) -> bool:
+    if entry is None or entry.mode is None:
+        return False
+    return S_ISGITLINK(entry.mode)
+
+
+# Kinds of change between two entries, as returned by _classify_change.
+_KIND_ADD = 0
+_KIND_DELETE = 1
+_KIND_MODIFY = 2
+_KIND_UNCHANGED = 3
+_KIND_TYPECHANGE = 4
+_KIND_MODE_CHANGE = 5
+
+
+def _classify_change(entry1: TreeEntry | None, entry2: TreeEntry | None) -> int:
+    """Classify the change between two entries for the same path.
+
+    Args:
+      entry1: The old entry, or None.
+      entry2: The new entry, or None.
+
+    Returns:
+      One of the _KIND_* constants. A change in file type (S_IFMT, so
+        between regular files, symlinks, gitlinks and trees) is
+        _KIND_TYPECHANGE, even between two kinds of blob. A change in mode
+        only (such as the executable bit) is _KIND_MODE_CHANGE, and other
+        changes are _KIND_MODIFY.
+
+    Raises:
+      ValueError: If both entries are None.
+    """
+    if entry1 is None:
+        if entry2 is None:
+            raise ValueError("at least one entry must not be None")
+        return _KIND_ADD
+    if entry2 is None:
+        return _KIND_DELETE
+    if entry1.mode == entry2.mode and entry1.sha == entry2.sha:
+        return _KIND_UNCHANGED
+    if (
+        entry1.mode is not None
+        and entry2.mode is not None
+        and stat.S_IFMT(entry1.mode) != stat.S_IFMT(entry2.mode)
+    ):
+        return _KIND_TYPECHANGE
+    if entry1.sha == entry2.sha:
+        return _KIND_MODE_CHANGE
+    return _KIND_MODIFY
+
+
+def _change_record(
+    entry1: TreeEntry | None, entry2: TreeEntry | None
+) -> tuple[
+    int,
+    bytes | None,
+    int | None,
+    bytes | None,
+    bytes | None,
+    int | None,
+    bytes | None,
+]:
+    """Describe the change between two entries for the same path.
+
+    Args:
+      entry1: The old entry, or None.
+      entry2: The new entry, or None.
+
+    Returns:
+      A tuple of (kind, old path, old mode, old sha, new path, new mode,
+        new sha), where kind is as returned by _classify_change. The old or
+        new fields are None for an add or a delete respectively.
+
+    Raises:
+      ValueError: If both entries are None.
+    """
+    kind = _classify_change(entry1, entry2)
+    old_path, old_mode, old_sha = entry1 if entry1 is not None else (None,) * 3
+    new_path, new_mode, new_sha = entry2 if entry2 is not None else (None,) * 3
+    return (kind, old_path, old_mode, old_sha, new_path, new_mode, new_sha)
+
+
+def _diff_entries(
+    path: bytes, tree1: Tree | None, tree2: Tree | None, want_unchanged: bool = False
+) -> list[tuple[int, TreeEntry | None, TreeEntry | None]]:
+    """Merge the entries of two trees and classify the change of each pair.
+
+    This is _merge_entries followed by _classify_change for every pair, in a
+    single call.
+
+    Args:
+      path: A path to prepend to all tree entry names.
+      tree1: The first Tree object to iterate, or None.
+      tree2: The second Tree object to iterate, or None.
+      want_unchanged: Whether to include the pairs of unchanged entries.
+
+    Returns:
+      A list of (kind, entry1, entry2) tuples in the order of _merge_entries,
+        where kind is as returned by _classify_change.
+    """
+    result = []
+    for entry1, entry2 in _merge_entries(path, tree1, tree2):
+        kind = _classify_change(entry1, entry2)
+        if kind != _KIND_UNCHANGED or want_unchanged:
+            result.append((kind, entry1, entry2))
+    return result
+
+
+def _index_tree_changes(
+    index_entries: Iterable[tuple[bytes, int, bytes]],
+    tree: Tree | None,
+    path: bytes = b"",
+    store: BaseObjectStore | None = None,
+) -> list[tuple[int, TreeEntry | None, TreeEntry | None]]:
+    """Compare the entries of an index with those of a tree, as git status does.
+
+    The index lists the blobs below all subtrees by their full paths, so the
+    subtrees of tree are looked up in store and their entries compared rather
+    than the subtrees themselves.
+
+    Args:
+      index_entries: The staged (path, mode, sha) tuples, sorted by path as
+        in the index.
+      tree: The committed Tree object, or None.
+      path: A path to prepend to all paths.
+      store: An ObjectStore to look up the subtrees of tree in. This is only
+        needed if tree has subtrees.
+
+    Returns:
+      A list of (kind, tree_entry, index_entry) tuples for the changed paths,
+        sorted by path, where kind is as returned by _classify_change.
+
+    Raises:
+      ValueError: If index_entries isn't sorted by path, or tree has subtrees
+        but there is no store.
+    """
+    committed: list[TreeEntry] = []
+
+    def add_entries(tree_path: bytes, tree: Tree | None) -> None:
+        for entry in _tree_entries(tree_path, tree):
+            if not _is_tree(entry):
+                committed.append(entry)
+                continue
+            if store is None:
+                raise ValueError("a store is needed to look up subtrees")
+            assert entry.path is not None and entry.sha is not None
+            subtree = store[entry.sha]
+            assert isinstance(subtree, Tree)
+            add_entries(entry.path, subtree)
+
+    add_entries(path, tree)
+    # The entries of a subtree come right after its name, but in path order
+    # b"a/b" sorts after b"a.c".
+    committed.sort(key=lambda entry: entry.path)
+    staged = _list_entries(path, index_entries)
+    result = []
+    for entry1, entry2 in _merge_sorted_entries(committed, staged):
+        kind = _classify_change(entry1, entry2)
+        if kind != _KIND_UNCHANGED:
+            result.append((kind, entry1, entry2))
+    return result
+
+
+# Outcomes of a three-way merge of one path, as returned by _merge3_entry.
+_MERGE3_UNCHANGED = 0
+_MERGE3_OURS = 1
+_MERGE3_THEIRS = 2
+_MERGE3_SAME = 3
+_MERGE3_CONFLICT = 4
+
+
+def _same_entry_contents(entry1: TreeEntry | None, entry2: TreeEntry | None) -> bool:
+    if entry1 is None or entry2 is None:
+        return entry1 is entry2
+    return entry1.mode == entry2.mode and entry1.sha == entry2.sha
+
+
+def _merge3_entry(
+    base: TreeEntry | None, ours: TreeEntry | None, theirs: TreeEntry | None
+) -> int:
+    """Decide how a path merges in a three-way merge.
+
+    Entries are compared by mode and sha; None means that the path doesn't
+    exist on that side.
+
+    Args:
+      base: The entry in the merge base, or None.
+      ours: The entry on our side, or None.
+      theirs: The entry on their side, or None.
+
+    Returns:
+      One of the _MERGE3_* constants: _MERGE3_UNCHANGED if neither side
+        changed the entry, _MERGE3_OURS or _MERGE3_THEIRS if only that side
+        did, _MERGE3_SAME if both sides made the same change, and
+        _MERGE3_CONFLICT if they made different changes.
+    """
+    ours_changed = not _same_entry_contents(base, ours)
+    theirs_changed = not _same_entry_contents(base, theirs)
+    if not ours_changed:
+        return _MERGE3_THEIRS if theirs_changed else _MERGE3_UNCHANGED
+    if not theirs_changed:
+        return _MERGE3_OURS
+    if _same_entry_contents(ours, theirs):
+        return _MERGE3_SAME
+    return _MERGE3_CONFLICT
+
+
 def walk_trees(
     store: BaseObjectStore,
     tree1_id: ObjectID | None,
     tree2_id: ObjectID | None,
     prune_identical: bool = False,
     paths: Sequence[bytes] | None = None,
+    max_depth: int | None = None,
 ) -> Iterator[tuple[TreeEntry | None, TreeEntry | None]]:
     """Recursively walk all the entries of two trees.
 
@@ -174,6 +782,9 @@ def walk_trees(
       tree2_id: The SHA of the second Tree object to iterate, or None.
       prune_identical: If True, identical subtrees will not be walked.
       paths: Optional list of paths to filter to (as bytes).
+      max_depth: If not None, subtrees that are max_depth or more levels
+        below the root (so every subtree for 0) are not walked; the entries
+        for the subtrees themselves are still included.
 
     Returns:
       Iterator over Pairs of TreeEntry objects for each pair of entries
@@ -193,8 +804,6 @@ def walk_trees(
         if prune_identical and is_tree1 and is_tree2 and entry1 == entry2:
             continue
 
-        tree1 = (is_tree1 and entry1 and store[entry1.sha]) or None
-        tree2 = (is_tree2 and entry2 and store[entry2.sha]) or None
         path = (
             (entry1.path if entry1 else None)
             or (entry2.path if entry2 else None)
@@ -226,11 +835,17 @@ def walk_trees(
                 # Skip this tree entirely
                 continue
 
+        # Only look up the trees once they are known to be walked.
+        tree1 = (is_tree1 and entry1 and store[entry1.sha]) or None
+        tree2 = (is_tree2 and entry2 and store[entry2.sha]) or None
+
         # Ensure trees are Tree objects before merging
         if tree1 is not None and not isinstance(tree1, Tree):
             tree1 = None
         if tree2 is not None and not isinstance(tree2, Tree):
             tree2 = None
+        if _beyond_depth(path, max_depth):
+            tree1 = tree2 = None
 
         if tree1 is not None or tree2 is not None:
             # Use empty trees for None values
@@ -265,6 +880,11 @@ def walk_trees(
                     break
 
 
+def _beyond_depth(path: bytes, max_depth: int | None) -> bool:
+    """Check whether the entry at path is too deep to recurse into."""
+    return max_depth is not None and bool(path) and path.count(b"/") >= max_depth
+
+
 def _skip_tree(entry: TreeEntry | None, include_trees: bool) -> TreeEntry | None:
     if entry is None or entry.mode is None:
         return None
@@ -299,8 +919,135 @@ def tree_changes(
 
     Returns:
       Iterator over TreeChange instances for each change between the
-        source and target tree.
+        source and target tree. With the Rust extension, the changes are all
+        found in one call before the first is yielded, so stopping early
+        saves no work; use walk_trees to walk the trees lazily.
+    """
+    yield from _tree_changes(
+        store,
+        tree1_id,
+        tree2_id,
+        want_unchanged=want_unchanged,
+        include_trees=include_trees,
+        rename_detector=rename_detector,
+        change_type_same=change_type_same,
+        paths=paths,
+    )
+
+
+class _TreeCache(BaseObjectStore):
+    """A bounded LRU cache of the objects looked up in a store, by SHA.
+
+    Anything other than looking up objects is passed on to the store.
+    """
+
+    def __init__(self, store: BaseObjectStore, capacity: int) -> None:
+        # Anything with __getitem__ will do as a store for walking trees.
+        super().__init__(object_format=getattr(store, "object_format", None))
+        self._store = store
+        self._capacity = capacity
+        self._objects: dict[ObjectID | RawObjectID, ShaFile] = {}
+
+    def __contains__(self, sha: ObjectID | RawObjectID) -> bool:
+        return sha in self._objects or sha in self._store
+
+    def get_raw(self, name: ObjectID | RawObjectID) -> tuple[int, bytes]:
+        return self._store.get_raw(name)
+
+    def __getitem__(self, sha: ObjectID | RawObjectID) -> ShaFile:
+        obj = self._objects.pop(sha, None)
+        if obj is None:
+            obj = self._store[sha]
+            if len(self._objects) >= self._capacity:
+                # Dicts keep their insertion order, so the first key is the
+                # least recently used.
+                del self._objects[next(iter(self._objects))]
+        self._objects[sha] = obj
+        return obj
+
+
+@overload
+def _tree_changes(
+    store: BaseObjectStore,
+    tree1_id: ObjectID | None,
+    tree2_id: ObjectID | None,
+    want_unchanged: bool = False,
+    include_trees: bool = False,
+    rename_detector: "RenameDetector | None" = None,
+    change_type_same: bool = False,
+    paths: Sequence[bytes] | None = None,
+    tree_cache_size: int = 256,
+    raw: Literal[False] = False,
+    max_depth: int | None = None,
+) -> Iterator[TreeChange]: ...
+
+
+@overload
+def _tree_changes(
+    store: BaseObjectStore,
+    tree1_id: ObjectID | None,
+    tree2_id: ObjectID | None,
+    want_unchanged: bool = False,
+    include_trees: bool = False,
+    rename_detector: "RenameDetector | None" = None,
+    change_type_same: bool = False,
+    paths: Sequence[bytes] | None = None,
+    tree_cache_size: int = 256,
+    *,
+    raw: Literal[True],
+    max_depth: int | None = None,
+) -> Iterator[_RawChange]: ...
+
+
+def _tree_changes(
+    store: BaseObjectStore,
+    tree1_id: ObjectID | None,
+    tree2_id: ObjectID | None,
+    want_unchanged: bool = False,
+    include_trees: bool = False,
+    rename_detector: "RenameDetector | None" = None,
+    change_type_same: bool = False,
+    paths: Sequence[bytes] | None = None,
+    tree_cache_size: int = 256,
+    raw: bool = False,
+    max_depth: int | None = None,
+) -> Iterator[TreeChange | _RawChange]:
+    """Implement tree_changes.
+
+    See tree_changes for the common arguments.
+
+    Args:
+      tree_cache_size: How many looked up trees to keep; 0 disables the cache.
+      raw: Whether to yield plain tuples rather than TreeChanges.
+      max_depth: If not None, subtrees that are max_depth or more levels
+        below the root (so every subtree for 0) are not recursed into.
+        Instead a pruned subtree that differs is reported as a single
+        change of its tree entry, a CHANGE_MODIFY if it is a tree on both
+        sides, even if include_trees is False.
+
+    Raises:
+      ValueError: If max_depth is combined with a rename_detector.
     """
+    if raw:
+        # Plain (type, old, new) tuples, with plain (path, mode, sha) tuples
+        # for the entries, are only cheaper to create in the Rust version.
+        for change in _tree_changes(
+            store,
+            tree1_id,
+            tree2_id,
+            want_unchanged,
+            include_trees,
+            rename_detector,
+            change_type_same,
+            paths,
+            tree_cache_size,
+            max_depth=max_depth,
+        ):
+            yield _raw_change(change)
+        return
+
+    if rename_detector is not None and max_depth is not None:
+        raise ValueError("max_depth cannot be combined with a rename_detector")
     if rename_detector is not None and tree1_id is not None and tree2_id is not None:
         yield from rename_detector.changes_with_renames(
             tree1_id,
@@ -310,16 +1057,34 @@ def tree_changes(
         )
         return
 
+    if paths is None and not tree1_id:
+        # Against no tree, as for a root commit, every entry is an add, so
+        # there is nothing to merge. The empty tree is still merged, as its
+        # root entry is reported as modified with include_trees.
+        yield from _tree_adds(store, tree2_id, include_trees, max_depth)
+        return
+
+    if tree_cache_size:
+        # Trees that occur more than once are only looked up once.
+        store = _TreeCache(store, tree_cache_size)
     entries = walk_trees(
-        store, tree1_id, tree2_id, prune_identical=(not want_unchanged), paths=paths
+        store,
+        tree1_id,
+        tree2_id,
+        prune_identical=(not want_unchanged),
+        paths=paths,
+        max_depth=max_depth,
     )
     for entry1, entry2 in entries:
         if entry1 == entry2 and not want_unchanged:
             continue
 
-        # Treat entries for trees as missing.
-        entry1 = _skip_tree(entry1, include_trees)
-        entry2 = _skip_tree(entry2, include_trees)
+        # Treat entries for trees as missing, unless they weren't recursed
+        # into.
+        path = (entry1 or entry2).path  # type: ignore[union-attr]
+        pruned = path is not None and _beyond_depth(path, max_depth)
+        entry1 = _skip_tree(entry1, include_trees or pruned)
+        entry2 = _skip_tree(entry2, include_trees or pruned)
 
         if entry1 is not None and entry2 is not None:
             if (
@@ -346,31 +1111,204 @@ def tree_changes(
         yield TreeChange(change_type, entry1, entry2)
 
 
-T = TypeVar("T")
-U = TypeVar("U")
+def _tree_changes_incremental(
+    store: BaseObjectStore,
+    tree1_id: ObjectID | None,
+    tree2_id: ObjectID | None,
+    changed_prefix: bytes,
+) -> Iterator[TreeChange]:
+    """Find the differences between two trees under a single changed path.
 
+    This is for callers that already know which path changed, such as
+    watchers re-diffing after a commit touching a single directory: only
+    the trees on the way to changed_prefix and the subtrees under it are
+    walked. If the directory at changed_prefix was added or deleted, its
+    contents show up as adds or deletes.
 
-def _all_eq(seq: Sequence[T], key: Callable[[T], U], value: U) -> bool:
-    for e in seq:
-        if key(e) != value:
-            return False
-    return True
+    The caller's prefix is trusted: the rest of the trees is assumed to be
+    unchanged, and changes elsewhere are not found.
 
+    Args:
+      store: An ObjectStore for looking up objects.
+      tree1_id: The SHA of the source tree.
+      tree2_id: The SHA of the target tree.
+      changed_prefix: The path of the changed file or directory; an empty
+        prefix diffs the whole trees.
 
-def _all_same(seq: Sequence[Any], key: Callable[[Any], Any]) -> bool:
-    return _all_eq(seq[1:], key, key(seq[0]))
+    Returns:
+      Iterator over TreeChange instances for each change under
+        changed_prefix, as from tree_changes.
+    """
+    changed_prefix = changed_prefix.rstrip(b"/")
+    paths = [changed_prefix] if changed_prefix else None
+    yield from _tree_changes(store, tree1_id, tree2_id, paths=paths)
 
 
-def tree_changes_for_merge(
+def _tree_adds(
     store: BaseObjectStore,
-    parent_tree_ids: Sequence[ObjectID],
-    tree_id: ObjectID,
-    rename_detector: "RenameDetector | None" = None,
-) -> Iterator[list[TreeChange | None]]:
-    """Get the tree changes for a merge tree relative to all its parents.
+    tree_id: ObjectID | None,
+    include_trees: bool,
+    max_depth: int | None = None,
+) -> Iterator[TreeChange]:
+    """Yield an add for every entry of a tree, in the order of _tree_changes."""
+    if not tree_id:
+        return
+    todo = [TreeEntry(b"", stat.S_IFDIR, tree_id)]
+    while todo:
+        entry = todo.pop()
+        assert entry.path is not None
+        is_tree = _is_tree(entry)
+        pruned = _beyond_depth(entry.path, max_depth)
+        if is_tree and not pruned:
+            assert entry.sha is not None
+            tree = store[entry.sha]
+            if isinstance(tree, Tree):
+                todo.extend(reversed(_tree_entries(entry.path, tree)))
+        if include_trees or pruned or not is_tree:
+            yield TreeChange.add(entry)
+
+
+def _tree_items(tree: Tree | None) -> list[tuple[bytes, int, bytes]]:
+    if tree is None:
+        return []
+    return sorted((name, *tree[name]) for name in tree)
+
+
+def _changed_paths(
+    store: BaseObjectStore,
+    tree1: Tree | None,
+    tree2: Tree | None,
+    path: bytes = b"",
+) -> list[bytes]:
+    """List the paths of the files that differ between two trees.
+
+    This gives the paths of the changes that tree_changes would report
+    (adds, deletes and modifies, recursing into subtrees), without looking
+    at anything but the names, modes and shas of the entries.
 
     Args:
-      store: An ObjectStore for looking up objects.
+      store: An ObjectStore for looking up subtrees.
+      tree1: The first Tree object, or None.
+      tree2: The second Tree object, or None.
+      path: A path to prepend to all entry names.
+
+    Returns:
+      A list of the changed paths, in the order tree_changes would report
+        them.
+    """
+    items1 = _tree_items(tree1)
+    items2 = _tree_items(tree2)
+    result: list[bytes] = []
+    i1 = i2 = 0
+    while i1 < len(items1) or i2 < len(items2):
+        item1: tuple[bytes, int, bytes] | None = None
+        item2: tuple[bytes, int, bytes] | None = None
+        if i2 == len(items2) or (i1 < len(items1) and items1[i1][0] < items2[i2][0]):
+            item1 = items1[i1]
+            i1 += 1
+        elif i1 == len(items1) or items1[i1][0] > items2[i2][0]:
+            item2 = items2[i2]
+            i2 += 1
+        else:
+            item1 = items1[i1]
+            item2 = items2[i2]
+            i1 += 1
+            i2 += 1
+        if item1 == item2:
+            continue
+
+        name = (item1 or item2)[0]  # type: ignore[index]
+        item_path = path + b"/" + name if path else name
+        subtree1 = subtree2 = None
+        if item1 is not None and stat.S_ISDIR(item1[1]):
+            subtree1 = store[item1[2]]
+        if item2 is not None and stat.S_ISDIR(item2[1]):
+            subtree2 = store[item2[2]]
+        # A file replaced by a tree (or the other way around) is a change of
+        # both the file and the contents of the tree.
+        if (item1 is not None and subtree1 is None) or (
+            item2 is not None and subtree2 is None
+        ):
+            result.append(item_path)
+        if subtree1 is not None or subtree2 is not None:
+            assert subtree1 is None or isinstance(subtree1, Tree)
+            assert subtree2 is None or isinstance(subtree2, Tree)
+            result.extend(_changed_paths(store, subtree1, subtree2, item_path))
+    return result
+
+
+def _trees_content_equal(
+    tree1: Tree | None,
+    tree2: Tree | None,
+    ignore_mode: bool = False,
+    store: BaseObjectStore | None = None,
+) -> bool:
+    """Check whether two trees have the same entries.
+
+    This stops at the first difference, so it is cheaper than diffing the
+    trees just to see whether there are any changes.
+
+    Args:
+      tree1: The first Tree object, or None for an empty tree.
+      tree2: The second Tree object, or None for an empty tree.
+      ignore_mode: If True, only compare the names and SHAs of the entries,
+        not their modes, e.g. to ignore executable bits.
+      store: An ObjectStore for looking up subtrees. Only needed with
+        ignore_mode, for subtrees whose SHAs differ because of the modes of
+        their entries; without it such subtrees count as different.
+
+    Returns:
+      Whether the trees are the same.
+    """
+    items1 = _tree_items(tree1)
+    items2 = _tree_items(tree2)
+    if len(items1) != len(items2):
+        return False
+    for (name1, mode1, sha1), (name2, mode2, sha2) in zip(items1, items2):
+        if name1 != name2:
+            return False
+        if sha1 == sha2 and (ignore_mode or mode1 == mode2):
+            continue
+        if (
+            ignore_mode
+            and store is not None
+            and stat.S_ISDIR(mode1)
+            and stat.S_ISDIR(mode2)
+        ):
+            subtree1 = store[sha1]
+            subtree2 = store[sha2]
+            assert isinstance(subtree1, Tree) and isinstance(subtree2, Tree)
+            if _trees_content_equal(subtree1, subtree2, ignore_mode, store):
+                continue
+        return False
+    return True
+
+
+T = TypeVar("T")
+U = TypeVar("U")
+
+
+def _all_eq(seq: Sequence[T], key: Callable[[T], U], value: U) -> bool:
+    for e in seq:
+        if key(e) != value:
+            return False
+    return True
+
+
+def _all_same(seq: Sequence[Any], key: Callable[[Any], Any]) -> bool:
+    return _all_eq(seq[1:], key, key(seq[0]))
+
+
+def tree_changes_for_merge(
+    store: BaseObjectStore,
+    parent_tree_ids: Sequence[ObjectID],
+    tree_id: ObjectID,
+    rename_detector: "RenameDetector | None" = None,
+) -> Iterator[list[TreeChange | None]]:
+    """Get the tree changes for a merge tree relative to all its parents.
+
+    Args:
+      store: An ObjectStore for looking up objects.
       parent_tree_ids: An iterable of the SHAs of the parent trees.
       tree_id: The SHA of the merge tree.
       rename_detector: RenameDetector object for detecting renames.
@@ -387,6 +1325,17 @@ def tree_changes_for_merge(
       in the merge tree is not found in any of the parents, or in the case of
       deletes, if not all of the old SHAs match.
     """
+    yield from _tree_changes_for_merge(
+        store, parent_tree_ids, tree_id, rename_detector=rename_detector
+    )
+
+
+def _tree_changes_for_merge(
+    store: BaseObjectStore,
+    parent_tree_ids: Sequence[ObjectID],
+    tree_id: ObjectID,
+    rename_detector: "RenameDetector | None" = None,
+) -> Iterator[list[TreeChange | None]]:
     all_parent_changes = [
         tree_changes(store, t, tree_id, rename_detector=rename_detector)
         for t in parent_tree_ids
@@ -408,120 +1357,1353 @@ def tree_changes_for_merge(
             assert path is not None
             changes_by_path[path][i] = change
 
-    def old_sha(c: TreeChange) -> ObjectID | None:
-        return c.old.sha if c.old is not None else None
+    def old_sha(c: TreeChange) -> ObjectID | None:
+        return c.old.sha if c.old is not None else None
+
+    def change_type(c: TreeChange) -> str:
+        return c.type
+
+    # Yield only conflicting changes.
+    for _, changes in sorted(changes_by_path.items()):
+        assert len(changes) == num_parents
+        have = [c for c in changes if c is not None]
+        if _all_eq(have, change_type, CHANGE_DELETE):
+            if not _all_same(have, old_sha):
+                yield changes
+        elif not _all_same(have, change_type):
+            yield changes
+        elif None not in changes:
+            # If no change was found relative to one parent, that means the SHA
+            # must have matched the SHA in that parent, so it is not a
+            # conflict.
+            yield changes
+
+
+_BLOCK_SIZE = 64
+
+# Number of bytes the rolling hash used for content-defined blocks covers.
+_ROLLING_WINDOW = 16
+_ROLLING_BASE = 0x9E3779B97F4A7C15
+
+_FNV_OFFSET_BASIS = 0xCBF29CE484222325
+_FNV_PRIME = 0x100000001B3
+
+
+def _stable_hash(data: bytes) -> int:
+    """Compute the 64-bit FNV-1a hash of data.
+
+    Unlike hash(), this is the same in every process.
+    """
+    value = _FNV_OFFSET_BASIS
+    for c in data:
+        value = ((value ^ c) * _FNV_PRIME) & 0xFFFFFFFFFFFFFFFF
+    return value
+
+
+_MASK64 = 0xFFFFFFFFFFFFFFFF
+_PRIME32_1 = 0x9E3779B1
+_PRIME32_2 = 0x85EBCA77
+_PRIME32_3 = 0xC2B2AE3D
+_PRIME64_1 = 0x9E3779B185EBCA87
+_PRIME64_2 = 0xC2B2AE3D27D4EB4F
+_PRIME64_3 = 0x165667B19E3779F9
+_PRIME64_4 = 0x85EBCA77C2B2AE63
+_PRIME64_5 = 0x27D4EB2F165667C5
+_PRIME_MX1 = 0x165667919E3779F9
+_PRIME_MX2 = 0x9FB21C651E98DF25
+
+# The default secret of XXH3.
+_XXH3_SECRET = bytes.fromhex(
+    "b8fe6c3923a44bbe7c01812cf721ad1cded46de9839097db7240a4a4b7b3671f"
+    "cb79e64eccc0e578825ad07dccff7221b8084674f743248ee03590e6813a264c"
+    "3c2852bb91c300cb88d0658b1b532ea371644897a20df94e3819ef46a9deacd8"
+    "a8fa763fe39c343ff9dcbbc7c70b4f1d8a51e04bcdb45931c89f7ec9d9787364"
+    "eac5ac8334d3ebc3c581a0fffa1363eb170ddd51b7f0da49d316552629d4689e"
+    "2b16be587d47a1fc8ff8b8d17ad031ce45cb3a8f95160428afd7fbcabb4b407e"
+)
+
+
+def _read32(data: bytes, offset: int) -> int:
+    return int.from_bytes(data[offset : offset + 4], "little")
+
+
+def _read64(data: bytes, offset: int) -> int:
+    return int.from_bytes(data[offset : offset + 8], "little")
+
+
+def _mul128_fold64(left: int, right: int) -> int:
+    product = left * right
+    return (product & _MASK64) ^ (product >> 64)
+
+
+def _xxh64_avalanche(value: int) -> int:
+    value = ((value ^ (value >> 33)) * _PRIME64_2) & _MASK64
+    value = ((value ^ (value >> 29)) * _PRIME64_3) & _MASK64
+    return value ^ (value >> 32)
+
+
+def _xxh3_avalanche(value: int) -> int:
+    value &= _MASK64
+    value = ((value ^ (value >> 37)) * _PRIME_MX1) & _MASK64
+    return value ^ (value >> 32)
+
+
+def _xxh3_mix16(data: bytes, offset: int, secret_offset: int) -> int:
+    return _mul128_fold64(
+        _read64(data, offset) ^ _read64(_XXH3_SECRET, secret_offset),
+        _read64(data, offset + 8) ^ _read64(_XXH3_SECRET, secret_offset + 8),
+    )
+
+
+def _xxh3_hash(data: bytes) -> int:
+    """Compute the 64-bit XXH3 hash of data, with the default secret and seed.
+
+    Like _stable_hash, this is the same in every process, but it has fewer
+    collisions. This follows the reference implementation, without any of
+    its optimizations.
+    """
+    length = len(data)
+    secret = _XXH3_SECRET
+    if length > 240:
+        return _xxh3_hash_long(data)
+    if length > 128:
+        acc = length * _PRIME64_1
+        for i in range(8):
+            acc += _xxh3_mix16(data, 16 * i, 16 * i)
+        acc = _xxh3_avalanche(acc)
+        for i in range(8, length // 16):
+            acc += _xxh3_mix16(data, 16 * i, 16 * (i - 8) + 3)
+        acc += _xxh3_mix16(data, length - 16, 136 - 17)
+        return _xxh3_avalanche(acc)
+    if length > 16:
+        acc = length * _PRIME64_1
+        # Mix in pairs of 16 bytes from either end, working inwards.
+        for i in range((length - 1) // 32 + 1):
+            acc += _xxh3_mix16(data, 16 * i, 32 * i)
+            acc += _xxh3_mix16(data, length - 16 * (i + 1), 32 * i + 16)
+        return _xxh3_avalanche(acc)
+    if length > 8:
+        low = _read64(data, 0) ^ _read64(secret, 24) ^ _read64(secret, 32)
+        high = _read64(data, length - 8) ^ _read64(secret, 40) ^ _read64(secret, 48)
+        swapped = int.from_bytes(low.to_bytes(8, "little"), "big")
+        return _xxh3_avalanche(length + swapped + high + _mul128_fold64(low, high))
+    if length >= 4:
+        value = (_read32(data, length - 4) + (_read32(data, 0) << 32)) ^ (
+            _read64(secret, 8) ^ _read64(secret, 16)
+        )
+        rotl49 = ((value << 49) | (value >> 15)) & _MASK64
+        rotl24 = ((value << 24) | (value >> 40)) & _MASK64
+        value = ((value ^ rotl49 ^ rotl24) * _PRIME_MX2) & _MASK64
+        value = ((value ^ ((value >> 35) + length)) * _PRIME_MX2) & _MASK64
+        return value ^ (value >> 28)
+    if length:
+        combo = data[length >> 1] << 24 | data[0] << 16 | length << 8 | data[-1]
+        return _xxh64_avalanche(combo ^ _read32(secret, 0) ^ _read32(secret, 4))
+    return _xxh64_avalanche(_read64(secret, 56) ^ _read64(secret, 64))
+
+
+def _xxh3_hash_long(data: bytes) -> int:
+    """Compute the XXH3 hash of more than 240 bytes, in stripes of 64 bytes."""
+    length = len(data)
+    secret = _XXH3_SECRET
+    acc = [
+        _PRIME32_3,
+        _PRIME64_1,
+        _PRIME64_2,
+        _PRIME64_3,
+        _PRIME64_4,
+        _PRIME32_2,
+        _PRIME64_5,
+        _PRIME32_1,
+    ]
+
+    def accumulate(offset: int, secret_offset: int) -> None:
+        for i in range(8):
+            value = _read64(data, offset + 8 * i)
+            key = value ^ _read64(secret, secret_offset + 8 * i)
+            acc[i ^ 1] = (acc[i ^ 1] + value) & _MASK64
+            acc[i] = (acc[i] + (key & 0xFFFFFFFF) * (key >> 32)) & _MASK64
+
+    # Each block of 16 stripes is followed by scrambling the accumulators.
+    stripes_per_block = (len(secret) - 64) // 8
+    block_len = 64 * stripes_per_block
+    num_blocks = (length - 1) // block_len
+    for block in range(num_blocks):
+        for stripe in range(stripes_per_block):
+            accumulate(block * block_len + 64 * stripe, 8 * stripe)
+        for i in range(8):
+            value = acc[i] ^ (acc[i] >> 47) ^ _read64(secret, len(secret) - 64 + 8 * i)
+            acc[i] = (value * _PRIME32_1) & _MASK64
+    for stripe in range((length - 1 - block_len * num_blocks) // 64):
+        accumulate(num_blocks * block_len + 64 * stripe, 8 * stripe)
+    accumulate(length - 64, len(secret) - 64 - 7)
+
+    result = length * _PRIME64_1
+    for i in range(4):
+        result += _mul128_fold64(
+            acc[2 * i] ^ _read64(secret, 11 + 16 * i),
+            acc[2 * i + 1] ^ _read64(secret, 11 + 16 * i + 8),
+        )
+    return _xxh3_avalanche(result)
+
+
+def _line_blocks(
+    data: Iterable[int],
+    block_size: int,
+    delimiter: int = 0x0A,
+    skip_partial: bool = False,
+    line_endings: str = "lf",
+) -> Iterator[bytes]:
+    """Split data into blocks on lines or after block_size bytes.
+
+    If delimiter is given, split on that byte rather than on newlines. If
+    skip_partial is set, the trailing partial block is not yielded.
+
+    line_endings says what ends a line: "lf" for a newline (or delimiter),
+    "crlf" for only a CR followed by a newline, and "any" for a newline, a
+    CR, or both together.
+    """
+    block = bytearray()
+    # Whether block ends with a CR that may be followed by a newline.
+    pending_cr = False
+    for c in data:
+        if pending_cr:
+            pending_cr = False
+            if c != 0x0A:
+                yield bytes(block)
+                block.clear()
+        block.append(c)
+        if len(block) == block_size:
+            pass
+        elif line_endings == "any":
+            if c == 0x0D:
+                pending_cr = True
+                continue
+            if c != 0x0A:
+                continue
+        elif line_endings == "crlf":
+            if c != 0x0A or not block.endswith(b"\r\n"):
+                continue
+        elif c != delimiter:
+            continue
+        yield bytes(block)
+        block.clear()
+    if block and (pending_cr or not skip_partial):
+        yield bytes(block)
+
+
+_LINE_ENDINGS = ("lf", "crlf", "any")
+
+
+def _rolling_blocks(
+    data: Iterable[int], block_size: int, skip_partial: bool = False
+) -> Iterator[bytes]:
+    """Split data into content-defined blocks.
+
+    A block ends wherever the rolling hash of the last _ROLLING_WINDOW bytes
+    is a multiple of block_size (about once every block_size bytes), so the
+    boundaries move along with the content when bytes are inserted or
+    removed. Blocks are cut after 4 * block_size bytes regardless, and the
+    first _ROLLING_WINDOW - 1 bytes never end a block. If skip_partial is
+    set, the trailing block (which ends at the end of the data rather than
+    at a boundary) is not yielded.
+    """
+    out_factor = pow(_ROLLING_BASE, _ROLLING_WINDOW, 1 << 64)
+    history: deque[int] = deque()
+    value = 0
+    block = bytearray()
+    for c in data:
+        value = (value * _ROLLING_BASE + c) & 0xFFFFFFFFFFFFFFFF
+        history.append(c)
+        if len(history) > _ROLLING_WINDOW:
+            value = (value - history.popleft() * out_factor) & 0xFFFFFFFFFFFFFFFF
+        block.append(c)
+        if len(block) == 4 * block_size or (
+            len(history) == _ROLLING_WINDOW and (value >> 32) % block_size == 0
+        ):
+            yield bytes(block)
+            block.clear()
+    if block and not skip_partial:
+        yield bytes(block)
+
+
+def _overlapping_blocks(
+    data: bytes, block_size: int, overlap: int, skip_partial: bool = False
+) -> Iterator[bytes]:
+    """Split data into blocks of block_size bytes that overlap.
+
+    A block starts every block_size - overlap bytes, up to the first block
+    reaching the end of the data, which may be shorter than block_size. If
+    skip_partial is set, such a shorter block is not yielded.
+    """
+    for start in range(0, len(data), block_size - overlap):
+        block = data[start : start + block_size]
+        if len(block) == block_size or not skip_partial:
+            yield block
+        if start + block_size >= len(data):
+            break
+
+
+def _normalize_block(block: bytes) -> bytes:
+    """Strip trailing spaces, tabs and CRs from a block, keeping its newline."""
+    if block.endswith(b"\n"):
+        return block[:-1].rstrip(b" \t\r") + b"\n"
+    return block.rstrip(b" \t\r")
+
+
+def _raw_data(obj: "ShaFile | bytes | bytearray | memoryview") -> Iterable[int]:
+    """Iterate over the bytes of an object, or of a buffer."""
+    try:
+        return memoryview(obj).cast("B")  # type: ignore[arg-type]
+    except TypeError:
+        return chain.from_iterable(obj.as_raw_chunks())  # type: ignore[union-attr]
+
+
+_HASH_ALGOS = ("py", "fnv", "xxh3")
+
+
+def _block_hash(hash_algo: str, stable_hash: bool = False) -> Callable[[bytes], int]:
+    """Look up the function to hash blocks with for a hash_algo."""
+    if hash_algo not in _HASH_ALGOS:
+        raise ValueError(f"invalid hash_algo: {hash_algo!r}")
+    if stable_hash and hash_algo == "xxh3":
+        raise ValueError("stable_hash cannot be combined with hash_algo 'xxh3'")
+    if stable_hash or hash_algo == "fnv":
+        return _stable_hash
+    if hash_algo == "xxh3":
+        return _xxh3_hash
+    return hash
+
+
+def _beyond_max_blocks(
+    block_counts: Mapping[int, int], hashcode: int, max_blocks: int | None
+) -> bool:
+    """Check whether counting a block would make for over max_blocks blocks."""
+    return (
+        max_blocks is not None
+        and len(block_counts) >= max_blocks
+        and hashcode not in block_counts
+    )
+
+
+def _count_blocks(
+    obj: "ShaFile | bytes | bytearray | memoryview",
+    block_size: int | None = None,
+    stable_hash: bool = False,
+    rolling: bool = False,
+    normalize_whitespace: bool = False,
+    delimiter: int | None = None,
+    skip_partial: bool = False,
+    size_hint: int | None = None,
+    line_endings: str = "lf",
+    verify_collisions: bool = False,
+    hash_algo: str = "py",
+    dedup_runs: bool = False,
+    overlap: int = 0,
+) -> dict[int, int]:
+    """Count the blocks in an object.
+
+    Splits the data into blocks either on lines or <=64-byte chunks of lines.
+
+    Args:
+      obj: The object to count blocks for, or its contents as an object
+        supporting the buffer protocol.
+      block_size: Maximum size of a block, or None to use _BLOCK_SIZE.
+      stable_hash: If True, key blocks on a 64-bit FNV-1a hash rather than
+        hash(), which is randomized per process. Block counts computed this
+        way can be persisted and reused by other processes. This is the same
+        as hash_algo="fnv".
+      rolling: If True, split the data into content-defined blocks of
+        block_size bytes on average (see _rolling_blocks) rather than on
+        lines. Inserting bytes then only affects the blocks around the
+        insertion, even within long lines.
+      normalize_whitespace: If True, strip trailing spaces, tabs and CRs from
+        each block (see _normalize_block) before hashing it, so that
+        objects which only differ in trailing whitespace or line endings
+        have the same blocks. The counts are of the normalized blocks.
+      delimiter: A byte value to split blocks on instead of newlines, for
+        content where another boundary is more natural. Blocks are still
+        cut after block_size bytes. Ignored if rolling is set.
+      skip_partial: If True, don't count the trailing partial block, such as
+        a final line without a newline. Only complete blocks are counted.
+      size_hint: The size of the object, if known, so that the native
+        implementation can reserve space for its blocks up front. The result
+        is the same whatever the hint; it is ignored here.
+      line_endings: What ends a line: "lf" for a newline, "crlf" for only a
+        CR followed by a newline, or "any" for a newline, a lone CR or a CR
+        followed by a newline. Ignored if delimiter or rolling is set.
+      verify_collisions: If True, check that blocks with the same hashcode
+        have the same contents, rather than silently adding up the counts of
+        different blocks. This guards against crafted collisions, at the
+        cost of keeping a copy of each distinct block.
+      hash_algo: What to key blocks on: "py" for hash(), "fnv" for a 64-bit
+        FNV-1a hash (see _stable_hash) or "xxh3" for a 64-bit XXH3 hash (see
+        _xxh3_hash). Both of the latter are unsigned and the same across
+        processes, but XXH3 has fewer collisions between the blocks of large
+        objects.
+      dedup_runs: If True, count a run of identical consecutive blocks (after
+        normalize_whitespace) only once, so that repeated boilerplate weighs
+        less in similarity than the structure around it.
+      overlap: If nonzero, split the data into windows of block_size bytes
+        that overlap by this many bytes (see _overlapping_blocks) rather than
+        on lines, so that content shifted by less than a block still shares
+        blocks, at the cost of more blocks. Can't be combined with rolling,
+        delimiter or line_endings other than "lf", which split blocks
+        differently.
+
+    Returns:
+      A dict of block hashcode -> total bytes occurring.
+
+    Raises:
+      ValueError: If verify_collisions is set and two different blocks have
+        the same hashcode, rolling is set with a block_size of 0, or overlap
+        is not less than block_size or is combined with rolling, delimiter or
+        line_endings.
+    """
+    block_counts, _ = _count_blocks_upto(
+        obj,
+        None,
+        block_size,
+        stable_hash,
+        rolling,
+        normalize_whitespace,
+        delimiter,
+        skip_partial,
+        size_hint,
+        line_endings,
+        verify_collisions,
+        hash_algo,
+        dedup_runs,
+        overlap,
+    )
+    return block_counts
+
+
+def _count_blocks_bounded(
+    obj: "ShaFile | bytes | bytearray | memoryview",
+    max_blocks: int | None,
+    block_size: int | None = None,
+    stable_hash: bool = False,
+    rolling: bool = False,
+    normalize_whitespace: bool = False,
+    delimiter: int | None = None,
+    skip_partial: bool = False,
+    size_hint: int | None = None,
+    line_endings: str = "lf",
+    verify_collisions: bool = False,
+    hash_algo: str = "py",
+    dedup_runs: bool = False,
+    overlap: int = 0,
+) -> tuple[dict[int, int], bool]:
+    """Count the blocks in an object, up to a number of distinct blocks.
+
+    Takes the same arguments as _count_blocks, plus max_blocks.
+
+    Args:
+      obj: The object to count blocks for.
+      max_blocks: Stop counting at the first block that would make for more
+        than this many distinct blocks, so that huge (or hostile) objects
+        can't take up unbounded memory, or None for no limit.
+
+    Returns:
+      A tuple of a dict of block hashcode -> total bytes occurring, which only
+        has the counts up to where counting stopped, and whether counting
+        stopped early.
+
+    Raises:
+      ValueError: As for _count_blocks.
+    """
+    return _count_blocks_upto(
+        obj,
+        max_blocks,
+        block_size,
+        stable_hash,
+        rolling,
+        normalize_whitespace,
+        delimiter,
+        skip_partial,
+        size_hint,
+        line_endings,
+        verify_collisions,
+        hash_algo,
+        dedup_runs,
+        overlap,
+    )
+
+
+def _count_blocks_upto(
+    obj: "ShaFile | bytes | bytearray | memoryview",
+    max_blocks: int | None,
+    block_size: int | None = None,
+    stable_hash: bool = False,
+    rolling: bool = False,
+    normalize_whitespace: bool = False,
+    delimiter: int | None = None,
+    skip_partial: bool = False,
+    size_hint: int | None = None,
+    line_endings: str = "lf",
+    verify_collisions: bool = False,
+    hash_algo: str = "py",
+    dedup_runs: bool = False,
+    overlap: int = 0,
+) -> tuple[dict[int, int], bool]:
+    """Count the blocks in an object, as for _count_blocks_bounded.
+
+    Shared by _count_blocks and _count_blocks_bounded, so that either keeps
+    to the pure-Python implementation when the other is overridden.
+    """
+    if block_size is None:
+        block_size = _BLOCK_SIZE
+    if rolling and block_size <= 0:
+        raise ValueError(f"block_size must be positive with rolling: {block_size}")
+    if overlap and not 0 <= overlap < block_size:
+        raise ValueError(
+            f"overlap must be less than block_size ({block_size}): {overlap}"
+        )
+    if overlap and (rolling or delimiter is not None or line_endings != "lf"):
+        raise ValueError(
+            "overlap cannot be combined with rolling, delimiter or line_endings"
+        )
+    if line_endings not in _LINE_ENDINGS:
+        raise ValueError(f"invalid line_endings: {line_endings!r}")
+    block_hash = _block_hash(hash_algo, stable_hash)
+    block_counts: dict[int, int] = defaultdict(int)
+    truncated = False
+    if (
+        rolling
+        or normalize_whitespace
+        or delimiter is not None
+        or line_endings != "lf"
+        or verify_collisions
+        or dedup_runs
+        or overlap
+    ):
+        data = _raw_data(obj)
+        if rolling:
+            blocks = _rolling_blocks(data, block_size, skip_partial)
+        elif overlap:
+            blocks = _overlapping_blocks(bytes(data), block_size, overlap, skip_partial)
+        elif delimiter is not None:
+            blocks = _line_blocks(data, block_size, delimiter, skip_partial)
+        else:
+            blocks = _line_blocks(
+                data, block_size, skip_partial=skip_partial, line_endings=line_endings
+            )
+        seen: dict[int, bytes] = {}
+        previous = None
+        for value in blocks:
+            if normalize_whitespace:
+                value = _normalize_block(value)
+                if not value:
+                    continue
+            if dedup_runs:
+                if value == previous:
+                    continue
+                previous = value
+            hashcode = block_hash(value)
+            if _beyond_max_blocks(block_counts, hashcode, max_blocks):
+                truncated = True
+                break
+            if verify_collisions:
+                first = seen.setdefault(hashcode, value)
+                if first != value:
+                    raise ValueError(
+                        f"hash collision between blocks {first!r} and {value!r}"
+                    )
+            block_counts[hashcode] += len(value)
+        return block_counts, truncated
+
+    block = BytesIO()
+    n = 0
+
+    # Cache attrs as locals to avoid expensive lookups in the inner loop.
+    block_write = block.write
+    block_seek = block.seek
+    block_truncate = block.truncate
+    block_getvalue = block.getvalue
+
+    for c in _raw_data(obj):
+        cb = c.to_bytes(1, "big")
+        block_write(cb)
+        n += 1
+        if cb == b"\n" or n == block_size:
+            value = block_getvalue()
+            hashcode = block_hash(value)
+            if _beyond_max_blocks(block_counts, hashcode, max_blocks):
+                truncated = True
+                break
+            block_counts[hashcode] += len(value)
+            block_seek(0)
+            block_truncate()
+            n = 0
+    if n > 0 and not skip_partial and not truncated:
+        last_block = block_getvalue()
+        hashcode = block_hash(last_block)
+        if _beyond_max_blocks(block_counts, hashcode, max_blocks):
+            truncated = True
+        else:
+            block_counts[hashcode] += len(last_block)
+    return block_counts, truncated
+
+
+def _iter_blocks(obj: ShaFile, block_size: int | None = None) -> list[tuple[int, int]]:
+    """List the blocks of an object.
+
+    The blocks are split in the same way as by _count_blocks, but are listed
+    in order and with duplicates, which is useful to see which blocks of two
+    objects match.
+
+    Args:
+      obj: The object to list the blocks of.
+      block_size: Maximum size of a block, or None to use _BLOCK_SIZE.
+
+    Returns:
+      A list of (block hashcode, length) tuples.
+    """
+    if block_size is None:
+        block_size = _BLOCK_SIZE
+    data = chain.from_iterable(obj.as_raw_chunks())
+    return [(hash(block), len(block)) for block in _line_blocks(data, block_size)]
+
+
+def _count_blocks_many(
+    objs: Sequence[ShaFile], block_size: int | None = None
+) -> list[dict[int, int]]:
+    """Count the blocks in several objects.
+
+    Args:
+      objs: The objects to count blocks for.
+      block_size: Maximum size of a block, or None to use _BLOCK_SIZE.
+
+    Returns:
+      A list with the block counts of each object, as returned by
+        _count_blocks, in the same order as objs.
+    """
+    return [_count_blocks(obj, block_size) for obj in objs]
+
+
+def _common_bytes(blocks1: Mapping[int, int], blocks2: Mapping[int, int]) -> int:
+    """Count the number of common bytes in two block count dicts.
+
+    Args:
+      blocks1: The first dict of block hashcode -> total bytes.
+      blocks2: The second dict of block hashcode -> total bytes.
+
+    Returns:
+      The number of bytes in common between blocks1 and blocks2. This is
+      only approximate due to possible hash collisions.
+    """
+    # Iterate over the smaller of the two dicts, since this is symmetrical.
+    if len(blocks1) > len(blocks2):
+        blocks1, blocks2 = blocks2, blocks1
+    score = 0
+    for block, count1 in blocks1.items():
+        count2 = blocks2.get(block)
+        if count2:
+            score += min(count1, count2)
+    return score
+
+
+def _block_count_distance(
+    blocks1: Mapping[int, int], blocks2: Mapping[int, int]
+) -> int:
+    """Measure how far apart two block count dicts are.
+
+    This is the counterpart of _common_bytes, e.g. for grouping similar
+    blobs.
+
+    Args:
+      blocks1: The first dict of block hashcode -> total bytes.
+      blocks2: The second dict of block hashcode -> total bytes.
+
+    Returns:
+      The sum of abs(count1 - count2) over every block in either dict.
+    """
+    # Walk the smaller dict, then whatever is only in the larger one.
+    if len(blocks1) > len(blocks2):
+        blocks1, blocks2 = blocks2, blocks1
+    distance = 0
+    for block, count1 in blocks1.items():
+        distance += abs(count1 - blocks2.get(block, 0))
+    for block, count2 in blocks2.items():
+        if block not in blocks1:
+            distance += count2
+    return distance
+
+
+def _block_count_diff(
+    blocks1: Mapping[int, int], blocks2: Mapping[int, int]
+) -> list[tuple[int, int, int]]:
+    """List the counts of every block in either of two block count dicts.
+
+    This is meant for analysing how two objects differ, e.g. as features
+    for predicting renames; it is not used for rename detection itself.
+
+    Args:
+      blocks1: The first dict of block hashcode -> total bytes.
+      blocks2: The second dict of block hashcode -> total bytes.
+
+    Returns:
+      A list of (block hashcode, bytes in blocks1, bytes in blocks2) tuples,
+        sorted by hashcode. A block missing from one dict has a count of 0.
+    """
+    return [
+        (block, blocks1.get(block, 0), blocks2.get(block, 0))
+        for block in sorted(blocks1.keys() | blocks2.keys())
+    ]
+
+
+def _common_prefix_suffix(a: bytes, b: bytes) -> tuple[int, int]:
+    """Find the lengths of the common prefix and suffix of two strings.
+
+    The suffix never overlaps the prefix, so if one string is a prefix of
+    the other the suffix is empty.
+
+    Args:
+      a: The first string.
+      b: The second string.
+
+    Returns:
+      A tuple of (prefix length, suffix length).
+    """
+    limit = min(len(a), len(b))
+    prefix = 0
+    while prefix < limit and a[prefix] == b[prefix]:
+        prefix += 1
+    limit -= prefix
+    suffix = 0
+    while suffix < limit and a[-suffix - 1] == b[-suffix - 1]:
+        suffix += 1
+    return prefix, suffix
+
+
+def _line_offsets(data: bytes) -> list[tuple[int, int]]:
+    """Find where each line of a string starts and ends.
+
+    Args:
+      data: The string to split into lines.
+
+    Returns:
+      A list of (start, end) offsets of each line, including its newline,
+        so that data[start:end] is the line. A final line without a newline
+        is included too.
+    """
+    result = []
+    start = 0
+    while start < len(data):
+        end = data.find(b"\n", start) + 1 or len(data)
+        result.append((start, end))
+        start = end
+    return result
+
+
+class BlockCountCache:
+    """A cache of the block counts of objects, keyed by SHA.
+
+    This can be used as the block_cache of _similarity_score and the
+    candidate scorers in place of a dict, so that the blocks of each object
+    are only counted once.
+    """
+
+    def __init__(self) -> None:
+        """Create an empty cache."""
+        self._counts: dict[ObjectID, dict[int, int]] = {}
+
+    def get(self, obj: ShaFile) -> dict[int, int]:
+        """Get the block counts of an object, counting them if not cached yet."""
+        try:
+            return self._counts[obj.id]
+        except KeyError:
+            counts = self._counts[obj.id] = _count_blocks(obj)
+            return counts
+
+    def clear(self) -> None:
+        """Remove all cached block counts."""
+        self._counts.clear()
+
+    def __len__(self) -> int:
+        return len(self._counts)
+
+    def __contains__(self, sha: ObjectID) -> bool:
+        return sha in self._counts
+
+    def __getitem__(self, sha: ObjectID) -> dict[int, int]:
+        return self._counts[sha]
+
+    def __setitem__(self, sha: ObjectID, blocks: dict[int, int]) -> None:
+        self._counts[sha] = blocks
+
+
+class BlockAccumulator:
+    """Counts the blocks of several objects together.
+
+    This gives the blocks of the concatenation of the objects, e.g. to
+    compare a new file against the union of several deleted ones. Blocks are
+    split as by _count_blocks.
+    """
+
+    def __init__(self, block_size: int | None = None, carry: bool = False) -> None:
+        """Create an empty accumulator.
+
+        Args:
+          block_size: Maximum size of a block, or None to use _BLOCK_SIZE.
+          carry: If True, a partial block at the end of an object is
+            continued by the start of the next object added, as if the objects
+            were concatenated. Otherwise it is counted as a block of its own.
+        """
+        self._block_size = _BLOCK_SIZE if block_size is None else block_size
+        self._carry = carry
+        self._counts: dict[int, int] = defaultdict(int)
+        self._block = bytearray()
+
+    def add(self, obj: ShaFile) -> None:
+        """Add the blocks of an object to the counts."""
+        block = self._block
+        for c in chain.from_iterable(obj.as_raw_chunks()):
+            block.append(c)
+            if c == 0x0A or len(block) == self._block_size:
+                self._counts[hash(bytes(block))] += len(block)
+                block.clear()
+        if block and not self._carry:
+            self._counts[hash(bytes(block))] += len(block)
+            block.clear()
+
+    def counts(self) -> dict[int, int]:
+        """Get the block counts of the objects added so far.
+
+        With carry, a partial block left over from the last object is
+        included, but is still continued by the next object added.
+        """
+        counts = dict(self._counts)
+        if self._block:
+            value = bytes(self._block)
+            counts[hash(value)] = counts.get(hash(value), 0) + len(value)
+        return counts
+
+    def reset(self) -> None:
+        """Forget all the objects added so far."""
+        self._counts.clear()
+        self._block.clear()
+
+
+def _similarity_score(
+    obj1: ShaFile,
+    obj2: ShaFile,
+    block_cache: "dict[ObjectID, dict[int, int]] | BlockCountCache | None" = None,
+    min_score: int | None = None,
+) -> int:
+    """Compute a similarity score for two objects.
+
+    Args:
+      obj1: The first object to score.
+      obj2: The second object to score.
+      block_cache: An optional dict of SHA to block counts, or a
+        BlockCountCache, to cache results between calls.
+      min_score: If given, the score is only exact if it is at least
+        min_score; implementations may stop early and return any lower
+        value once min_score can no longer be reached.
+
+    Returns:
+      The similarity score between the two objects, defined as the
+        number of bytes in common between the two objects divided by the
+        maximum size, scaled to the range 0-100.
+    """
+    # Objects with the same SHA have the same contents.
+    if _same_sha(obj1.id, obj2.id):
+        return _MAX_SCORE
+    if block_cache is None:
+        block_cache = {}
+    if obj1.id not in block_cache:
+        block_cache[obj1.id] = _count_blocks(obj1)
+    if obj2.id not in block_cache:
+        block_cache[obj2.id] = _count_blocks(obj2)
+
+    common_bytes = _common_bytes(block_cache[obj1.id], block_cache[obj2.id])
+    max_size = max(obj1.raw_length(), obj2.raw_length())
+    if not max_size:
+        return _MAX_SCORE
+    return int(float(common_bytes) * _MAX_SCORE / max_size)
+
+
+def _best_rename_for(
+    deleted_obj: ShaFile,
+    deleted_path: bytes,
+    adds: Iterable[tuple[bytes, ShaFile]],
+    block_cache: "dict[ObjectID, dict[int, int]] | BlockCountCache | None" = None,
+    threshold: int | None = None,
+) -> tuple[bytes, int] | None:
+    """Find what a single deleted object was most likely renamed to.
+
+    This scores the deleted object against each added object with
+    _similarity_score, for callers that only need one rename (as for
+    git log --follow) rather than all of them.
+
+    Args:
+      deleted_obj: The deleted object.
+      deleted_path: The path the object was deleted from.
+      adds: An iterable of (path, object) for the added objects.
+      block_cache: An optional dict of SHA to block counts, or a
+        BlockCountCache, to cache results between calls.
+      threshold: Only adds scoring higher than this are considered, or None
+        to use RENAME_THRESHOLD.
+
+    Returns:
+      The (add_path, score) of the best scoring add, or None if no add
+        scores higher than threshold. Of several adds with the best score,
+        one with the same basename as deleted_path is preferred, and
+        otherwise the one with the smallest path.
+    """
+    if threshold is None:
+        threshold = RENAME_THRESHOLD
+    if block_cache is None:
+        block_cache = {}
+    basename = deleted_path.rsplit(b"/", 1)[-1]
+    best: tuple[int, bool] | None = None
+    best_path = None
+    for add_path, add_obj in sorted(adds, key=lambda add: add[0]):
+        min_score = threshold + 1 if best is None else max(threshold + 1, best[0])
+        score = _similarity_score(deleted_obj, add_obj, block_cache, min_score)
+        if score < min_score:
+            continue
+        key = (score, add_path.rsplit(b"/", 1)[-1] == basename)
+        if best is None or key > best:
+            best = key
+            best_path = add_path
+    if best is None:
+        return None
+    assert best_path is not None
+    return best_path, best[0]
+
+
+def _detect_split(
+    deleted_obj: ShaFile,
+    adds: Iterable[tuple[bytes, ShaFile]],
+    block_cache: "dict[ObjectID, dict[int, int]] | BlockCountCache | None" = None,
+    coverage_threshold: int | None = None,
+) -> list[bytes]:
+    """Find the added objects that the content of a deleted one was split into.
+
+    Adds are picked greedily, each time taking the one that covers the most
+    of the deleted content not yet covered by the adds picked before (of
+    equally good adds, the one with the smallest path).
+
+    Args:
+      deleted_obj: The deleted object.
+      adds: An iterable of (path, object) for the added objects.
+      block_cache: An optional dict of SHA to block counts, or a
+        BlockCountCache, to cache results between calls.
+      coverage_threshold: The percentage of the deleted content the adds
+        have to cover more than, or None to use RENAME_THRESHOLD.
+
+    Returns:
+      The sorted paths of the adds picked once they cover more than
+        coverage_threshold percent of the deleted content, or an empty list
+        if all adds together don't.
+    """
+    if coverage_threshold is None:
+        coverage_threshold = RENAME_THRESHOLD
+    if block_cache is None:
+        block_cache = {}
+    adds = sorted(adds, key=lambda add: add[0])
+    for obj in chain([deleted_obj], (add_obj for _, add_obj in adds)):
+        if obj.id not in block_cache:
+            block_cache[obj.id] = _count_blocks(obj)
+    remaining = dict(block_cache[deleted_obj.id])
+    size = sum(remaining.values())
+    candidates = [(add_path, block_cache[add_obj.id]) for add_path, add_obj in adds]
+    picked: list[bytes] = []
+    covered = 0
+    while candidates:
+        best = None
+        best_covered = 0
+        for i, (_, blocks) in enumerate(candidates):
+            add_covered = _common_bytes(remaining, blocks)
+            if add_covered > best_covered:
+                best = i
+                best_covered = add_covered
+        if best is None:
+            break
+        add_path, blocks = candidates.pop(best)
+        for block, count in blocks.items():
+            if block in remaining:
+                remaining[block] -= min(remaining[block], count)
+        picked.append(add_path)
+        covered += best_covered
+        if covered * _MAX_SCORE > coverage_threshold * size:
+            return sorted(picked)
+    return []
+
+
+def _same_sha(sha1: bytes, sha2: bytes) -> bool:
+    """Check whether two SHAs are the same, where either may be hex or binary."""
+    if len(sha1) == len(sha2):
+        return sha1 == sha2
+    if len(sha1) == 2 * len(sha2):
+        sha1, sha2 = sha2, sha1
+    elif len(sha2) != 2 * len(sha1):
+        return False
+    try:
+        return binascii.unhexlify(sha2) == sha1
+    except binascii.Error:
+        return False
+
+
+def _find_exact_renames(
+    deletes: Sequence[TreeEntry], adds: Sequence[TreeEntry]
+) -> tuple[list[tuple[bytes, bytes]], list[TreeEntry], list[TreeEntry]]:
+    """Pair up deleted and added entries with the same SHA and file type.
+
+    If several deletes share a SHA, they are paired with the adds of that
+    SHA in order of path, so the delete with the smallest path is paired
+    first whatever the order of deletes.
+
+    Args:
+      deletes: The deleted entries.
+      adds: The added entries.
+
+    Returns:
+      A tuple of the (delete_path, add_path) pairs sorted by delete path, and
+        the lists of deletes and adds that weren't paired, in their original
+        order.
+    """
+
+    def by_content(
+        entries: Sequence[TreeEntry],
+    ) -> dict[tuple[bytes, int], list[TreeEntry]]:
+        result: dict[tuple[bytes, int], list[TreeEntry]] = defaultdict(list)
+        for entry in sorted(entries, key=lambda entry: entry.path):
+            assert entry.mode is not None
+            result[(entry.sha, stat.S_IFMT(entry.mode))].append(entry)
+        return result
+
+    add_map = by_content(adds)
+    pairs = []
+    for key, key_deletes in by_content(deletes).items():
+        for delete, add in zip(key_deletes, add_map.get(key, [])):
+            pairs.append((delete.path, add.path))
+    pairs.sort()
+    delete_paths = {delete_path for delete_path, _ in pairs}
+    add_paths = {add_path for _, add_path in pairs}
+    return (
+        pairs,
+        [entry for entry in deletes if entry.path not in delete_paths],
+        [entry for entry in adds if entry.path not in add_paths],
+    )
+
+
+def _score_candidates(
+    deletes: Sequence[TreeEntry],
+    adds: Sequence[TreeEntry],
+    block_cache: dict[ObjectID, dict[int, int]],
+    rename_threshold: int,
+    best_only: bool = False,
+    scorer: Callable[[TreeEntry, TreeEntry], int] | None = None,
+    same_extension_only: bool = False,
+    exclude_prefixes: Sequence[bytes] = (),
+) -> list[tuple[bytes, bytes, int]]:
+    """Compute similarity scores for all pairs of deleted and added entries.
+
+    Args:
+      deletes: The deleted entries.
+      adds: The added entries.
+      block_cache: A dict of SHA to block counts, containing the block counts
+        of all entries in deletes and adds.
+      rename_threshold: Only pairs scoring higher than this are returned.
+      best_only: If True, only return the best scoring pair for each add, in
+        the order of adds. Ties are broken by taking the delete with the
+        smallest path, whatever the order of deletes. Scoring for an add
+        stops once a perfect score is found.
+      scorer: If given, a callable taking the delete and add entries of a
+        pair and returning its score, for trying out other similarity
+        measures. It is called in place of comparing block counts, for each
+        pair with the same file type but different SHAs; block_cache is
+        unused then.
+      same_extension_only: If True, skip the pairs whose paths have
+        different extensions (see _path_extension). With many deletes and
+        adds of mixed file types this leaves far fewer pairs to score, but
+        renames that change the extension are missed.
+      exclude_prefixes: Deletes and adds whose paths start with any of these
+        prefixes, such as vendored directories, are left out before pairing
+        and then stay plain deletes and adds. They need no block counts in
+        block_cache.
+
+    Returns:
+      A list of (delete_path, add_path, score) tuples. Pairs whose file types
+        differ are never scored.
+    """
+    return _score_pairs(
+        _without_prefixes(deletes, exclude_prefixes),
+        _without_prefixes(adds, exclude_prefixes),
+        block_cache,
+        rename_threshold,
+        best_only,
+        scorer=scorer,
+        same_extension_only=same_extension_only,
+    )
+
+
+def _score_candidates_limited(
+    deletes: Sequence[TreeEntry],
+    adds: Sequence[TreeEntry],
+    block_cache: dict[ObjectID, dict[int, int]],
+    rename_threshold: int,
+    rename_limit: int | None,
+    best_only: bool = False,
+    scorer: Callable[[TreeEntry, TreeEntry], int] | None = None,
+    same_extension_only: bool = False,
+    exclude_prefixes: Sequence[bytes] = (),
+) -> tuple[list[tuple[bytes, bytes, int]], bool]:
+    """Compute similarity scores for pairs, up to a number of pairs.
+
+    Takes the same arguments as _score_candidates, plus rename_limit.
+
+    Args:
+      deletes: The deleted entries.
+      adds: The added entries.
+      block_cache: A dict of SHA to block counts, containing the block counts
+        of all entries in deletes and adds.
+      rename_threshold: Only pairs scoring higher than this are returned.
+      rename_limit: The most pairs to score by content, as for git's
+        diff.renameLimit, or None for no limit. With more pairs than this,
+        only pairs with the same SHA are returned. Entries left out by
+        exclude_prefixes don't count towards it.
+
+    Returns:
+      A tuple of the list of (delete_path, add_path, score) tuples, as from
+        _score_candidates, and whether the limit was exceeded.
+    """
+    deletes = _without_prefixes(deletes, exclude_prefixes)
+    adds = _without_prefixes(adds, exclude_prefixes)
+    exhausted = rename_limit is not None and len(deletes) * len(adds) > rename_limit
+    pairs = _score_pairs(
+        deletes,
+        adds,
+        block_cache,
+        rename_threshold,
+        best_only,
+        exhausted,
+        scorer,
+        same_extension_only,
+    )
+    return pairs, exhausted
+
 
-    def change_type(c: TreeChange) -> str:
-        return c.type
+def _without_prefixes(
+    entries: Sequence[TreeEntry], prefixes: Sequence[bytes]
+) -> Sequence[TreeEntry]:
+    """Leave out the entries whose paths start with any of prefixes."""
+    if not prefixes:
+        return entries
+    prefix_tuple = tuple(prefixes)
+    return [e for e in entries if not (e.path or b"").startswith(prefix_tuple)]
 
-    # Yield only conflicting changes.
-    for _, changes in sorted(changes_by_path.items()):
-        assert len(changes) == num_parents
-        have = [c for c in changes if c is not None]
-        if _all_eq(have, change_type, CHANGE_DELETE):
-            if not _all_same(have, old_sha):
-                yield changes
-        elif not _all_same(have, change_type):
-            yield changes
-        elif None not in changes:
-            # If no change was found relative to one parent, that means the SHA
-            # must have matched the SHA in that parent, so it is not a
-            # conflict.
-            yield changes
 
+def _path_extension(path: bytes) -> bytes:
+    """Find the extension of a path: what follows the last dot of its name.
 
-_BLOCK_SIZE = 64
+    Names without a dot have an empty extension.
+    """
+    name = path.rsplit(b"/", 1)[-1]
+    _, dot, extension = name.rpartition(b".")
+    return extension if dot else b""
+
+
+def _score_pairs(
+    deletes: Sequence[TreeEntry],
+    adds: Sequence[TreeEntry],
+    block_cache: dict[ObjectID, dict[int, int]],
+    rename_threshold: int,
+    best_only: bool = False,
+    exact_only: bool = False,
+    scorer: Callable[[TreeEntry, TreeEntry], int] | None = None,
+    same_extension_only: bool = False,
+) -> list[tuple[bytes, bytes, int]]:
+    """Score pairs for _score_candidates, only by SHA if exact_only is set."""
+    # The block counts of an object add up to its size. They aren't needed
+    # to score with a scorer.
+    sizes = {
+        entry.sha: sum(block_cache[entry.sha].values())
+        for entry in chain(deletes, adds)
+        if scorer is None
+    }
+
+    def pair_score(delete: TreeEntry, add: TreeEntry) -> int | None:
+        assert delete.mode is not None and delete.sha is not None
+        assert add.mode is not None and add.sha is not None
+        if stat.S_IFMT(delete.mode) != stat.S_IFMT(add.mode):
+            return None
+        if same_extension_only:
+            assert delete.path is not None and add.path is not None
+            if _path_extension(delete.path) != _path_extension(add.path):
+                return None
+        if _same_sha(delete.sha, add.sha):
+            return _MAX_SCORE
+        if exact_only:
+            return None
+        if scorer is not None:
+            return scorer(delete, add)
+        common_bytes = _common_bytes(block_cache[delete.sha], block_cache[add.sha])
+        max_size = max(sizes[delete.sha], sizes[add.sha])
+        if not max_size:
+            return _MAX_SCORE
+        return int(float(common_bytes) * _MAX_SCORE / max_size)
+
+    result = []
+    if best_only:
+        # With the deletes in order of path, the first best delete is also
+        # the one with the smallest path.
+        deletes = sorted(deletes, key=lambda delete: delete.path or b"")
+        for add in adds:
+            best: tuple[bytes, bytes, int] | None = None
+            for delete in deletes:
+                score = pair_score(delete, add)
+                if score is None or score <= rename_threshold:
+                    continue
+                if best is None or score > best[2]:
+                    assert delete.path is not None and add.path is not None
+                    best = (delete.path, add.path, score)
+                    if score == _MAX_SCORE:
+                        break
+            if best is not None:
+                result.append(best)
+        return result
+    for delete in deletes:
+        for add in adds:
+            score = pair_score(delete, add)
+            if score is not None and score > rename_threshold:
+                assert delete.path is not None and add.path is not None
+                result.append((delete.path, add.path, score))
+    return result
 
 
-def _count_blocks(obj: ShaFile) -> dict[int, int]:
-    """Count the blocks in an object.
+def _score_copy_candidates(
+    sources: Sequence[TreeEntry],
+    adds: Sequence[TreeEntry],
+    block_cache: dict[ObjectID, dict[int, int]],
+    copy_threshold: int,
+) -> list[tuple[bytes, bytes, int]]:
+    """Compute similarity scores for all pairs of copy sources and added entries.
 
-    Splits the data into blocks either on lines or <=64-byte chunks of lines.
+    This is scored the same way as _score_candidates, but the sources include
+    entries that are unchanged or modified rather than only deleted ones, as
+    for git diff -C.
 
     Args:
-      obj: The object to count blocks for.
+      sources: The entries that may have been copied.
+      adds: The added entries.
+      block_cache: A dict of SHA to block counts, containing the block counts
+        of all entries in sources and adds.
+      copy_threshold: Only pairs scoring higher than this are returned.
 
     Returns:
-      A dict of block hashcode -> total bytes occurring.
+      A list of (source_path, add_path, score) tuples.
     """
-    block_counts: dict[int, int] = defaultdict(int)
-    block = BytesIO()
-    n = 0
-
-    # Cache attrs as locals to avoid expensive lookups in the inner loop.
-    block_write = block.write
-    block_seek = block.seek
-    block_truncate = block.truncate
-    block_getvalue = block.getvalue
+    return _score_candidates(sources, adds, block_cache, copy_threshold)
 
-    for c in chain.from_iterable(obj.as_raw_chunks()):
-        cb = c.to_bytes(1, "big")
-        block_write(cb)
-        n += 1
-        if cb == b"\n" or n == _BLOCK_SIZE:
-            value = block_getvalue()
-            block_counts[hash(value)] += len(value)
-            block_seek(0)
-            block_truncate()
-            n = 0
-    if n > 0:
-        last_block = block_getvalue()
-        block_counts[hash(last_block)] += len(last_block)
-    return block_counts
 
+def _annotate_rename_scores(
+    renames: Iterable[TreeChange],
+    block_cache: Mapping[ObjectID, Mapping[int, int]],
+) -> list[tuple[TreeChange, int]]:
+    """Pair each of the given renames or copies with its similarity score.
 
-def _common_bytes(blocks1: Mapping[int, int], blocks2: Mapping[int, int]) -> int:
-    """Count the number of common bytes in two block count dicts.
+    The score is computed the same way as by _score_candidates, so it is the
+    score the rename was detected with.
 
     Args:
-      blocks1: The first dict of block hashcode -> total bytes.
-      blocks2: The second dict of block hashcode -> total bytes.
+      renames: The renames or copies to score.
+      block_cache: A dict of SHA to block counts, containing the block counts
+        of the old and new entries of all changes in renames.
 
     Returns:
-      The number of bytes in common between blocks1 and blocks2. This is
-      only approximate due to possible hash collisions.
+      A list of (change, score) tuples, in the order of renames.
     """
-    # Iterate over the smaller of the two dicts, since this is symmetrical.
-    if len(blocks1) > len(blocks2):
-        blocks1, blocks2 = blocks2, blocks1
-    score = 0
-    for block, count1 in blocks1.items():
-        count2 = blocks2.get(block)
-        if count2:
-            score += min(count1, count2)
-    return score
+    result = []
+    for change in renames:
+        old, new = change.old, change.new
+        if old is None or new is None:
+            raise ValueError(f"not a rename or copy: {change!r}")
+        assert old.sha is not None and new.sha is not None
+        if _same_sha(old.sha, new.sha):
+            score = _MAX_SCORE
+        else:
+            blocks1 = block_cache[old.sha]
+            blocks2 = block_cache[new.sha]
+            # The block counts of an object add up to its size.
+            max_size = max(sum(blocks1.values()), sum(blocks2.values()))
+            if not max_size:
+                score = _MAX_SCORE
+            else:
+                common_bytes = _common_bytes(blocks1, blocks2)
+                score = int(float(common_bytes) * _MAX_SCORE / max_size)
+        result.append((change, score))
+    return result
 
 
-def _similarity_score(
-    obj1: ShaFile,
-    obj2: ShaFile,
-    block_cache: dict[ObjectID, dict[int, int]] | None = None,
-) -> int:
-    """Compute a similarity score for two objects.
+# Same as dulwich.patch.FIRST_FEW_BYTES.
+_FIRST_FEW_BYTES = 8000
+
+
+def _is_binary(data: bytes) -> bool:
+    """Check whether data is binary, i.e. has a NUL in its first 8000 bytes."""
+    return b"\0" in data[:_FIRST_FEW_BYTES]
+
+
+def _is_binary_obj(obj: ShaFile, force_binary: bool | None = None) -> bool:
+    """Check whether the contents of an object are binary.
+
+    Only the chunks making up the first 8000 bytes are looked at.
 
     Args:
-      obj1: The first object to score.
-      obj2: The second object to score.
-      block_cache: An optional dict of SHA to block counts to cache
-        results between calls.
+      obj: The object to check.
+      force_binary: If not None, whether the object is binary as already
+        decided by the attributes of its path (e.g. binary or -diff in
+        .gitattributes). The contents are then not looked at.
+    """
+    if force_binary is not None:
+        return force_binary
+    remaining = _FIRST_FEW_BYTES
+    for chunk in obj.as_raw_chunks():
+        if b"\0" in chunk[:remaining]:
+            return True
+        remaining -= len(chunk)
+        if remaining <= 0:
+            break
+    return False
+
+
+def _change_content_id(change: TreeChange, store: BaseObjectStore) -> int:
+    """Compute an id for the contents of a change, as for git cherry.
+
+    The id only depends on the lines removed from and added to the old
+    contents, so two changes making the same edit have the same id even if
+    their paths, blobs and unchanged lines differ. The removed and added
+    lines are each sorted, so the order of the edits doesn't matter either.
+    Gitlinks and missing entries have no contents.
+
+    Args:
+      change: The TreeChange to compute the id of.
+      store: An ObjectStore to look up the blobs of the change in.
 
     Returns:
-      The similarity score between the two objects, defined as the
-        number of bytes in common between the two objects divided by the
-        maximum size, scaled to the range 0-100.
+      The 64-bit FNV-1a hash of the removed lines, each prefixed with "-",
+        followed by the added lines, each prefixed with "+".
     """
-    if block_cache is None:
-        block_cache = {}
-    if obj1.id not in block_cache:
-        block_cache[obj1.id] = _count_blocks(obj1)
-    if obj2.id not in block_cache:
-        block_cache[obj2.id] = _count_blocks(obj2)
 
-    common_bytes = _common_bytes(block_cache[obj1.id], block_cache[obj2.id])
-    max_size = max(obj1.raw_length(), obj2.raw_length())
-    if not max_size:
-        return _MAX_SCORE
-    return int(float(common_bytes) * _MAX_SCORE / max_size)
+    def lines(entry: TreeEntry | None) -> Counter[bytes]:
+        if entry is None or entry.mode is None or S_ISGITLINK(entry.mode):
+            return Counter()
+        assert entry.sha is not None
+        data = store[entry.sha].as_raw_string()
+        return Counter(data[start:end] for start, end in _line_offsets(data))
+
+    old_lines = lines(change.old)
+    new_lines = lines(change.new)
+    removed = sorted((old_lines - new_lines).elements())
+    added = sorted((new_lines - old_lines).elements())
+    return _stable_hash(
+        b"".join([b"-" + line for line in removed] + [b"+" + line for line in added])
+    )
 
 
 def _tree_change_key(entry: TreeChange) -> tuple[bytes, bytes]:
@@ -537,6 +2719,155 @@ def _tree_change_key(entry: TreeChange) -> tuple[bytes, bytes]:
     return (path1, path2)
 
 
+def _bucket_changes(
+    changes: Iterable[TreeChange],
+) -> tuple[list[TreeChange], list[TreeChange], list[TreeChange]]:
+    """Split changes into adds, deletes and the rest, in a single pass.
+
+    Args:
+      changes: An iterable of TreeChanges.
+
+    Returns:
+      A tuple of (adds, deletes, others) lists of TreeChanges, each in the
+        order of changes. For changes from tree_changes without
+        want_unchanged, the others are all modifies.
+    """
+    adds = []
+    deletes = []
+    others = []
+    for change in changes:
+        if change.type == CHANGE_ADD:
+            adds.append(change)
+        elif change.type == CHANGE_DELETE:
+            deletes.append(change)
+        else:
+            others.append(change)
+    return adds, deletes, others
+
+
+def _dir_change_summary(
+    changes: Iterable[TreeChange],
+) -> dict[bytes, tuple[int, int, int]]:
+    """Count the changes under each directory, as for a tree of changed files.
+
+    Each change counts towards every directory containing it, up to the
+    top-level directory, whose path is empty. Renames and copies count as
+    modifies of their new path, and unchanged entries are not counted.
+
+    Args:
+      changes: An iterable of TreeChanges.
+
+    Returns:
+      A dict of directory path to (adds, deletes, modifies) totals, for each
+        directory with any changes.
+    """
+    totals: dict[bytes, list[int]] = {}
+    for change in changes:
+        if change.type == CHANGE_UNCHANGED:
+            continue
+        if change.type == CHANGE_ADD:
+            kind = 0
+        elif change.type == CHANGE_DELETE:
+            kind = 1
+        else:
+            kind = 2
+        entry = change.new if change.type != CHANGE_DELETE else change.old
+        assert entry is not None and entry.path is not None
+        path = entry.path
+        while True:
+            path = _split_path(path)[0]
+            totals.setdefault(path, [0, 0, 0])[kind] += 1
+            if not path:
+                break
+    return {path: (counts[0], counts[1], counts[2]) for path, counts in totals.items()}
+
+
+def _split_path(path: bytes) -> tuple[bytes, bytes]:
+    """Split a path into its directory, empty at the top level, and name."""
+    dirname, _, name = path.rpartition(b"/")
+    return dirname, name
+
+
+def _basename_rename_candidates(
+    deletes: Sequence[TreeEntry], adds: Sequence[TreeEntry]
+) -> list[tuple[bytes, bytes]]:
+    """Pair the deleted and added entries with the same name, as likely renames.
+
+    Moving a file to another directory keeps its name, so these pairs are
+    worth scoring before all the others: once they are matched, most files
+    don't need to be scored against every other.
+
+    Args:
+      deletes: The deleted entries.
+      adds: The added entries.
+
+    Returns:
+      A list of (delete_path, add_path) tuples for the pairs with the same
+        name after the last "/", in the order of deletes, then of adds.
+        Entries with the same path aren't paired, as that isn't a move.
+    """
+    adds_by_name: dict[bytes, list[bytes]] = defaultdict(list)
+    for add in adds:
+        assert add.path is not None
+        adds_by_name[_split_path(add.path)[1]].append(add.path)
+    result = []
+    for delete in deletes:
+        assert delete.path is not None
+        for add_path in adds_by_name.get(_split_path(delete.path)[1], ()):
+            if add_path != delete.path:
+                result.append((delete.path, add_path))
+    return result
+
+
+def _detect_dir_renames(
+    renames: Iterable[TreeChange], threshold: int = 50
+) -> list[tuple[bytes, bytes, int]]:
+    """Infer renames of whole directories from the renames of their files.
+
+    A directory counts as renamed to another when more than threshold
+    percent of the renamed files directly in it moved directly into that
+    other directory, as for git's directory rename detection.
+
+    Args:
+      renames: The detected changes, of which only renames count.
+      threshold: The percentage of the renames out of a directory that must
+        go to the same directory; the default asks for a majority.
+
+    Returns:
+      A list of (old_dir, new_dir, confidence) tuples sorted by old_dir and
+        new_dir, where confidence is the percentage of the renames out of
+        old_dir going to new_dir. The top-level directory is never renamed,
+        nor renamed to.
+    """
+    moves: dict[bytes, Counter[bytes]] = defaultdict(Counter)
+    for change in renames:
+        if change.type != CHANGE_RENAME:
+            continue
+        assert change.old is not None and change.old.path is not None
+        assert change.new is not None and change.new.path is not None
+        old_dir = _split_path(change.old.path)[0]
+        if old_dir:
+            moves[old_dir][_split_path(change.new.path)[0]] += 1
+    result = []
+    for old_dir in sorted(moves):
+        targets = moves[old_dir]
+        total = sum(targets.values())
+        for new_dir in sorted(targets):
+            confidence = targets[new_dir] * 100 // total
+            if new_dir and new_dir != old_dir and confidence > threshold:
+                result.append((old_dir, new_dir, confidence))
+    return result
+
+
+def _rename_candidate_key(
+    candidate: tuple[int, TreeChange],
+) -> tuple[int, bytes, bytes]:
+    neg_score, change = candidate
+    assert change.old is not None and change.old.path is not None
+    assert change.new is not None and change.new.path is not None
+    return (neg_score, change.old.path, change.new.path)
+
+
 class RenameDetector:
     """Object for handling rename detection between two trees."""
 
@@ -544,6 +2875,9 @@ class RenameDetector:
     _deletes: list[TreeChange]
     _changes: list[TreeChange]
     _candidates: list[tuple[int, TreeChange]]
+    # The block counts content renames were last scored with, so that their
+    # scores can be recomputed without counting again.
+    _block_cache: dict[ObjectID, dict[int, int]]
 
     def __init__(
         self,
@@ -576,11 +2910,13 @@ class RenameDetector:
         self._max_files = max_files
         self._find_copies_harder = find_copies_harder
         self._want_unchanged = False
+        self._as_pairs = False
 
     def _reset(self) -> None:
         self._adds = []
         self._deletes = []
         self._changes = []
+        self._block_cache = {}
 
     def _should_split(self, change: TreeChange) -> bool:
         if self._rewrite_threshold is None or change.type != CHANGE_MODIFY:
@@ -712,34 +3048,58 @@ class RenameDetector:
         if not self._should_find_content_renames():
             return
 
-        block_cache = {}
+        # Git links don't exist in this repo.
+        deletes = {
+            d.old.path: d
+            for d in self._deletes
+            if d.old is not None
+            and d.old.mode is not None
+            and not S_ISGITLINK(d.old.mode)
+        }
+        delete_fmts = {stat.S_IFMT(d.old.mode) for d in deletes.values()}
+        adds = {
+            a.new.path: a
+            for a in self._adds
+            if a.new is not None
+            and a.new.mode is not None
+            and stat.S_IFMT(a.new.mode) in delete_fmts
+        }
+
+        entries = chain(
+            (d.old for d in deletes.values()), (a.new for a in adds.values())
+        )
+        shas = list(dict.fromkeys(entry.sha for entry in entries))
+        objs = [self._store[sha] for sha in shas]
+        block_cache = self._block_cache = dict(zip(shas, _count_blocks_many(objs)))
+
+        # Deletes that aren't marked as deletes are only there as copy
+        # sources, due to find_copies_harder.
+        add_entries = [a.new for a in adds.values()]
+        scores = _score_candidates(
+            [d.old for d in deletes.values() if d.type == CHANGE_DELETE],
+            add_entries,
+            block_cache,
+            self._rename_threshold,
+        )
+        sources = [d.old for d in deletes.values() if d.type != CHANGE_DELETE]
+        if sources:
+            scores += _score_copy_candidates(
+                sources, add_entries, block_cache, self._rename_threshold
+            )
+
         check_paths = self._rename_threshold is not None
-        for delete in self._deletes:
-            assert delete.old is not None
-            assert delete.old.mode is not None
-            if S_ISGITLINK(delete.old.mode):
-                continue  # Git links don't exist in this repo.
-            assert delete.old.sha is not None
-            old_sha = delete.old.sha
-            old_obj = self._store[old_sha]
-            block_cache[old_sha] = _count_blocks(old_obj)
-            for add in self._adds:
-                assert add.new is not None
-                assert add.new.mode is not None
-                if stat.S_IFMT(delete.old.mode) != stat.S_IFMT(add.new.mode):
-                    continue
-                assert add.new.sha is not None
-                new_obj = self._store[add.new.sha]
-                score = _similarity_score(old_obj, new_obj, block_cache=block_cache)
-                if score > self._rename_threshold:
-                    new_type = self._rename_type(check_paths, delete, add)
-                    rename = TreeChange(new_type, delete.old, add.new)
-                    candidates.append((-score, rename))
+        for delete_path, add_path, score in scores:
+            delete = deletes[delete_path]
+            add = adds[add_path]
+            new_type = self._rename_type(check_paths, delete, add)
+            rename = TreeChange(new_type, delete.old, add.new)
+            candidates.append((-score, rename))
 
     def _choose_content_renames(self) -> None:
         # Sort scores from highest to lowest, but keep names in ascending
-        # order.
-        self._candidates.sort()
+        # order, so that of several equally good sources for an add, the one
+        # with the smallest path is chosen.
+        self._candidates.sort(key=_rename_candidate_key)
 
         delete_paths = set()
         add_paths = set()
@@ -801,7 +3161,13 @@ class RenameDetector:
         result = []
         result.extend(self._adds)
         result.extend(self._deletes)
-        result.extend(self._changes)
+        for change in self._changes:
+            if self._as_pairs and change.type == CHANGE_RENAME:
+                assert change.old is not None and change.new is not None
+                result.append(TreeChange.delete(change.old))
+                result.append(TreeChange.add(change.new))
+            else:
+                result.append(change)
         result.sort(key=_tree_change_key)
         return result
 
@@ -816,11 +3182,27 @@ class RenameDetector:
         tree2_id: ObjectID | None,
         want_unchanged: bool = False,
         include_trees: bool = False,
+        as_pairs: bool = False,
     ) -> list[TreeChange]:
-        """Iterate TreeChanges between two tree SHAs, with rename detection."""
+        """Iterate TreeChanges between two tree SHAs, with rename detection.
+
+        Args:
+          tree1_id: The SHA of the source tree.
+          tree2_id: The SHA of the target tree.
+          want_unchanged: If True, include TreeChanges for unmodified entries
+            as well.
+          include_trees: Whether to include trees.
+          as_pairs: If True, report each rename as a delete of the old entry
+            and an add of the new one, rather than as a single CHANGE_RENAME.
+            Copies are still reported as CHANGE_COPY.
+
+        Returns:
+          A list of TreeChanges, sorted by path.
+        """
         self._reset()
         self._want_unchanged = want_unchanged
         self._include_trees = include_trees
+        self._as_pairs = as_pairs
         self._collect_changes(tree1_id, tree2_id)
         self._find_exact_renames()
         self._find_content_rename_candidates()
@@ -832,8 +3214,57 @@ class RenameDetector:
 
 # Hold on to the pure-python implementations for testing.
 _is_tree_py = _is_tree
+_are_trees_py = _are_trees
+_is_blob_py = _is_blob
+_is_gitlink_py = _is_gitlink
+_classify_change_py = _classify_change
+_change_record_py = _change_record
+_diff_entries_py = _diff_entries
+_index_tree_changes_py = _index_tree_changes
+_cmp_tree_entries_py = _cmp_tree_entries
+_sort_tree_entries_py = _sort_tree_entries
+_check_tree_sorted_py = _check_tree_sorted
 _merge_entries_py = _merge_entries
+_normalize_tree_path_py = _normalize_tree_path
+_merge3_entry_py = _merge3_entry
+_merge_entries_multi_py = _merge_entries_multi
+_merge_entries_prefixed_py = _merge_entries_prefixed
+_merge_entry_lists_py = _merge_entry_lists
+_merge_sorted_lists_py = _merge_sorted_lists
+_tree_changes_py = _tree_changes
+_tree_changes_incremental_py = _tree_changes_incremental
+_changed_paths_py = _changed_paths
+_tree_change_key_py = _tree_change_key
+_tree_entries_from_store_py = _tree_entries_from_store
+_tree_entry_tuples_py = _tree_entry_tuples
+_trees_content_equal_py = _trees_content_equal
+_bucket_changes_py = _bucket_changes
+_dir_change_summary_py = _dir_change_summary
+_basename_rename_candidates_py = _basename_rename_candidates
+_detect_dir_renames_py = _detect_dir_renames
+_tree_changes_for_merge_py = _tree_changes_for_merge
+_is_binary_py = _is_binary
+_is_binary_obj_py = _is_binary_obj
+_change_content_id_py = _change_content_id
 _count_blocks_py = _count_blocks
+_count_blocks_bounded_py = _count_blocks_bounded
+_count_blocks_many_py = _count_blocks_many
+_iter_blocks_py = _iter_blocks
+_common_bytes_py = _common_bytes
+_block_count_diff_py = _block_count_diff
+_block_count_distance_py = _block_count_distance
+_common_prefix_suffix_py = _common_prefix_suffix
+_line_offsets_py = _line_offsets
+_similarity_score_py = _similarity_score
+_best_rename_for_py = _best_rename_for
+_detect_split_py = _detect_split
+BlockAccumulator_py = BlockAccumulator
+BlockCountCache_py = BlockCountCache
+_find_exact_renames_py = _find_exact_renames
+_score_candidates_py = _score_candidates
+_score_candidates_limited_py = _score_candidates_limited
+_score_copy_candidates_py = _score_copy_candidates
+_annotate_rename_scores_py = _annotate_rename_scores
 
 if TYPE_CHECKING:
     # For type checking, use the Python implementations
@@ -842,19 +3273,215 @@ else:
     # At runtime, try to import Rust extensions
     try:
         # Try to import Rust versions
+        from dulwich._diff_tree import (
+            BlockAccumulator as _rust_BlockAccumulator,
+        )
+        from dulwich._diff_tree import (
+            BlockCountCache as _rust_BlockCountCache,
+        )
+        from dulwich._diff_tree import (
+            _annotate_rename_scores as _rust_annotate_rename_scores,
+        )
+        from dulwich._diff_tree import (
+            _are_trees as _rust_are_trees,
+        )
+        from dulwich._diff_tree import (
+            _basename_rename_candidates as _rust_basename_rename_candidates,
+        )
+        from dulwich._diff_tree import (
+            _best_rename_for as _rust_best_rename_for,
+        )
+        from dulwich._diff_tree import (
+            _block_count_diff as _rust_block_count_diff,
+        )
+        from dulwich._diff_tree import (
+            _block_count_distance as _rust_block_count_distance,
+        )
+        from dulwich._diff_tree import (
+            _bucket_changes as _rust_bucket_changes,
+        )
+        from dulwich._diff_tree import (
+            _change_content_id as _rust_change_content_id,
+        )
+        from dulwich._diff_tree import (
+            _change_record as _rust_change_record,
+        )
+        from dulwich._diff_tree import (
+            _changed_paths as _rust_changed_paths,
+        )
+        from dulwich._diff_tree import (
+            _check_tree_sorted as _rust_check_tree_sorted,
+        )
+        from dulwich._diff_tree import (
+            _classify_change as _rust_classify_change,
+        )
+        from dulwich._diff_tree import (
+            _cmp_tree_entries as _rust_cmp_tree_entries,
+        )
+        from dulwich._diff_tree import (
+            _common_bytes as _rust_common_bytes,
+        )
+        from dulwich._diff_tree import (
+            _common_prefix_suffix as _rust_common_prefix_suffix,
+        )
         from dulwich._diff_tree import (
             _count_blocks as _rust_count_blocks,
         )
+        from dulwich._diff_tree import (
+            _count_blocks_bounded as _rust_count_blocks_bounded,
+        )
+        from dulwich._diff_tree import (
+            _count_blocks_many as _rust_count_blocks_many,
+        )
+        from dulwich._diff_tree import (
+            _detect_dir_renames as _rust_detect_dir_renames,
+        )
+        from dulwich._diff_tree import (
+            _detect_split as _rust_detect_split,
+        )
+        from dulwich._diff_tree import (
+            _diff_entries as _rust_diff_entries,
+        )
+        from dulwich._diff_tree import (
+            _dir_change_summary as _rust_dir_change_summary,
+        )
+        from dulwich._diff_tree import (
+            _find_exact_renames as _rust_find_exact_renames,
+        )
+        from dulwich._diff_tree import (
+            _index_tree_changes as _rust_index_tree_changes,
+        )
+        from dulwich._diff_tree import (
+            _is_binary as _rust_is_binary,
+        )
+        from dulwich._diff_tree import (
+            _iter_blocks as _rust_iter_blocks,
+        )
+        from dulwich._diff_tree import (
+            _is_binary_obj as _rust_is_binary_obj,
+        )
+        from dulwich._diff_tree import (
+            _is_blob as _rust_is_blob,
+        )
+        from dulwich._diff_tree import (
+            _is_gitlink as _rust_is_gitlink,
+        )
         from dulwich._diff_tree import (
             _is_tree as _rust_is_tree,
         )
+        from dulwich._diff_tree import (
+            _line_offsets as _rust_line_offsets,
+        )
+        from dulwich._diff_tree import (
+            _merge3_entry as _rust_merge3_entry,
+        )
         from dulwich._diff_tree import (
             _merge_entries as _rust_merge_entries,
         )
+        from dulwich._diff_tree import (
+            _merge_entries_multi as _rust_merge_entries_multi,
+        )
+        from dulwich._diff_tree import (
+            _merge_entries_prefixed as _rust_merge_entries_prefixed,
+        )
+        from dulwich._diff_tree import (
+            _merge_entry_lists as _rust_merge_entry_lists,
+        )
+        from dulwich._diff_tree import (
+            _merge_sorted_lists as _rust_merge_sorted_lists,
+        )
+        from dulwich._diff_tree import (
+            _normalize_tree_path as _rust_normalize_tree_path,
+        )
+        from dulwich._diff_tree import (
+            _score_candidates as _rust_score_candidates,
+        )
+        from dulwich._diff_tree import (
+            _score_candidates_limited as _rust_score_candidates_limited,
+        )
+        from dulwich._diff_tree import (
+            _score_copy_candidates as _rust_score_copy_candidates,
+        )
+        from dulwich._diff_tree import (
+            _similarity_score as _rust_similarity_score,
+        )
+        from dulwich._diff_tree import (
+            _sort_tree_entries as _rust_sort_tree_entries,
+        )
+        from dulwich._diff_tree import (
+            _tree_change_key as _rust_tree_change_key,
+        )
+        from dulwich._diff_tree import (
+            _tree_changes as _rust_tree_changes,
+        )
+        from dulwich._diff_tree import (
+            _tree_changes_for_merge as _rust_tree_changes_for_merge,
+        )
+        from dulwich._diff_tree import (
+            _tree_changes_incremental as _rust_tree_changes_incremental,
+        )
+        from dulwich._diff_tree import (
+            _tree_entries_from_store as _rust_tree_entries_from_store,
+        )
+        from dulwich._diff_tree import (
+            _tree_entry_tuples as _rust_tree_entry_tuples,
+        )
+        from dulwich._diff_tree import (
+            _trees_content_equal as _rust_trees_content_equal,
+        )
 
         # Override with Rust versions
+        BlockAccumulator = _rust_BlockAccumulator
+        BlockCountCache = _rust_BlockCountCache
+        _annotate_rename_scores = _rust_annotate_rename_scores
+        _are_trees = _rust_are_trees
+        _basename_rename_candidates = _rust_basename_rename_candidates
+        _best_rename_for = _rust_best_rename_for
+        _block_count_diff = _rust_block_count_diff
+        _block_count_distance = _rust_block_count_distance
+        _bucket_changes = _rust_bucket_changes
+        _change_content_id = _rust_change_content_id
+        _change_record = _rust_change_record
+        _changed_paths = _rust_changed_paths
+        _check_tree_sorted = _rust_check_tree_sorted
+        _classify_change = _rust_classify_change
+        _cmp_tree_entries = _rust_cmp_tree_entries
+        _common_bytes = _rust_common_bytes
+        _common_prefix_suffix = _rust_common_prefix_suffix
         _count_blocks = _rust_count_blocks
+        _count_blocks_bounded = _rust_count_blocks_bounded
+        _count_blocks_many = _rust_count_blocks_many
+        _detect_dir_renames = _rust_detect_dir_renames
+        _detect_split = _rust_detect_split
+        _diff_entries = _rust_diff_entries
+        _dir_change_summary = _rust_dir_change_summary
+        _find_exact_renames = _rust_find_exact_renames
+        _index_tree_changes = _rust_index_tree_changes
+        _is_binary = _rust_is_binary
+        _is_binary_obj = _rust_is_binary_obj
+        _is_blob = _rust_is_blob
+        _is_gitlink = _rust_is_gitlink
         _is_tree = _rust_is_tree
+        _iter_blocks = _rust_iter_blocks
+        _line_offsets = _rust_line_offsets
+        _merge3_entry = _rust_merge3_entry
         _merge_entries = _rust_merge_entries
+        _merge_entries_multi = _rust_merge_entries_multi
+        _merge_entries_prefixed = _rust_merge_entries_prefixed
+        _merge_entry_lists = _rust_merge_entry_lists
+        _merge_sorted_lists = _rust_merge_sorted_lists
+        _normalize_tree_path = _rust_normalize_tree_path
+        _score_candidates = _rust_score_candidates
+        _score_candidates_limited = _rust_score_candidates_limited
+        _score_copy_candidates = _rust_score_copy_candidates
+        _similarity_score = _rust_similarity_score
+        _sort_tree_entries = _rust_sort_tree_entries
+        _tree_change_key = _rust_tree_change_key
+        _tree_changes = _rust_tree_changes
+        _tree_changes_for_merge = _rust_tree_changes_for_merge
+        _tree_changes_incremental = _rust_tree_changes_incremental
+        _tree_entries_from_store = _rust_tree_entries_from_store
+        _tree_entry_tuples = _rust_tree_entry_tuples
+        _trees_content_equal = _rust_trees_content_equal
     except ImportError:
         pass
@@ -21,31 +21,180 @@
 
 """Tests for file and tree diff utilities."""
 
+import operator
+import stat
+from collections import defaultdict
+from io import BytesIO
 from itertools import permutations
+from unittest.mock import patch
 
 from dulwich.diff_tree import (
     CHANGE_COPY,
     CHANGE_MODIFY,
     CHANGE_RENAME,
     CHANGE_UNCHANGED,
+    _KIND_ADD,
+    _KIND_DELETE,
+    _KIND_MODE_CHANGE,
+    _KIND_MODIFY,
+    _KIND_TYPECHANGE,
+    _KIND_UNCHANGED,
+    _MERGE3_CONFLICT,
+    _MERGE3_OURS,
+    _MERGE3_SAME,
+    _MERGE3_THEIRS,
+    _MERGE3_UNCHANGED,
+    BlockAccumulator,
+    BlockAccumulator_py,
+    BlockCountCache,
+    BlockCountCache_py,
     RenameDetector,
     TreeChange,
+    _annotate_rename_scores,
+    _annotate_rename_scores_py,
+    _are_trees,
+    _are_trees_py,
+    _basename_rename_candidates,
+    _basename_rename_candidates_py,
+    _best_rename_for,
+    _best_rename_for_py,
+    _block_count_diff,
+    _block_count_diff_py,
+    _block_count_distance,
+    _block_count_distance_py,
+    _bucket_changes,
+    _bucket_changes_py,
+    _change_content_id,
+    _change_content_id_py,
+    _change_record,
+    _change_record_py,
+    _changed_paths,
+    _changed_paths_py,
+    _check_tree_sorted,
+    _check_tree_sorted_py,
+    _classify_change,
+    _classify_change_py,
+    _cmp_tree_entries,
+    _cmp_tree_entries_py,
+    _common_bytes,
+    _common_bytes_py,
+    _common_prefix_suffix,
+    _common_prefix_suffix_py,
     _count_blocks,
+    _count_blocks_bounded,
+    _count_blocks_bounded_py,
+    _count_blocks_many,
+    _count_blocks_many_py,
     _count_blocks_py,
+    _detect_dir_renames,
+    _detect_dir_renames_py,
+    _detect_split,
+    _detect_split_py,
+    _diff_entries,
+    _diff_entries_py,
+    _dir_change_summary,
+    _dir_change_summary_py,
+    _find_exact_renames,
+    _find_exact_renames_py,
+    _index_tree_changes,
+    _index_tree_changes_py,
+    _is_binary,
+    _is_binary_obj,
+    _is_binary_obj_py,
+    _is_binary_py,
+    _is_blob,
+    _is_blob_py,
+    _is_gitlink,
+    _is_gitlink_py,
     _is_tree,
     _is_tree_py,
+    _iter_blocks,
+    _iter_blocks_py,
+    _line_offsets,
+    _line_offsets_py,
+    _merge3_entry,
+    _merge3_entry_py,
     _merge_entries,
+    _merge_entries_multi,
+    _merge_entries_multi_py,
+    _merge_entries_prefixed,
+    _merge_entries_prefixed_py,
     _merge_entries_py,
+    _merge_entry_lists,
+    _merge_entry_lists_py,
+    _merge_sorted_lists,
+    _merge_sorted_lists_py,
+    _normalize_tree_path,
+    _normalize_tree_path_py,
+    _path_extension,
+    _same_sha,
+    _score_candidates,
+    _score_candidates_limited,
+    _score_candidates_limited_py,
+    _score_candidates_py,
+    _score_copy_candidates,
+    _score_copy_candidates_py,
     _similarity_score,
+    _similarity_score_py,
+    _sort_tree_entries,
+    _sort_tree_entries_py,
+    _stable_hash,
     _tree_change_key,
+    _tree_change_key_py,
+    _tree_changes_for_merge_py,
+    _tree_changes,
+    _tree_changes_incremental,
+    _tree_changes_incremental_py,
+    _tree_changes_py,
+    _tree_entries_from_store,
+    _tree_entries_from_store_py,
+    _tree_entry_tuples,
+    _tree_entry_tuples_py,
+    _trees_content_equal,
+    _trees_content_equal_py,
+    _xxh3_hash,
     tree_changes,
     tree_changes_for_merge,
 )
 from dulwich.index import commit_tree
 from dulwich.object_store import MemoryObjectStore
-from dulwich.objects import Blob, ShaFile, Tree, TreeEntry
+from dulwich.objects import Blob, ShaFile, Tree, TreeEntry, hex_to_sha
+from dulwich.patch import patch_id, write_tree_diff
 from dulwich.tests.utils import F, ext_functest_builder, functest_builder, make_object
 
+try:
+    from dulwich._diff_tree import (
+        _blob_hunks,
+        _blob_line_stats,
+        _common_bytes_sorted,
+        _count_blocks_bytes,
+        _count_blocks_cacheable,
+        _count_blocks_from_read,
+        _count_blocks_lines,
+        _diff_shortstat,
+        _first_parent_changes,
+        _iter_merge_entries,
+        _name_status,
+        _patch_id,
+        _similarity_streaming,
+    )
+    from dulwich._diff_tree import TreeEntryIter
+except ImportError:
+    _blob_hunks = None
+    _blob_line_stats = None
+    _common_bytes_sorted = None
+    _count_blocks_bytes = None
+    _count_blocks_cacheable = None
+    _count_blocks_from_read = None
+    _count_blocks_lines = None
+    _diff_shortstat = None
+    _first_parent_changes = None
+    _iter_merge_entries = None
+    _name_status = None
+    _patch_id = None
+    _similarity_streaming = None
+    TreeEntryIter = None
+
 from . import TestCase
 
 
@@ -147,808 +296,4083 @@ class TreeChangesTest(DiffTestCase):
         _do_test_merge_entries, _merge_entries
     )
 
-    def _do_test_is_tree(self, is_tree) -> None:
-        self.assertFalse(is_tree(None))
-        self.assertFalse(is_tree(TreeEntry(b"a", 0o100644, b"a" * 40)))
-        self.assertFalse(is_tree(TreeEntry(b"a", 0o100755, b"a" * 40)))
-        self.assertFalse(is_tree(TreeEntry(b"a", 0o120000, b"a" * 40)))
-        self.assertTrue(is_tree(TreeEntry(b"a", 0o040000, b"a" * 40)))
-        self.assertRaises(TypeError, is_tree, TreeEntry(b"a", b"x", b"a" * 40))
-        self.assertRaises(AttributeError, is_tree, 1234)
-
-    test_is_tree = functest_builder(_do_test_is_tree, _is_tree_py)
-    test_is_tree_extension = ext_functest_builder(_do_test_is_tree, _is_tree)
+    def _do_test_merge_entries_max_entries(self, merge_entries) -> None:
+        blob = make_object(Blob, data=b"blob")
+        tree1 = self.commit_tree([(b"a", blob), (b"b", blob)])
+        tree2 = self.commit_tree([(b"b", blob), (b"c", blob)])
+        self.assertEqual(3, len(merge_entries(b"", tree1, tree2, max_entries=3)))
+        self.assertEqual(3, len(merge_entries(b"", tree1, tree2)))
+        self.assertRaises(ValueError, merge_entries, b"", tree1, tree2, max_entries=2)
+        self.assertRaises(ValueError, merge_entries, b"", tree1, None, max_entries=1)
+        self.assertEqual([], merge_entries(b"", None, None, max_entries=0))
 
-    def assertChangesEqual(self, expected, tree1, tree2, **kwargs) -> None:
-        actual = list(tree_changes(self.store, tree1.id, tree2.id, **kwargs))
-        self.assertEqual(expected, actual)
+    test_merge_entries_max_entries = functest_builder(
+        _do_test_merge_entries_max_entries, _merge_entries_py
+    )
+    test_merge_entries_max_entries_extension = ext_functest_builder(
+        _do_test_merge_entries_max_entries, _merge_entries
+    )
 
-    # For brevity, the following tests use tuples instead of TreeEntry objects.
+    def _do_test_merge_entries_skip_identical(self, merge_entries) -> None:
+        blob = make_object(Blob, data=b"blob")
+        tree1 = self.commit_tree([(b"a", blob), (b"b/c", blob)])
+        tree2 = self.commit_tree([(b"a", blob), (b"b/c", blob)])
+        self.assertIsNot(tree1, tree2)
+        self.assertEqual([], merge_entries(b"", tree1, tree1, skip_identical=True))
+        self.assertEqual([], merge_entries(b"", tree1, tree2, skip_identical=True))
+        self.assertEqual(2, len(merge_entries(b"", tree1, tree2)))
+        tree3 = self.commit_tree([(b"a", blob)])
+        self.assertEqual(
+            merge_entries(b"", tree1, tree3),
+            merge_entries(b"", tree1, tree3, skip_identical=True),
+        )
+        self.assertEqual(
+            merge_entries(b"", tree1, None),
+            merge_entries(b"", tree1, None, skip_identical=True),
+        )
 
-    def test_tree_changes_empty(self) -> None:
-        self.assertChangesEqual([], self.empty_tree, self.empty_tree)
+    test_merge_entries_skip_identical = functest_builder(
+        _do_test_merge_entries_skip_identical, _merge_entries_py
+    )
+    test_merge_entries_skip_identical_extension = ext_functest_builder(
+        _do_test_merge_entries_skip_identical, _merge_entries
+    )
 
-    def test_tree_changes_no_changes(self) -> None:
+    def _do_test_merge_entries_ignore_modes(self, merge_entries) -> None:
         blob = make_object(Blob, data=b"blob")
-        tree = self.commit_tree([(b"a", blob), (b"b/c", blob)])
-        self.assertChangesEqual([], self.empty_tree, self.empty_tree)
-        self.assertChangesEqual([], tree, tree)
-        self.assertChangesEqual(
+        tree1 = Tree()
+        tree1.add(b"a", F, blob.id)
+        tree1.add(b"sub", 0o160000, b"1" * 40)
+        tree1.add(b"x", 0o120000, blob.id)
+        tree2 = Tree()
+        tree2.add(b"a", F, blob.id)
+        tree2.add(b"b", 0o160000, b"2" * 40)
+        tree2.add(b"sub", 0o160000, b"3" * 40)
+        self.assertEqual(4, len(merge_entries(b"", tree1, tree2)))
+        self.assertEqual(
             [
-                TreeChange(CHANGE_UNCHANGED, (b"a", F, blob.id), (b"a", F, blob.id)),
-                TreeChange(
-                    CHANGE_UNCHANGED,
-                    (b"b/c", F, blob.id),
-                    (b"b/c", F, blob.id),
-                ),
+                (TreeEntry(b"a", F, blob.id), TreeEntry(b"a", F, blob.id)),
+                (TreeEntry(b"x", 0o120000, blob.id), None),
             ],
-            tree,
-            tree,
-            want_unchanged=True,
+            merge_entries(b"", tree1, tree2, ignore_modes=[0o160000]),
+        )
+        self.assertEqual(
+            [(TreeEntry(b"a", F, blob.id), TreeEntry(b"a", F, blob.id))],
+            merge_entries(b"", tree1, tree2, ignore_modes=[0o160000, 0o120000]),
+        )
+        # Only the file type is compared.
+        self.assertEqual(
+            [],
+            merge_entries(b"", tree1, tree2, ignore_modes=[0o160000, 0o120000, F]),
+        )
+        self.assertEqual(
+            merge_entries(b"", tree1, tree2),
+            merge_entries(b"", tree1, tree2, ignore_modes=[]),
         )
 
-    def test_tree_changes_add_delete(self) -> None:
-        blob_a = make_object(Blob, data=b"a")
-        blob_b = make_object(Blob, data=b"b")
-        tree = self.commit_tree([(b"a", blob_a, 0o100644), (b"x/b", blob_b, 0o100755)])
-        self.assertChangesEqual(
-            [
-                TreeChange.add(TreeEntry(b"a", 0o100644, blob_a.id)),
-                TreeChange.add(TreeEntry(b"x/b", 0o100755, blob_b.id)),
-            ],
-            self.empty_tree,
-            tree,
+    test_merge_entries_ignore_modes = functest_builder(
+        _do_test_merge_entries_ignore_modes, _merge_entries_py
+    )
+    test_merge_entries_ignore_modes_extension = ext_functest_builder(
+        _do_test_merge_entries_ignore_modes, _merge_entries
+    )
+
+    def _do_test_merge_entries_normalize_path(self, merge_entries) -> None:
+        blob = make_object(Blob, data=b"blob")
+        tree = Tree()
+        tree.add(b"a", F, blob.id)
+        self.assertEqual(
+            [(TreeEntry(b"d//e/a", F, blob.id), None)],
+            merge_entries(b"d//e", tree, None),
         )
-        self.assertChangesEqual(
-            [
-                TreeChange.delete(TreeEntry(b"a", 0o100644, blob_a.id)),
-                TreeChange.delete(TreeEntry(b"x/b", 0o100755, blob_b.id)),
-            ],
-            tree,
-            self.empty_tree,
+        self.assertEqual(
+            [(TreeEntry(b"d/e/a", F, blob.id), None)],
+            merge_entries(b"d//e/", tree, None, normalize_path=True),
+        )
+        self.assertEqual(
+            [(None, TreeEntry(b"a", F, blob.id))],
+            merge_entries(b"/", None, tree, normalize_path=True),
         )
 
-    def test_tree_changes_modify_contents(self) -> None:
+    test_merge_entries_normalize_path = functest_builder(
+        _do_test_merge_entries_normalize_path, _merge_entries_py
+    )
+    test_merge_entries_normalize_path_extension = ext_functest_builder(
+        _do_test_merge_entries_normalize_path, _merge_entries
+    )
+
+    def _do_test_merge_entries_only_changed(self, merge_entries) -> None:
         blob_a1 = make_object(Blob, data=b"a1")
         blob_a2 = make_object(Blob, data=b"a2")
-        tree1 = self.commit_tree([(b"a", blob_a1)])
-        tree2 = self.commit_tree([(b"a", blob_a2)])
-        self.assertChangesEqual(
-            [TreeChange(CHANGE_MODIFY, (b"a", F, blob_a1.id), (b"a", F, blob_a2.id))],
-            tree1,
-            tree2,
+        tree1 = self.commit_tree(
+            [
+                (b"a", blob_a1),
+                (b"b", blob_a1),
+                (b"c", blob_a1, 0o100755),
+                (b"d", blob_a1),
+            ]
         )
-
-    def test_tree_changes_modify_mode(self) -> None:
-        blob_a = make_object(Blob, data=b"a")
-        tree1 = self.commit_tree([(b"a", blob_a, 0o100644)])
-        tree2 = self.commit_tree([(b"a", blob_a, 0o100755)])
-        self.assertChangesEqual(
+        tree2 = self.commit_tree(
+            [(b"a", blob_a1), (b"b", blob_a2), (b"c", blob_a1), (b"e", blob_a1)]
+        )
+        self.assertEqual(5, len(merge_entries(b"", tree1, tree2)))
+        self.assertEqual(
             [
-                TreeChange(
-                    CHANGE_MODIFY,
-                    (b"a", 0o100644, blob_a.id),
-                    (b"a", 0o100755, blob_a.id),
-                )
+                (TreeEntry(b"b", F, blob_a1.id), TreeEntry(b"b", F, blob_a2.id)),
+                (
+                    TreeEntry(b"c", 0o100755, blob_a1.id),
+                    TreeEntry(b"c", F, blob_a1.id),
+                ),
+                (TreeEntry(b"d", F, blob_a1.id), None),
+                (None, TreeEntry(b"e", F, blob_a1.id)),
             ],
+            merge_entries(b"", tree1, tree2, only_changed=True),
+        )
+        self.assertEqual([], merge_entries(b"", tree1, tree1, only_changed=True))
+        # Only the changed pairs count towards max_entries.
+        self.assertEqual(
+            [], merge_entries(b"", tree1, tree1, max_entries=0, only_changed=True)
+        )
+        self.assertRaises(
+            ValueError,
+            merge_entries,
+            b"",
             tree1,
             tree2,
+            max_entries=3,
+            only_changed=True,
         )
 
-    def test_tree_changes_change_type(self) -> None:
-        blob_a1 = make_object(Blob, data=b"a")
-        blob_a2 = make_object(Blob, data=b"/foo/bar")
-        tree1 = self.commit_tree([(b"a", blob_a1, 0o100644)])
-        tree2 = self.commit_tree([(b"a", blob_a2, 0o120000)])
-        self.assertChangesEqual(
+    test_merge_entries_only_changed = functest_builder(
+        _do_test_merge_entries_only_changed, _merge_entries_py
+    )
+    test_merge_entries_only_changed_extension = ext_functest_builder(
+        _do_test_merge_entries_only_changed, _merge_entries
+    )
+
+    def _do_test_tree_entries_from_store(self, tree_entries_from_store) -> None:
+        blob = make_object(Blob, data=b"blob")
+        tree = self.commit_tree([(b"a", blob), (b"b/c", blob)])
+        self.assertEqual(
             [
-                TreeChange.delete(TreeEntry(b"a", 0o100644, blob_a1.id)),
-                TreeChange.add(TreeEntry(b"a", 0o120000, blob_a2.id)),
+                TreeEntry(b"a", F, blob.id),
+                TreeEntry(b"b", 0o040000, tree[b"b"][1]),
             ],
-            tree1,
-            tree2,
+            tree_entries_from_store(self.store, tree.id),
+        )
+        self.assertEqual(
+            [TreeEntry(b"d/c", F, blob.id)],
+            tree_entries_from_store(self.store, tree[b"b"][1], b"d"),
         )
 
-    def test_tree_changes_change_type_same(self) -> None:
-        blob_a1 = make_object(Blob, data=b"a")
-        blob_a2 = make_object(Blob, data=b"/foo/bar")
-        tree1 = self.commit_tree([(b"a", blob_a1, 0o100644)])
-        tree2 = self.commit_tree([(b"a", blob_a2, 0o120000)])
-        self.assertChangesEqual(
+        class NoLookupStore:
+            def __getitem__(self, sha):
+                raise AssertionError(f"Unexpected lookup of {sha!r}")
+
+        for tree_id in (
+            None,
+            Tree().id,
+            b"6ef19b41225c5369f1c104d45d8d85efa9b057b53b14b4b9b939dd74decc5321",
+            b"0" * 40,
+        ):
+            self.assertEqual([], tree_entries_from_store(NoLookupStore(), tree_id))
+        self.assertRaises(KeyError, tree_entries_from_store, self.store, b"1" * 40)
+
+    test_tree_entries_from_store = functest_builder(
+        _do_test_tree_entries_from_store, _tree_entries_from_store_py
+    )
+    test_tree_entries_from_store_extension = ext_functest_builder(
+        _do_test_tree_entries_from_store, _tree_entries_from_store
+    )
+
+    def _do_test_tree_entry_tuples(self, tree_entry_tuples) -> None:
+        blob = make_object(Blob, data=b"blob")
+        tree = self.commit_tree([(b"a", blob), (b"b.c", blob), (b"b/c", blob)])
+        entries = tree_entry_tuples(b"", tree)
+        self.assertEqual(
             [
-                TreeChange(
-                    CHANGE_MODIFY,
-                    (b"a", 0o100644, blob_a1.id),
-                    (b"a", 0o120000, blob_a2.id),
-                )
+                (b"a", F, blob.id),
+                (b"b", 0o040000, tree[b"b"][1]),
+                (b"b.c", F, blob.id),
             ],
-            tree1,
-            tree2,
-            change_type_same=True,
+            entries,
+        )
+        self.assertEqual(tuple, type(entries[0]))
+        self.assertEqual(
+            [(b"d/c", F, blob.id)], tree_entry_tuples(b"d", self.store[tree[b"b"][1]])
         )
+        self.assertEqual([], tree_entry_tuples(b"", None))
+        self.assertEqual([], tree_entry_tuples(b"d", Tree()))
 
-    def test_tree_changes_to_tree(self) -> None:
-        blob_a = make_object(Blob, data=b"a")
-        blob_x = make_object(Blob, data=b"x")
-        tree1 = self.commit_tree([(b"a", blob_a)])
-        tree2 = self.commit_tree([(b"a/x", blob_x)])
-        self.assertChangesEqual(
+    test_tree_entry_tuples = functest_builder(
+        _do_test_tree_entry_tuples, _tree_entry_tuples_py
+    )
+    test_tree_entry_tuples_extension = ext_functest_builder(
+        _do_test_tree_entry_tuples, _tree_entry_tuples
+    )
+
+    def _do_test_merge_entries_raw(self, merge_entries) -> None:
+        blob_a1 = make_object(Blob, data=b"a1")
+        blob_a2 = make_object(Blob, data=b"a2")
+        tree1 = self.commit_tree([(b"a", blob_a1), (b"b", blob_a1)])
+        tree2 = self.commit_tree([(b"a", blob_a2), (b"c/d", blob_a2)])
+        pairs = merge_entries(b"x", tree1, tree2, raw=True)
+        self.assertEqual(
             [
-                TreeChange.delete(TreeEntry(b"a", F, blob_a.id)),
-                TreeChange.add(TreeEntry(b"a/x", F, blob_x.id)),
+                ((b"x/a", F, blob_a1.id), (b"x/a", F, blob_a2.id)),
+                ((b"x/b", F, blob_a1.id), None),
+                (None, (b"x/c", 0o040000, tree2[b"c"][1])),
             ],
-            tree1,
-            tree2,
+            pairs,
+        )
+        for pair in pairs:
+            for entry in pair:
+                self.assertIn(type(entry), (tuple, type(None)))
+        self.assertEqual(
+            merge_entries(b"x", tree1, tree2, only_changed=True),
+            merge_entries(b"x", tree1, tree2, only_changed=True, raw=True),
         )
 
-    def test_tree_changes_complex(self) -> None:
-        blob_a_1 = make_object(Blob, data=b"a1_1")
-        blob_bx1_1 = make_object(Blob, data=b"bx1_1")
-        blob_bx2_1 = make_object(Blob, data=b"bx2_1")
-        blob_by1_1 = make_object(Blob, data=b"by1_1")
-        blob_by2_1 = make_object(Blob, data=b"by2_1")
-        tree1 = self.commit_tree(
+    test_merge_entries_raw = functest_builder(
+        _do_test_merge_entries_raw, _merge_entries_py
+    )
+    test_merge_entries_raw_extension = ext_functest_builder(
+        _do_test_merge_entries_raw, _merge_entries
+    )
+
+    def _do_test_merge_entries_reverse(self, merge_entries) -> None:
+        blob_a1 = make_object(Blob, data=b"a1")
+        blob_a2 = make_object(Blob, data=b"a2")
+        tree1 = self.commit_tree([(b"a", blob_a1), (b"b", blob_a1), (b"d", blob_a1)])
+        tree2 = self.commit_tree([(b"a", blob_a2), (b"c/d", blob_a2), (b"d", blob_a1)])
+        self.assertEqual(
             [
-                (b"a", blob_a_1),
-                (b"b/x/1", blob_bx1_1),
-                (b"b/x/2", blob_bx2_1),
-                (b"b/y/1", blob_by1_1),
-                (b"b/y/2", blob_by2_1),
-            ]
+                (TreeEntry(b"a", F, blob_a2.id), TreeEntry(b"a", F, blob_a1.id)),
+                (None, TreeEntry(b"b", F, blob_a1.id)),
+                (TreeEntry(b"c", 0o040000, tree2[b"c"][1]), None),
+                (TreeEntry(b"d", F, blob_a1.id), TreeEntry(b"d", F, blob_a1.id)),
+            ],
+            merge_entries(b"", tree1, tree2, reverse=True),
+        )
+        for kwargs in ({}, {"raw": True}, {"only_changed": True}):
+            self.assertEqual(
+                merge_entries(b"x", tree2, tree1, **kwargs),
+                merge_entries(b"x", tree1, tree2, reverse=True, **kwargs),
+            )
+        self.assertEqual(
+            merge_entries(b"", tree2, None),
+            merge_entries(b"", None, tree2, reverse=True),
         )
 
-        blob_a_2 = make_object(Blob, data=b"a1_2")
-        blob_bx1_2 = blob_bx1_1
-        blob_by_2 = make_object(Blob, data=b"by_2")
-        blob_c_2 = make_object(Blob, data=b"c_2")
-        tree2 = self.commit_tree(
-            [
-                (b"a", blob_a_2),
-                (b"b/x/1", blob_bx1_2),
-                (b"b/y", blob_by_2),
-                (b"c", blob_c_2),
-            ]
+    test_merge_entries_reverse = functest_builder(
+        _do_test_merge_entries_reverse, _merge_entries_py
+    )
+    test_merge_entries_reverse_extension = ext_functest_builder(
+        _do_test_merge_entries_reverse, _merge_entries
+    )
+
+    def _do_test_normalize_tree_path(self, normalize_tree_path) -> None:
+        self.assertEqual(b"", normalize_tree_path(b""))
+        self.assertEqual(b"a/b", normalize_tree_path(b"a/b"))
+        self.assertEqual(b"a/b/c", normalize_tree_path(b"a//b///c"))
+        self.assertEqual(b"a/b", normalize_tree_path(b"a/b//"))
+        self.assertEqual(b"/a", normalize_tree_path(b"//a"))
+        self.assertEqual(b"", normalize_tree_path(b"///"))
+
+    test_normalize_tree_path = functest_builder(
+        _do_test_normalize_tree_path, _normalize_tree_path_py
+    )
+    test_normalize_tree_path_extension = ext_functest_builder(
+        _do_test_normalize_tree_path, _normalize_tree_path
+    )
+
+    def _do_test_merge_entries_prefixed(self, merge_entries_prefixed) -> None:
+        blob_a1 = make_object(Blob, data=b"a1")
+        blob_a2 = make_object(Blob, data=b"a2")
+        tree1 = self.commit_tree(
+            [(b"a", blob_a1), (b"ab", blob_a1), (b"b/c", blob_a1), (b"c", blob_a1)]
         )
+        tree2 = self.commit_tree([(b"ab", blob_a2), (b"ac", blob_a2), (b"b", blob_a2)])
 
-        self.assertChangesEqual(
+        self.assertEqual(
             [
-                TreeChange(
-                    CHANGE_MODIFY,
-                    (b"a", F, blob_a_1.id),
-                    (b"a", F, blob_a_2.id),
-                ),
-                TreeChange.delete(TreeEntry(b"b/x/2", F, blob_bx2_1.id)),
-                TreeChange.add(TreeEntry(b"b/y", F, blob_by_2.id)),
-                TreeChange.delete(TreeEntry(b"b/y/1", F, blob_by1_1.id)),
-                TreeChange.delete(TreeEntry(b"b/y/2", F, blob_by2_1.id)),
-                TreeChange.add(TreeEntry(b"c", F, blob_c_2.id)),
+                (TreeEntry(b"a", F, blob_a1.id), None),
+                (TreeEntry(b"ab", F, blob_a1.id), TreeEntry(b"ab", F, blob_a2.id)),
+                (None, TreeEntry(b"ac", F, blob_a2.id)),
             ],
-            tree1,
-            tree2,
+            merge_entries_prefixed(b"", tree1, tree2, b"a"),
         )
-
-    def test_tree_changes_name_order(self) -> None:
-        blob = make_object(Blob, data=b"a")
-        tree1 = self.commit_tree([(b"a", blob), (b"a.", blob), (b"a..", blob)])
-        # Tree order is the reverse of this, so if we used tree order, 'a..'
-        # would not be merged.
-        tree2 = self.commit_tree([(b"a/x", blob), (b"a./x", blob), (b"a..", blob)])
-
-        self.assertChangesEqual(
+        self.assertEqual(
+            [(TreeEntry(b"ab", F, blob_a1.id), TreeEntry(b"ab", F, blob_a2.id))],
+            merge_entries_prefixed(b"", tree1, tree2, b"ab"),
+        )
+        self.assertEqual(
             [
-                TreeChange.delete(TreeEntry(b"a", F, blob.id)),
-                TreeChange.add(TreeEntry(b"a/x", F, blob.id)),
-                TreeChange.delete(TreeEntry(b"a.", F, blob.id)),
-                TreeChange.add(TreeEntry(b"a./x", F, blob.id)),
+                (
+                    TreeEntry(b"x/b", 0o040000, tree1[b"b"][1]),
+                    TreeEntry(b"x/b", F, blob_a2.id),
+                )
             ],
-            tree1,
-            tree2,
+            merge_entries_prefixed(b"x", tree1, tree2, b"x/b"),
+        )
+        self.assertEqual([], merge_entries_prefixed(b"", tree1, tree2, b"d"))
+        self.assertEqual([], merge_entries_prefixed(b"", tree1, tree2, b"x"))
+        self.assertEqual(
+            _merge_entries(b"", tree1, None),
+            merge_entries_prefixed(b"", tree1, None, b""),
         )
 
-    def test_tree_changes_prune(self) -> None:
+    test_merge_entries_prefixed = functest_builder(
+        _do_test_merge_entries_prefixed, _merge_entries_prefixed_py
+    )
+    test_merge_entries_prefixed_extension = ext_functest_builder(
+        _do_test_merge_entries_prefixed, _merge_entries_prefixed
+    )
+
+    def _do_test_merge_entry_lists(self, merge_entry_lists) -> None:
         blob_a1 = make_object(Blob, data=b"a1")
         blob_a2 = make_object(Blob, data=b"a2")
-        blob_x = make_object(Blob, data=b"x")
-        tree1 = self.commit_tree([(b"a", blob_a1), (b"b/x", blob_x)])
-        tree2 = self.commit_tree([(b"a", blob_a2), (b"b/x", blob_x)])
-        # Remove identical items so lookups will fail unless we prune.
-        subtree = self.store[tree1[b"b"][1]]
-        for entry in subtree.items():
-            del self.store[entry.sha]
-        del self.store[subtree.id]
+        blob_b1 = make_object(Blob, data=b"b1")
+        blob_c2 = make_object(Blob, data=b"c2")
+        list1 = [(b"a", 0o100644, blob_a1.id), (b"b", 0o100755, blob_b1.id)]
+        list2 = [(b"a", 0o100644, blob_a2.id), (b"c", 0o100755, blob_c2.id)]
+        tree1 = self.commit_tree([(b"a", blob_a1, 0o100644), (b"b", blob_b1, 0o100755)])
+        tree2 = self.commit_tree([(b"a", blob_a2, 0o100644), (b"c", blob_c2, 0o100755)])
 
-        self.assertChangesEqual(
-            [TreeChange(CHANGE_MODIFY, (b"a", F, blob_a1.id), (b"a", F, blob_a2.id))],
-            tree1,
-            tree2,
+        self.assertEqual([], merge_entry_lists(b"", [], []))
+        self.assertEqual(
+            _merge_entries(b"", tree1, tree2), merge_entry_lists(b"", list1, list2)
+        )
+        self.assertEqual(
+            _merge_entries(b"x", None, tree2), merge_entry_lists(b"x", [], list2)
         )
 
-    def test_tree_changes_rename_detector(self) -> None:
-        blob_a1 = make_object(Blob, data=b"a\nb\nc\nd\n")
-        blob_a2 = make_object(Blob, data=b"a\nb\nc\ne\n")
-        blob_b = make_object(Blob, data=b"b")
-        tree1 = self.commit_tree([(b"a", blob_a1), (b"b", blob_b)])
-        tree2 = self.commit_tree([(b"c", blob_a2), (b"b", blob_b)])
-        detector = RenameDetector(self.store)
+        unsorted = [(b"b", 0o100644, blob_b1.id), (b"a", 0o100644, blob_a1.id)]
+        self.assertRaises(ValueError, merge_entry_lists, b"", unsorted, list2)
+        duplicate = [(b"a", 0o100644, blob_a1.id), (b"a", 0o100644, blob_a2.id)]
+        self.assertRaises(ValueError, merge_entry_lists, b"", list1, duplicate)
 
-        self.assertChangesEqual(
-            [
-                TreeChange.delete(TreeEntry(b"a", F, blob_a1.id)),
-                TreeChange.add(TreeEntry(b"c", F, blob_a2.id)),
-            ],
-            tree1,
-            tree2,
+    test_merge_entry_lists = functest_builder(
+        _do_test_merge_entry_lists, _merge_entry_lists_py
+    )
+    test_merge_entry_lists_extension = ext_functest_builder(
+        _do_test_merge_entry_lists, _merge_entry_lists
+    )
+
+    def _do_test_merge_sorted_lists(self, merge_sorted_lists) -> None:
+        sha = b"1" * 40
+        a, b, c, d = (TreeEntry(name, F, sha) for name in [b"a", b"b", b"c", b"d"])
+        self.assertEqual(
+            [(a, None), (None, b), (c, c), (d, None)],
+            merge_sorted_lists([a, c, d], [b, c], "path"),
         )
-        self.assertChangesEqual(
+        self.assertEqual([], merge_sorted_lists([], [], "path"))
+        self.assertEqual([(None, a)], merge_sorted_lists([], iter([a]), "path"))
+
+        class Ref:
+            def __init__(self, name, sha) -> None:
+                self.name = name
+                self.sha = sha
+
+        old = [Ref(b"refs/heads/main", b"1"), Ref(b"refs/tags/v1", b"2")]
+        new = [Ref(b"refs/heads/dev", b"3"), Ref(b"refs/heads/main", b"4")]
+        self.assertEqual(
+            [(None, new[0]), (old[0], new[1]), (old[1], None)],
+            merge_sorted_lists(old, new, "name"),
+        )
+        self.assertRaises(AttributeError, merge_sorted_lists, old, new, "missing")
+
+    test_merge_sorted_lists = functest_builder(
+        _do_test_merge_sorted_lists, _merge_sorted_lists_py
+    )
+    test_merge_sorted_lists_extension = ext_functest_builder(
+        _do_test_merge_sorted_lists, _merge_sorted_lists
+    )
+
+    def _do_test_iter_merge_entries(self, iter_merge_entries) -> None:
+        blob_a1 = make_object(Blob, data=b"a1")
+        blob_a2 = make_object(Blob, data=b"a2")
+        blob_b1 = make_object(Blob, data=b"b1")
+        blob_c2 = make_object(Blob, data=b"c2")
+        tree1 = self.commit_tree([(b"a", blob_a1, 0o100644), (b"b", blob_b1, 0o100755)])
+        tree2 = self.commit_tree([(b"a", blob_a2, 0o100644), (b"c", blob_c2, 0o100755)])
+
+        for t1, t2 in [(tree1, tree2), (tree1, self.empty_tree), (None, tree2)]:
+            self.assertEqual(
+                _merge_entries(b"", t1, t2), list(iter_merge_entries(b"", t1, t2))
+            )
+
+        it = iter_merge_entries(b"", tree1, tree2)
+        self.assertIs(it, iter(it))
+        self.assertEqual(
+            (
+                TreeEntry(b"a", 0o100644, blob_a1.id),
+                TreeEntry(b"a", 0o100644, blob_a2.id),
+            ),
+            next(it),
+        )
+        self.assertEqual((TreeEntry(b"b", 0o100755, blob_b1.id), None), next(it))
+        self.assertEqual((None, TreeEntry(b"c", 0o100755, blob_c2.id)), next(it))
+        self.assertRaises(StopIteration, next, it)
+
+    test_iter_merge_entries_extension = ext_functest_builder(
+        _do_test_iter_merge_entries, _iter_merge_entries
+    )
+
+    def test_tree_entry_iter_extension(self) -> None:
+        if TreeEntryIter is None:
+            self.skipTest("Rust extension not available")
+        blob = make_object(Blob, data=b"blob")
+        # "a.b" sorts before the tree "a" in tree order, but after it by name.
+        tree = self.commit_tree(
+            [(b"a.b", blob), (b"a/x", blob), (b"b", blob, 0o100755)]
+        )
+        it = TreeEntryIter(tree, b"x")
+        self.assertIs(it, iter(it))
+        self.assertEqual(3, operator.length_hint(it))
+        self.assertEqual(TreeEntry(b"x/a", 0o040000, tree[b"a"][1]), next(it))
+        self.assertEqual(2, operator.length_hint(it))
+        self.assertEqual(
+            [TreeEntry(b"x/a.b", F, blob.id), TreeEntry(b"x/b", 0o100755, blob.id)],
+            list(it),
+        )
+        self.assertRaises(StopIteration, next, it)
+        self.assertEqual(
+            _merge_entries_py(b"", tree, None),
+            [(entry, None) for entry in TreeEntryIter(tree)],
+        )
+        self.assertEqual([], list(TreeEntryIter(None)))
+        self.assertEqual([], list(TreeEntryIter(self.empty_tree, b"x")))
+
+    def _do_test_merge_entries_name_collisions(self, merge_entries) -> None:
+        # Tree order sorts "foo" as "foo/", i.e. after "foo-bar" and
+        # "foo.txt"; make sure that doesn't misalign the merge.
+        blob = make_object(Blob, data=b"blob")
+        tree1 = self.commit_tree(
+            [(b"foo", blob), (b"foo-bar", blob), (b"foo.txt", blob)]
+        )
+        tree2 = self.commit_tree(
+            [(b"foo/x", blob), (b"foo-bar", blob), (b"foo.txt", blob)]
+        )
+        foo_tree = self.store[tree2[b"foo"][1]]
+        self.assertEqual(
             [
-                TreeChange.delete(TreeEntry(b"a", F, blob_a1.id)),
-                TreeChange(
-                    CHANGE_UNCHANGED,
-                    (b"b", F, blob_b.id),
-                    (b"b", F, blob_b.id),
+                (
+                    TreeEntry(b"foo", F, blob.id),
+                    TreeEntry(b"foo", 0o040000, foo_tree.id),
                 ),
-                TreeChange.add(TreeEntry(b"c", F, blob_a2.id)),
+                (TreeEntry(b"foo-bar", F, blob.id), TreeEntry(b"foo-bar", F, blob.id)),
+                (TreeEntry(b"foo.txt", F, blob.id), TreeEntry(b"foo.txt", F, blob.id)),
             ],
-            tree1,
-            tree2,
-            want_unchanged=True,
-        )
-        self.assertChangesEqual(
-            [TreeChange(CHANGE_RENAME, (b"a", F, blob_a1.id), (b"c", F, blob_a2.id))],
-            tree1,
-            tree2,
-            rename_detector=detector,
+            merge_entries(b"", tree1, tree2),
         )
-        self.assertChangesEqual(
+
+        tree3 = self.commit_tree([(b"foo-bar", blob)])
+        self.assertEqual(
             [
-                TreeChange(CHANGE_RENAME, (b"a", F, blob_a1.id), (b"c", F, blob_a2.id)),
-                TreeChange(
-                    CHANGE_UNCHANGED,
-                    (b"b", F, blob_b.id),
-                    (b"b", F, blob_b.id),
-                ),
+                (None, TreeEntry(b"foo", 0o040000, foo_tree.id)),
+                (TreeEntry(b"foo-bar", F, blob.id), TreeEntry(b"foo-bar", F, blob.id)),
+                (None, TreeEntry(b"foo.txt", F, blob.id)),
             ],
-            tree1,
-            tree2,
-            rename_detector=detector,
-            want_unchanged=True,
+            merge_entries(b"", tree3, tree2),
         )
 
-    def assertChangesForMergeEqual(
-        self, expected, parent_trees, merge_tree, **kwargs
-    ) -> None:
-        parent_tree_ids = [t.id for t in parent_trees]
-        actual = list(
-            tree_changes_for_merge(self.store, parent_tree_ids, merge_tree.id, **kwargs)
+    test_merge_entries_name_collisions = functest_builder(
+        _do_test_merge_entries_name_collisions, _merge_entries_py
+    )
+    test_merge_entries_name_collisions_extension = ext_functest_builder(
+        _do_test_merge_entries_name_collisions, _merge_entries
+    )
+
+    def _do_test_classify_change(self, classify_change) -> None:
+        sha1 = b"a" * 40
+        sha2 = b"b" * 40
+        file1 = TreeEntry(b"a", F, sha1)
+        self.assertEqual(_KIND_ADD, classify_change(None, file1))
+        self.assertEqual(_KIND_DELETE, classify_change(file1, None))
+        self.assertEqual(_KIND_UNCHANGED, classify_change(file1, file1))
+        self.assertEqual(_KIND_MODIFY, classify_change(file1, TreeEntry(b"a", F, sha2)))
+        self.assertEqual(
+            _KIND_MODIFY, classify_change(file1, TreeEntry(b"a", 0o100755, sha2))
         )
-        self.assertEqual(expected, actual)
+        self.assertEqual(
+            _KIND_TYPECHANGE, classify_change(file1, TreeEntry(b"a", 0o120000, sha1))
+        )
+        self.assertEqual(
+            _KIND_TYPECHANGE, classify_change(TreeEntry(b"a", 0o040000, sha1), file1)
+        )
+        self.assertRaises(ValueError, classify_change, None, None)
 
-        parent_tree_ids.reverse()
-        expected = [list(reversed(cs)) for cs in expected]
-        actual = list(
-            tree_changes_for_merge(self.store, parent_tree_ids, merge_tree.id, **kwargs)
+    test_classify_change = functest_builder(
+        _do_test_classify_change, _classify_change_py
+    )
+    test_classify_change_extension = ext_functest_builder(
+        _do_test_classify_change, _classify_change
+    )
+
+    def _do_test_classify_change_mode(self, classify_change) -> None:
+        sha = b"a" * 40
+        regular = TreeEntry(b"a", 0o100644, sha)
+        executable = TreeEntry(b"a", 0o100755, sha)
+        symlink = TreeEntry(b"a", 0o120000, sha)
+        # Toggling the executable bit of a regular file only changes its mode.
+        self.assertEqual(_KIND_MODE_CHANGE, classify_change(regular, executable))
+        self.assertEqual(_KIND_MODE_CHANGE, classify_change(executable, regular))
+        # Changing between a symlink and an executable file changes its type.
+        self.assertEqual(_KIND_TYPECHANGE, classify_change(symlink, executable))
+        self.assertEqual(_KIND_TYPECHANGE, classify_change(executable, symlink))
+        self.assertEqual(
+            _KIND_MODE_CHANGE, classify_change(symlink, TreeEntry(b"a", 0o120755, sha))
         )
-        self.assertEqual(expected, actual)
 
-    def test_tree_changes_for_merge_add_no_conflict(self) -> None:
-        blob = make_object(Blob, data=b"blob")
-        parent1 = self.commit_tree([])
-        parent2 = merge = self.commit_tree([(b"a", blob)])
-        self.assertChangesForMergeEqual([], [parent1, parent2], merge)
-        self.assertChangesForMergeEqual([], [parent2, parent2], merge)
+    test_classify_change_mode = functest_builder(
+        _do_test_classify_change_mode, _classify_change_py
+    )
+    test_classify_change_mode_extension = ext_functest_builder(
+        _do_test_classify_change_mode, _classify_change
+    )
 
-    def test_tree_changes_for_merge_add_modify_conflict(self) -> None:
-        blob1 = make_object(Blob, data=b"1")
-        blob2 = make_object(Blob, data=b"2")
-        parent1 = self.commit_tree([])
-        parent2 = self.commit_tree([(b"a", blob1)])
-        merge = self.commit_tree([(b"a", blob2)])
-        self.assertChangesForMergeEqual(
+    def _do_test_classify_change_sha256(self, classify_change) -> None:
+        for sha1, sha2 in [(b"a" * 64, b"b" * 64), (b"\xaa" * 32, b"\xbb" * 32)]:
+            file1 = TreeEntry(b"a", F, sha1)
+            self.assertEqual(_KIND_UNCHANGED, classify_change(file1, file1))
+            self.assertEqual(
+                _KIND_MODIFY, classify_change(file1, TreeEntry(b"a", F, sha2))
+            )
+            executable = TreeEntry(b"a", 0o100755, sha1)
+            self.assertEqual(_KIND_MODE_CHANGE, classify_change(file1, executable))
+        # SHAs that only share a prefix differ.
+        self.assertEqual(
+            _KIND_MODIFY,
+            classify_change(
+                TreeEntry(b"a", F, b"a" * 40), TreeEntry(b"a", F, b"a" * 64)
+            ),
+        )
+
+    test_classify_change_sha256 = functest_builder(
+        _do_test_classify_change_sha256, _classify_change_py
+    )
+    test_classify_change_sha256_extension = ext_functest_builder(
+        _do_test_classify_change_sha256, _classify_change
+    )
+
+    def _do_test_change_record(self, change_record) -> None:
+        sha1 = b"a" * 40
+        sha2 = b"b" * 40
+        file1 = TreeEntry(b"a", F, sha1)
+        symlink = TreeEntry(b"a", 0o120000, sha2)
+        self.assertEqual(
+            (_KIND_ADD, None, None, None, b"a", F, sha1), change_record(None, file1)
+        )
+        self.assertEqual(
+            (_KIND_DELETE, b"a", F, sha1, None, None, None), change_record(file1, None)
+        )
+        self.assertEqual(
+            (_KIND_TYPECHANGE, b"a", F, sha1, b"a", 0o120000, sha2),
+            change_record(file1, symlink),
+        )
+        self.assertEqual(
+            (_KIND_MODE_CHANGE, b"a", F, sha1, b"a", 0o100755, sha1),
+            change_record(file1, TreeEntry(b"a", 0o100755, sha1)),
+        )
+        self.assertEqual(
+            (_KIND_UNCHANGED, b"a", F, sha1, b"a", F, sha1), change_record(file1, file1)
+        )
+        self.assertRaises(ValueError, change_record, None, None)
+
+    test_change_record = functest_builder(_do_test_change_record, _change_record_py)
+    test_change_record_extension = ext_functest_builder(
+        _do_test_change_record, _change_record
+    )
+
+    def _do_test_diff_entries(self, diff_entries) -> None:
+        blob_a1 = make_object(Blob, data=b"a1")
+        blob_a2 = make_object(Blob, data=b"a2")
+        tree1 = self.commit_tree(
+            [(b"a", blob_a1), (b"b", blob_a1), (b"c", blob_a1), (b"d", blob_a1)]
+        )
+        tree2 = self.commit_tree(
             [
-                [
-                    TreeChange.add(TreeEntry(b"a", F, blob2.id)),
-                    TreeChange(CHANGE_MODIFY, (b"a", F, blob1.id), (b"a", F, blob2.id)),
-                ]
-            ],
-            [parent1, parent2],
-            merge,
+                (b"a", blob_a2),
+                (b"b", blob_a1, 0o100755),
+                (b"c", blob_a1, 0o120000),
+                (b"d", blob_a1),
+                (b"e/f", blob_a1),
+            ]
+        )
+        e = tree2[b"e"][1]
+        expected = [
+            (
+                _KIND_MODIFY,
+                TreeEntry(b"x/a", F, blob_a1.id),
+                TreeEntry(b"x/a", F, blob_a2.id),
+            ),
+            (
+                _KIND_MODE_CHANGE,
+                TreeEntry(b"x/b", F, blob_a1.id),
+                TreeEntry(b"x/b", 0o100755, blob_a1.id),
+            ),
+            (
+                _KIND_TYPECHANGE,
+                TreeEntry(b"x/c", F, blob_a1.id),
+                TreeEntry(b"x/c", 0o120000, blob_a1.id),
+            ),
+            (_KIND_ADD, None, TreeEntry(b"x/e", 0o040000, e)),
+        ]
+        self.assertEqual(expected, diff_entries(b"x", tree1, tree2))
+        unchanged = (
+            _KIND_UNCHANGED,
+            TreeEntry(b"x/d", F, blob_a1.id),
+            TreeEntry(b"x/d", F, blob_a1.id),
+        )
+        self.assertEqual(
+            [*expected[:3], unchanged, expected[3]],
+            diff_entries(b"x", tree1, tree2, want_unchanged=True),
+        )
+        self.assertEqual(
+            [(_KIND_DELETE, TreeEntry(b"a", F, blob_a1.id), None)],
+            diff_entries(b"", self.commit_tree([(b"a", blob_a1)]), None),
         )
+        self.assertEqual([], diff_entries(b"", tree1, tree1))
+        self.assertEqual([], diff_entries(b"", None, None))
 
-    def test_tree_changes_for_merge_modify_modify_conflict(self) -> None:
-        blob1 = make_object(Blob, data=b"1")
-        blob2 = make_object(Blob, data=b"2")
-        blob3 = make_object(Blob, data=b"3")
-        parent1 = self.commit_tree([(b"a", blob1)])
-        parent2 = self.commit_tree([(b"a", blob2)])
-        merge = self.commit_tree([(b"a", blob3)])
-        self.assertChangesForMergeEqual(
+    test_diff_entries = functest_builder(_do_test_diff_entries, _diff_entries_py)
+    test_diff_entries_extension = ext_functest_builder(
+        _do_test_diff_entries, _diff_entries
+    )
+
+    def _do_test_index_tree_changes(self, index_tree_changes) -> None:
+        blob_a1 = make_object(Blob, data=b"a1")
+        blob_a2 = make_object(Blob, data=b"a2")
+        tree = self.commit_tree(
             [
-                [
-                    TreeChange(CHANGE_MODIFY, (b"a", F, blob1.id), (b"a", F, blob3.id)),
-                    TreeChange(CHANGE_MODIFY, (b"a", F, blob2.id), (b"a", F, blob3.id)),
-                ]
+                (b"a", blob_a1),
+                (b"b", blob_a1),
+                (b"c/d", blob_a1),
+                (b"c/e", blob_a1),
+                (b"c.f", blob_a1),
+            ]
+        )
+        index_entries = [
+            (b"a", F, blob_a2.id),
+            (b"c.f", 0o100755, blob_a1.id),
+            (b"c/d", F, blob_a1.id),
+            (b"c/g", F, blob_a2.id),
+        ]
+        self.assertEqual(
+            [
+                (
+                    _KIND_MODIFY,
+                    TreeEntry(b"x/a", F, blob_a1.id),
+                    TreeEntry(b"x/a", F, blob_a2.id),
+                ),
+                (_KIND_DELETE, TreeEntry(b"x/b", F, blob_a1.id), None),
+                (
+                    _KIND_MODE_CHANGE,
+                    TreeEntry(b"x/c.f", F, blob_a1.id),
+                    TreeEntry(b"x/c.f", 0o100755, blob_a1.id),
+                ),
+                (_KIND_DELETE, TreeEntry(b"x/c/e", F, blob_a1.id), None),
+                (_KIND_ADD, None, TreeEntry(b"x/c/g", F, blob_a2.id)),
             ],
-            [parent1, parent2],
-            merge,
+            index_tree_changes(index_entries, tree, b"x", self.store),
+        )
+        self.assertEqual(
+            [(_KIND_ADD, None, TreeEntry(b"a", F, blob_a1.id))],
+            index_tree_changes([(b"a", F, blob_a1.id)], None),
+        )
+        flat_tree = self.commit_tree([(b"a", blob_a1)])
+        self.assertEqual([], index_tree_changes([(b"a", F, blob_a1.id)], flat_tree))
+        self.assertRaises(ValueError, index_tree_changes, index_entries, tree)
+        self.assertRaises(
+            ValueError, index_tree_changes, index_entries[::-1], flat_tree
         )
 
-    def test_tree_changes_for_merge_modify_no_conflict(self) -> None:
-        blob1 = make_object(Blob, data=b"1")
-        blob2 = make_object(Blob, data=b"2")
-        parent1 = self.commit_tree([(b"a", blob1)])
-        parent2 = merge = self.commit_tree([(b"a", blob2)])
-        self.assertChangesForMergeEqual([], [parent1, parent2], merge)
+    test_index_tree_changes = functest_builder(
+        _do_test_index_tree_changes, _index_tree_changes_py
+    )
+    test_index_tree_changes_extension = ext_functest_builder(
+        _do_test_index_tree_changes, _index_tree_changes
+    )
 
-    def test_tree_changes_for_merge_delete_delete_conflict(self) -> None:
-        blob1 = make_object(Blob, data=b"1")
-        blob2 = make_object(Blob, data=b"2")
-        parent1 = self.commit_tree([(b"a", blob1)])
-        parent2 = self.commit_tree([(b"a", blob2)])
-        merge = self.commit_tree([])
-        self.assertChangesForMergeEqual(
+    def _do_test_classify_change_typechange(self, classify_change) -> None:
+        modes = {
+            "file": 0o100644,
+            "executable": 0o100755,
+            "symlink": 0o120000,
+            "gitlink": 0o160000,
+            "tree": 0o040000,
+        }
+        for name1, mode1 in modes.items():
+            for name2, mode2 in modes.items():
+                for sha2 in (b"a" * 40, b"b" * 40):
+                    entry1 = TreeEntry(b"a", mode1, b"a" * 40)
+                    entry2 = TreeEntry(b"a", mode2, sha2)
+                    if stat.S_IFMT(mode1) != stat.S_IFMT(mode2):
+                        expected = _KIND_TYPECHANGE
+                    elif mode1 != mode2 and sha2 == entry1.sha:
+                        expected = _KIND_MODE_CHANGE
+                    elif sha2 == entry1.sha:
+                        expected = _KIND_UNCHANGED
+                    else:
+                        expected = _KIND_MODIFY
+                    self.assertEqual(
+                        expected,
+                        classify_change(entry1, entry2),
+                        f"{name1} -> {name2}",
+                    )
+        sha = b"a" * 40
+        file = TreeEntry(b"a", modes["file"], sha)
+        symlink = TreeEntry(b"a", modes["symlink"], sha)
+        gitlink = TreeEntry(b"a", modes["gitlink"], sha)
+        tree = TreeEntry(b"a", modes["tree"], sha)
+        self.assertEqual(_KIND_TYPECHANGE, classify_change(file, symlink))
+        self.assertEqual(_KIND_TYPECHANGE, classify_change(symlink, gitlink))
+        self.assertEqual(_KIND_TYPECHANGE, classify_change(file, tree))
+
+    test_classify_change_typechange = functest_builder(
+        _do_test_classify_change_typechange, _classify_change_py
+    )
+    test_classify_change_typechange_extension = ext_functest_builder(
+        _do_test_classify_change_typechange, _classify_change
+    )
+
+    def _do_test_merge3_entry(self, merge3_entry) -> None:
+        base = TreeEntry(b"a", F, b"1" * 40)
+        changed = TreeEntry(b"a", F, b"2" * 40)
+        other = TreeEntry(b"a", F, b"3" * 40)
+        executable = TreeEntry(b"a", 0o100755, b"1" * 40)
+        self.assertEqual(_MERGE3_UNCHANGED, merge3_entry(base, base, base))
+        self.assertEqual(_MERGE3_UNCHANGED, merge3_entry(None, None, None))
+        self.assertEqual(_MERGE3_OURS, merge3_entry(base, changed, base))
+        self.assertEqual(_MERGE3_OURS, merge3_entry(base, None, base))
+        self.assertEqual(_MERGE3_THEIRS, merge3_entry(base, base, executable))
+        self.assertEqual(_MERGE3_THEIRS, merge3_entry(None, None, changed))
+        self.assertEqual(_MERGE3_SAME, merge3_entry(base, changed, changed))
+        self.assertEqual(_MERGE3_SAME, merge3_entry(base, None, None))
+        self.assertEqual(_MERGE3_SAME, merge3_entry(None, changed, changed))
+        self.assertEqual(_MERGE3_CONFLICT, merge3_entry(base, changed, other))
+        self.assertEqual(_MERGE3_CONFLICT, merge3_entry(base, changed, None))
+        self.assertEqual(_MERGE3_CONFLICT, merge3_entry(base, changed, executable))
+        self.assertEqual(_MERGE3_CONFLICT, merge3_entry(None, changed, other))
+
+    test_merge3_entry = functest_builder(_do_test_merge3_entry, _merge3_entry_py)
+    test_merge3_entry_extension = ext_functest_builder(
+        _do_test_merge3_entry, _merge3_entry
+    )
+
+    def _do_test_cmp_tree_entries(self, cmp_tree_entries) -> None:
+        sha = b"a" * 40
+        file_a = TreeEntry(b"a", F, sha)
+        dir_a = TreeEntry(b"a", 0o040000, sha)
+        self.assertEqual(0, cmp_tree_entries(file_a, file_a))
+        self.assertEqual(-1, cmp_tree_entries(file_a, TreeEntry(b"b", F, sha)))
+        self.assertEqual(1, cmp_tree_entries(TreeEntry(b"b", F, sha), file_a))
+        self.assertEqual(-1, cmp_tree_entries(file_a, dir_a))
+        # A directory sorts as if it had a trailing slash: after "a.c", but
+        # before "a0".
+        self.assertEqual(1, cmp_tree_entries(dir_a, TreeEntry(b"a.c", F, sha)))
+        self.assertEqual(-1, cmp_tree_entries(dir_a, TreeEntry(b"a0", F, sha)))
+        self.assertEqual(-1, cmp_tree_entries(file_a, TreeEntry(b"a.c", F, sha)))
+
+    test_cmp_tree_entries = functest_builder(
+        _do_test_cmp_tree_entries, _cmp_tree_entries_py
+    )
+    test_cmp_tree_entries_extension = ext_functest_builder(
+        _do_test_cmp_tree_entries, _cmp_tree_entries
+    )
+
+    def _do_test_sort_tree_entries(self, sort_tree_entries) -> None:
+        blob = make_object(Blob, data=b"blob")
+        tree = self.commit_tree(
             [
-                [
-                    TreeChange.delete(TreeEntry(b"a", F, blob1.id)),
-                    TreeChange.delete(TreeEntry(b"a", F, blob2.id)),
-                ]
-            ],
-            [parent1, parent2],
-            merge,
+                (b"a", blob),
+                (b"a-b", blob),
+                (b"a.c", blob),
+                (b"a0", blob),
+                (b"b/x", blob),
+                (b"b.txt", blob),
+                (b"c/y", blob),
+            ]
+        )
+        expected = list(tree.iteritems())
+        self.assertEqual(
+            [b"a", b"a-b", b"a.c", b"a0", b"b.txt", b"b", b"c"],
+            [entry.path for entry in expected],
         )
+        entries = list(reversed(expected))
+        self.assertIsNone(sort_tree_entries(entries))
+        self.assertEqual(expected, entries)
+        entries = []
+        sort_tree_entries(entries)
+        self.assertEqual([], entries)
 
-    def test_tree_changes_for_merge_delete_no_conflict(self) -> None:
+    test_sort_tree_entries = functest_builder(
+        _do_test_sort_tree_entries, _sort_tree_entries_py
+    )
+    test_sort_tree_entries_extension = ext_functest_builder(
+        _do_test_sort_tree_entries, _sort_tree_entries
+    )
+
+    def _do_test_check_tree_sorted(self, check_tree_sorted) -> None:
         blob = make_object(Blob, data=b"blob")
-        has = self.commit_tree([(b"a", blob)])
-        doesnt_have = self.commit_tree([])
-        self.assertChangesForMergeEqual([], [has, has], doesnt_have)
-        self.assertChangesForMergeEqual([], [has, doesnt_have], doesnt_have)
+        tree = self.commit_tree([(b"a", blob), (b"a.c", blob), (b"a/x", blob)])
+        self.assertIsNone(check_tree_sorted(tree))
+        self.assertIsNone(check_tree_sorted(Tree()))
 
-    def test_tree_changes_for_merge_octopus_no_conflict(self) -> None:
-        r = list(range(5))
-        blobs = [make_object(Blob, data=bytes(i)) for i in r]
-        parents = [self.commit_tree([(b"a", blobs[i])]) for i in r]
-        for i in r:
-            # Take the SHA from each of the parents.
-            self.assertChangesForMergeEqual([], parents, parents[i])
+        def raw_tree(*entries):
+            raw = b"".join(
+                b"%o %s\0%s" % (mode, name, hex_to_sha(blob.id))
+                for name, mode in entries
+            )
+            return Tree.from_raw_string(Tree.type_num, raw)
 
-    def test_tree_changes_for_merge_octopus_modify_conflict(self) -> None:
-        # Because the octopus merge strategy is limited, I doubt it's possible
-        # to create this with the git command line. But the output is well-
-        # defined, so test it anyway.
-        r = list(range(5))
-        parent_blobs = [make_object(Blob, data=bytes(i)) for i in r]
-        merge_blob = make_object(Blob, data=b"merge")
-        parents = [self.commit_tree([(b"a", parent_blobs[i])]) for i in r]
-        merge = self.commit_tree([(b"a", merge_blob)])
-        expected = [
+        self.assertEqual(
+            (2, b"c", b"b"),
+            check_tree_sorted(raw_tree((b"a", F), (b"c", F), (b"b", F))),
+        )
+        # Directories sort as if they had a trailing slash.
+        self.assertEqual(
+            (1, b"a", b"a.c"),
+            check_tree_sorted(raw_tree((b"a", 0o040000), (b"a.c", F))),
+        )
+        self.assertIsNone(check_tree_sorted(raw_tree((b"a.c", F), (b"a", 0o040000))))
+        self.assertEqual(
+            (1, b"a", b"a"), check_tree_sorted(raw_tree((b"a", F), (b"a", 0o040000)))
+        )
+
+    test_check_tree_sorted = functest_builder(
+        _do_test_check_tree_sorted, _check_tree_sorted_py
+    )
+    test_check_tree_sorted_extension = ext_functest_builder(
+        _do_test_check_tree_sorted, _check_tree_sorted
+    )
+
+    def _do_test_merge_entries_case_insensitive(self, merge_entries) -> None:
+        blob_a = make_object(Blob, data=b"a")
+        blob_b = make_object(Blob, data=b"b")
+        # "B" < "a" but "a" < "b" in byte order, so the entries have to be
+        # sorted case-insensitively for them to line up.
+        tree1 = self.commit_tree([(b"B", blob_b), (b"README", blob_a), (b"a", blob_a)])
+        tree2 = self.commit_tree([(b"A", blob_a), (b"b", blob_b), (b"readme", blob_b)])
+
+        self.assertEqual(
             [
-                TreeChange(
-                    CHANGE_MODIFY,
-                    (b"a", F, parent_blobs[i].id),
-                    (b"a", F, merge_blob.id),
-                )
-                for i in r
-            ]
-        ]
-        self.assertChangesForMergeEqual(expected, parents, merge)
+                (TreeEntry(b"a", F, blob_a.id), TreeEntry(b"A", F, blob_a.id)),
+                (TreeEntry(b"B", F, blob_b.id), TreeEntry(b"b", F, blob_b.id)),
+                (
+                    TreeEntry(b"README", F, blob_a.id),
+                    TreeEntry(b"readme", F, blob_b.id),
+                ),
+            ],
+            merge_entries(b"", tree1, tree2, case_insensitive=True),
+        )
+        self.assertEqual(6, len(merge_entries(b"", tree1, tree2)))
 
-    def test_tree_changes_for_merge_octopus_delete(self) -> None:
-        blob1 = make_object(Blob, data=b"1")
-        blob2 = make_object(Blob, data=b"3")
-        parent1 = self.commit_tree([(b"a", blob1)])
-        parent2 = self.commit_tree([(b"a", blob2)])
-        parent3 = merge = self.commit_tree([])
-        self.assertChangesForMergeEqual([], [parent1, parent1, parent1], merge)
-        self.assertChangesForMergeEqual([], [parent1, parent1, parent3], merge)
-        self.assertChangesForMergeEqual([], [parent1, parent3, parent3], merge)
-        self.assertChangesForMergeEqual(
+        # Entries that only differ in case within one tree are paired in
+        # byte order.
+        tree3 = self.commit_tree([(b"README", blob_a), (b"readme", blob_b)])
+        tree4 = self.commit_tree([(b"Readme", blob_a)])
+        self.assertEqual(
             [
-                [
-                    TreeChange.delete(TreeEntry(b"a", F, blob1.id)),
-                    TreeChange.delete(TreeEntry(b"a", F, blob2.id)),
-                    None,
-                ]
+                (
+                    TreeEntry(b"README", F, blob_a.id),
+                    TreeEntry(b"Readme", F, blob_a.id),
+                ),
+                (TreeEntry(b"readme", F, blob_b.id), None),
             ],
-            [parent1, parent2, parent3],
-            merge,
+            merge_entries(b"", tree3, tree4, case_insensitive=True),
+        )
+        self.assertEqual(
+            [
+                (
+                    TreeEntry(b"Readme", F, blob_a.id),
+                    TreeEntry(b"README", F, blob_a.id),
+                ),
+                (None, TreeEntry(b"readme", F, blob_b.id)),
+            ],
+            merge_entries(b"", tree4, tree3, case_insensitive=True),
         )
 
-    def test_tree_changes_for_merge_add_add_same_conflict(self) -> None:
-        blob = make_object(Blob, data=b"a\nb\nc\nd\n")
-        parent1 = self.commit_tree([(b"a", blob)])
-        parent2 = self.commit_tree([])
-        merge = self.commit_tree([(b"b", blob)])
-        add = TreeChange.add(TreeEntry(b"b", F, blob.id))
-        self.assertChangesForMergeEqual([[add, add]], [parent1, parent2], merge)
+    test_merge_entries_case_insensitive = functest_builder(
+        _do_test_merge_entries_case_insensitive, _merge_entries_py
+    )
+    test_merge_entries_case_insensitive_extension = ext_functest_builder(
+        _do_test_merge_entries_case_insensitive, _merge_entries
+    )
 
-    def test_tree_changes_for_merge_add_exact_rename_conflict(self) -> None:
-        blob = make_object(Blob, data=b"a\nb\nc\nd\n")
-        parent1 = self.commit_tree([(b"a", blob)])
-        parent2 = self.commit_tree([])
-        merge = self.commit_tree([(b"b", blob)])
-        self.assertChangesForMergeEqual(
+    def _do_test_merge_entries_multi(self, merge_entries_multi) -> None:
+        blob_a1 = make_object(Blob, data=b"a1")
+        blob_a2 = make_object(Blob, data=b"a2")
+        blob_b1 = make_object(Blob, data=b"b1")
+        blob_c2 = make_object(Blob, data=b"c2")
+        blob_c3 = make_object(Blob, data=b"c3")
+        tree1 = self.commit_tree([(b"a", blob_a1), (b"b", blob_b1)])
+        tree2 = self.commit_tree([(b"a", blob_a2), (b"c", blob_c2)])
+        tree3 = self.commit_tree([(b"c", blob_c3)])
+
+        self.assertEqual([], merge_entries_multi(b"", []))
+        self.assertEqual([], merge_entries_multi(b"", [self.empty_tree, None]))
+        self.assertEqual(
+            [
+                (
+                    TreeEntry(b"x/a", F, blob_a1.id),
+                    TreeEntry(b"x/a", F, blob_a2.id),
+                    None,
+                ),
+                (TreeEntry(b"x/b", F, blob_b1.id), None, None),
+                (
+                    None,
+                    TreeEntry(b"x/c", F, blob_c2.id),
+                    TreeEntry(b"x/c", F, blob_c3.id),
+                ),
+            ],
+            merge_entries_multi(b"x", [tree1, tree2, tree3]),
+        )
+        self.assertEqual(
             [
+                (None, TreeEntry(b"a", F, blob_a1.id)),
+                (None, TreeEntry(b"b", F, blob_b1.id)),
+            ],
+            merge_entries_multi(b"", [None, tree1]),
+        )
+        # With two trees this is the same as _merge_entries.
+        self.assertEqual(
+            _merge_entries_py(b"", tree1, tree2),
+            merge_entries_multi(b"", [tree1, tree2]),
+        )
+
+    test_merge_entries_multi = functest_builder(
+        _do_test_merge_entries_multi, _merge_entries_multi_py
+    )
+    test_merge_entries_multi_extension = ext_functest_builder(
+        _do_test_merge_entries_multi, _merge_entries_multi
+    )
+
+    def _do_test_is_tree(self, is_tree) -> None:
+        self.assertFalse(is_tree(None))
+        self.assertFalse(is_tree(TreeEntry(b"a", 0o100644, b"a" * 40)))
+        self.assertFalse(is_tree(TreeEntry(b"a", 0o100755, b"a" * 40)))
+        self.assertFalse(is_tree(TreeEntry(b"a", 0o120000, b"a" * 40)))
+        self.assertTrue(is_tree(TreeEntry(b"a", 0o040000, b"a" * 40)))
+        self.assertFalse(is_tree(TreeEntry(b"a", 0o160000, b"a" * 40)))
+        self.assertRaises(TypeError, is_tree, TreeEntry(b"a", b"x", b"a" * 40))
+        self.assertRaises(AttributeError, is_tree, 1234)
+
+    test_is_tree = functest_builder(_do_test_is_tree, _is_tree_py)
+    test_is_tree_extension = ext_functest_builder(_do_test_is_tree, _is_tree)
+
+    def _do_test_are_trees(self, are_trees) -> None:
+        self.assertEqual([], are_trees([]))
+        self.assertEqual(
+            [False, False, False, True, False],
+            are_trees(
                 [
-                    TreeChange(CHANGE_RENAME, (b"a", F, blob.id), (b"b", F, blob.id)),
-                    TreeChange.add(TreeEntry(b"b", F, blob.id)),
+                    None,
+                    TreeEntry(b"a", None, None),
+                    TreeEntry(b"a", 0o100644, b"a" * 40),
+                    TreeEntry(b"a", 0o040000, b"a" * 40),
+                    TreeEntry(b"a", 0o160000, b"a" * 40),
                 ]
+            ),
+        )
+        self.assertRaises(TypeError, are_trees, [TreeEntry(b"a", b"x", b"a" * 40)])
+
+    test_are_trees = functest_builder(_do_test_are_trees, _are_trees_py)
+    test_are_trees_extension = ext_functest_builder(_do_test_are_trees, _are_trees)
+
+    def _do_test_is_binary(self, is_binary) -> None:
+        self.assertFalse(is_binary(b""))
+        self.assertFalse(is_binary(b"foo\nbar\n"))
+        self.assertTrue(is_binary(b"foo\0bar"))
+        self.assertTrue(is_binary(b"a" * 7999 + b"\0"))
+        self.assertFalse(is_binary(b"a" * 8000 + b"\0"))
+
+    test_is_binary = functest_builder(_do_test_is_binary, _is_binary_py)
+    test_is_binary_extension = ext_functest_builder(_do_test_is_binary, _is_binary)
+
+    def _do_test_is_binary_obj(self, is_binary_obj) -> None:
+        self.assertFalse(is_binary_obj(make_object(Blob, data=b"foo\n")))
+        self.assertTrue(is_binary_obj(make_object(Blob, data=b"foo\0")))
+        blob = ShaFile.from_raw_chunks(
+            Blob.type_num, [b"a" * 5000, b"b" * 2999 + b"\0", b"c"]
+        )
+        self.assertTrue(is_binary_obj(blob))
+        blob = ShaFile.from_raw_chunks(
+            Blob.type_num, [b"a" * 5000, b"b" * 3000 + b"\0", b"\0"]
+        )
+        self.assertFalse(is_binary_obj(blob))
+        blob = make_object(Blob, data=b"foo\0")
+        self.assertFalse(is_binary_obj(blob, force_binary=False))
+        self.assertTrue(is_binary_obj(blob, force_binary=None))
+        blob = make_object(Blob, data=b"foo\n")
+        self.assertTrue(is_binary_obj(blob, force_binary=True))
+        self.assertFalse(is_binary_obj(blob, None))
+
+    test_is_binary_obj = functest_builder(_do_test_is_binary_obj, _is_binary_obj_py)
+    test_is_binary_obj_extension = ext_functest_builder(
+        _do_test_is_binary_obj, _is_binary_obj
+    )
+
+    def _do_test_blob_line_stats(self, blob_line_stats) -> None:
+        old = b"a\nb\nc\nd\n"
+        self.assertEqual((0, 0), blob_line_stats(old, old))
+        self.assertEqual((0, 0), blob_line_stats(None, None))
+        self.assertEqual((4, 0), blob_line_stats(None, old))
+        self.assertEqual((0, 4), blob_line_stats(old, None))
+        self.assertEqual((4, 0), blob_line_stats(b"", old))
+        self.assertEqual((1, 1), blob_line_stats(old, b"a\nx\nc\nd\n"))
+        self.assertEqual((2, 1), blob_line_stats(old, b"a\nc\nd\ne\nf\n"))
+        # A missing newline at the end changes the last line.
+        self.assertEqual((1, 1), blob_line_stats(old, b"a\nb\nc\nd"))
+        self.assertEqual((1, 0), blob_line_stats(b"", b"x"))
+
+    test_blob_line_stats_extension = ext_functest_builder(
+        _do_test_blob_line_stats, _blob_line_stats
+    )
+
+    def _do_test_blob_line_stats_ignore_whitespace(self, blob_line_stats) -> None:
+        old = b"def f():\n    return 1\n"
+        reindented = b"def  f():\n\t\treturn   1 \n"
+        self.assertEqual((2, 2), blob_line_stats(old, reindented))
+        self.assertEqual(
+            (0, 0), blob_line_stats(old, reindented, ignore_whitespace=True)
+        )
+        changed = b"def f():\n  return 2\n"
+        self.assertEqual((1, 1), blob_line_stats(old, changed, ignore_whitespace=True))
+        # Whitespace between words can't be removed altogether.
+        self.assertEqual(
+            (1, 1), blob_line_stats(b"a b\n", b"ab\n", ignore_whitespace=True)
+        )
+        self.assertEqual((2, 0), blob_line_stats(None, old, ignore_whitespace=True))
+
+    test_blob_line_stats_ignore_whitespace_extension = ext_functest_builder(
+        _do_test_blob_line_stats_ignore_whitespace, _blob_line_stats
+    )
+
+    def _do_test_blob_hunks(self, blob_hunks) -> None:
+        old = b"".join(b"%d\n" % i for i in range(10))
+        new = old.replace(b"1\n", b"one\n").replace(b"8\n", b"")
+        self.assertEqual([], blob_hunks(old, old))
+        self.assertEqual([], blob_hunks(None, None))
+        self.assertEqual(
+            [
+                (0, 4, 0, 4, [b" 0\n", b"-1\n", b"+one\n", b" 2\n", b" 3\n"]),
+                (6, 4, 6, 3, [b" 6\n", b" 7\n", b"-8\n", b" 9\n"]),
             ],
-            [parent1, parent2],
-            merge,
-            rename_detector=self.detector,
+            blob_hunks(old, new, context=2),
         )
+        # Changes up to 2 * context lines apart share a hunk.
+        ((old_start, old_len, new_start, new_len, lines),) = blob_hunks(old, new)
+        self.assertEqual((0, 10, 0, 9), (old_start, old_len, new_start, new_len))
+        self.assertEqual(11, len(lines))
+        self.assertEqual(
+            [(1, 1, 1, 1, [b"-1\n", b"+one\n"]), (8, 1, 8, 0, [b"-8\n"])],
+            blob_hunks(old, new, context=0),
+        )
+        self.assertEqual([(0, 0, 0, 2, [b"+a\n", b"+b"])], blob_hunks(None, b"a\nb"))
+        self.assertEqual([(0, 1, 0, 0, [b"-a\n"])], blob_hunks(b"a\n", b""))
 
-    def test_tree_changes_for_merge_add_content_rename_conflict(self) -> None:
-        blob1 = make_object(Blob, data=b"a\nb\nc\nd\n")
-        blob2 = make_object(Blob, data=b"a\nb\nc\ne\n")
-        parent1 = self.commit_tree([(b"a", blob1)])
-        parent2 = self.commit_tree([])
-        merge = self.commit_tree([(b"b", blob2)])
-        self.assertChangesForMergeEqual(
+    test_blob_hunks_extension = ext_functest_builder(_do_test_blob_hunks, _blob_hunks)
+
+    def _do_test_diff_shortstat(self, diff_shortstat) -> None:
+        blob_a1 = make_object(Blob, data=b"a\nb\nc\n")
+        blob_a2 = make_object(Blob, data=b"a\nx\nc\nd\n")
+        blob_b = make_object(Blob, data=b"b\n")
+        blob_c = make_object(Blob, data=b"c\nc\n")
+        binary1 = make_object(Blob, data=b"\0x\ny\n")
+        binary2 = make_object(Blob, data=b"\0z\n")
+        tree1 = self.commit_tree(
+            [
+                (b"a", blob_a1),
+                (b"b", blob_b),
+                (b"bin", binary1),
+                (b"d/e", blob_b),
+                (b"link", blob_b, 0o100644),
+            ]
+        )
+        tree2 = self.commit_tree(
+            [
+                (b"a", blob_a2),
+                (b"bin", binary2),
+                (b"c", blob_c),
+                (b"d/e", blob_b),
+                (b"link", blob_c, 0o120000),
+            ]
+        )
+        # a: +2 -1, b: -1, bin: binary, c: +2, link: +2 -1 (a typechange).
+        self.assertEqual((5, 6, 3), diff_shortstat(self.store, tree1.id, tree2.id))
+        self.assertEqual((5, 3, 6), diff_shortstat(self.store, tree2.id, tree1.id))
+        self.assertEqual((0, 0, 0), diff_shortstat(self.store, tree1.id, tree1.id))
+        self.assertEqual((5, 6, 0), diff_shortstat(self.store, None, tree1.id))
+
+    test_diff_shortstat_extension = ext_functest_builder(
+        _do_test_diff_shortstat, _diff_shortstat
+    )
+
+    def _do_test_name_status(self, name_status) -> None:
+        blob_a1 = make_object(Blob, data=b"a\nb\nc\nd\n")
+        blob_a2 = make_object(Blob, data=b"a\nb\nc\ne\n")
+        blob_b = make_object(Blob, data=b"b\n")
+        blob_c = make_object(Blob, data=b"c\n")
+        tree1 = self.commit_tree(
             [
+                (b"a", blob_a1),
+                (b"b", blob_b),
+                (b"c", blob_c),
+                (b"link", blob_b),
+            ]
+        )
+        tree2 = self.commit_tree(
+            [
+                (b"b", blob_c),
+                (b"d", blob_a2),
+                (b"e", blob_c, 0o100755),
+                (b"link", blob_b, 0o120000),
+            ]
+        )
+        self.assertEqual(
+            [
+                (b"D", b"a", None),
+                (b"M", b"b", b"b"),
+                (b"D", b"c", None),
+                (b"A", None, b"d"),
+                (b"A", None, b"e"),
+                (b"T", b"link", b"link"),
+            ],
+            name_status(self.store, tree1.id, tree2.id),
+        )
+        self.assertEqual(
+            [
+                (b"R075", b"a", b"d"),
+                (b"M", b"b", b"b"),
+                (b"R100", b"c", b"e"),
+                (b"T", b"link", b"link"),
+            ],
+            name_status(self.store, tree1.id, tree2.id, RenameDetector(self.store)),
+        )
+        tree3 = self.commit_tree([(b"a", blob_b), (b"b", blob_c)])
+        self.assertEqual(
+            [(b"A", None, b"a"), (b"A", None, b"b")],
+            name_status(self.store, None, tree3.id),
+        )
+        self.assertEqual([], name_status(self.store, tree1.id, tree1.id))
+
+    test_name_status_extension = ext_functest_builder(
+        _do_test_name_status, _name_status
+    )
+
+    def _do_test_first_parent_changes(self, first_parent_changes) -> None:
+        blob_a = make_object(Blob, data=b"a\n")
+        blob_b1 = make_object(Blob, data=b"1\n2\n3\n4\n")
+        blob_b2 = make_object(Blob, data=b"1\n2\n3\n5\n")
+        blob_c = make_object(Blob, data=b"c\n")
+        parent1 = self.commit_tree([(b"a", blob_a), (b"b", blob_b1)])
+        parent2 = self.commit_tree([(b"a", blob_a), (b"c", blob_c)])
+        merge = self.commit_tree([(b"a", blob_a), (b"b2", blob_b2), (b"c", blob_c)])
+        # Only the changes against the first parent are listed, with renames.
+        self.assertEqual(
+            [(b"R075", b"b", b"b2"), (b"A", None, b"c")],
+            first_parent_changes(self.store, merge.id, parent1.id),
+        )
+        self.assertEqual(
+            [(b"A", None, b"b2")],
+            first_parent_changes(self.store, merge.id, parent2.id),
+        )
+        detector = RenameDetector(self.store, rename_threshold=80)
+        self.assertEqual(
+            [(b"D", b"b", None), (b"A", None, b"b2"), (b"A", None, b"c")],
+            first_parent_changes(self.store, merge.id, parent1.id, detector),
+        )
+
+    test_first_parent_changes_extension = ext_functest_builder(
+        _do_test_first_parent_changes, _first_parent_changes
+    )
+
+    def _do_test_patch_id(self, patch_id_func) -> None:
+        lines = b"".join(b"line %d\n" % i for i in range(20))
+        blob_a1 = make_object(Blob, data=lines)
+        blob_a2 = make_object(Blob, data=lines.replace(b"line 3", b"new 3"))
+        blob_a3 = make_object(Blob, data=lines.replace(b"line 3", b"ne w\t3 "))
+        blob_b = make_object(Blob, data=b"b\r\nc\rd")
+        blob_c = make_object(Blob, data=b"x\ny")
+        blob_d = make_object(Blob, data=b"-- x\nz\n")
+        binary = make_object(Blob, data=b"\0x\n")
+        # Diffs of repeated lines can be aligned more than one way, and
+        # lines repeated often enough in a long file are not matched alone.
+        blob_r1 = make_object(Blob, data=b"a\n")
+        blob_r2 = make_object(Blob, data=b"b\na\na\n")
+        popular = b"".join(b"x\n" if i % 3 else b"y %d\n" % i for i in range(300))
+        blob_p1 = make_object(Blob, data=popular)
+        blob_p2 = make_object(Blob, data=b"x\n" + popular.replace(b"y 9\n", b""))
+        trees = [
+            self.commit_tree(
                 [
-                    TreeChange(CHANGE_RENAME, (b"a", F, blob1.id), (b"b", F, blob2.id)),
-                    TreeChange.add(TreeEntry(b"b", F, blob2.id)),
+                    (b"a", blob_a1),
+                    (b"bin", binary),
+                    (b"c", blob_c),
+                    (b"p", blob_p1),
+                    (b"r", blob_r1),
                 ]
+            ),
+            self.commit_tree([(b"a", blob_a2), (b"b", blob_b), (b"c", blob_d)]),
+            self.commit_tree([(b"a", blob_a3), (b"b", blob_b), (b"c", blob_d)]),
+            self.commit_tree(
+                [(b"b", blob_c), (b"c", blob_c), (b"p", blob_p2), (b"r", blob_r2)]
+            ),
+        ]
+        for old, new in permutations(trees + [None], 2):
+            old_id = old.id if old else None
+            new_id = new.id if new else None
+            changes = list(tree_changes(self.store, old_id, new_id))
+            f = BytesIO()
+            write_tree_diff(f, self.store, old_id, new_id)
+            expected = patch_id(f.getvalue())
+            self.assertEqual(expected, patch_id_func(changes, self.store))
+        # Only whitespace in changed lines differs between the second and
+        # third trees.
+        changes1 = list(tree_changes(self.store, trees[0].id, trees[1].id))
+        changes2 = list(tree_changes(self.store, trees[0].id, trees[2].id))
+        self.assertNotEqual(changes1, changes2)
+        self.assertEqual(
+            patch_id_func(changes1, self.store), patch_id_func(changes2, self.store)
+        )
+        self.assertEqual(
+            b"da39a3ee5e6b4b0d3255bfef95601890afd80709", patch_id_func([], self.store)
+        )
+
+    test_patch_id_extension = ext_functest_builder(_do_test_patch_id, _patch_id)
+
+    def _do_test_change_content_id(self, change_content_id) -> None:
+        blob_a1 = make_object(Blob, data=b"a\nb\nc\nd\n")
+        blob_a2 = make_object(Blob, data=b"a\nB\nc\nD\n")
+        blob_b1 = make_object(Blob, data=b"x\nd\ny\nb\n")
+        blob_b2 = make_object(Blob, data=b"x\nD\ny\nB\n")
+        for blob in (blob_a1, blob_a2, blob_b1, blob_b2):
+            self.store.add_object(blob)
+
+        def modify(path, blob1, blob2):
+            return TreeChange(
+                CHANGE_MODIFY,
+                TreeEntry(path, F, blob1.id),
+                TreeEntry(path, F, blob2.id),
+            )
+
+        content_id = change_content_id(modify(b"a", blob_a1, blob_a2), self.store)
+        self.assertEqual(_stable_hash(b"-b\n-d\n+B\n+D\n"), content_id)
+        # The same edit to another file in a different order.
+        self.assertEqual(
+            content_id,
+            change_content_id(modify(b"b", blob_b1, blob_b2), self.store),
+        )
+        # Reverting the edit is a different change.
+        self.assertNotEqual(
+            content_id,
+            change_content_id(modify(b"a", blob_a2, blob_a1), self.store),
+        )
+        add = TreeChange.add(TreeEntry(b"a", F, blob_a1.id))
+        delete = TreeChange.delete(TreeEntry(b"a", F, blob_a1.id))
+        self.assertEqual(
+            _stable_hash(b"+a\n+b\n+c\n+d\n"), change_content_id(add, self.store)
+        )
+        self.assertNotEqual(
+            change_content_id(add, self.store), change_content_id(delete, self.store)
+        )
+        self.assertEqual(
+            _stable_hash(b""),
+            change_content_id(modify(b"a", blob_a1, blob_a1), self.store),
+        )
+
+    test_change_content_id = functest_builder(
+        _do_test_change_content_id, _change_content_id_py
+    )
+    test_change_content_id_extension = ext_functest_builder(
+        _do_test_change_content_id, _change_content_id
+    )
+
+    def _do_test_is_blob(self, is_blob) -> None:
+        self.assertFalse(is_blob(None))
+        self.assertFalse(is_blob(TreeEntry(b"a", None, None)))
+        self.assertTrue(is_blob(TreeEntry(b"a", 0o100644, b"a" * 40)))
+        self.assertTrue(is_blob(TreeEntry(b"a", 0o100755, b"a" * 40)))
+        self.assertTrue(is_blob(TreeEntry(b"a", 0o120000, b"a" * 40)))
+        self.assertFalse(is_blob(TreeEntry(b"a", 0o040000, b"a" * 40)))
+        self.assertFalse(is_blob(TreeEntry(b"a", 0o160000, b"a" * 40)))
+        self.assertRaises(TypeError, is_blob, TreeEntry(b"a", b"x", b"a" * 40))
+        self.assertRaises(AttributeError, is_blob, 1234)
+
+    test_is_blob = functest_builder(_do_test_is_blob, _is_blob_py)
+    test_is_blob_extension = ext_functest_builder(_do_test_is_blob, _is_blob)
+
+    def _do_test_is_gitlink(self, is_gitlink) -> None:
+        self.assertFalse(is_gitlink(None))
+        self.assertFalse(is_gitlink(TreeEntry(b"a", None, None)))
+        self.assertFalse(is_gitlink(TreeEntry(b"a", 0o100644, b"a" * 40)))
+        self.assertFalse(is_gitlink(TreeEntry(b"a", 0o120000, b"a" * 40)))
+        self.assertFalse(is_gitlink(TreeEntry(b"a", 0o040000, b"a" * 40)))
+        self.assertTrue(is_gitlink(TreeEntry(b"a", 0o160000, b"a" * 40)))
+        self.assertRaises(TypeError, is_gitlink, TreeEntry(b"a", b"x", b"a" * 40))
+        self.assertRaises(AttributeError, is_gitlink, 1234)
+
+    test_is_gitlink = functest_builder(_do_test_is_gitlink, _is_gitlink_py)
+    test_is_gitlink_extension = ext_functest_builder(
+        _do_test_is_gitlink, _is_gitlink
+    )
+
+    def assertChangesEqual(self, expected, tree1, tree2, **kwargs) -> None:
+        actual = list(tree_changes(self.store, tree1.id, tree2.id, **kwargs))
+        self.assertEqual(expected, actual)
+
+    # For brevity, the following tests use tuples instead of TreeEntry objects.
+
+    def test_tree_changes_empty(self) -> None:
+        self.assertChangesEqual([], self.empty_tree, self.empty_tree)
+        self.assertChangesEqual(
+            [], self.empty_tree, self.empty_tree, include_trees=True
+        )
+
+    def test_tree_changes_from_empty_tree_include_trees(self) -> None:
+        blob = make_object(Blob, data=b"a")
+        tree = self.commit_tree([(b"a", blob)])
+        # Unlike against no tree, the root is modified rather than added.
+        self.assertChangesEqual(
+            [
+                TreeChange(
+                    CHANGE_MODIFY,
+                    (b"", 0o040000, self.empty_tree.id),
+                    (b"", 0o040000, tree.id),
+                ),
+                TreeChange.add((b"a", F, blob.id)),
             ],
-            [parent1, parent2],
-            merge,
+            self.empty_tree,
+            tree,
+            include_trees=True,
+        )
+
+    def test_tree_changes_no_changes(self) -> None:
+        blob = make_object(Blob, data=b"blob")
+        tree = self.commit_tree([(b"a", blob), (b"b/c", blob)])
+        self.assertChangesEqual([], self.empty_tree, self.empty_tree)
+        self.assertChangesEqual([], tree, tree)
+        self.assertChangesEqual(
+            [
+                TreeChange(CHANGE_UNCHANGED, (b"a", F, blob.id), (b"a", F, blob.id)),
+                TreeChange(
+                    CHANGE_UNCHANGED,
+                    (b"b/c", F, blob.id),
+                    (b"b/c", F, blob.id),
+                ),
+            ],
+            tree,
+            tree,
+            want_unchanged=True,
+        )
+
+    def test_tree_changes_add_delete(self) -> None:
+        blob_a = make_object(Blob, data=b"a")
+        blob_b = make_object(Blob, data=b"b")
+        tree = self.commit_tree([(b"a", blob_a, 0o100644), (b"x/b", blob_b, 0o100755)])
+        self.assertChangesEqual(
+            [
+                TreeChange.add(TreeEntry(b"a", 0o100644, blob_a.id)),
+                TreeChange.add(TreeEntry(b"x/b", 0o100755, blob_b.id)),
+            ],
+            self.empty_tree,
+            tree,
+        )
+        self.assertChangesEqual(
+            [
+                TreeChange.delete(TreeEntry(b"a", 0o100644, blob_a.id)),
+                TreeChange.delete(TreeEntry(b"x/b", 0o100755, blob_b.id)),
+            ],
+            tree,
+            self.empty_tree,
+        )
+
+    def test_tree_changes_modify_contents(self) -> None:
+        blob_a1 = make_object(Blob, data=b"a1")
+        blob_a2 = make_object(Blob, data=b"a2")
+        tree1 = self.commit_tree([(b"a", blob_a1)])
+        tree2 = self.commit_tree([(b"a", blob_a2)])
+        self.assertChangesEqual(
+            [TreeChange(CHANGE_MODIFY, (b"a", F, blob_a1.id), (b"a", F, blob_a2.id))],
+            tree1,
+            tree2,
+        )
+
+    def test_tree_changes_modify_mode(self) -> None:
+        blob_a = make_object(Blob, data=b"a")
+        tree1 = self.commit_tree([(b"a", blob_a, 0o100644)])
+        tree2 = self.commit_tree([(b"a", blob_a, 0o100755)])
+        self.assertChangesEqual(
+            [
+                TreeChange(
+                    CHANGE_MODIFY,
+                    (b"a", 0o100644, blob_a.id),
+                    (b"a", 0o100755, blob_a.id),
+                )
+            ],
+            tree1,
+            tree2,
+        )
+
+    def test_tree_changes_change_type(self) -> None:
+        blob_a1 = make_object(Blob, data=b"a")
+        blob_a2 = make_object(Blob, data=b"/foo/bar")
+        tree1 = self.commit_tree([(b"a", blob_a1, 0o100644)])
+        tree2 = self.commit_tree([(b"a", blob_a2, 0o120000)])
+        self.assertChangesEqual(
+            [
+                TreeChange.delete(TreeEntry(b"a", 0o100644, blob_a1.id)),
+                TreeChange.add(TreeEntry(b"a", 0o120000, blob_a2.id)),
+            ],
+            tree1,
+            tree2,
+        )
+
+    def test_tree_changes_change_type_same(self) -> None:
+        blob_a1 = make_object(Blob, data=b"a")
+        blob_a2 = make_object(Blob, data=b"/foo/bar")
+        tree1 = self.commit_tree([(b"a", blob_a1, 0o100644)])
+        tree2 = self.commit_tree([(b"a", blob_a2, 0o120000)])
+        self.assertChangesEqual(
+            [
+                TreeChange(
+                    CHANGE_MODIFY,
+                    (b"a", 0o100644, blob_a1.id),
+                    (b"a", 0o120000, blob_a2.id),
+                )
+            ],
+            tree1,
+            tree2,
+            change_type_same=True,
+        )
+
+    def test_tree_changes_to_tree(self) -> None:
+        blob_a = make_object(Blob, data=b"a")
+        blob_x = make_object(Blob, data=b"x")
+        tree1 = self.commit_tree([(b"a", blob_a)])
+        tree2 = self.commit_tree([(b"a/x", blob_x)])
+        self.assertChangesEqual(
+            [
+                TreeChange.delete(TreeEntry(b"a", F, blob_a.id)),
+                TreeChange.add(TreeEntry(b"a/x", F, blob_x.id)),
+            ],
+            tree1,
+            tree2,
+        )
+
+    def test_tree_changes_gitlink(self) -> None:
+        # Submodule commits are not in the store, so they must not be walked.
+        sha1 = b"1" * 40
+        sha2 = b"2" * 40
+        tree1 = self.commit_tree([(b"sub", sha1, 0o160000)])
+        tree2 = self.commit_tree([(b"sub", sha2, 0o160000)])
+        self.assertChangesEqual(
+            [
+                TreeChange(
+                    CHANGE_MODIFY, (b"sub", 0o160000, sha1), (b"sub", 0o160000, sha2)
+                )
+            ],
+            tree1,
+            tree2,
+        )
+
+    def test_tree_changes_name_collisions(self) -> None:
+        blob = make_object(Blob, data=b"blob")
+        tree1 = self.commit_tree([(b"foo-bar", blob), (b"foo.txt", blob)])
+        tree2 = self.commit_tree(
+            [(b"foo/x", blob), (b"foo-bar", blob), (b"foo.txt", blob)]
+        )
+        self.assertChangesEqual(
+            [TreeChange.add(TreeEntry(b"foo/x", F, blob.id))], tree1, tree2
+        )
+        self.assertChangesEqual(
+            [TreeChange.delete(TreeEntry(b"foo/x", F, blob.id))], tree2, tree1
+        )
+
+    def test_tree_changes_complex(self) -> None:
+        blob_a_1 = make_object(Blob, data=b"a1_1")
+        blob_bx1_1 = make_object(Blob, data=b"bx1_1")
+        blob_bx2_1 = make_object(Blob, data=b"bx2_1")
+        blob_by1_1 = make_object(Blob, data=b"by1_1")
+        blob_by2_1 = make_object(Blob, data=b"by2_1")
+        tree1 = self.commit_tree(
+            [
+                (b"a", blob_a_1),
+                (b"b/x/1", blob_bx1_1),
+                (b"b/x/2", blob_bx2_1),
+                (b"b/y/1", blob_by1_1),
+                (b"b/y/2", blob_by2_1),
+            ]
+        )
+
+        blob_a_2 = make_object(Blob, data=b"a1_2")
+        blob_bx1_2 = blob_bx1_1
+        blob_by_2 = make_object(Blob, data=b"by_2")
+        blob_c_2 = make_object(Blob, data=b"c_2")
+        tree2 = self.commit_tree(
+            [
+                (b"a", blob_a_2),
+                (b"b/x/1", blob_bx1_2),
+                (b"b/y", blob_by_2),
+                (b"c", blob_c_2),
+            ]
+        )
+
+        self.assertChangesEqual(
+            [
+                TreeChange(
+                    CHANGE_MODIFY,
+                    (b"a", F, blob_a_1.id),
+                    (b"a", F, blob_a_2.id),
+                ),
+                TreeChange.delete(TreeEntry(b"b/x/2", F, blob_bx2_1.id)),
+                TreeChange.add(TreeEntry(b"b/y", F, blob_by_2.id)),
+                TreeChange.delete(TreeEntry(b"b/y/1", F, blob_by1_1.id)),
+                TreeChange.delete(TreeEntry(b"b/y/2", F, blob_by2_1.id)),
+                TreeChange.add(TreeEntry(b"c", F, blob_c_2.id)),
+            ],
+            tree1,
+            tree2,
+        )
+
+    def test_tree_changes_name_order(self) -> None:
+        blob = make_object(Blob, data=b"a")
+        tree1 = self.commit_tree([(b"a", blob), (b"a.", blob), (b"a..", blob)])
+        # Tree order is the reverse of this, so if we used tree order, 'a..'
+        # would not be merged.
+        tree2 = self.commit_tree([(b"a/x", blob), (b"a./x", blob), (b"a..", blob)])
+
+        self.assertChangesEqual(
+            [
+                TreeChange.delete(TreeEntry(b"a", F, blob.id)),
+                TreeChange.add(TreeEntry(b"a/x", F, blob.id)),
+                TreeChange.delete(TreeEntry(b"a.", F, blob.id)),
+                TreeChange.add(TreeEntry(b"a./x", F, blob.id)),
+            ],
+            tree1,
+            tree2,
+        )
+
+    def test_tree_changes_prune(self) -> None:
+        blob_a1 = make_object(Blob, data=b"a1")
+        blob_a2 = make_object(Blob, data=b"a2")
+        blob_x = make_object(Blob, data=b"x")
+        tree1 = self.commit_tree([(b"a", blob_a1), (b"b/x", blob_x)])
+        tree2 = self.commit_tree([(b"a", blob_a2), (b"b/x", blob_x)])
+        # Remove identical items so lookups will fail unless we prune.
+        subtree = self.store[tree1[b"b"][1]]
+        for entry in subtree.items():
+            del self.store[entry.sha]
+        del self.store[subtree.id]
+
+        self.assertChangesEqual(
+            [TreeChange(CHANGE_MODIFY, (b"a", F, blob_a1.id), (b"a", F, blob_a2.id))],
+            tree1,
+            tree2,
+        )
+
+    def _do_test_tree_changes_walk(self, tree_changes) -> None:
+        blob_a1 = make_object(Blob, data=b"a1")
+        blob_a2 = make_object(Blob, data=b"a2")
+        blob_x = make_object(Blob, data=b"x")
+        blob_y = make_object(Blob, data=b"y")
+        tree1 = self.commit_tree(
+            [(b"a", blob_a1), (b"b/x", blob_x), (b"c", blob_y), (b"d/y", blob_y)]
+        )
+        tree2 = self.commit_tree(
+            [(b"a", blob_a2), (b"b/x", blob_x), (b"c/y", blob_y), (b"d", blob_x)]
+        )
+        b_id = tree1[b"b"][1]
+        c_id = tree2[b"c"][1]
+        d_id = tree1[b"d"][1]
+
+        def changes(**kwargs):
+            return list(tree_changes(self.store, tree1.id, tree2.id, **kwargs))
+
+        self.assertEqual(
+            [
+                TreeChange(CHANGE_MODIFY, (b"a", F, blob_a1.id), (b"a", F, blob_a2.id)),
+                TreeChange.delete((b"c", F, blob_y.id)),
+                TreeChange.add((b"c/y", F, blob_y.id)),
+                TreeChange.add((b"d", F, blob_x.id)),
+                TreeChange.delete((b"d/y", F, blob_y.id)),
+            ],
+            changes(),
+        )
+        self.assertEqual(
+            [
+                TreeChange(
+                    CHANGE_MODIFY, (b"", 0o40000, tree1.id), (b"", 0o40000, tree2.id)
+                ),
+                TreeChange(CHANGE_MODIFY, (b"a", F, blob_a1.id), (b"a", F, blob_a2.id)),
+                TreeChange.delete((b"c", F, blob_y.id)),
+                TreeChange.add((b"c", 0o40000, c_id)),
+                TreeChange.add((b"c/y", F, blob_y.id)),
+                TreeChange.delete((b"d", 0o40000, d_id)),
+                TreeChange.add((b"d", F, blob_x.id)),
+                TreeChange.delete((b"d/y", F, blob_y.id)),
+            ],
+            changes(include_trees=True),
+        )
+        self.assertEqual(
+            [
+                TreeChange(CHANGE_MODIFY, (b"c", F, blob_y.id), (b"c", 0o40000, c_id)),
+                TreeChange.add((b"c/y", F, blob_y.id)),
+            ],
+            changes(include_trees=True, change_type_same=True, paths=[b"c"]),
+        )
+        self.assertEqual(
+            [
+                TreeChange(
+                    CHANGE_UNCHANGED, (b"b/x", F, blob_x.id), (b"b/x", F, blob_x.id)
+                )
+            ],
+            changes(want_unchanged=True, paths=[b"b/x"]),
+        )
+        self.assertEqual(
+            [
+                TreeChange(
+                    CHANGE_UNCHANGED, (b"b", 0o40000, b_id), (b"b", 0o40000, b_id)
+                ),
+                TreeChange(
+                    CHANGE_UNCHANGED, (b"b/x", F, blob_x.id), (b"b/x", F, blob_x.id)
+                ),
+            ],
+            changes(want_unchanged=True, include_trees=True, paths=[b"b"]),
+        )
+        self.assertEqual(
+            [TreeChange.add((b"a", F, blob_a2.id))],
+            list(tree_changes(self.store, None, tree2.id, paths=[b"a"])),
+        )
+
+    test_tree_changes_walk = functest_builder(
+        _do_test_tree_changes_walk, _tree_changes_py
+    )
+    test_tree_changes_walk_extension = ext_functest_builder(
+        _do_test_tree_changes_walk, _tree_changes
+    )
+
+    def _do_test_tree_changes_prune_deep(self, tree_changes) -> None:
+        blob1 = make_object(Blob, data=b"1")
+        blob2 = make_object(Blob, data=b"2")
+        siblings = [(b"a/b/c/y", blob1), (b"a/b/x/y", blob1), (b"a/z/y", blob1)]
+        tree1 = self.commit_tree([(b"a/b/c/d", blob1), *siblings])
+        tree2 = self.commit_tree([(b"a/b/c/d", blob2), *siblings])
+        store = self.store
+        looked_up = []
+
+        class RecordingStore:
+            def __getitem__(self, sha):
+                looked_up.append(sha)
+                return store[sha]
+
+        def lookups(**kwargs):
+            del looked_up[:]
+            changes = tree_changes(RecordingStore(), tree1.id, tree2.id, **kwargs)
+            return list(changes), len(looked_up)
+
+        changes, count = lookups()
+        self.assertEqual(
+            [
+                TreeChange(
+                    CHANGE_MODIFY, (b"a/b/c/d", F, blob1.id), (b"a/b/c/d", F, blob2.id)
+                )
+            ],
+            changes,
+        )
+        # Only the pairs of trees on the way to the changed file are looked
+        # up, and none of the identical sibling subtrees.
+        self.assertEqual(8, count)
+        self.assertNotIn(tree1.lookup_path(store.__getitem__, b"a/z")[1], looked_up)
+        # Unchanged entries are wanted, so nothing can be pruned, but the
+        # identical subtrees are only looked up once.
+        changes, count = lookups(want_unchanged=True)
+        self.assertEqual(4, len(changes))
+        self.assertEqual(9, count)
+        # Each pair of identical subtrees is looked up one after the other.
+        self.assertEqual(9, lookups(want_unchanged=True, tree_cache_size=1)[1])
+        self.assertEqual(12, lookups(want_unchanged=True, tree_cache_size=0)[1])
+
+    test_tree_changes_prune_deep = functest_builder(
+        _do_test_tree_changes_prune_deep, _tree_changes_py
+    )
+    test_tree_changes_prune_deep_extension = ext_functest_builder(
+        _do_test_tree_changes_prune_deep, _tree_changes
+    )
+
+    def _do_test_tree_changes_incremental(self, tree_changes_incremental) -> None:
+        blob1 = make_object(Blob, data=b"1")
+        blob2 = make_object(Blob, data=b"2")
+        tree1 = self.commit_tree(
+            [(b"a/x", blob1), (b"a/y/z", blob2), (b"b/x", blob1), (b"c", blob1)]
+        )
+        tree2 = self.commit_tree(
+            [
+                (b"a/x", blob2),
+                (b"a/y/z", blob2),
+                (b"b/x", blob2),
+                (b"c", blob2),
+                (b"d/e", blob1),
+            ]
+        )
+        store = self.store
+        looked_up = []
+
+        class RecordingStore:
+            def __getitem__(self, sha):
+                looked_up.append(sha)
+                return store[sha]
+
+        def changes(prefix):
+            del looked_up[:]
+            return list(
+                tree_changes_incremental(RecordingStore(), tree1.id, tree2.id, prefix)
+            )
+
+        modify_a = TreeChange(
+            CHANGE_MODIFY, (b"a/x", F, blob1.id), (b"a/x", F, blob2.id)
+        )
+        self.assertEqual([modify_a], changes(b"a"))
+        # The changes elsewhere aren't looked for.
+        self.assertNotIn(tree1.lookup_path(store.__getitem__, b"b")[1], looked_up)
+        self.assertEqual([modify_a], changes(b"a/"))
+        self.assertEqual([], changes(b"a/y"))
+        self.assertEqual([TreeChange.add((b"d/e", F, blob1.id))], changes(b"d"))
+        self.assertEqual(
+            [TreeChange(CHANGE_MODIFY, (b"c", F, blob1.id), (b"c", F, blob2.id))],
+            changes(b"c"),
+        )
+        self.assertEqual(4, len(changes(b"")))
+
+    test_tree_changes_incremental = functest_builder(
+        _do_test_tree_changes_incremental, _tree_changes_incremental_py
+    )
+    test_tree_changes_incremental_extension = ext_functest_builder(
+        _do_test_tree_changes_incremental, _tree_changes_incremental
+    )
+
+    def _do_test_tree_changes_tree_cache(self, tree_changes) -> None:
+        blob1 = make_object(Blob, data=b"1")
+        blob2 = make_object(Blob, data=b"2")
+        blob3 = make_object(Blob, data=b"3")
+        # The subtree with blob1 moves from a to b.
+        tree1 = self.commit_tree([(b"a/x", blob1), (b"b/x", blob2)])
+        tree2 = self.commit_tree([(b"a/x", blob3), (b"b/x", blob1)])
+        store = self.store
+        looked_up = []
+
+        class RecordingStore:
+            def __getitem__(self, sha):
+                looked_up.append(sha)
+                return store[sha]
+
+        def lookups(tree_cache_size):
+            del looked_up[:]
+            changes = tree_changes(
+                RecordingStore(), tree1.id, tree2.id, tree_cache_size=tree_cache_size
+            )
+            self.assertEqual(2, len(list(changes)))
+            return len(looked_up)
+
+        self.assertEqual(6, lookups(0))
+        self.assertEqual(5, lookups(256))
+        # The least recently used trees are evicted first.
+        self.assertEqual(5, lookups(3))
+        self.assertEqual(6, lookups(2))
+
+    test_tree_changes_tree_cache = functest_builder(
+        _do_test_tree_changes_tree_cache, _tree_changes_py
+    )
+    test_tree_changes_tree_cache_extension = ext_functest_builder(
+        _do_test_tree_changes_tree_cache, _tree_changes
+    )
+
+    def _do_test_tree_changes_from_empty(self, tree_changes) -> None:
+        blob_a = make_object(Blob, data=b"a")
+        blob_b = make_object(Blob, data=b"b")
+        tree = self.commit_tree(
+            [(b"a", blob_a), (b"b/c", blob_b), (b"b/d/e", blob_a), (b"f", blob_b)]
+        )
+        subtree_b = tree[b"b"][1]
+        subtree_d = self.store[subtree_b][b"d"][1]
+        adds = [
+            TreeChange.add((b"a", F, blob_a.id)),
+            TreeChange.add((b"b/c", F, blob_b.id)),
+            TreeChange.add((b"b/d/e", F, blob_a.id)),
+            TreeChange.add((b"f", F, blob_b.id)),
+        ]
+        store = self.store
+        looked_up = []
+
+        class RecordingStore:
+            def __getitem__(self, sha):
+                looked_up.append(sha)
+                return store[sha]
+
+        # No first tree is looked up.
+        self.assertEqual(adds, list(tree_changes(RecordingStore(), None, tree.id)))
+        self.assertEqual([tree.id, subtree_b, subtree_d], looked_up)
+        self.assertEqual(adds, list(tree_changes(self.store, Tree().id, tree.id)))
+        self.assertEqual(
+            [
+                TreeChange.add((b"", 0o040000, tree.id)),
+                adds[0],
+                TreeChange.add((b"b", 0o040000, subtree_b)),
+                adds[1],
+                TreeChange.add((b"b/d", 0o040000, subtree_d)),
+                adds[2],
+                adds[3],
+            ],
+            list(tree_changes(self.store, None, tree.id, include_trees=True)),
+        )
+        self.assertEqual([], list(tree_changes(self.store, None, None)))
+        # The adds are the same as when the trees are merged.
+        empty = self.commit_tree([])
+        self.assertEqual(
+            adds[1:3], list(tree_changes(self.store, empty.id, tree.id, paths=[b"b"]))
+        )
+
+    test_tree_changes_from_empty = functest_builder(
+        _do_test_tree_changes_from_empty, _tree_changes_py
+    )
+    test_tree_changes_from_empty_extension = ext_functest_builder(
+        _do_test_tree_changes_from_empty, _tree_changes
+    )
+
+    def _do_test_tree_changes_raw(self, tree_changes) -> None:
+        blob_a1 = make_object(Blob, data=b"a1")
+        blob_a2 = make_object(Blob, data=b"a2")
+        blob_x = make_object(Blob, data=b"x\ny\nz\n")
+        tree1 = self.commit_tree(
+            [(b"a", blob_a1), (b"b", blob_x), (b"c", blob_a1), (b"d/e", blob_a1)]
+        )
+        tree2 = self.commit_tree(
+            [
+                (b"a", blob_a2),
+                (b"c", blob_a1, 0o120000),
+                (b"d/e", blob_a1),
+                (b"f", blob_x),
+            ]
+        )
+
+        def check_raw(**kwargs):
+            changes = list(tree_changes(self.store, tree1.id, tree2.id, **kwargs))
+            raw_changes = list(
+                tree_changes(self.store, tree1.id, tree2.id, raw=True, **kwargs)
+            )
+            self.assertEqual(changes, raw_changes)
+            for change in raw_changes:
+                self.assertIs(tuple, type(change))
+                for entry in change[1:]:
+                    self.assertIn(type(entry), (tuple, type(None)))
+            return raw_changes
+
+        self.assertEqual(
+            [
+                ("modify", (b"a", F, blob_a1.id), (b"a", F, blob_a2.id)),
+                ("delete", (b"b", F, blob_x.id), None),
+                ("delete", (b"c", F, blob_a1.id), None),
+                ("add", None, (b"c", 0o120000, blob_a1.id)),
+                ("add", None, (b"f", F, blob_x.id)),
+            ],
+            check_raw(),
+        )
+        self.assertEqual(8, len(check_raw(want_unchanged=True, include_trees=True)))
+        self.assertEqual(2, len(check_raw(change_type_same=True, paths=[b"a", b"c"])))
+        renames = check_raw(rename_detector=RenameDetector(self.store))
+        self.assertIn(("rename", (b"b", F, blob_x.id), (b"f", F, blob_x.id)), renames)
+        self.assertEqual(
+            [("add", None, (b"a", F, blob_a1.id))],
+            list(
+                tree_changes(
+                    self.store, None, self.commit_tree([(b"a", blob_a1)]).id, raw=True
+                )
+            ),
+        )
+
+    test_tree_changes_raw = functest_builder(
+        _do_test_tree_changes_raw, _tree_changes_py
+    )
+    test_tree_changes_raw_extension = ext_functest_builder(
+        _do_test_tree_changes_raw, _tree_changes
+    )
+
+    def _do_test_tree_changes_max_depth(self, tree_changes) -> None:
+        blob_a1 = make_object(Blob, data=b"a1")
+        blob_a2 = make_object(Blob, data=b"a2")
+        tree1 = self.commit_tree(
+            [(b"a", blob_a1), (b"b/c", blob_a1), (b"b/d/e", blob_a1), (b"h", blob_a1)]
+        )
+        tree2 = self.commit_tree(
+            [
+                (b"a", blob_a2),
+                (b"b/c", blob_a2),
+                (b"b/d/e", blob_a2),
+                (b"f/g", blob_a1),
+                (b"h", blob_a1),
+            ]
+        )
+        b1, b2 = tree1[b"b"][1], tree2[b"b"][1]
+        d1, d2 = self.store[b1][b"d"][1], self.store[b2][b"d"][1]
+        f = tree2[b"f"][1]
+        modify_a = TreeChange(
+            CHANGE_MODIFY, (b"a", F, blob_a1.id), (b"a", F, blob_a2.id)
+        )
+
+        def changes(tree1_id, **kwargs):
+            return list(tree_changes(self.store, tree1_id, tree2.id, **kwargs))
+
+        # Differing subtrees that aren't recursed into are reported as a
+        # whole, even without include_trees.
+        self.assertEqual(
+            [
+                modify_a,
+                TreeChange(CHANGE_MODIFY, (b"b", 0o040000, b1), (b"b", 0o040000, b2)),
+                TreeChange.add((b"f", 0o040000, f)),
+            ],
+            changes(tree1.id, max_depth=0),
+        )
+        self.assertEqual(
+            [
+                modify_a,
+                TreeChange(
+                    CHANGE_MODIFY, (b"b/c", F, blob_a1.id), (b"b/c", F, blob_a2.id)
+                ),
+                TreeChange(
+                    CHANGE_MODIFY, (b"b/d", 0o040000, d1), (b"b/d", 0o040000, d2)
+                ),
+                TreeChange.add((b"f/g", F, blob_a1.id)),
+            ],
+            changes(tree1.id, max_depth=1),
+        )
+        self.assertEqual(changes(tree1.id), changes(tree1.id, max_depth=2))
+        self.assertEqual(
+            [(b"a", F), (b"b", 0o040000), (b"f", 0o040000), (b"h", F)],
+            [
+                (change.new.path, change.new.mode)
+                for change in changes(tree2.id, max_depth=0, want_unchanged=True)
+            ],
+        )
+        self.assertEqual(
+            [
+                TreeChange.add((b"a", F, blob_a2.id)),
+                TreeChange.add((b"b", 0o040000, b2)),
+                TreeChange.add((b"f", 0o040000, f)),
+                TreeChange.add((b"h", F, blob_a1.id)),
+            ],
+            changes(None, max_depth=0),
+        )
+        self.assertRaises(
+            ValueError,
+            changes,
+            tree1.id,
+            rename_detector=RenameDetector(self.store),
+            max_depth=0,
+        )
+
+    test_tree_changes_max_depth = functest_builder(
+        _do_test_tree_changes_max_depth, _tree_changes_py
+    )
+    test_tree_changes_max_depth_extension = ext_functest_builder(
+        _do_test_tree_changes_max_depth, _tree_changes
+    )
+
+    def _do_test_changed_paths(self, changed_paths) -> None:
+        blob_a1 = make_object(Blob, data=b"a1")
+        blob_a2 = make_object(Blob, data=b"a2")
+        blob_x = make_object(Blob, data=b"x")
+        tree1 = self.commit_tree(
+            [
+                (b"a", blob_a1),
+                (b"b/x", blob_x),
+                (b"b/y", blob_x),
+                (b"c", blob_x),
+                (b"d", blob_x),
+                (b"e/x", blob_x),
+            ]
+        )
+        tree2 = self.commit_tree(
+            [
+                (b"a", blob_a2),
+                (b"b/x", blob_x),
+                (b"b/z", blob_x),
+                (b"c", blob_x, 0o100755),
+                (b"d/x", blob_x),
+                (b"e/x", blob_x),
+                (b"f", blob_x),
+            ]
+        )
+
+        expected = [b"a", b"b/y", b"b/z", b"c", b"d", b"d/x", b"f"]
+        self.assertEqual(expected, changed_paths(self.store, tree1, tree2))
+        self.assertEqual(
+            expected,
+            [
+                (change.new or change.old).path
+                for change in tree_changes(self.store, tree1.id, tree2.id)
+            ],
+        )
+        self.assertEqual([], changed_paths(self.store, tree1, tree1))
+        subtree = self.store[tree2[b"b"][1]]
+        self.assertEqual(
+            [b"x/x", b"x/z"], changed_paths(self.store, None, subtree, b"x")
+        )
+        self.assertEqual([b"x", b"z"], changed_paths(self.store, subtree, None))
+
+    test_changed_paths = functest_builder(_do_test_changed_paths, _changed_paths_py)
+    test_changed_paths_extension = ext_functest_builder(
+        _do_test_changed_paths, _changed_paths
+    )
+
+    def _do_test_trees_content_equal(self, trees_content_equal) -> None:
+        blob_a = make_object(Blob, data=b"a")
+        blob_b = make_object(Blob, data=b"b")
+        tree1 = self.commit_tree([(b"a", blob_a), (b"d/x", blob_a), (b"d/y", blob_b)])
+        tree2 = self.commit_tree(
+            [(b"a", blob_a, 0o100755), (b"d/x", blob_a, 0o100755), (b"d/y", blob_b)]
+        )
+        store = self.store
+        looked_up = []
+
+        class RecordingStore:
+            def __getitem__(self, sha):
+                looked_up.append(sha)
+                return store[sha]
+
+        self.assertTrue(trees_content_equal(tree1, tree1))
+        self.assertFalse(trees_content_equal(tree1, tree2))
+        # Without a store, subtrees that only differ in modes still differ.
+        self.assertFalse(trees_content_equal(tree1, tree2, ignore_mode=True))
+        self.assertTrue(
+            trees_content_equal(tree1, tree2, ignore_mode=True, store=RecordingStore())
+        )
+        self.assertEqual([tree1[b"d"][1], tree2[b"d"][1]], looked_up)
+
+        # Comparing stops at the first difference.
+        del looked_up[:]
+        tree3 = self.commit_tree([(b"a", blob_b), (b"d/x", blob_b), (b"d/y", blob_b)])
+        self.assertFalse(
+            trees_content_equal(tree1, tree3, ignore_mode=True, store=RecordingStore())
+        )
+        self.assertEqual([], looked_up)
+        self.assertFalse(
+            trees_content_equal(
+                tree2,
+                self.commit_tree([(b"a", blob_a), (b"d/x", blob_b), (b"d/y", blob_b)]),
+                ignore_mode=True,
+                store=self.store,
+            )
+        )
+        self.assertFalse(trees_content_equal(tree1, self.commit_tree([(b"a", blob_a)])))
+        self.assertFalse(
+            trees_content_equal(
+                self.commit_tree([(b"a", blob_a)]), self.commit_tree([(b"b", blob_a)])
+            )
+        )
+        self.assertTrue(trees_content_equal(None, self.empty_tree))
+        self.assertFalse(trees_content_equal(None, tree1, ignore_mode=True))
+
+    test_trees_content_equal = functest_builder(
+        _do_test_trees_content_equal, _trees_content_equal_py
+    )
+    test_trees_content_equal_extension = ext_functest_builder(
+        _do_test_trees_content_equal, _trees_content_equal
+    )
+
+    def test_tree_changes_rename_detector(self) -> None:
+        blob_a1 = make_object(Blob, data=b"a\nb\nc\nd\n")
+        blob_a2 = make_object(Blob, data=b"a\nb\nc\ne\n")
+        blob_b = make_object(Blob, data=b"b")
+        tree1 = self.commit_tree([(b"a", blob_a1), (b"b", blob_b)])
+        tree2 = self.commit_tree([(b"c", blob_a2), (b"b", blob_b)])
+        detector = RenameDetector(self.store)
+
+        self.assertChangesEqual(
+            [
+                TreeChange.delete(TreeEntry(b"a", F, blob_a1.id)),
+                TreeChange.add(TreeEntry(b"c", F, blob_a2.id)),
+            ],
+            tree1,
+            tree2,
+        )
+        self.assertChangesEqual(
+            [
+                TreeChange.delete(TreeEntry(b"a", F, blob_a1.id)),
+                TreeChange(
+                    CHANGE_UNCHANGED,
+                    (b"b", F, blob_b.id),
+                    (b"b", F, blob_b.id),
+                ),
+                TreeChange.add(TreeEntry(b"c", F, blob_a2.id)),
+            ],
+            tree1,
+            tree2,
+            want_unchanged=True,
+        )
+        self.assertChangesEqual(
+            [TreeChange(CHANGE_RENAME, (b"a", F, blob_a1.id), (b"c", F, blob_a2.id))],
+            tree1,
+            tree2,
+            rename_detector=detector,
+        )
+        self.assertChangesEqual(
+            [
+                TreeChange(CHANGE_RENAME, (b"a", F, blob_a1.id), (b"c", F, blob_a2.id)),
+                TreeChange(
+                    CHANGE_UNCHANGED,
+                    (b"b", F, blob_b.id),
+                    (b"b", F, blob_b.id),
+                ),
+            ],
+            tree1,
+            tree2,
+            rename_detector=detector,
+            want_unchanged=True,
+        )
+
+    def assertChangesForMergeEqual(
+        self, expected, parent_trees, merge_tree, **kwargs
+    ) -> None:
+        parent_tree_ids = [t.id for t in parent_trees]
+        for changes_for_merge in (tree_changes_for_merge, _tree_changes_for_merge_py):
+            actual = list(
+                changes_for_merge(self.store, parent_tree_ids, merge_tree.id, **kwargs)
+            )
+            self.assertEqual(expected, actual)
+
+        parent_tree_ids.reverse()
+        expected = [list(reversed(cs)) for cs in expected]
+        for changes_for_merge in (tree_changes_for_merge, _tree_changes_for_merge_py):
+            actual = list(
+                changes_for_merge(self.store, parent_tree_ids, merge_tree.id, **kwargs)
+            )
+            self.assertEqual(expected, actual)
+
+    def test_tree_changes_for_merge_add_no_conflict(self) -> None:
+        blob = make_object(Blob, data=b"blob")
+        parent1 = self.commit_tree([])
+        parent2 = merge = self.commit_tree([(b"a", blob)])
+        self.assertChangesForMergeEqual([], [parent1, parent2], merge)
+        self.assertChangesForMergeEqual([], [parent2, parent2], merge)
+
+    def test_tree_changes_for_merge_add_modify_conflict(self) -> None:
+        blob1 = make_object(Blob, data=b"1")
+        blob2 = make_object(Blob, data=b"2")
+        parent1 = self.commit_tree([])
+        parent2 = self.commit_tree([(b"a", blob1)])
+        merge = self.commit_tree([(b"a", blob2)])
+        self.assertChangesForMergeEqual(
+            [
+                [
+                    TreeChange.add(TreeEntry(b"a", F, blob2.id)),
+                    TreeChange(CHANGE_MODIFY, (b"a", F, blob1.id), (b"a", F, blob2.id)),
+                ]
+            ],
+            [parent1, parent2],
+            merge,
+        )
+
+    def test_tree_changes_for_merge_modify_modify_conflict(self) -> None:
+        blob1 = make_object(Blob, data=b"1")
+        blob2 = make_object(Blob, data=b"2")
+        blob3 = make_object(Blob, data=b"3")
+        parent1 = self.commit_tree([(b"a", blob1)])
+        parent2 = self.commit_tree([(b"a", blob2)])
+        merge = self.commit_tree([(b"a", blob3)])
+        self.assertChangesForMergeEqual(
+            [
+                [
+                    TreeChange(CHANGE_MODIFY, (b"a", F, blob1.id), (b"a", F, blob3.id)),
+                    TreeChange(CHANGE_MODIFY, (b"a", F, blob2.id), (b"a", F, blob3.id)),
+                ]
+            ],
+            [parent1, parent2],
+            merge,
+        )
+
+    def test_tree_changes_for_merge_modify_no_conflict(self) -> None:
+        blob1 = make_object(Blob, data=b"1")
+        blob2 = make_object(Blob, data=b"2")
+        parent1 = self.commit_tree([(b"a", blob1)])
+        parent2 = merge = self.commit_tree([(b"a", blob2)])
+        self.assertChangesForMergeEqual([], [parent1, parent2], merge)
+
+    def test_tree_changes_for_merge_delete_delete_conflict(self) -> None:
+        blob1 = make_object(Blob, data=b"1")
+        blob2 = make_object(Blob, data=b"2")
+        parent1 = self.commit_tree([(b"a", blob1)])
+        parent2 = self.commit_tree([(b"a", blob2)])
+        merge = self.commit_tree([])
+        self.assertChangesForMergeEqual(
+            [
+                [
+                    TreeChange.delete(TreeEntry(b"a", F, blob1.id)),
+                    TreeChange.delete(TreeEntry(b"a", F, blob2.id)),
+                ]
+            ],
+            [parent1, parent2],
+            merge,
+        )
+
+    def test_tree_changes_for_merge_delete_no_conflict(self) -> None:
+        blob = make_object(Blob, data=b"blob")
+        has = self.commit_tree([(b"a", blob)])
+        doesnt_have = self.commit_tree([])
+        self.assertChangesForMergeEqual([], [has, has], doesnt_have)
+        self.assertChangesForMergeEqual([], [has, doesnt_have], doesnt_have)
+
+    def test_tree_changes_for_merge_octopus_no_conflict(self) -> None:
+        r = list(range(5))
+        blobs = [make_object(Blob, data=bytes(i)) for i in r]
+        parents = [self.commit_tree([(b"a", blobs[i])]) for i in r]
+        for i in r:
+            # Take the SHA from each of the parents.
+            self.assertChangesForMergeEqual([], parents, parents[i])
+
+    def test_tree_changes_for_merge_octopus_modify_conflict(self) -> None:
+        # Because the octopus merge strategy is limited, I doubt it's possible
+        # to create this with the git command line. But the output is well-
+        # defined, so test it anyway.
+        r = list(range(5))
+        parent_blobs = [make_object(Blob, data=bytes(i)) for i in r]
+        merge_blob = make_object(Blob, data=b"merge")
+        parents = [self.commit_tree([(b"a", parent_blobs[i])]) for i in r]
+        merge = self.commit_tree([(b"a", merge_blob)])
+        expected = [
+            [
+                TreeChange(
+                    CHANGE_MODIFY,
+                    (b"a", F, parent_blobs[i].id),
+                    (b"a", F, merge_blob.id),
+                )
+                for i in r
+            ]
+        ]
+        self.assertChangesForMergeEqual(expected, parents, merge)
+
+    def test_tree_changes_for_merge_octopus_delete(self) -> None:
+        blob1 = make_object(Blob, data=b"1")
+        blob2 = make_object(Blob, data=b"3")
+        parent1 = self.commit_tree([(b"a", blob1)])
+        parent2 = self.commit_tree([(b"a", blob2)])
+        parent3 = merge = self.commit_tree([])
+        self.assertChangesForMergeEqual([], [parent1, parent1, parent1], merge)
+        self.assertChangesForMergeEqual([], [parent1, parent1, parent3], merge)
+        self.assertChangesForMergeEqual([], [parent1, parent3, parent3], merge)
+        self.assertChangesForMergeEqual(
+            [
+                [
+                    TreeChange.delete(TreeEntry(b"a", F, blob1.id)),
+                    TreeChange.delete(TreeEntry(b"a", F, blob2.id)),
+                    None,
+                ]
+            ],
+            [parent1, parent2, parent3],
+            merge,
+        )
+
+    def test_tree_changes_for_merge_add_add_same_conflict(self) -> None:
+        blob = make_object(Blob, data=b"a\nb\nc\nd\n")
+        parent1 = self.commit_tree([(b"a", blob)])
+        parent2 = self.commit_tree([])
+        merge = self.commit_tree([(b"b", blob)])
+        add = TreeChange.add(TreeEntry(b"b", F, blob.id))
+        self.assertChangesForMergeEqual([[add, add]], [parent1, parent2], merge)
+
+    def test_tree_changes_for_merge_add_exact_rename_conflict(self) -> None:
+        blob = make_object(Blob, data=b"a\nb\nc\nd\n")
+        parent1 = self.commit_tree([(b"a", blob)])
+        parent2 = self.commit_tree([])
+        merge = self.commit_tree([(b"b", blob)])
+        self.assertChangesForMergeEqual(
+            [
+                [
+                    TreeChange(CHANGE_RENAME, (b"a", F, blob.id), (b"b", F, blob.id)),
+                    TreeChange.add(TreeEntry(b"b", F, blob.id)),
+                ]
+            ],
+            [parent1, parent2],
+            merge,
+            rename_detector=self.detector,
+        )
+
+    def test_tree_changes_for_merge_add_content_rename_conflict(self) -> None:
+        blob1 = make_object(Blob, data=b"a\nb\nc\nd\n")
+        blob2 = make_object(Blob, data=b"a\nb\nc\ne\n")
+        parent1 = self.commit_tree([(b"a", blob1)])
+        parent2 = self.commit_tree([])
+        merge = self.commit_tree([(b"b", blob2)])
+        self.assertChangesForMergeEqual(
+            [
+                [
+                    TreeChange(CHANGE_RENAME, (b"a", F, blob1.id), (b"b", F, blob2.id)),
+                    TreeChange.add(TreeEntry(b"b", F, blob2.id)),
+                ]
+            ],
+            [parent1, parent2],
+            merge,
+            rename_detector=self.detector,
+        )
+
+    def test_tree_changes_for_merge_modify_rename_conflict(self) -> None:
+        blob1 = make_object(Blob, data=b"a\nb\nc\nd\n")
+        blob2 = make_object(Blob, data=b"a\nb\nc\ne\n")
+        parent1 = self.commit_tree([(b"a", blob1)])
+        parent2 = self.commit_tree([(b"b", blob1)])
+        merge = self.commit_tree([(b"b", blob2)])
+        self.assertChangesForMergeEqual(
+            [
+                [
+                    TreeChange(CHANGE_RENAME, (b"a", F, blob1.id), (b"b", F, blob2.id)),
+                    TreeChange(CHANGE_MODIFY, (b"b", F, blob1.id), (b"b", F, blob2.id)),
+                ]
+            ],
+            [parent1, parent2],
+            merge,
             rename_detector=self.detector,
         )
 
-    def test_tree_changes_for_merge_modify_rename_conflict(self) -> None:
-        blob1 = make_object(Blob, data=b"a\nb\nc\nd\n")
-        blob2 = make_object(Blob, data=b"a\nb\nc\ne\n")
-        parent1 = self.commit_tree([(b"a", blob1)])
-        parent2 = self.commit_tree([(b"b", blob1)])
-        merge = self.commit_tree([(b"b", blob2)])
-        self.assertChangesForMergeEqual(
-            [
-                [
-                    TreeChange(CHANGE_RENAME, (b"a", F, blob1.id), (b"b", F, blob2.id)),
-                    TreeChange(CHANGE_MODIFY, (b"b", F, blob1.id), (b"b", F, blob2.id)),
-                ]
-            ],
-            [parent1, parent2],
-            merge,
-            rename_detector=self.detector,
+    def test_tree_changes_with_paths(self) -> None:
+        # Test filtering to specific paths
+        blob_a = make_object(Blob, data=b"a")
+        blob_b = make_object(Blob, data=b"b")
+        blob_c = make_object(Blob, data=b"c")
+        tree1 = self.commit_tree(
+            [
+                (b"file_a", blob_a),
+                (b"file_b", blob_b),
+            ]
+        )
+        tree2 = self.commit_tree(
+            [
+                (b"file_a", blob_b),  # modified
+                (b"file_b", blob_b),  # unchanged
+                (b"file_c", blob_c),  # added
+            ]
+        )
+
+        # No filtering - should see all changes
+        self.assertChangesEqual(
+            [
+                TreeChange(
+                    CHANGE_MODIFY, (b"file_a", F, blob_a.id), (b"file_a", F, blob_b.id)
+                ),
+                TreeChange.add(TreeEntry(b"file_c", F, blob_c.id)),
+            ],
+            tree1,
+            tree2,
+        )
+
+        # Filter to file_a only
+        changes = list(tree_changes(self.store, tree1.id, tree2.id, paths=[b"file_a"]))
+        self.assertEqual(
+            [
+                TreeChange(
+                    CHANGE_MODIFY, (b"file_a", F, blob_a.id), (b"file_a", F, blob_b.id)
+                ),
+            ],
+            changes,
+        )
+
+        # Filter to file_c only
+        changes = list(tree_changes(self.store, tree1.id, tree2.id, paths=[b"file_c"]))
+        self.assertEqual(
+            [
+                TreeChange.add(TreeEntry(b"file_c", F, blob_c.id)),
+            ],
+            changes,
+        )
+
+        # Filter to multiple files
+        changes = list(
+            tree_changes(self.store, tree1.id, tree2.id, paths=[b"file_a", b"file_c"])
+        )
+        self.assertEqual(
+            [
+                TreeChange(
+                    CHANGE_MODIFY, (b"file_a", F, blob_a.id), (b"file_a", F, blob_b.id)
+                ),
+                TreeChange.add(TreeEntry(b"file_c", F, blob_c.id)),
+            ],
+            changes,
+        )
+
+        # Filter to non-existent file
+        changes = list(tree_changes(self.store, tree1.id, tree2.id, paths=[b"file_d"]))
+        self.assertEqual([], changes)
+
+    def test_tree_changes_with_paths_dirs(self) -> None:
+        # Test filtering with directories
+        blob_a = make_object(Blob, data=b"a")
+        blob_b = make_object(Blob, data=b"b")
+        blob_c = make_object(Blob, data=b"c")
+        tree1 = self.commit_tree(
+            [
+                (b"dir1/file_a", blob_a),
+                (b"dir2/file_b", blob_b),
+            ]
+        )
+        tree2 = self.commit_tree(
+            [
+                (b"dir1/file_a", blob_b),  # modified
+                (b"dir1/file_c", blob_c),  # added
+                (b"dir2/file_b", blob_b),  # unchanged
+            ]
+        )
+
+        # Filter to dir1 only
+        changes = list(tree_changes(self.store, tree1.id, tree2.id, paths=[b"dir1"]))
+        self.assertEqual(
+            [
+                TreeChange(
+                    CHANGE_MODIFY,
+                    (b"dir1/file_a", F, blob_a.id),
+                    (b"dir1/file_a", F, blob_b.id),
+                ),
+                TreeChange.add(TreeEntry(b"dir1/file_c", F, blob_c.id)),
+            ],
+            changes,
+        )
+
+        # Filter to specific file in directory
+        changes = list(
+            tree_changes(self.store, tree1.id, tree2.id, paths=[b"dir1/file_a"])
+        )
+        self.assertEqual(
+            [
+                TreeChange(
+                    CHANGE_MODIFY,
+                    (b"dir1/file_a", F, blob_a.id),
+                    (b"dir1/file_a", F, blob_b.id),
+                ),
+            ],
+            changes,
+        )
+
+        # Filter to dir2 (no changes)
+        changes = list(tree_changes(self.store, tree1.id, tree2.id, paths=[b"dir2"]))
+        self.assertEqual([], changes)
+
+    def test_tree_changes_with_paths_deep(self) -> None:
+        # Test filtering with deep directory structure
+        blob_a = make_object(Blob, data=b"a")
+        blob_b = make_object(Blob, data=b"b")
+        tree1 = self.commit_tree(
+            [
+                (b"a/b/c/file1", blob_a),
+                (b"a/b/d/file2", blob_a),
+                (b"x/y/z/file3", blob_a),
+            ]
+        )
+        tree2 = self.commit_tree(
+            [
+                (b"a/b/c/file1", blob_b),  # modified
+                (b"a/b/d/file2", blob_a),  # unchanged
+                (b"x/y/z/file3", blob_b),  # modified
+            ]
+        )
+
+        # Filter to a/b/c
+        changes = list(tree_changes(self.store, tree1.id, tree2.id, paths=[b"a/b/c"]))
+        self.assertEqual(
+            [
+                TreeChange(
+                    CHANGE_MODIFY,
+                    (b"a/b/c/file1", F, blob_a.id),
+                    (b"a/b/c/file1", F, blob_b.id),
+                ),
+            ],
+            changes,
+        )
+
+        # Filter to a/b (should include both subdirs)
+        changes = list(tree_changes(self.store, tree1.id, tree2.id, paths=[b"a/b"]))
+        self.assertEqual(
+            [
+                TreeChange(
+                    CHANGE_MODIFY,
+                    (b"a/b/c/file1", F, blob_a.id),
+                    (b"a/b/c/file1", F, blob_b.id),
+                ),
+            ],
+            changes,
+        )
+
+        # Filter to multiple deep paths
+        changes = list(
+            tree_changes(
+                self.store, tree1.id, tree2.id, paths=[b"a/b/c/file1", b"x/y/z/file3"]
+            )
+        )
+        self.assertEqual(
+            [
+                TreeChange(
+                    CHANGE_MODIFY,
+                    (b"a/b/c/file1", F, blob_a.id),
+                    (b"a/b/c/file1", F, blob_b.id),
+                ),
+                TreeChange(
+                    CHANGE_MODIFY,
+                    (b"x/y/z/file3", F, blob_a.id),
+                    (b"x/y/z/file3", F, blob_b.id),
+                ),
+            ],
+            changes,
+        )
+
+    def test_tree_changes_with_paths_include_unchanged(self) -> None:
+        # Test path filtering with want_unchanged=True
+        blob_a = make_object(Blob, data=b"a")
+        blob_b = make_object(Blob, data=b"b")
+        tree1 = self.commit_tree(
+            [
+                (b"file_a", blob_a),
+                (b"file_b", blob_b),
+            ]
+        )
+        tree2 = self.commit_tree(
+            [
+                (b"file_a", blob_b),  # modified
+                (b"file_b", blob_b),  # unchanged
+            ]
+        )
+
+        # Filter to file_b with want_unchanged=True
+        changes = list(
+            tree_changes(
+                self.store, tree1.id, tree2.id, paths=[b"file_b"], want_unchanged=True
+            )
+        )
+        self.assertEqual(
+            [
+                TreeChange(
+                    CHANGE_UNCHANGED,
+                    (b"file_b", F, blob_b.id),
+                    (b"file_b", F, blob_b.id),
+                ),
+            ],
+            changes,
+        )
+
+        # Filter to both files with want_unchanged=True
+        changes = list(
+            tree_changes(
+                self.store,
+                tree1.id,
+                tree2.id,
+                paths=[b"file_a", b"file_b"],
+                want_unchanged=True,
+            )
+        )
+        self.assertEqual(
+            [
+                TreeChange(
+                    CHANGE_MODIFY, (b"file_a", F, blob_a.id), (b"file_a", F, blob_b.id)
+                ),
+                TreeChange(
+                    CHANGE_UNCHANGED,
+                    (b"file_b", F, blob_b.id),
+                    (b"file_b", F, blob_b.id),
+                ),
+            ],
+            changes,
+        )
+
+
+class RenameDetectionTest(DiffTestCase):
+    def _do_test_count_blocks(self, count_blocks) -> None:
+        blob = make_object(Blob, data=b"a\nb\na\n")
+        counts = count_blocks(blob)
+        self.assertIsInstance(counts, dict)
+        self.assertBlockCountEqual({b"a\n": 4, b"b\n": 2}, counts)
+
+    test_count_blocks = functest_builder(_do_test_count_blocks, _count_blocks_py)
+    test_count_blocks_extension = ext_functest_builder(
+        _do_test_count_blocks, _count_blocks
+    )
+
+    def _do_test_count_blocks_no_newline(self, count_blocks) -> None:
+        blob = make_object(Blob, data=b"a\na")
+        self.assertBlockCountEqual({b"a\n": 2, b"a": 1}, _count_blocks(blob))
+
+    test_count_blocks_no_newline = functest_builder(
+        _do_test_count_blocks_no_newline, _count_blocks_py
+    )
+    test_count_blocks_no_newline_extension = ext_functest_builder(
+        _do_test_count_blocks_no_newline, _count_blocks
+    )
+
+    def _do_test_count_blocks_bounded(self, count_blocks_bounded) -> None:
+        blob = make_object(Blob, data=b"a\nb\na\nc\nd")
+        counts, truncated = count_blocks_bounded(blob, 2)
+        self.assertTrue(truncated)
+        # Blocks already counted are still counted until counting stops.
+        self.assertBlockCountEqual({b"a\n": 4, b"b\n": 2}, counts)
+        counts, truncated = count_blocks_bounded(blob, 3)
+        self.assertTrue(truncated)
+        self.assertBlockCountEqual({b"a\n": 4, b"b\n": 2, b"c\n": 2}, counts)
+        for max_blocks in (4, 5, None):
+            counts, truncated = count_blocks_bounded(blob, max_blocks)
+            self.assertFalse(truncated)
+            self.assertEqual(_count_blocks_py(blob), counts)
+        self.assertEqual(
+            _count_blocks_py(blob, skip_partial=True),
+            count_blocks_bounded(blob, 3, skip_partial=True)[0],
+        )
+        counts, truncated = count_blocks_bounded(
+            blob, 1, normalize_whitespace=True, stable_hash=True
+        )
+        self.assertTrue(truncated)
+        self.assertEqual(1, len(counts))
+        self.assertEqual(({}, False), count_blocks_bounded(b"", 0))
+        self.assertEqual(({}, True), count_blocks_bounded(b"a", 0))
+
+    test_count_blocks_bounded = functest_builder(
+        _do_test_count_blocks_bounded, _count_blocks_bounded_py
+    )
+    test_count_blocks_bounded_extension = ext_functest_builder(
+        _do_test_count_blocks_bounded, _count_blocks_bounded
+    )
+
+    def assertBlockCountEqual(self, expected, got) -> None:
+        self.assertEqual(
+            {(hash(block) & 0xFFFFFFFF): count for (block, count) in expected.items()},
+            {(block & 0xFFFFFFFF): count for (block, count) in got.items()},
+        )
+
+    def _do_test_count_blocks_chunks(self, count_blocks) -> None:
+        blob = ShaFile.from_raw_chunks(Blob.type_num, [b"a\nb", b"\na\n"])
+        self.assertBlockCountEqual({b"a\n": 4, b"b\n": 2}, _count_blocks(blob))
+
+    test_count_blocks_chunks = functest_builder(
+        _do_test_count_blocks_chunks, _count_blocks_py
+    )
+    test_count_blocks_chunks_extension = ext_functest_builder(
+        _do_test_count_blocks_chunks, _count_blocks
+    )
+
+    def _do_test_count_blocks_buffer_chunks(self, count_blocks) -> None:
+        blob = make_object(Blob, data=b"a\nb\na\n")
+        blob.as_raw_chunks = lambda: [bytearray(b"a\nb"), memoryview(b"\na\n")]
+        self.assertBlockCountEqual({b"a\n": 4, b"b\n": 2}, count_blocks(blob))
+
+    test_count_blocks_buffer_chunks = functest_builder(
+        _do_test_count_blocks_buffer_chunks, _count_blocks_py
+    )
+    test_count_blocks_buffer_chunks_extension = ext_functest_builder(
+        _do_test_count_blocks_buffer_chunks, _count_blocks
+    )
+
+    def _do_test_count_blocks_long_lines(self, count_blocks) -> None:
+        a = b"a" * 64
+        data = a + b"xxx\ny\n" + a + b"zzz\n"
+        blob = make_object(Blob, data=data)
+        self.assertBlockCountEqual(
+            {b"a" * 64: 128, b"xxx\n": 4, b"y\n": 2, b"zzz\n": 4},
+            _count_blocks(blob),
+        )
+
+    test_count_blocks_long_lines = functest_builder(
+        _do_test_count_blocks_long_lines, _count_blocks_py
+    )
+    test_count_blocks_long_lines_extension = ext_functest_builder(
+        _do_test_count_blocks_long_lines, _count_blocks
+    )
+
+    def _do_test_common_bytes(self, common_bytes) -> None:
+        blocks1 = {1: 3, 2: 5, 3: 4}
+        blocks2 = defaultdict(int, {2: 2, 3: 6, 4: 10})
+        self.assertEqual(6, common_bytes(blocks1, blocks2))
+        self.assertEqual(6, common_bytes(blocks2, blocks1))
+        self.assertEqual(0, common_bytes(blocks1, {}))
+        self.assertEqual(0, common_bytes({}, {}))
+        # Missing keys must not be inserted into defaultdicts.
+        self.assertNotIn(1, blocks2)
+
+    test_common_bytes = functest_builder(_do_test_common_bytes, _common_bytes_py)
+    test_common_bytes_extension = ext_functest_builder(
+        _do_test_common_bytes, _common_bytes
+    )
+
+    def _do_test_common_prefix_suffix(self, common_prefix_suffix) -> None:
+        self.assertEqual((0, 0), common_prefix_suffix(b"", b""))
+        self.assertEqual((0, 0), common_prefix_suffix(b"abc", b""))
+        self.assertEqual((0, 0), common_prefix_suffix(b"abc", b"xyz"))
+        self.assertEqual((3, 0), common_prefix_suffix(b"abc", b"abc"))
+        self.assertEqual((2, 2), common_prefix_suffix(b"abxcd", b"abyycd"))
+        # The suffix doesn't overlap the prefix.
+        self.assertEqual((3, 0), common_prefix_suffix(b"abc", b"abcabc"))
+        self.assertEqual((3, 0), common_prefix_suffix(b"abcabc", b"abc"))
+        self.assertEqual((0, 3), common_prefix_suffix(b"abc", b"xabc"))
+        self.assertEqual((2, 0), common_prefix_suffix(b"aa", b"aaa"))
+
+    test_common_prefix_suffix = functest_builder(
+        _do_test_common_prefix_suffix, _common_prefix_suffix_py
+    )
+    test_common_prefix_suffix_extension = ext_functest_builder(
+        _do_test_common_prefix_suffix, _common_prefix_suffix
+    )
+
+    def _do_test_line_offsets(self, line_offsets) -> None:
+        data = b"ab\n\ncd"
+        self.assertEqual([(0, 3), (3, 4), (4, 6)], line_offsets(data))
+        self.assertEqual(
+            [b"ab\n", b"\n", b"cd"],
+            [data[start:end] for start, end in line_offsets(data)],
+        )
+        self.assertEqual([(0, 3), (3, 4)], line_offsets(b"ab\n\n"))
+        self.assertEqual([(0, 1)], line_offsets(b"a"))
+        self.assertEqual([], line_offsets(b""))
+
+    test_line_offsets = functest_builder(_do_test_line_offsets, _line_offsets_py)
+    test_line_offsets_extension = ext_functest_builder(
+        _do_test_line_offsets, _line_offsets
+    )
+
+    def _do_test_block_count_diff(self, block_count_diff) -> None:
+        blocks1 = {1: 3, 2: 5, 3: 4}
+        blocks2 = defaultdict(int, {2: 2, 3: 6, 4: 10})
+        self.assertEqual(
+            [(1, 3, 0), (2, 5, 2), (3, 4, 6), (4, 0, 10)],
+            block_count_diff(blocks1, blocks2),
+        )
+        self.assertEqual(
+            [(1, 0, 3), (2, 2, 5), (3, 6, 4), (4, 10, 0)],
+            block_count_diff(blocks2, blocks1),
+        )
+        self.assertEqual([], block_count_diff({}, {}))
+        self.assertNotIn(1, blocks2)
+        # Hashes can be negative (hash()) or above 2**63 (the stable hash).
+        self.assertEqual(
+            [(-(2**63), 1, 0), (2**64 - 1, 0, 2)],
+            block_count_diff({-(2**63): 1}, {2**64 - 1: 2}),
+        )
+
+    test_block_count_diff = functest_builder(
+        _do_test_block_count_diff, _block_count_diff_py
+    )
+    test_block_count_diff_extension = ext_functest_builder(
+        _do_test_block_count_diff, _block_count_diff
+    )
+
+    def _do_test_block_count_distance(self, block_count_distance) -> None:
+        blocks1 = {1: 3, 2: 5, 3: 4}
+        blocks2 = defaultdict(int, {2: 2, 3: 6, 4: 10})
+        self.assertEqual(18, block_count_distance(blocks1, blocks2))
+        self.assertEqual(18, block_count_distance(blocks2, blocks1))
+        self.assertEqual(12, block_count_distance(blocks1, {}))
+        self.assertEqual(0, block_count_distance(blocks1, dict(blocks1)))
+        self.assertEqual(0, block_count_distance({}, {}))
+        self.assertEqual(3, block_count_distance({-(2**63): 1}, {2**64 - 1: 2}))
+        # Missing keys must not be inserted into defaultdicts.
+        self.assertNotIn(1, blocks2)
+
+    test_block_count_distance = functest_builder(
+        _do_test_block_count_distance, _block_count_distance_py
+    )
+    test_block_count_distance_extension = ext_functest_builder(
+        _do_test_block_count_distance, _block_count_distance
+    )
+
+    def _do_test_count_blocks_block_size(self, count_blocks) -> None:
+        blob = make_object(Blob, data=b"aaaaa\nb\n")
+        self.assertBlockCountEqual(
+            {b"aa": 4, b"a\n": 2, b"b\n": 2}, count_blocks(blob, block_size=2)
+        )
+        self.assertBlockCountEqual(
+            {b"aaaaa\n": 6, b"b\n": 2}, count_blocks(blob, block_size=None)
+        )
+
+    test_count_blocks_block_size = functest_builder(
+        _do_test_count_blocks_block_size, _count_blocks_py
+    )
+    test_count_blocks_block_size_extension = ext_functest_builder(
+        _do_test_count_blocks_block_size, _count_blocks
+    )
+
+    def _do_test_count_blocks_stable_hash(self, count_blocks) -> None:
+        blob = make_object(Blob, data=b"a\nb\na\n")
+        # 64-bit FNV-1a of b"a\n" and b"b\n".
+        self.assertEqual(
+            {0x089BDC07B544E7B2: 4, 0x08A61E07B54DAC5D: 2},
+            count_blocks(blob, stable_hash=True),
+        )
+
+    test_count_blocks_stable_hash = functest_builder(
+        _do_test_count_blocks_stable_hash, _count_blocks_py
+    )
+    test_count_blocks_stable_hash_extension = ext_functest_builder(
+        _do_test_count_blocks_stable_hash, _count_blocks
+    )
+
+    def _do_test_count_blocks_hash_algo(self, count_blocks) -> None:
+        blob = make_object(Blob, data=b"a\nb\na\n")
+        self.assertEqual(count_blocks(blob), count_blocks(blob, hash_algo="py"))
+        self.assertEqual(
+            count_blocks(blob, stable_hash=True), count_blocks(blob, hash_algo="fnv")
+        )
+        # 64-bit XXH3 of b"a\n" and b"b\n".
+        self.assertEqual(
+            {0xE841F27363849A18: 4, 0x5B19EF905838A84A: 2},
+            count_blocks(blob, hash_algo="xxh3"),
+        )
+        # Blocks of all the sizes that XXH3 hashes differently.
+        lines = [b"x" * n + b"\n" for n in (0, 3, 7, 15, 40, 100, 200, 1500)]
+        self.assertEqual(
+            {_xxh3_hash(line): len(line) for line in lines},
+            count_blocks(b"".join(lines), block_size=2000, hash_algo="xxh3"),
+        )
+        self.assertRaises(ValueError, count_blocks, blob, hash_algo="md5")
+        self.assertRaises(
+            ValueError, count_blocks, blob, stable_hash=True, hash_algo="xxh3"
+        )
+
+    test_count_blocks_hash_algo = functest_builder(
+        _do_test_count_blocks_hash_algo, _count_blocks_py
+    )
+    test_count_blocks_hash_algo_extension = ext_functest_builder(
+        _do_test_count_blocks_hash_algo, _count_blocks
+    )
+
+    def _do_test_count_blocks_dedup_runs(self, count_blocks) -> None:
+        blob = make_object(Blob, data=b"a\na\na\nb\na\n")
+        self.assertBlockCountEqual({b"a\n": 8, b"b\n": 2}, count_blocks(blob))
+        # Only the run of a\n is folded, not its later occurrence.
+        self.assertBlockCountEqual(
+            {b"a\n": 4, b"b\n": 2}, count_blocks(blob, dedup_runs=True)
+        )
+        # Blocks are compared after normalizing them.
+        blob = make_object(Blob, data=b"a \na\t\n")
+        self.assertBlockCountEqual(
+            {b"a\n": 2},
+            count_blocks(blob, normalize_whitespace=True, dedup_runs=True),
+        )
+        # Runs of the blocks of a long line are folded too.
+        blob = make_object(Blob, data=b"x" * 130)
+        self.assertBlockCountEqual(
+            {b"x" * 64: 64, b"xx": 2}, count_blocks(blob, dedup_runs=True)
+        )
+
+    test_count_blocks_dedup_runs = functest_builder(
+        _do_test_count_blocks_dedup_runs, _count_blocks_py
+    )
+    test_count_blocks_dedup_runs_extension = ext_functest_builder(
+        _do_test_count_blocks_dedup_runs, _count_blocks
+    )
+
+    def _do_test_count_blocks_overlap(self, count_blocks) -> None:
+        blob = make_object(Blob, data=b"ab\ncdefgh")
+        self.assertBlockCountEqual(
+            {b"ab\nc": 4, b"cdef": 4, b"fgh": 3},
+            count_blocks(blob, block_size=4, overlap=1),
+        )
+        self.assertBlockCountEqual(
+            {b"ab\nc": 4, b"cdef": 4},
+            count_blocks(blob, block_size=4, overlap=1, skip_partial=True),
+        )
+        # The windows span the chunks of an object.
+        blob = ShaFile.from_raw_chunks(Blob.type_num, [b"abc", b"defgh"])
+        self.assertBlockCountEqual(
+            {b"abcd": 4, b"cdef": 4, b"efgh": 4},
+            count_blocks(blob, block_size=4, overlap=2),
+        )
+        self.assertBlockCountEqual({}, count_blocks(b"", block_size=4, overlap=2))
+        self.assertRaises(ValueError, count_blocks, blob, block_size=4, overlap=4)
+        # Other ways of splitting blocks can't be combined with overlap.
+        for kwargs in ({"rolling": True}, {"delimiter": 0}, {"line_endings": "any"}):
+            self.assertRaises(
+                ValueError, count_blocks, blob, block_size=4, overlap=2, **kwargs
+            )
+
+    test_count_blocks_overlap = functest_builder(
+        _do_test_count_blocks_overlap, _count_blocks_py
+    )
+    test_count_blocks_overlap_extension = ext_functest_builder(
+        _do_test_count_blocks_overlap, _count_blocks
+    )
+
+    def test_xxh3_hash(self) -> None:
+        # The hash of no data in the XXH3 reference implementation.
+        self.assertEqual(0x2D06800538D394C2, _xxh3_hash(b""))
+
+    def _do_test_count_blocks_normalize_whitespace(self, count_blocks) -> None:
+        blob1 = make_object(Blob, data=b"a\nb\nc")
+        blob2 = make_object(Blob, data=b"a\r\nb \t\nc  ")
+        self.assertBlockCountEqual(
+            {b"a\n": 2, b"b\n": 2, b"c": 1},
+            count_blocks(blob2, normalize_whitespace=True),
+        )
+        self.assertEqual(
+            count_blocks(blob1, normalize_whitespace=True),
+            count_blocks(blob2, normalize_whitespace=True),
+        )
+        self.assertNotEqual(count_blocks(blob1), count_blocks(blob2))
+        # Blocks that consist only of whitespace are dropped.
+        blob3 = make_object(Blob, data=b"a\n   ")
+        self.assertBlockCountEqual(
+            {b"a\n": 2}, count_blocks(blob3, normalize_whitespace=True)
+        )
+
+        # The normalized counts can be used to score objects; the score is
+        # still relative to the size of the larger object.
+        blob4 = make_object(Blob, data=b"line one\nline two\n")
+        blob5 = make_object(Blob, data=b"line one\r\nline two\r\n")
+        block_cache = {
+            blob.id: count_blocks(blob, normalize_whitespace=True)
+            for blob in (blob4, blob5)
+        }
+        self.assertEqual(
+            90, _similarity_score_py(blob4, blob5, block_cache=block_cache)
+        )
+        self.assertEqual(0, _similarity_score_py(blob4, blob5))
+
+    test_count_blocks_normalize_whitespace = functest_builder(
+        _do_test_count_blocks_normalize_whitespace, _count_blocks_py
+    )
+    test_count_blocks_normalize_whitespace_extension = ext_functest_builder(
+        _do_test_count_blocks_normalize_whitespace, _count_blocks
+    )
+
+    def _do_test_count_blocks_delimiter(self, count_blocks) -> None:
+        blob = make_object(Blob, data=b'{"a":1},{"b":2},{"a":1}\n')
+        self.assertBlockCountEqual(
+            {b'{"a":1},': 8, b'{"b":2},': 8, b'{"a":1}\n': 8},
+            count_blocks(blob, delimiter=ord(",")),
+        )
+        blob = make_object(Blob, data=b"ab,cdefgh,ab,\n")
+        self.assertBlockCountEqual(
+            {b"ab,": 6, b"cdef": 4, b"gh,": 3, b"\n": 1},
+            count_blocks(blob, block_size=4, delimiter=ord(",")),
+        )
+        self.assertEqual(count_blocks(blob), count_blocks(blob, delimiter=ord("\n")))
+
+    test_count_blocks_delimiter = functest_builder(
+        _do_test_count_blocks_delimiter, _count_blocks_py
+    )
+    test_count_blocks_delimiter_extension = ext_functest_builder(
+        _do_test_count_blocks_delimiter, _count_blocks
+    )
+
+    def _do_test_count_blocks_skip_partial(self, count_blocks) -> None:
+        blob = make_object(Blob, data=b"a\nb\na\nno newline")
+        self.assertBlockCountEqual(
+            {b"a\n": 4, b"b\n": 2}, count_blocks(blob, skip_partial=True)
+        )
+        self.assertBlockCountEqual(
+            {b"a\n": 4, b"b\n": 2, b"no newline": 10}, count_blocks(blob)
+        )
+        self.assertBlockCountEqual(
+            {b"a\n": 4, b"b\n": 2, b"no n": 4, b"ewli": 4},
+            count_blocks(blob, block_size=4, skip_partial=True),
+        )
+        complete = make_object(Blob, data=b"a\nb\n")
+        self.assertEqual(
+            count_blocks(complete), count_blocks(complete, skip_partial=True)
+        )
+        self.assertEqual(
+            count_blocks(blob, normalize_whitespace=True, skip_partial=True),
+            count_blocks(blob, skip_partial=True),
+        )
+
+    test_count_blocks_skip_partial = functest_builder(
+        _do_test_count_blocks_skip_partial, _count_blocks_py
+    )
+    test_count_blocks_skip_partial_extension = ext_functest_builder(
+        _do_test_count_blocks_skip_partial, _count_blocks
+    )
+
+    def _do_test_count_blocks_size_hint(self, count_blocks) -> None:
+        data = b"a\nb\na\n" * 100 + b"c" * 200
+        blob = make_object(Blob, data=data)
+        expected = count_blocks(blob)
+        for size_hint in (len(data), 0, 1, 2**62):
+            self.assertEqual(expected, count_blocks(blob, size_hint=size_hint))
+        self.assertEqual(
+            count_blocks(blob, block_size=8),
+            count_blocks(blob, block_size=8, size_hint=len(data)),
+        )
+        self.assertEqual(
+            count_blocks(blob, block_size=0),
+            count_blocks(blob, block_size=0, size_hint=len(data)),
+        )
+
+    test_count_blocks_size_hint = functest_builder(
+        _do_test_count_blocks_size_hint, _count_blocks_py
+    )
+    test_count_blocks_size_hint_extension = ext_functest_builder(
+        _do_test_count_blocks_size_hint, _count_blocks
+    )
+
+    def _do_test_count_blocks_buffer(self, count_blocks) -> None:
+        data = b"a\nb\na\nno newline"
+        blob = make_object(Blob, data=data)
+        for buf in (data, bytearray(data), memoryview(data)):
+            self.assertEqual(count_blocks(blob), count_blocks(buf))
+            self.assertEqual(
+                count_blocks(blob, block_size=4, rolling=True),
+                count_blocks(buf, block_size=4, rolling=True),
+            )
+        self.assertEqual({}, count_blocks(b""))
+        self.assertRaises((AttributeError, TypeError), count_blocks, "a\n")
+
+    test_count_blocks_buffer = functest_builder(
+        _do_test_count_blocks_buffer, _count_blocks_py
+    )
+    test_count_blocks_buffer_extension = ext_functest_builder(
+        _do_test_count_blocks_buffer, _count_blocks
+    )
+
+    def _do_test_count_blocks_verify_collisions(self, count_blocks) -> None:
+        blob = make_object(Blob, data=b"a\nb\na\nno newline")
+        for kwargs in ({}, {"stable_hash": True}, {"normalize_whitespace": True}):
+            self.assertEqual(
+                count_blocks(blob, **kwargs),
+                count_blocks(blob, verify_collisions=True, **kwargs),
+            )
+
+    test_count_blocks_verify_collisions = functest_builder(
+        _do_test_count_blocks_verify_collisions, _count_blocks_py
+    )
+    test_count_blocks_verify_collisions_extension = ext_functest_builder(
+        _do_test_count_blocks_verify_collisions, _count_blocks
+    )
+
+    def test_count_blocks_collision(self) -> None:
+        blob = make_object(Blob, data=b"a\nb\na\n")
+        # Make every block of the same length collide.
+        with patch("dulwich.diff_tree._stable_hash", len):
+            self.assertEqual({2: 6}, _count_blocks_py(blob, stable_hash=True))
+            self.assertRaises(
+                ValueError,
+                _count_blocks_py,
+                blob,
+                stable_hash=True,
+                verify_collisions=True,
+            )
+
+    def _do_test_count_blocks_line_endings(self, count_blocks) -> None:
+        blob = make_object(Blob, data=b"a\r\nb\nc\rd\r")
+        self.assertBlockCountEqual(
+            {b"a\r\n": 3, b"b\n": 2, b"c\rd\r": 4},
+            count_blocks(blob, line_endings="lf"),
+        )
+        self.assertEqual(count_blocks(blob), count_blocks(blob, line_endings="lf"))
+        self.assertBlockCountEqual(
+            {b"a\r\n": 3, b"b\nc\rd\r": 6}, count_blocks(blob, line_endings="crlf")
+        )
+        self.assertBlockCountEqual(
+            {b"a\r\n": 3, b"b\n": 2, b"c\r": 2, b"d\r": 2},
+            count_blocks(blob, line_endings="any"),
+        )
+        # A trailing CR already ends a line under "any".
+        self.assertEqual(
+            count_blocks(blob, line_endings="any"),
+            count_blocks(blob, line_endings="any", skip_partial=True),
+        )
+        self.assertRaises(ValueError, count_blocks, blob, line_endings="cr")
+
+    test_count_blocks_line_endings = functest_builder(
+        _do_test_count_blocks_line_endings, _count_blocks_py
+    )
+    test_count_blocks_line_endings_extension = ext_functest_builder(
+        _do_test_count_blocks_line_endings, _count_blocks
+    )
+
+    def _do_test_count_blocks_rolling(self, count_blocks) -> None:
+        # A long line of pseudo-random lowercase letters.
+        state = 1
+        data = bytearray()
+        for _ in range(4096):
+            state = (state * 1103515245 + 12345) & 0xFFFFFFFF
+            data.append(ord("a") + (state >> 16) % 26)
+        blob1 = make_object(Blob, data=bytes(data))
+        blob2 = make_object(Blob, data=b"x" + bytes(data))
+
+        blocks1 = count_blocks(blob1, rolling=True)
+        blocks2 = count_blocks(blob2, rolling=True)
+        self.assertEqual(len(data), sum(blocks1.values()))
+        self.assertGreater(_common_bytes_py(blocks1, blocks2), len(data) * 0.95)
+        # Fixed-size blocks all shift along with the insertion.
+        self.assertEqual(0, _common_bytes_py(count_blocks(blob1), count_blocks(blob2)))
+        self.assertEqual(_count_blocks_py(blob1, rolling=True), blocks1)
+        self.assertEqual({}, count_blocks(make_object(Blob, data=b""), rolling=True))
+        # Rolling blocks are block_size bytes on average, so there is no
+        # uncapped size as for lines.
+        self.assertRaises(ValueError, count_blocks, blob1, block_size=0, rolling=True)
+
+    test_count_blocks_rolling = functest_builder(
+        _do_test_count_blocks_rolling, _count_blocks_py
+    )
+    test_count_blocks_rolling_extension = ext_functest_builder(
+        _do_test_count_blocks_rolling, _count_blocks
+    )
+
+    def _do_test_iter_blocks(self, iter_blocks) -> None:
+        blob = ShaFile.from_raw_chunks(Blob.type_num, [b"a\nb", b"\na\n", b"cccc"])
+        a, b = (hash(b"a\n"), 2), (hash(b"b\n"), 2)
+        self.assertEqual([a, b, a, (hash(b"cccc"), 4)], iter_blocks(blob))
+        self.assertEqual(
+            [a, b, a, (hash(b"cc"), 2), (hash(b"cc"), 2)],
+            iter_blocks(blob, block_size=2),
+        )
+        self.assertEqual([], iter_blocks(make_object(Blob, data=b"")))
+
+    test_iter_blocks = functest_builder(_do_test_iter_blocks, _iter_blocks_py)
+    test_iter_blocks_extension = ext_functest_builder(
+        _do_test_iter_blocks, _iter_blocks
+    )
+
+    def _do_test_count_blocks_many(self, count_blocks_many) -> None:
+        blob1 = make_object(Blob, data=b"a\nb\na\n")
+        blob2 = make_object(Blob, data=b"a" * 70 + b"\nb")
+        blob3 = make_object(Blob, data=b"")
+        self.assertEqual(
+            [_count_blocks_py(blob1), _count_blocks_py(blob2), {}],
+            count_blocks_many([blob1, blob2, blob3]),
+        )
+        self.assertEqual(
+            [_count_blocks_py(blob2, block_size=8)],
+            count_blocks_many([blob2], block_size=8),
+        )
+        self.assertEqual([], count_blocks_many([]))
+
+        blob1.as_raw_chunks = lambda: [bytearray(b"a\nb"), memoryview(b"\na\n")]
+        self.assertEqual([_count_blocks_py(blob1)], count_blocks_many([blob1]))
+
+    test_count_blocks_many = functest_builder(
+        _do_test_count_blocks_many, _count_blocks_many_py
+    )
+    test_count_blocks_many_extension = ext_functest_builder(
+        _do_test_count_blocks_many, _count_blocks_many
+    )
+
+    def _do_test_count_blocks_bytes(self, count_blocks_bytes) -> None:
+        data = b"a" * 70 + b"\nb\na"
+        blob = make_object(Blob, data=data)
+        self.assertEqual(_count_blocks_py(blob), count_blocks_bytes(data))
+        self.assertEqual(
+            _count_blocks_py(blob, block_size=8), count_blocks_bytes(data, 8)
+        )
+        self.assertEqual({}, count_blocks_bytes(b""))
+
+    test_count_blocks_bytes_extension = ext_functest_builder(
+        _do_test_count_blocks_bytes, _count_blocks_bytes
+    )
+
+    def _do_test_count_blocks_lines(self, count_blocks_lines) -> None:
+        lines = [b"a" * 70 + b"\n", b"b\n", b"\n", b"a"]
+        blob = make_object(Blob, data=b"".join(lines))
+        self.assertEqual(_count_blocks_py(blob), count_blocks_lines(lines))
+        self.assertEqual(
+            _count_blocks_py(blob, block_size=8), count_blocks_lines(lines, 8)
+        )
+        self.assertEqual(
+            _count_blocks_py(blob, block_size=0), count_blocks_lines(lines, 0)
+        )
+        # The lines aren't split on newlines again.
+        self.assertEqual({hash(b"a\nb"): 3}, count_blocks_lines([b"a\nb"]))
+        self.assertEqual(
+            count_blocks_lines(lines),
+            count_blocks_lines([bytearray(lines[0]), *map(memoryview, lines[1:])]),
+        )
+        self.assertEqual({}, count_blocks_lines([]))
+        self.assertEqual({}, count_blocks_lines([b""]))
+
+    test_count_blocks_lines_extension = ext_functest_builder(
+        _do_test_count_blocks_lines, _count_blocks_lines
+    )
+
+    def _do_test_count_blocks_cacheable(self, count_blocks_cacheable) -> None:
+        data = b"a" * 70 + b"\nb\na\nb\n"
+        blob = make_object(Blob, data=data)
+        self.assertEqual(
+            sorted(_count_blocks_py(blob, stable_hash=True).items()),
+            count_blocks_cacheable(data),
+        )
+        self.assertEqual(
+            sorted(_count_blocks_py(blob, block_size=8, stable_hash=True).items()),
+            count_blocks_cacheable(data, 8),
+        )
+        self.assertEqual([], count_blocks_cacheable(b""))
+
+    test_count_blocks_cacheable_extension = ext_functest_builder(
+        _do_test_count_blocks_cacheable, _count_blocks_cacheable
+    )
+
+    def _do_test_common_bytes_sorted(self, common_bytes_sorted) -> None:
+        blocks1 = _count_blocks_cacheable(b"ab\ncd\ncd\nef\n")
+        blocks2 = _count_blocks_cacheable(b"cd\nef\nef\ngh\n")
+        self.assertEqual(6, common_bytes_sorted(blocks1, blocks2))
+        self.assertEqual(12, common_bytes_sorted(blocks1, blocks1))
+        self.assertEqual(0, common_bytes_sorted(blocks1, []))
+        self.assertRaises(
+            ValueError, common_bytes_sorted, list(reversed(blocks1)), blocks2
         )
 
-    def test_tree_changes_with_paths(self) -> None:
-        # Test filtering to specific paths
-        blob_a = make_object(Blob, data=b"a")
-        blob_b = make_object(Blob, data=b"b")
-        blob_c = make_object(Blob, data=b"c")
-        tree1 = self.commit_tree(
-            [
-                (b"file_a", blob_a),
-                (b"file_b", blob_b),
-            ]
+    test_common_bytes_sorted_extension = ext_functest_builder(
+        _do_test_common_bytes_sorted, _common_bytes_sorted
+    )
+
+    def _do_test_count_blocks_from_read(self, count_blocks_from_read) -> None:
+        data = b"a" * 70 + b"\nb\nccc\nd"
+        blob = make_object(Blob, data=data)
+        self.assertEqual(_count_blocks_py(blob), count_blocks_from_read(BytesIO(data)))
+        self.assertEqual({}, count_blocks_from_read(BytesIO(b"")))
+
+        class ShortReader:
+            # Return fewer bytes than requested, so that blocks span reads.
+            def __init__(self, data) -> None:
+                self._f = BytesIO(data)
+
+            def read(self, n):
+                return self._f.read(3)
+
+        self.assertEqual(
+            _count_blocks_py(blob), count_blocks_from_read(ShortReader(data))
         )
-        tree2 = self.commit_tree(
-            [
-                (b"file_a", blob_b),  # modified
-                (b"file_b", blob_b),  # unchanged
-                (b"file_c", blob_c),  # added
-            ]
+        self.assertEqual(
+            _count_blocks_py(blob, block_size=2),
+            count_blocks_from_read(ShortReader(data), block_size=2),
         )
 
-        # No filtering - should see all changes
-        self.assertChangesEqual(
-            [
-                TreeChange(
-                    CHANGE_MODIFY, (b"file_a", F, blob_a.id), (b"file_a", F, blob_b.id)
-                ),
-                TreeChange.add(TreeEntry(b"file_c", F, blob_c.id)),
-            ],
-            tree1,
-            tree2,
+        class StrReader:
+            def read(self, n):
+                return "foo"
+
+        self.assertRaises(TypeError, count_blocks_from_read, StrReader())
+
+    test_count_blocks_from_read_extension = ext_functest_builder(
+        _do_test_count_blocks_from_read, _count_blocks_from_read
+    )
+
+    def assertSimilar(self, similarity_score, expected_score, blob1, blob2) -> None:
+        self.assertEqual(expected_score, similarity_score(blob1, blob2))
+        self.assertEqual(expected_score, similarity_score(blob2, blob1))
+
+    def _do_test_similarity_score(self, similarity_score) -> None:
+        blob0 = make_object(Blob, data=b"")
+        blob1 = make_object(Blob, data=b"ab\ncd\ncd\n")
+        blob2 = make_object(Blob, data=b"ab\n")
+        blob3 = make_object(Blob, data=b"cd\n")
+        blob4 = make_object(Blob, data=b"cd\ncd\n")
+
+        self.assertSimilar(similarity_score, 100, blob0, blob0)
+        self.assertSimilar(similarity_score, 0, blob0, blob1)
+        self.assertSimilar(similarity_score, 33, blob1, blob2)
+        self.assertSimilar(similarity_score, 33, blob1, blob3)
+        self.assertSimilar(similarity_score, 66, blob1, blob4)
+        self.assertSimilar(similarity_score, 0, blob2, blob3)
+        self.assertSimilar(similarity_score, 50, blob3, blob4)
+
+    test_similarity_score = functest_builder(
+        _do_test_similarity_score, _similarity_score_py
+    )
+    test_similarity_score_extension = ext_functest_builder(
+        _do_test_similarity_score, _similarity_score
+    )
+
+    def _do_test_similarity_score_cache(self, similarity_score) -> None:
+        blob1 = make_object(Blob, data=b"ab\ncd\n")
+        blob2 = make_object(Blob, data=b"ab\n")
+
+        block_cache: dict[bytes, dict[int, int]] = {}
+        self.assertEqual(50, similarity_score(blob1, blob2, block_cache=block_cache))
+        self.assertEqual({blob1.id, blob2.id}, set(block_cache))
+
+        def fail_chunks() -> None:
+            self.fail("Unexpected call to as_raw_chunks()")
+
+        blob1.as_raw_chunks = blob2.as_raw_chunks = fail_chunks
+        blob1.raw_length = lambda: 6
+        blob2.raw_length = lambda: 3
+        self.assertEqual(50, similarity_score(blob1, blob2, block_cache=block_cache))
+
+    test_similarity_score_cache = functest_builder(
+        _do_test_similarity_score_cache, _similarity_score_py
+    )
+    test_similarity_score_cache_extension = ext_functest_builder(
+        _do_test_similarity_score_cache, _similarity_score
+    )
+
+    def _do_test_similarity_score_min_score(self, similarity_score) -> None:
+        blob1 = make_object(Blob, data=b"a\nb\nc\nd\n")
+        blob2 = make_object(Blob, data=b"a\nb\ne\nf\n")
+        blob3 = make_object(Blob, data=b"w\nx\ny\nz\n")
+        self.assertEqual(50, similarity_score(blob1, blob2, min_score=50))
+        self.assertEqual(50, similarity_score(blob1, blob2, min_score=0))
+        self.assertLess(similarity_score(blob1, blob2, min_score=51), 51)
+        self.assertLess(similarity_score(blob1, blob3, min_score=1), 1)
+        self.assertEqual(100, similarity_score(blob1, blob1, min_score=100))
+        empty = make_object(Blob, data=b"")
+        self.assertEqual(100, similarity_score(empty, empty, min_score=100))
+
+    test_similarity_score_min_score = functest_builder(
+        _do_test_similarity_score_min_score, _similarity_score_py
+    )
+    test_similarity_score_min_score_extension = ext_functest_builder(
+        _do_test_similarity_score_min_score, _similarity_score
+    )
+
+    def _do_test_similarity_streaming(self, similarity_streaming) -> None:
+        class ShortReader:
+            # Return fewer bytes than requested, so that blocks span reads.
+            def __init__(self, data) -> None:
+                self._f = BytesIO(data)
+
+            def read(self, n):
+                return self._f.read(3)
+
+        data = [
+            b"",
+            b"ab\ncd\ncd\n",
+            b"ab\n",
+            b"cd\ncd\n",
+            b"a" * 70 + b"\nb\nccc\nd",
+            b"a" * 64 + b"\nb\nd",
+        ]
+        for data1 in data:
+            for data2 in data:
+                blob1 = make_object(Blob, data=data1)
+                blob2 = make_object(Blob, data=data2)
+                expected = _similarity_score_py(blob1, blob2)
+                self.assertEqual(
+                    expected, similarity_streaming(BytesIO(data1), BytesIO(data2))
+                )
+                self.assertEqual(
+                    expected,
+                    similarity_streaming(ShortReader(data1), ShortReader(data2)),
+                )
+        self.assertEqual(
+            50,
+            similarity_streaming(BytesIO(b"abcd"), BytesIO(b"abef"), block_size=2),
         )
 
-        # Filter to file_a only
-        changes = list(tree_changes(self.store, tree1.id, tree2.id, paths=[b"file_a"]))
+    test_similarity_streaming_extension = ext_functest_builder(
+        _do_test_similarity_streaming, _similarity_streaming
+    )
+
+    def _do_test_similarity_score_identical(self, similarity_score) -> None:
+        blob1 = make_object(Blob, data=b"ab\ncd\n")
+        blob2 = make_object(Blob, data=b"ab\ncd\n")
+        self.assertEqual(blob1.id, blob2.id)
+
+        def fail_chunks() -> None:
+            self.fail("Unexpected call to as_raw_chunks()")
+
+        blob1.as_raw_chunks = blob2.as_raw_chunks = fail_chunks
+        block_cache: dict[bytes, dict[int, int]] = {}
+        self.assertEqual(100, similarity_score(blob1, blob2, block_cache=block_cache))
+        self.assertEqual({}, block_cache)
+
+    test_similarity_score_identical = functest_builder(
+        _do_test_similarity_score_identical, _similarity_score_py
+    )
+    test_similarity_score_identical_extension = ext_functest_builder(
+        _do_test_similarity_score_identical, _similarity_score
+    )
+
+    def _do_test_block_count_cache(self, cache_cls) -> None:
+        blob1 = make_object(Blob, data=b"ab\ncd\n")
+        blob2 = make_object(Blob, data=b"ab\n")
+        cache = cache_cls()
+        self.assertEqual(0, len(cache))
+        self.assertEqual(_count_blocks_py(blob1), cache.get(blob1))
+        self.assertEqual(1, len(cache))
+        self.assertIn(blob1.id, cache)
+        self.assertNotIn(blob2.id, cache)
+        self.assertRaises(KeyError, lambda: cache[blob2.id])
+
+        def fail_chunks() -> None:
+            self.fail("Unexpected call to as_raw_chunks()")
+
+        blob1.as_raw_chunks = fail_chunks
+        blob1.raw_length = lambda: 6
+        self.assertEqual(_count_blocks_py(blob2), cache.get(blob2))
+        self.assertEqual(_count_blocks_py(blob2), cache[blob2.id])
+        self.assertEqual(2, len(cache))
+        self.assertEqual(50, _similarity_score(blob1, blob2, block_cache=cache))
         self.assertEqual(
-            [
-                TreeChange(
-                    CHANGE_MODIFY, (b"file_a", F, blob_a.id), (b"file_a", F, blob_b.id)
-                ),
-            ],
-            changes,
+            [(b"a", b"b", 50)],
+            _score_candidates(
+                [TreeEntry(b"a", F, blob1.id)], [TreeEntry(b"b", F, blob2.id)], cache, 0
+            ),
         )
 
-        # Filter to file_c only
-        changes = list(tree_changes(self.store, tree1.id, tree2.id, paths=[b"file_c"]))
+        cache.clear()
+        self.assertEqual(0, len(cache))
+        cache[blob1.id] = {1: 6}
+        self.assertEqual({1: 6}, cache.get(blob1))
+        # Unsigned keys, such as those of stable hashes, can be set too.
+        cache[blob2.id] = {2**64 - 1: 1, 1: 2}
+        self.assertEqual({2**64 - 1: 1, 1: 2}, cache[blob2.id])
+        self.assertEqual(33, _similarity_score(blob1, blob2, block_cache=cache))
+
+    test_block_count_cache = functest_builder(
+        _do_test_block_count_cache, BlockCountCache_py
+    )
+
+    def test_block_count_cache_extension(self) -> None:
+        if BlockCountCache is BlockCountCache_py:
+            self.skipTest("BlockCountCache extension not found")
+        self._do_test_block_count_cache(BlockCountCache)
+
+    def _do_test_block_accumulator(self, accumulator_cls) -> None:
+        blob1 = make_object(Blob, data=b"ab\ncd")
+        blob2 = make_object(Blob, data=b"ef\nab\n")
+        acc = accumulator_cls()
+        self.assertEqual({}, acc.counts())
+        acc.add(blob1)
+        self.assertEqual(_count_blocks_py(blob1), acc.counts())
+        acc.add(blob2)
+        self.assertBlockCountEqual({b"ab\n": 6, b"cd": 2, b"ef\n": 3}, acc.counts())
+        acc.reset()
+        self.assertEqual({}, acc.counts())
+
+        acc = accumulator_cls(carry=True)
+        acc.add(blob1)
+        # The partial block is counted, but still carried over.
+        self.assertEqual(_count_blocks_py(blob1), acc.counts())
+        acc.add(blob2)
+        self.assertBlockCountEqual({b"ab\n": 6, b"cdef\n": 5}, acc.counts())
         self.assertEqual(
-            [
-                TreeChange.add(TreeEntry(b"file_c", F, blob_c.id)),
-            ],
-            changes,
+            _count_blocks_py(make_object(Blob, data=blob1.data + blob2.data)),
+            acc.counts(),
         )
+        acc.add(blob1)
+        acc.reset()
+        acc.add(blob2)
+        self.assertEqual(_count_blocks_py(blob2), acc.counts())
 
-        # Filter to multiple files
-        changes = list(
-            tree_changes(self.store, tree1.id, tree2.id, paths=[b"file_a", b"file_c"])
+        acc = accumulator_cls(block_size=2, carry=True)
+        acc.add(make_object(Blob, data=b"a"))
+        acc.add(make_object(Blob, data=b"bc"))
+        self.assertBlockCountEqual({b"ab": 2, b"c": 1}, acc.counts())
+
+    test_block_accumulator = functest_builder(
+        _do_test_block_accumulator, BlockAccumulator_py
+    )
+
+    def test_block_accumulator_extension(self) -> None:
+        if BlockAccumulator is BlockAccumulator_py:
+            self.skipTest("BlockAccumulator extension not found")
+        self._do_test_block_accumulator(BlockAccumulator)
+
+    def test_same_sha(self) -> None:
+        hexsha = b"ab" * 20
+        self.assertTrue(_same_sha(hexsha, hexsha))
+        self.assertTrue(_same_sha(hexsha, hex_to_sha(hexsha)))
+        self.assertTrue(_same_sha(hex_to_sha(hexsha), hexsha))
+        self.assertFalse(_same_sha(hexsha, b"ac" * 20))
+        self.assertFalse(_same_sha(hexsha, hex_to_sha(b"ac" * 20)))
+        self.assertFalse(_same_sha(b"zz" * 20, b"\0" * 20))
+
+    def _do_test_find_exact_renames(self, find_exact_renames) -> None:
+        sha1 = b"1" * 40
+        sha2 = b"2" * 40
+        deletes = [
+            TreeEntry(b"c", F, sha1),
+            TreeEntry(b"a", F, sha1),
+            TreeEntry(b"b", F, sha2),
+            TreeEntry(b"d", F, b"3" * 40),
+        ]
+        adds = [
+            TreeEntry(b"y", F, sha1),
+            TreeEntry(b"x", F, sha1),
+            TreeEntry(b"z", 0o120000, sha2),
+            TreeEntry(b"w", F, b"4" * 40),
+        ]
+        self.assertEqual(
+            (
+                [(b"a", b"x"), (b"c", b"y")],
+                [deletes[2], deletes[3]],
+                [adds[2], adds[3]],
+            ),
+            find_exact_renames(deletes, adds),
         )
+        # With more deletes than adds of a SHA, the first paths are paired.
         self.assertEqual(
-            [
-                TreeChange(
-                    CHANGE_MODIFY, (b"file_a", F, blob_a.id), (b"file_a", F, blob_b.id)
-                ),
-                TreeChange.add(TreeEntry(b"file_c", F, blob_c.id)),
-            ],
-            changes,
+            ([(b"a", b"y")], [deletes[0]], []),
+            find_exact_renames(deletes[:2], adds[:1]),
         )
+        self.assertEqual(([], deletes[:1], []), find_exact_renames(deletes[:1], []))
 
-        # Filter to non-existent file
-        changes = list(tree_changes(self.store, tree1.id, tree2.id, paths=[b"file_d"]))
-        self.assertEqual([], changes)
+    test_find_exact_renames = functest_builder(
+        _do_test_find_exact_renames, _find_exact_renames_py
+    )
+    test_find_exact_renames_extension = ext_functest_builder(
+        _do_test_find_exact_renames, _find_exact_renames
+    )
 
-    def test_tree_changes_with_paths_dirs(self) -> None:
-        # Test filtering with directories
-        blob_a = make_object(Blob, data=b"a")
-        blob_b = make_object(Blob, data=b"b")
-        blob_c = make_object(Blob, data=b"c")
-        tree1 = self.commit_tree(
-            [
-                (b"dir1/file_a", blob_a),
-                (b"dir2/file_b", blob_b),
-            ]
-        )
-        tree2 = self.commit_tree(
-            [
-                (b"dir1/file_a", blob_b),  # modified
-                (b"dir1/file_c", blob_c),  # added
-                (b"dir2/file_b", blob_b),  # unchanged
+    def _do_test_find_exact_renames_sha256(self, find_exact_renames) -> None:
+        for sha1, sha2 in [(b"1" * 64, b"2" * 64), (b"\x11" * 32, b"\x22" * 32)]:
+            deletes = [TreeEntry(b"b", F, sha1), TreeEntry(b"a", F, sha2)]
+            adds = [
+                TreeEntry(b"x", F, sha2),
+                TreeEntry(b"y", F, sha1),
+                TreeEntry(b"z", F, sha1[:20]),
             ]
+            self.assertEqual(
+                ([(b"a", b"x"), (b"b", b"y")], [], [adds[2]]),
+                find_exact_renames(deletes, adds),
+            )
+        # A SHA-1 sha isn't paired with a SHA-256 sha it is a prefix of.
+        self.assertEqual(
+            ([], [TreeEntry(b"a", F, b"1" * 40)], [TreeEntry(b"b", F, b"1" * 64)]),
+            find_exact_renames(
+                [TreeEntry(b"a", F, b"1" * 40)], [TreeEntry(b"b", F, b"1" * 64)]
+            ),
         )
 
-        # Filter to dir1 only
-        changes = list(tree_changes(self.store, tree1.id, tree2.id, paths=[b"dir1"]))
+    test_find_exact_renames_sha256 = functest_builder(
+        _do_test_find_exact_renames_sha256, _find_exact_renames_py
+    )
+    test_find_exact_renames_sha256_extension = ext_functest_builder(
+        _do_test_find_exact_renames_sha256, _find_exact_renames
+    )
+
+    def _do_test_score_candidates(self, score_candidates) -> None:
+        blob1 = make_object(Blob, data=b"ab\ncd\n")
+        blob2 = make_object(Blob, data=b"ab\n")
+        blob3 = make_object(Blob, data=b"ef\n")
+        empty = make_object(Blob, data=b"")
+        block_cache = {
+            blob.id: _count_blocks_py(blob) for blob in (blob1, blob2, blob3, empty)
+        }
+        deletes = [TreeEntry(b"a", F, blob1.id), TreeEntry(b"e", F, empty.id)]
+        adds = [
+            TreeEntry(b"b", F, blob2.id),
+            TreeEntry(b"c", F, blob3.id),
+            TreeEntry(b"d", 0o120000, blob1.id),
+            TreeEntry(b"f", F, empty.id),
+        ]
         self.assertEqual(
-            [
-                TreeChange(
-                    CHANGE_MODIFY,
-                    (b"dir1/file_a", F, blob_a.id),
-                    (b"dir1/file_a", F, blob_b.id),
-                ),
-                TreeChange.add(TreeEntry(b"dir1/file_c", F, blob_c.id)),
-            ],
-            changes,
+            [(b"a", b"b", 50), (b"e", b"f", 100)],
+            score_candidates(deletes, adds, block_cache, 0),
         )
-
-        # Filter to specific file in directory
-        changes = list(
-            tree_changes(self.store, tree1.id, tree2.id, paths=[b"dir1/file_a"])
+        self.assertEqual(
+            [(b"e", b"f", 100)], score_candidates(deletes, adds, block_cache, 50)
         )
+        self.assertEqual([], score_candidates([], adds, block_cache, 0))
         self.assertEqual(
-            [
-                TreeChange(
-                    CHANGE_MODIFY,
-                    (b"dir1/file_a", F, blob_a.id),
-                    (b"dir1/file_a", F, blob_b.id),
-                ),
-            ],
-            changes,
+            [(b"b", b"c", 100)],
+            score_candidates(
+                [TreeEntry(b"b", F, blob2.id)],
+                [TreeEntry(b"c", F, blob2.id)],
+                block_cache,
+                50,
+            ),
         )
 
-        # Filter to dir2 (no changes)
-        changes = list(tree_changes(self.store, tree1.id, tree2.id, paths=[b"dir2"]))
-        self.assertEqual([], changes)
+    test_score_candidates = functest_builder(
+        _do_test_score_candidates, _score_candidates_py
+    )
+    test_score_candidates_extension = ext_functest_builder(
+        _do_test_score_candidates, _score_candidates
+    )
 
-    def test_tree_changes_with_paths_deep(self) -> None:
-        # Test filtering with deep directory structure
-        blob_a = make_object(Blob, data=b"a")
-        blob_b = make_object(Blob, data=b"b")
-        tree1 = self.commit_tree(
-            [
-                (b"a/b/c/file1", blob_a),
-                (b"a/b/d/file2", blob_a),
-                (b"x/y/z/file3", blob_a),
-            ]
+    def _do_test_score_candidates_best_only(self, score_candidates) -> None:
+        blob1 = make_object(Blob, data=b"ab\n")
+        blob2 = make_object(Blob, data=b"ab\ncd\n")
+        blob3 = make_object(Blob, data=b"ab\ncd\nef\n")
+        blob4 = make_object(Blob, data=b"ab\ncd\ngh\n")
+        block_cache = {
+            blob.id: _count_blocks_py(blob) for blob in (blob1, blob2, blob3, blob4)
+        }
+        deletes = [
+            TreeEntry(b"a", F, blob1.id),
+            TreeEntry(b"b", F, blob2.id),
+            TreeEntry(b"c", F, blob3.id),
+            TreeEntry(b"d", F, blob2.id),
+        ]
+        adds = [
+            TreeEntry(b"x", F, blob4.id),
+            TreeEntry(b"y", F, blob3.id),
+            TreeEntry(b"z", 0o120000, blob3.id),
+        ]
+        # Ties go to the first delete.
+        self.assertEqual(
+            [(b"b", b"x", 66), (b"c", b"y", 100)],
+            score_candidates(deletes, adds, block_cache, 0, best_only=True),
         )
-        tree2 = self.commit_tree(
-            [
-                (b"a/b/c/file1", blob_b),  # modified
-                (b"a/b/d/file2", blob_a),  # unchanged
-                (b"x/y/z/file3", blob_b),  # modified
-            ]
+        self.assertEqual(
+            [(b"c", b"y", 100)],
+            score_candidates(deletes, adds, block_cache, 66, best_only=True),
+        )
+        all_pairs = score_candidates(deletes, adds, block_cache, 0)
+        self.assertEqual(8, len(all_pairs))
+        for _, add_path, score in score_candidates(
+            deletes, adds, block_cache, 0, best_only=True
+        ):
+            self.assertEqual(score, max(s for (_, a, s) in all_pairs if a == add_path))
+        self.assertEqual([], score_candidates([], adds, block_cache, 0, best_only=True))
+        # The tie-break doesn't depend on the order of deletes.
+        self.assertEqual(
+            [(b"b", b"x", 66), (b"c", b"y", 100)],
+            score_candidates(deletes[::-1], adds, block_cache, 0, best_only=True),
         )
 
-        # Filter to a/b/c
-        changes = list(tree_changes(self.store, tree1.id, tree2.id, paths=[b"a/b/c"]))
+    test_score_candidates_best_only = functest_builder(
+        _do_test_score_candidates_best_only, _score_candidates_py
+    )
+    test_score_candidates_best_only_extension = ext_functest_builder(
+        _do_test_score_candidates_best_only, _score_candidates
+    )
+
+    def _do_test_detect_split(self, detect_split) -> None:
+        deleted = make_object(Blob, data=b"".join(b"%d\n" % i for i in range(10)))
+        # The first half, the second half, and a bit of each.
+        blob1 = make_object(Blob, data=b"0\n1\n2\n3\n4\nx\n")
+        blob2 = make_object(Blob, data=b"y\n5\n6\n7\n8\n9\n")
+        blob3 = make_object(Blob, data=b"4\n5\n")
+        blob4 = make_object(Blob, data=b"z\n")
+        adds = [(b"d", blob4), (b"c", blob3), (b"b", blob2), (b"a", blob1)]
+        self.assertEqual([b"a", b"b"], detect_split(deleted, adds))
+        self.assertEqual([b"a", b"b"], detect_split(deleted, adds, None, 99))
+        self.assertEqual([b"a", b"b"], detect_split(deleted, adds[2:], None, 90))
+        self.assertEqual([], detect_split(deleted, adds[2:], None, 100))
+        # Adds are picked until the threshold is reached, largest first.
+        self.assertEqual([b"a"], detect_split(deleted, adds, None, 40))
         self.assertEqual(
-            [
-                TreeChange(
-                    CHANGE_MODIFY,
-                    (b"a/b/c/file1", F, blob_a.id),
-                    (b"a/b/c/file1", F, blob_b.id),
-                ),
-            ],
-            changes,
+            [b"a", b"c"], detect_split(deleted, adds[:2] + adds[3:], None, 50)
         )
+        self.assertEqual([], detect_split(deleted, adds[:2]))
+        self.assertEqual([], detect_split(deleted, []))
+        self.assertEqual([], detect_split(make_object(Blob, data=b""), adds))
 
-        # Filter to a/b (should include both subdirs)
-        changes = list(tree_changes(self.store, tree1.id, tree2.id, paths=[b"a/b"]))
+        block_cache: dict[bytes, dict[int, int]] = {}
+        detect_split(deleted, adds, block_cache)
         self.assertEqual(
-            [
-                TreeChange(
-                    CHANGE_MODIFY,
-                    (b"a/b/c/file1", F, blob_a.id),
-                    (b"a/b/c/file1", F, blob_b.id),
-                ),
-            ],
-            changes,
+            {blob.id for blob in (deleted, blob1, blob2, blob3, blob4)},
+            set(block_cache),
         )
 
-        # Filter to multiple deep paths
-        changes = list(
-            tree_changes(
-                self.store, tree1.id, tree2.id, paths=[b"a/b/c/file1", b"x/y/z/file3"]
+    test_detect_split = functest_builder(_do_test_detect_split, _detect_split_py)
+    test_detect_split_extension = ext_functest_builder(
+        _do_test_detect_split, _detect_split
+    )
+
+    def _do_test_score_candidates_limited(self, score_candidates_limited) -> None:
+        blob1 = make_object(Blob, data=b"ab\ncd\n")
+        blob2 = make_object(Blob, data=b"ab\ncd\nef\n")
+        block_cache = {blob.id: _count_blocks_py(blob) for blob in (blob1, blob2)}
+        deletes = [TreeEntry(b"a", F, blob1.id), TreeEntry(b"b", F, blob2.id)]
+        adds = [TreeEntry(b"c", F, blob2.id), TreeEntry(b"d", 0o120000, blob1.id)]
+        all_pairs = [(b"a", b"c", 66), (b"b", b"c", 100)]
+        for rename_limit in (4, None):
+            self.assertEqual(
+                (all_pairs, False),
+                score_candidates_limited(deletes, adds, block_cache, 0, rename_limit),
             )
+        # Over the limit, only exact renames are found.
+        self.assertEqual(
+            ([(b"b", b"c", 100)], True),
+            score_candidates_limited(deletes, adds, block_cache, 0, 3),
         )
         self.assertEqual(
-            [
-                TreeChange(
-                    CHANGE_MODIFY,
-                    (b"a/b/c/file1", F, blob_a.id),
-                    (b"a/b/c/file1", F, blob_b.id),
-                ),
-                TreeChange(
-                    CHANGE_MODIFY,
-                    (b"x/y/z/file3", F, blob_a.id),
-                    (b"x/y/z/file3", F, blob_b.id),
-                ),
-            ],
-            changes,
+            ([(b"b", b"c", 100)], True),
+            score_candidates_limited(deletes, adds, block_cache, 0, 0, True),
+        )
+        self.assertEqual(
+            ([], False), score_candidates_limited([], adds, block_cache, 0, 0)
         )
 
-    def test_tree_changes_with_paths_include_unchanged(self) -> None:
-        # Test path filtering with want_unchanged=True
-        blob_a = make_object(Blob, data=b"a")
-        blob_b = make_object(Blob, data=b"b")
-        tree1 = self.commit_tree(
-            [
-                (b"file_a", blob_a),
-                (b"file_b", blob_b),
-            ]
+    test_score_candidates_limited = functest_builder(
+        _do_test_score_candidates_limited, _score_candidates_limited_py
+    )
+    test_score_candidates_limited_extension = ext_functest_builder(
+        _do_test_score_candidates_limited, _score_candidates_limited
+    )
+
+    def _do_test_score_candidates_limited_scorer(
+        self, score_candidates_limited
+    ) -> None:
+        sha1, sha3 = b"1" * 40, b"3" * 40
+        deletes = [TreeEntry(b"b", F, sha1), TreeEntry(b"a", F, b"2" * 40)]
+        adds = [TreeEntry(b"x", F, sha3), TreeEntry(b"y", F, sha1)]
+        # Over the limit, the scorer isn't called at all.
+        self.assertEqual(
+            ([(b"b", b"y", 100)], True),
+            score_candidates_limited(
+                deletes, adds, {}, 50, 1, scorer=lambda d, a: {}[0]
+            ),
         )
-        tree2 = self.commit_tree(
-            [
-                (b"file_a", blob_b),  # modified
-                (b"file_b", blob_b),  # unchanged
-            ]
+
+    test_score_candidates_limited_scorer = functest_builder(
+        _do_test_score_candidates_limited_scorer, _score_candidates_limited_py
+    )
+    test_score_candidates_limited_scorer_extension = ext_functest_builder(
+        _do_test_score_candidates_limited_scorer, _score_candidates_limited
+    )
+
+    def _do_test_score_candidates_limited_exclude_prefixes(
+        self, score_candidates_limited
+    ) -> None:
+        blob = make_object(Blob, data=b"a\nb\nc\nd\n")
+        block_cache = {blob.id: _count_blocks_py(blob)}
+        deletes = [
+            TreeEntry(b"a", F, blob.id),
+            TreeEntry(b"vendor/b", F, b"2" * 40),
+        ]
+        adds = [TreeEntry(b"c", F, blob.id), TreeEntry(b"vendor/d", F, blob.id)]
+        # Excluded pairs don't count towards the rename limit.
+        self.assertEqual(
+            ([(b"a", b"c", 100)], False),
+            score_candidates_limited(
+                deletes, adds, block_cache, 50, 1, exclude_prefixes=[b"vendor/"]
+            ),
         )
 
-        # Filter to file_b with want_unchanged=True
-        changes = list(
-            tree_changes(
-                self.store, tree1.id, tree2.id, paths=[b"file_b"], want_unchanged=True
-            )
+    test_score_candidates_limited_exclude_prefixes = functest_builder(
+        _do_test_score_candidates_limited_exclude_prefixes,
+        _score_candidates_limited_py,
+    )
+    test_score_candidates_limited_exclude_prefixes_extension = ext_functest_builder(
+        _do_test_score_candidates_limited_exclude_prefixes, _score_candidates_limited
+    )
+
+    def _do_test_score_candidates_scorer(self, score_candidates) -> None:
+        sha1, sha2, sha3 = b"1" * 40, b"2" * 40, b"3" * 40
+        deletes = [TreeEntry(b"b", F, sha1), TreeEntry(b"a", F, sha2)]
+        adds = [
+            TreeEntry(b"x", F, sha3),
+            TreeEntry(b"y", F, sha1),
+            TreeEntry(b"z", 0o120000, sha3),
+        ]
+        scored = []
+
+        def scorer(delete, add):
+            scored.append((delete.path, add.path))
+            return {b"x": 60, b"y": 30}[add.path] + (delete.path == b"a")
+
+        # No block counts are needed, and the scorer isn't called for pairs
+        # with the same SHA or different file types.
+        self.assertEqual(
+            [(b"b", b"x", 60), (b"b", b"y", 100), (b"a", b"x", 61)],
+            score_candidates(deletes, adds, {}, 50, scorer=scorer),
         )
+        self.assertEqual([(b"b", b"x"), (b"a", b"x"), (b"a", b"y")], scored)
         self.assertEqual(
-            [
-                TreeChange(
-                    CHANGE_UNCHANGED,
-                    (b"file_b", F, blob_b.id),
-                    (b"file_b", F, blob_b.id),
-                ),
-            ],
-            changes,
+            [(b"a", b"x", 61), (b"b", b"y", 100)],
+            score_candidates(deletes, adds, {}, 50, best_only=True, scorer=scorer),
+        )
+        self.assertRaises(
+            KeyError, score_candidates, deletes, adds, {}, 50, scorer=lambda d, a: {}[0]
         )
 
-        # Filter to both files with want_unchanged=True
-        changes = list(
-            tree_changes(
-                self.store,
-                tree1.id,
-                tree2.id,
-                paths=[b"file_a", b"file_b"],
-                want_unchanged=True,
+    test_score_candidates_scorer = functest_builder(
+        _do_test_score_candidates_scorer, _score_candidates_py
+    )
+    test_score_candidates_scorer_extension = ext_functest_builder(
+        _do_test_score_candidates_scorer, _score_candidates
+    )
+
+    def _do_test_score_candidates_same_extension_only(self, score_candidates) -> None:
+        blob1 = make_object(Blob, data=b"a\nb\nc\nd\n")
+        blob2 = make_object(Blob, data=b"a\nb\nc\ne\n")
+        block_cache = {blob.id: _count_blocks_py(blob) for blob in (blob1, blob2)}
+        deletes = [
+            TreeEntry(b"d.x/a.c", F, blob1.id),
+            TreeEntry(b"b.h", F, blob1.id),
+            TreeEntry(b"c", F, blob1.id),
+        ]
+        adds = [
+            TreeEntry(b"e/f.c", F, blob2.id),
+            TreeEntry(b"g.cc", F, blob1.id),
+            TreeEntry(b"d.x/h", F, blob2.id),
+        ]
+        self.assertEqual(9, len(score_candidates(deletes, adds, block_cache, 50)))
+        # Only files with the same extension, or both without one, are
+        # paired, even if they have the same contents.
+        for best_only in (False, True):
+            self.assertEqual(
+                [(b"d.x/a.c", b"e/f.c", 75), (b"c", b"d.x/h", 75)],
+                score_candidates(
+                    deletes, adds, block_cache, 50, best_only, same_extension_only=True
+                ),
             )
-        )
+
+    test_score_candidates_same_extension_only = functest_builder(
+        _do_test_score_candidates_same_extension_only, _score_candidates_py
+    )
+    test_score_candidates_same_extension_only_extension = ext_functest_builder(
+        _do_test_score_candidates_same_extension_only, _score_candidates
+    )
+
+    def _do_test_score_candidates_exclude_prefixes(self, score_candidates) -> None:
+        blob1 = make_object(Blob, data=b"a\nb\nc\nd\n")
+        blob2 = make_object(Blob, data=b"a\nb\nc\ne\n")
+        # Excluded entries need no block counts.
+        block_cache = {blob1.id: _count_blocks_py(blob1)}
+        deletes = [
+            TreeEntry(b"a", F, blob1.id),
+            TreeEntry(b"vendor/b", F, blob2.id),
+        ]
+        adds = [
+            TreeEntry(b"c", F, blob1.id),
+            TreeEntry(b"vendor/d", F, blob1.id),
+            TreeEntry(b"node_modules/e", F, blob2.id),
+        ]
+        exclude = [b"vendor/", b"node_modules/"]
         self.assertEqual(
-            [
-                TreeChange(
-                    CHANGE_MODIFY, (b"file_a", F, blob_a.id), (b"file_a", F, blob_b.id)
-                ),
-                TreeChange(
-                    CHANGE_UNCHANGED,
-                    (b"file_b", F, blob_b.id),
-                    (b"file_b", F, blob_b.id),
-                ),
-            ],
-            changes,
+            [(b"a", b"c", 100)],
+            score_candidates(deletes, adds, block_cache, 50, exclude_prefixes=exclude),
         )
 
+    test_score_candidates_exclude_prefixes = functest_builder(
+        _do_test_score_candidates_exclude_prefixes, _score_candidates_py
+    )
+    test_score_candidates_exclude_prefixes_extension = ext_functest_builder(
+        _do_test_score_candidates_exclude_prefixes, _score_candidates
+    )
 
-class RenameDetectionTest(DiffTestCase):
-    def _do_test_count_blocks(self, count_blocks) -> None:
-        blob = make_object(Blob, data=b"a\nb\na\n")
-        self.assertBlockCountEqual({b"a\n": 4, b"b\n": 2}, count_blocks(blob))
+    def test_path_extension(self) -> None:
+        self.assertEqual(b"c", _path_extension(b"a.c"))
+        self.assertEqual(b"gz", _path_extension(b"d/a.tar.gz"))
+        self.assertEqual(b"", _path_extension(b"d.x/a"))
+        self.assertEqual(b"", _path_extension(b"a."))
+        self.assertEqual(b"bashrc", _path_extension(b".bashrc"))
 
-    test_count_blocks = functest_builder(_do_test_count_blocks, _count_blocks_py)
-    test_count_blocks_extension = ext_functest_builder(
-        _do_test_count_blocks, _count_blocks
+    def _do_test_basename_rename_candidates(self, basename_rename_candidates) -> None:
+        blob = make_object(Blob, data=b"a")
+        deletes = [
+            TreeEntry(b"a/x.c", F, blob.id),
+            TreeEntry(b"y", F, blob.id),
+            TreeEntry(b"z", F, blob.id),
+        ]
+        adds = [
+            TreeEntry(b"b/x.c", F, blob.id),
+            TreeEntry(b"b/y", F, blob.id),
+            TreeEntry(b"c/x.c", F, blob.id),
+            TreeEntry(b"z", 0o120000, blob.id),
+            TreeEntry(b"x", F, blob.id),
+        ]
+        # Only the names after the last slash count, and the same path isn't
+        # a move.
+        self.assertEqual(
+            [(b"a/x.c", b"b/x.c"), (b"a/x.c", b"c/x.c"), (b"y", b"b/y")],
+            basename_rename_candidates(deletes, adds),
+        )
+        self.assertEqual([], basename_rename_candidates(deletes, []))
+
+    test_basename_rename_candidates = functest_builder(
+        _do_test_basename_rename_candidates, _basename_rename_candidates_py
+    )
+    test_basename_rename_candidates_extension = ext_functest_builder(
+        _do_test_basename_rename_candidates, _basename_rename_candidates
     )
 
-    def _do_test_count_blocks_no_newline(self, count_blocks) -> None:
-        blob = make_object(Blob, data=b"a\na")
-        self.assertBlockCountEqual({b"a\n": 2, b"a": 1}, _count_blocks(blob))
+    def _do_test_detect_dir_renames(self, detect_dir_renames) -> None:
+        blob = make_object(Blob, data=b"a")
 
-    test_count_blocks_no_newline = functest_builder(
-        _do_test_count_blocks_no_newline, _count_blocks_py
+        def rename(old_path, new_path):
+            return TreeChange(
+                CHANGE_RENAME,
+                TreeEntry(old_path, F, blob.id),
+                TreeEntry(new_path, F, blob.id),
+            )
+
+        renames = [
+            rename(b"src/a", b"lib/a"),
+            rename(b"src/b", b"lib/b"),
+            rename(b"src/c", b"other/c"),
+            rename(b"src/x/d", b"lib/x/d"),
+            rename(b"doc/e", b"doc/f"),
+            rename(b"g", b"top/g"),
+            rename(b"h/i", b"i"),
+            TreeChange(
+                CHANGE_COPY,
+                TreeEntry(b"t/j", F, blob.id),
+                TreeEntry(b"u/j", F, blob.id),
+            ),
+        ]
+        self.assertEqual(
+            [(b"src", b"lib", 66), (b"src/x", b"lib/x", 100)],
+            detect_dir_renames(renames),
+        )
+        self.assertEqual(
+            [(b"src", b"lib", 66), (b"src", b"other", 33), (b"src/x", b"lib/x", 100)],
+            detect_dir_renames(renames, threshold=30),
+        )
+        self.assertEqual(
+            [(b"src/x", b"lib/x", 100)], detect_dir_renames(renames, threshold=66)
+        )
+        self.assertEqual([], detect_dir_renames([]))
+
+    test_detect_dir_renames = functest_builder(
+        _do_test_detect_dir_renames, _detect_dir_renames_py
     )
-    test_count_blocks_no_newline_extension = ext_functest_builder(
-        _do_test_count_blocks_no_newline, _count_blocks
+    test_detect_dir_renames_extension = ext_functest_builder(
+        _do_test_detect_dir_renames, _detect_dir_renames
     )
 
-    def assertBlockCountEqual(self, expected, got) -> None:
+    def _do_test_best_rename_for(self, best_rename_for) -> None:
+        deleted = make_object(Blob, data=b"a\nb\nc\nd\n")
+        blob1 = make_object(Blob, data=b"a\nb\nc\ne\n")
+        blob2 = make_object(Blob, data=b"a\nb\ne\nf\n")
+        blob3 = make_object(Blob, data=b"a\nb\nc\nf\n")
+        blob4 = make_object(Blob, data=b"w\nx\ny\nz\n")
+        adds = [(b"y", blob2), (b"x", blob1), (b"z", blob4)]
+        self.assertEqual((b"x", 75), best_rename_for(deleted, b"old", adds))
         self.assertEqual(
-            {(hash(block) & 0xFFFFFFFF): count for (block, count) in expected.items()},
-            {(block & 0xFFFFFFFF): count for (block, count) in got.items()},
+            (b"y", 50), best_rename_for(deleted, b"old", adds[:1], None, 0)
         )
+        self.assertIsNone(best_rename_for(deleted, b"old", adds, threshold=75))
+        self.assertIsNone(best_rename_for(deleted, b"old", adds[2:], threshold=0))
+        self.assertIsNone(best_rename_for(deleted, b"old", []))
 
-    def _do_test_count_blocks_chunks(self, count_blocks) -> None:
-        blob = ShaFile.from_raw_chunks(Blob.type_num, [b"a\nb", b"\na\n"])
-        self.assertBlockCountEqual({b"a\n": 4, b"b\n": 2}, _count_blocks(blob))
+        # Ties go to an add with the same basename, then the smallest path.
+        adds = [(b"dir/x", blob1), (b"w", blob3), (b"dir/y/old", blob3)]
+        self.assertEqual((b"dir/y/old", 75), best_rename_for(deleted, b"old", adds))
+        self.assertEqual((b"dir/x", 75), best_rename_for(deleted, b"new", adds))
 
-    test_count_blocks_chunks = functest_builder(
-        _do_test_count_blocks_chunks, _count_blocks_py
+        block_cache: dict[bytes, dict[int, int]] = {}
+        best_rename_for(deleted, b"old", adds, block_cache)
+        self.assertEqual({deleted.id, blob1.id, blob3.id}, set(block_cache))
+
+    test_best_rename_for = functest_builder(
+        _do_test_best_rename_for, _best_rename_for_py
     )
-    test_count_blocks_chunks_extension = ext_functest_builder(
-        _do_test_count_blocks_chunks, _count_blocks
+    test_best_rename_for_extension = ext_functest_builder(
+        _do_test_best_rename_for, _best_rename_for
     )
 
-    def _do_test_count_blocks_long_lines(self, count_blocks) -> None:
-        a = b"a" * 64
-        data = a + b"xxx\ny\n" + a + b"zzz\n"
-        blob = make_object(Blob, data=data)
-        self.assertBlockCountEqual(
-            {b"a" * 64: 128, b"xxx\n": 4, b"y\n": 2, b"zzz\n": 4},
-            _count_blocks(blob),
+    def _do_test_score_copy_candidates(self, score_copy_candidates) -> None:
+        blob1 = make_object(Blob, data=b"ab\ncd\n")
+        blob2 = make_object(Blob, data=b"ab\ncd\nef\n")
+        blob3 = make_object(Blob, data=b"gh\n")
+        block_cache = {
+            blob.id: _count_blocks_py(blob) for blob in (blob1, blob2, blob3)
+        }
+        sources = [TreeEntry(b"a", F, blob1.id), TreeEntry(b"b", F, blob3.id)]
+        adds = [TreeEntry(b"c", F, blob2.id), TreeEntry(b"d", F, blob1.id)]
+        self.assertEqual(
+            [(b"a", b"c", 66), (b"a", b"d", 100)],
+            score_copy_candidates(sources, adds, block_cache, 50),
+        )
+        self.assertEqual(
+            [(b"a", b"d", 100)], score_copy_candidates(sources, adds, block_cache, 66)
         )
 
-    test_count_blocks_long_lines = functest_builder(
-        _do_test_count_blocks_long_lines, _count_blocks_py
+    test_score_copy_candidates = functest_builder(
+        _do_test_score_copy_candidates, _score_copy_candidates_py
     )
-    test_count_blocks_long_lines_extension = ext_functest_builder(
-        _do_test_count_blocks_long_lines, _count_blocks
+    test_score_copy_candidates_extension = ext_functest_builder(
+        _do_test_score_copy_candidates, _score_copy_candidates
     )
 
-    def assertSimilar(self, expected_score, blob1, blob2) -> None:
-        self.assertEqual(expected_score, _similarity_score(blob1, blob2))
-        self.assertEqual(expected_score, _similarity_score(blob2, blob1))
+    def _do_test_annotate_rename_scores(self, annotate_rename_scores) -> None:
+        blob1 = make_object(Blob, data=b"a\nb\nc\nd\n")
+        blob2 = make_object(Blob, data=b"a\nb\nc\ne\n")
+        block_cache = {blob.id: _count_blocks_py(blob) for blob in (blob1, blob2)}
+        rename = TreeChange(
+            CHANGE_RENAME, TreeEntry(b"a", F, blob1.id), TreeEntry(b"b", F, blob2.id)
+        )
+        copy = TreeChange(
+            CHANGE_COPY, TreeEntry(b"a", F, blob1.id), TreeEntry(b"c", F, blob1.id)
+        )
+        self.assertEqual(
+            [(rename, _similarity_score_py(blob1, blob2)), (copy, 100)],
+            annotate_rename_scores([rename, copy], block_cache),
+        )
+        self.assertEqual([], annotate_rename_scores([], block_cache))
+        add = TreeChange.add(TreeEntry(b"d", F, blob2.id))
+        self.assertRaises(ValueError, annotate_rename_scores, [add], block_cache)
+
+    test_annotate_rename_scores = functest_builder(
+        _do_test_annotate_rename_scores, _annotate_rename_scores_py
+    )
+    test_annotate_rename_scores_extension = ext_functest_builder(
+        _do_test_annotate_rename_scores, _annotate_rename_scores
+    )
 
-    def test_similarity_score(self) -> None:
-        blob0 = make_object(Blob, data=b"")
-        blob1 = make_object(Blob, data=b"ab\ncd\ncd\n")
-        blob2 = make_object(Blob, data=b"ab\n")
-        blob3 = make_object(Blob, data=b"cd\n")
-        blob4 = make_object(Blob, data=b"cd\ncd\n")
+    def _do_test_bucket_changes(self, bucket_changes) -> None:
+        blob1 = make_object(Blob, data=b"1")
+        blob2 = make_object(Blob, data=b"2")
+        entry_a = TreeEntry(b"a", F, blob1.id)
+        entry_b = TreeEntry(b"b", F, blob1.id)
+        entry_c = TreeEntry(b"c", F, blob2.id)
+        add_a = TreeChange.add(entry_a)
+        add_c = TreeChange.add(entry_c)
+        delete_b = TreeChange.delete(entry_b)
+        modify = TreeChange(CHANGE_MODIFY, entry_a, entry_c)
+        unchanged = TreeChange(CHANGE_UNCHANGED, entry_b, entry_b)
+        self.assertEqual(
+            ([add_a, add_c], [delete_b], [modify, unchanged]),
+            tuple(bucket_changes([add_a, modify, delete_b, unchanged, add_c])),
+        )
+        self.assertEqual(([], [], []), tuple(bucket_changes(iter([]))))
+        tree1 = self.commit_tree([(b"a", blob1), (b"b", blob1)])
+        tree2 = self.commit_tree([(b"a", blob2), (b"c", blob2)])
+        adds, deletes, others = bucket_changes(
+            tree_changes(self.store, tree1.id, tree2.id)
+        )
+        self.assertEqual([TreeChange.add((b"c", F, blob2.id))], adds)
+        self.assertEqual([TreeChange.delete((b"b", F, blob1.id))], deletes)
+        self.assertEqual([CHANGE_MODIFY], [change.type for change in others])
 
-        self.assertSimilar(100, blob0, blob0)
-        self.assertSimilar(0, blob0, blob1)
-        self.assertSimilar(33, blob1, blob2)
-        self.assertSimilar(33, blob1, blob3)
-        self.assertSimilar(66, blob1, blob4)
-        self.assertSimilar(0, blob2, blob3)
-        self.assertSimilar(50, blob3, blob4)
+    test_bucket_changes = functest_builder(_do_test_bucket_changes, _bucket_changes_py)
+    test_bucket_changes_extension = ext_functest_builder(
+        _do_test_bucket_changes, _bucket_changes
+    )
 
-    def test_similarity_score_cache(self) -> None:
-        blob1 = make_object(Blob, data=b"ab\ncd\n")
-        blob2 = make_object(Blob, data=b"ab\n")
+    def _do_test_dir_change_summary(self, dir_change_summary) -> None:
+        blob1 = make_object(Blob, data=b"1")
+        blob2 = make_object(Blob, data=b"2")
+        tree1 = self.commit_tree(
+            [(b"a", blob1), (b"b/c", blob1), (b"b/d/e", blob1), (b"f/g", blob1)]
+        )
+        tree2 = self.commit_tree(
+            [(b"a", blob2), (b"b/d/e", blob2), (b"b/d/h", blob1), (b"f/g", blob1)]
+        )
+        self.assertEqual(
+            {
+                b"": (1, 1, 2),
+                b"b": (1, 1, 1),
+                b"b/d": (1, 0, 1),
+            },
+            dir_change_summary(tree_changes(self.store, tree1.id, tree2.id)),
+        )
+        self.assertEqual({}, dir_change_summary([]))
+        entry_a = TreeEntry(b"a", F, blob1.id)
+        entry_b = TreeEntry(b"x/y/b", F, blob1.id)
+        self.assertEqual(
+            {b"": (0, 0, 1), b"x": (0, 0, 1), b"x/y": (0, 0, 1)},
+            dir_change_summary(
+                [
+                    TreeChange(CHANGE_RENAME, entry_a, entry_b),
+                    TreeChange(CHANGE_UNCHANGED, entry_a, entry_a),
+                ]
+            ),
+        )
 
-        block_cache: dict[bytes, dict[int, int]] = {}
-        self.assertEqual(50, _similarity_score(blob1, blob2, block_cache=block_cache))
-        self.assertEqual({blob1.id, blob2.id}, set(block_cache))
+    test_dir_change_summary = functest_builder(
+        _do_test_dir_change_summary, _dir_change_summary_py
+    )
+    test_dir_change_summary_extension = ext_functest_builder(
+        _do_test_dir_change_summary, _dir_change_summary
+    )
 
-        def fail_chunks() -> None:
-            self.fail("Unexpected call to as_raw_chunks()")
+    def _do_test_tree_change_key(self, tree_change_key) -> None:
+        sha = b"abcd" * 10
+        entry_a = TreeEntry(b"a", F, sha)
+        entry_b = TreeEntry(b"b", F, sha)
+        self.assertEqual((b"a", b"a"), tree_change_key(TreeChange.add(entry_a)))
+        self.assertEqual((b"b", b"b"), tree_change_key(TreeChange.delete(entry_b)))
+        self.assertEqual(
+            (b"b", b"a"), tree_change_key(TreeChange(CHANGE_RENAME, entry_b, entry_a))
+        )
+        self.assertEqual(
+            (b"a", b"a"), tree_change_key(TreeChange(CHANGE_MODIFY, entry_a, entry_a))
+        )
 
-        blob1.as_raw_chunks = blob2.as_raw_chunks = fail_chunks
-        blob1.raw_length = lambda: 6
-        blob2.raw_length = lambda: 3
-        self.assertEqual(50, _similarity_score(blob1, blob2, block_cache=block_cache))
+    test_tree_change_key = functest_builder(
+        _do_test_tree_change_key, _tree_change_key_py
+    )
+    test_tree_change_key_extension = ext_functest_builder(
+        _do_test_tree_change_key, _tree_change_key
+    )
 
     def test_tree_entry_sort(self) -> None:
         sha = "abcd" * 10
@@ -1005,6 +4429,34 @@ class RenameDetectionTest(DiffTestCase):
             self.detect_renames(tree1, tree2),
         )
 
+    def test_renames_as_pairs(self) -> None:
+        blob1 = make_object(Blob, data=b"1")
+        blob2 = make_object(Blob, data=b"a\nb\nc\nd\n")
+        blob3 = make_object(Blob, data=b"a\nb\nc\ne\n")
+        tree1 = self.commit_tree([(b"a", blob1), (b"b", blob2)])
+        tree2 = self.commit_tree([(b"a", blob1), (b"c", blob1), (b"d", blob3)])
+        self.assertEqual(
+            [
+                TreeChange(CHANGE_COPY, (b"a", F, blob1.id), (b"c", F, blob1.id)),
+                TreeChange(CHANGE_RENAME, (b"b", F, blob2.id), (b"d", F, blob3.id)),
+            ],
+            self.detect_renames(tree1, tree2, find_copies_harder=True),
+        )
+        detector = RenameDetector(self.store, find_copies_harder=True)
+        self.assertEqual(
+            [
+                TreeChange(CHANGE_COPY, (b"a", F, blob1.id), (b"c", F, blob1.id)),
+                TreeChange.delete((b"b", F, blob2.id)),
+                TreeChange.add((b"d", F, blob3.id)),
+            ],
+            detector.changes_with_renames(tree1.id, tree2.id, as_pairs=True),
+        )
+        # The same detector goes back to renames when as_pairs isn't set.
+        self.assertEqual(
+            self.detect_renames(tree1, tree2, find_copies_harder=True),
+            detector.changes_with_renames(tree1.id, tree2.id),
+        )
+
     def test_exact_rename_split_different_type(self) -> None:
         blob = make_object(Blob, data=b"/foo")
         tree1 = self.commit_tree([(b"a", blob, 0o100644)])
@@ -1054,6 +4506,28 @@ class RenameDetectionTest(DiffTestCase):
             self.detect_renames(tree1, tree2),
         )
 
+    def test_rename_tie_break(self) -> None:
+        blob = make_object(Blob, data=b"a\nb\nc\nd\n")
+        new_blob = make_object(Blob, data=b"a\nb\nc\ne\n")
+        tree1 = self.commit_tree([(b"b", blob), (b"a", blob)])
+        # Exact and content renames both take the source with the smallest
+        # path.
+        for tree2 in (
+            self.commit_tree([(b"c", blob)]),
+            self.commit_tree([(b"c", new_blob)]),
+        ):
+            (rename,) = [
+                change
+                for change in self.detect_renames(tree1, tree2)
+                if change.type == CHANGE_RENAME
+            ]
+            self.assertEqual(b"a", rename.old.path)
+        deletes = [TreeEntry(b"b", F, blob.id), TreeEntry(b"a", F, blob.id)]
+        adds = [TreeEntry(b"c", F, blob.id)]
+        for find_exact_renames in (_find_exact_renames_py, _find_exact_renames):
+            self.assertEqual([(b"a", b"c")], find_exact_renames(deletes, adds)[0])
+            self.assertEqual([(b"a", b"c")], find_exact_renames(deletes[::-1], adds)[0])
+
     def test_exact_rename_many_to_many(self) -> None:
         blob = make_object(Blob, data=b"1")
         tree1 = self.commit_tree([(b"a", blob), (b"b", blob)])